@@ -1,6 +1,8 @@
 //! A demonstration of customization of exported aggregated metrics.
 //! Using match on origin metric kind or score type to alter publication output.
 
+#![allow(clippy::manual_map)]
+
 use dipstick::*;
 use std::time::Duration;
 
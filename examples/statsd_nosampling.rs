@@ -1,5 +1,7 @@
 //! A sample application sending ad-hoc counter values both to statsd _and_ to stdout.
 
+#![allow(clippy::unnecessary_cast)]
+
 use dipstick::*;
 use std::time::Duration;
 
@@ -1,20 +1,29 @@
 //! Maintain aggregated metrics for deferred reporting,
 
-use crate::attributes::{Attributes, MetricId, OnFlush, Prefixed, WithAttributes};
+use crate::attributes::{
+    Attributes, MetricId, OnFlush, Prefixed, Sampled, Sampling, WithAttributes,
+};
 use crate::clock::TimeHandle;
-use crate::input::{Input, InputDyn, InputKind, InputMetric, InputScope};
-use crate::name::MetricName;
+use crate::input::{AsValue, Counter, Gauge, Input, InputDyn, InputKind, InputMetric, InputScope};
+use crate::label::Labels;
+use crate::metrics;
+use crate::name::{MetricName, NameParts};
+use crate::pcg32;
 use crate::stats::ScoreType::*;
 use crate::stats::{stats_summary, ScoreType};
 use crate::{Flush, MetricValue, Void};
 
 use std::borrow::Borrow;
-use std::collections::BTreeMap;
-use std::isize;
+use std::cell::Cell;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::hash::{Hash, Hasher};
 use std::mem;
-use std::sync::atomic::AtomicIsize;
+use std::ptr;
 use std::sync::atomic::Ordering::*;
+use std::sync::atomic::{AtomicBool, AtomicIsize, AtomicU64, AtomicU8};
 use std::sync::Arc;
+use std::time::{Duration, SystemTime};
 use std::{fmt, io};
 
 #[cfg(not(feature = "parking_lot"))]
@@ -27,6 +36,262 @@ use parking_lot::RwLock;
 pub type Stat = Option<(InputKind, MetricName, MetricValue)>;
 pub type StatsFn = dyn Fn(InputKind, MetricName, ScoreType) -> Stat + Send + Sync + 'static;
 
+type FlushCallback = Arc<dyn Fn(&[ScoreType]) + Send + Sync + 'static>;
+
+/// The value labels of separately aggregated scores, sorted by key.
+type LabelKey = Vec<(String, Arc<String>)>;
+
+/// A gauge derived from the totals of two metrics of the bucket.
+#[derive(Debug)]
+struct Ratio {
+    numerator: MetricName,
+    denominator: MetricName,
+    scale: MetricValue,
+}
+
+/// Excludes metric updates while a consistent snapshot of the bucket is taken.
+#[derive(Debug, Default)]
+struct SnapshotGate {
+    enabled: AtomicBool,
+    /// Set once a gauge group is created, snapshots then exclude group updates.
+    grouped: AtomicBool,
+    lock: RwLock<()>,
+    /// Time of the oldest write since the last full flush, in microseconds since the gate was created plus one,
+    /// or zero if nothing was written since.
+    oldest_unflushed: (TimeHandle, AtomicU64),
+}
+
+thread_local! {
+    /// Address of the gate held by a gauge group update of this thread, its values must not lock it again.
+    static IN_GROUP: Cell<usize> = const { Cell::new(0) };
+}
+
+/// Marks a gate as held by this thread's gauge group update until dropped, even if a value panics.
+struct InGroup {
+    previous: usize,
+}
+
+impl InGroup {
+    fn enter(gate: &SnapshotGate) -> Self {
+        let previous = IN_GROUP.with(|in_group| in_group.replace(gate.address()));
+        InGroup { previous }
+    }
+}
+
+impl Drop for InGroup {
+    fn drop(&mut self) {
+        IN_GROUP.with(|in_group| in_group.set(self.previous));
+    }
+}
+
+impl SnapshotGate {
+    /// Returns true if snapshots must be taken under the write lock.
+    fn locking(&self) -> bool {
+        self.enabled.load(Relaxed) || self.grouped.load(Relaxed)
+    }
+
+    fn address(&self) -> usize {
+        ptr::addr_of!(*self) as usize
+    }
+
+    /// Time elapsed since the oldest write not yet flushed, if any.
+    fn oldest_unflushed_age(&self) -> Option<Duration> {
+        let (created, oldest) = &self.oldest_unflushed;
+        match oldest.load(Relaxed) {
+            0 => None,
+            oldest => Some(Duration::from_micros(
+                (created.elapsed_us() + 1).saturating_sub(oldest),
+            )),
+        }
+    }
+
+    /// Record a value, waiting for any snapshot in progress if consistency is enabled.
+    #[inline]
+    fn update(&self, scores: &AtomicScores, value: MetricValue) {
+        self.record(|| scores.update(value))
+    }
+
+    #[inline]
+    fn record<F: FnOnce()>(&self, record: F) {
+        let (created, oldest) = &self.oldest_unflushed;
+        if oldest.load(Relaxed) == 0 {
+            let _ = oldest.compare_exchange(0, created.elapsed_us() + 1, Relaxed, Relaxed);
+        }
+        if self.enabled.load(Relaxed) && IN_GROUP.with(Cell::get) != self.address() {
+            let _recording = read_lock!(self.lock);
+            record()
+        } else {
+            record()
+        }
+    }
+}
+
+/// Gauges of a bucket updated together, so that a flush never publishes some of their new values with others' old values,
+/// e.g. the idle, active and total connections of a pool. Created with `AtomicBucket::gauge_group()`.
+#[derive(Debug, Clone)]
+pub struct GaugeGroup {
+    gate: Arc<SnapshotGate>,
+}
+
+impl GaugeGroup {
+    /// Record a value for each gauge, excluding any flush of the bucket until all are recorded.
+    /// The gauges must have been defined by the bucket that created this group.
+    pub fn set_all(&self, values: &[(&Gauge, f64)]) {
+        let _group = read_lock!(self.gate.lock);
+        let _in_group = InGroup::enter(&self.gate);
+        for (gauge, value) in values {
+            gauge.value(*value)
+        }
+    }
+}
+
+/// A counter of an `AtomicBucket` publishing its running total upon every flush, created with `cumulative_counter()`.
+#[derive(Debug, Clone)]
+pub struct CumulativeCounter {
+    counter: Counter,
+    scores: Arc<AtomicScores>,
+    gate: Arc<SnapshotGate>,
+}
+
+impl CumulativeCounter {
+    /// Add a value count to the running total.
+    pub fn count(&self, count: usize) {
+        self.counter.count(count)
+    }
+
+    /// Replace the running total, e.g. with a count saved before a restart.
+    /// Values counted concurrently are either replaced or added to the new total, never partially lost.
+    pub fn set_total(&self, total: usize) {
+        self.gate
+            .record(|| self.scores.set_total(total.as_metric_value()))
+    }
+}
+
+/// Leading bytes of exported bucket states.
+const EXPORT_MAGIC: &[u8] = b"DPSB";
+
+/// Version of the exported bucket state format, to be incremented upon any change.
+const EXPORT_VERSION: u8 = 1;
+
+fn invalid_state<E: Into<Box<dyn std::error::Error + Send + Sync>>>(error: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
+fn kind_code(kind: InputKind) -> u8 {
+    match kind {
+        InputKind::Marker => 0,
+        InputKind::Counter => 1,
+        InputKind::Level => 2,
+        InputKind::Gauge => 3,
+        InputKind::Timer => 4,
+        InputKind::Distribution => 5,
+    }
+}
+
+fn kind_from_code(code: u8) -> io::Result<InputKind> {
+    Ok(match code {
+        0 => InputKind::Marker,
+        1 => InputKind::Counter,
+        2 => InputKind::Level,
+        3 => InputKind::Gauge,
+        4 => InputKind::Timer,
+        5 => InputKind::Distribution,
+        _ => return Err(invalid_state(format!("unknown metric kind {}", code))),
+    })
+}
+
+/// Reads the fields of an exported bucket state.
+struct StateReader<'a>(&'a [u8]);
+
+impl<'a> StateReader<'a> {
+    fn take(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        if self.0.len() < len {
+            return Err(invalid_state("truncated bucket state"));
+        }
+        let (taken, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(taken)
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(self.take(4)?);
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    fn i64(&mut self) -> io::Result<i64> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        Ok(i64::from_le_bytes(bytes))
+    }
+}
+
+/// Statistics of values already aggregated elsewhere, e.g. imported from another metrics system.
+/// Quantiles of upstream summaries can not be merged and are not supported.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Summary {
+    /// Number of values.
+    pub count: usize,
+    /// Sum of values.
+    pub sum: MetricValue,
+    /// Smallest value.
+    pub min: MetricValue,
+    /// Biggest value.
+    pub max: MetricValue,
+}
+
+/// The statistics of a bucket's metrics at a point in time, by published name, taken with `AtomicBucket::snapshot()`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MetricsSnapshot {
+    values: BTreeMap<String, MetricValue>,
+}
+
+/// Changes of statistics between two snapshots, by published name.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MetricsDiff {
+    /// Difference between the later and earlier values of statistics present in both snapshots, if not zero.
+    pub changed: BTreeMap<String, MetricValue>,
+    /// Values of statistics only present in the later snapshot.
+    pub added: BTreeMap<String, MetricValue>,
+    /// Values of statistics only present in the earlier snapshot.
+    pub removed: BTreeMap<String, MetricValue>,
+}
+
+impl MetricsSnapshot {
+    /// The value of a statistic, e.g. `requests` or `latency.max`, depending on the bucket's statistics function.
+    pub fn get(&self, name: &str) -> Option<MetricValue> {
+        self.values.get(name).copied()
+    }
+
+    /// All values of the snapshot, by published name.
+    pub fn values(&self) -> &BTreeMap<String, MetricValue> {
+        &self.values
+    }
+
+    /// Compute what changed from this snapshot to a later one, e.g. counter increments during an operation.
+    pub fn diff(&self, later: &MetricsSnapshot) -> MetricsDiff {
+        let mut diff = MetricsDiff::default();
+        for (name, value) in &later.values {
+            match self.values.get(name) {
+                Some(earlier) if earlier != value => {
+                    diff.changed
+                        .insert(name.clone(), value.saturating_sub(*earlier));
+                }
+                Some(_) => {}
+                None => {
+                    diff.added.insert(name.clone(), *value);
+                }
+            }
+        }
+        for (name, value) in &self.values {
+            if !later.values.contains_key(name) {
+                diff.removed.insert(name.clone(), *value);
+            }
+        }
+        diff
+    }
+}
+
 fn initial_stats() -> &'static StatsFn {
     &stats_summary
 }
@@ -52,16 +317,187 @@ pub struct AtomicBucket {
 
 #[derive(Default)]
 struct InnerAtomicBucket {
-    metrics: BTreeMap<MetricName, Arc<AtomicScores>>,
+    metrics: ShardedScores,
     period_start: TimeHandle,
+    /// Start of the period of metrics flushed by prefix since the last full flush
+    prefix_starts: BTreeMap<String, TimeHandle>,
     stats: Option<Arc<StatsFn>>,
     drain: Option<Arc<dyn InputDyn + Send + Sync + 'static>>,
     publish_metadata: bool,
+    update_age: bool,
+    split_labels: bool,
+    publish_zero: bool,
+    flush_callbacks: BTreeMap<MetricName, Vec<FlushCallback>>,
+    ratios: BTreeMap<MetricName, Ratio>,
+    snapshot_gate: Arc<SnapshotGate>,
+    history: History,
+    coalesce_window: Option<Duration>,
+    last_flush: Option<TimeHandle>,
+    skip_idle_flush: bool,
+    downsample: Option<Downsample>,
+    /// Start and length of the period during which flushed values are discarded
+    warmup: Option<(TimeHandle, Duration)>,
+    late: Option<LateWindows>,
+    /// Length of a window closed for late values, over which its rates are computed
+    closed_period: Option<Duration>,
+}
+
+/// Flushed windows kept open to values recorded late, until they are older than the allowed lateness.
+struct LateWindows {
+    lateness: usize,
+    current_start: SystemTime,
+    /// Start, end and scores of each window not yet published, the oldest first
+    closed: VecDeque<(SystemTime, SystemTime, AtomicBucket)>,
+}
+
+/// Number of independently locked shards of a bucket's metrics.
+const SHARDS: usize = 16;
+
+/// All metrics of a bucket, ordered by name.
+type SortedScores = Vec<(MetricName, Arc<AtomicScores>)>;
+
+/// Scores of a bucket's metrics by full name, spread over independently locked shards
+/// so that threads defining distinct metrics concurrently do not contend on a single lock.
+struct ShardedScores {
+    shards: Vec<RwLock<BTreeMap<MetricName, Arc<AtomicScores>>>>,
+    /// Sorted view of all shards, kept until a metric is added or removed.
+    sorted: RwLock<Option<Arc<SortedScores>>>,
+}
+
+impl Default for ShardedScores {
+    fn default() -> Self {
+        ShardedScores {
+            shards: (0..SHARDS).map(|_| RwLock::default()).collect(),
+            sorted: RwLock::default(),
+        }
+    }
+}
+
+impl ShardedScores {
+    fn shard(&self, name: &MetricName) -> &RwLock<BTreeMap<MetricName, Arc<AtomicScores>>> {
+        let mut hasher = DefaultHasher::new();
+        name.hash(&mut hasher);
+        &self.shards[hasher.finish() as usize % self.shards.len()]
+    }
+
+    /// Lookup the scores of a metric, creating them if required.
+    /// Concurrent callers defining the same metric all get the same scores.
+    fn get_or_insert_with(
+        &self,
+        name: MetricName,
+        create: impl FnOnce() -> AtomicScores,
+    ) -> Arc<AtomicScores> {
+        let shard = self.shard(&name);
+        if let Some(scores) = read_lock!(shard).get(&name) {
+            return scores.clone();
+        }
+        let mut inserted = false;
+        let scores = write_lock!(shard)
+            .entry(name)
+            .or_insert_with(|| {
+                inserted = true;
+                Arc::new(create())
+            })
+            .clone();
+        if inserted {
+            // invalidated after the insert so that a view being built concurrently is discarded
+            *write_lock!(self.sorted) = None;
+        }
+        scores
+    }
+
+    /// All metrics of all shards, ordered by name.
+    /// The view is only rebuilt after metrics were added or removed.
+    fn sorted(&self) -> Arc<SortedScores> {
+        if let Some(sorted) = &*read_lock!(self.sorted) {
+            return sorted.clone();
+        }
+        let mut cached = write_lock!(self.sorted);
+        cached
+            .get_or_insert_with(|| {
+                let mut metrics: SortedScores = self
+                    .shards
+                    .iter()
+                    .flat_map(|shard| {
+                        read_lock!(shard)
+                            .iter()
+                            .map(|(name, scores)| (name.clone(), scores.clone()))
+                            .collect::<Vec<_>>()
+                    })
+                    .collect();
+                metrics.sort_by(|a, b| a.0.cmp(&b.0));
+                Arc::new(metrics)
+            })
+            .clone()
+    }
+
+    /// Remove the metrics no longer referenced outside of the bucket.
+    fn retain_referenced(&self) {
+        // the sorted view references every metric, drop it before counting references
+        let mut cached = write_lock!(self.sorted);
+        *cached = None;
+        for shard in &self.shards {
+            write_lock!(shard).retain(|_k, v| Arc::strong_count(v) > 1);
+        }
+    }
+}
+
+/// Scores of several flush windows merged into another bucket, to be published together at a coarser resolution.
+struct Downsample {
+    factor: usize,
+    windows: usize,
+    rollup: AtomicBucket,
+}
+
+impl Downsample {
+    /// Merge the raw scores of a flushed metric into the rollup.
+    fn merge(&self, name: &MetricName, scores: &AtomicScores, raw: &[isize; SCORES_LEN]) {
+        let rollup = read_lock!(self.rollup.inner)
+            .metrics
+            .get_or_insert_with(name.clone(), || {
+                AtomicScores::new(scores.kind, scores.sampling)
+            });
+        if scores.cumulative.load(Acquire) {
+            rollup.accumulate();
+            rollup.set_total(scores.running_total());
+        }
+        rollup.import(raw)
+    }
+
+    /// Count a flush window, flushing the rollup every `factor` windows.
+    fn window_flushed(&mut self) -> io::Result<()> {
+        self.windows += 1;
+        if self.windows < self.factor {
+            return Ok(());
+        }
+        self.windows = 0;
+        self.rollup.flush()
+    }
+}
+
+/// Values published by the last flushes, by published metric name.
+#[derive(Default)]
+struct History {
+    windows: usize,
+    series: BTreeMap<String, VecDeque<(SystemTime, MetricValue)>>,
+}
+
+impl History {
+    fn record(&mut self, name: &MetricName, timestamp: SystemTime, value: MetricValue) {
+        if self.windows == 0 {
+            return;
+        }
+        let series = self.series.entry(name.join(".")).or_default();
+        if series.len() == self.windows {
+            series.pop_front();
+        }
+        series.push_back((timestamp, value));
+    }
 }
 
 impl fmt::Debug for InnerAtomicBucket {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "metrics: {:?}", self.metrics)?;
+        write!(f, "metrics: {:?}", self.metrics.sorted())?;
         write!(f, "period_start: {:?}", self.period_start)
     }
 }
@@ -70,6 +506,9 @@ lazy_static! {
     static ref PERIOD_LENGTH: MetricName = "_period_length".into();
 }
 
+/// Name suffix of the published time elapsed since a metric was last updated.
+const UPDATE_AGE: &str = "last_update_age_seconds";
+
 impl InnerAtomicBucket {
     fn flush(&mut self) -> io::Result<()> {
         let pub_scope: Arc<dyn InputScope> = match self.drain {
@@ -77,54 +516,263 @@ impl InnerAtomicBucket {
             None => read_lock!(DEFAULT_AGGREGATE_INPUT).input_dyn(),
         };
 
-        self.flush_to(pub_scope.borrow())?;
+        self.flush_to(pub_scope.borrow(), None)?;
 
         // all metrics published!
         // purge: if stats is the last owner of the metric, remove it
         // TODO parameterize whether to keep ad-hoc metrics after publish
-        let mut purged = self.metrics.clone();
-        self.metrics
-            .iter()
-            .filter(|&(_k, v)| Arc::strong_count(v) == 1)
-            .map(|(k, _v)| k)
-            .for_each(|k| {
-                purged.remove(k);
-            });
-        self.metrics = purged;
+        self.metrics.retain_referenced();
 
         Ok(())
     }
 
+    /// Flush only the metrics whose name is or starts with the prefix, to the assigned or default output.
+    /// Unlike a full flush, unreferenced metrics are not purged.
+    fn flush_prefix(&mut self, prefix: &str) -> io::Result<()> {
+        let pub_scope: Arc<dyn InputScope> = match self.drain {
+            Some(ref out) => out.input_dyn(),
+            None => read_lock!(DEFAULT_AGGREGATE_INPUT).input_dyn(),
+        };
+
+        self.flush_to(pub_scope.borrow(), Some(prefix))
+    }
+
     /// Take a snapshot of aggregated values and reset them.
     /// Compute stats on captured values using assigned or default stats function.
     /// Write stats to assigned or default output.
-    fn flush_to(&mut self, target: &dyn InputScope) -> io::Result<()> {
+    /// If a prefix is provided, only matching metrics are flushed and the aggregation period is not reset.
+    fn flush_to(&mut self, target: &dyn InputScope, prefix: Option<&str>) -> io::Result<()> {
+        if prefix.is_none() {
+            if let (Some(window), Some(last_flush)) = (self.coalesce_window, self.last_flush) {
+                if u128::from(last_flush.elapsed_us()) < window.as_micros() {
+                    trace!("Bucket was flushed less than {:?} ago, skipping", window);
+                    return Ok(());
+                }
+            }
+        }
+
+        let expired = match prefix {
+            None => self.close_late_window(),
+            Some(_) => vec![],
+        };
+        let published = self.publish_to(target, prefix);
+        if prefix.is_none() {
+            // values written after the snapshot are left to the next flush
+            self.snapshot_gate.oldest_unflushed.1.store(0, Relaxed);
+        }
+        let published = expired.iter().fold(published, |published, window| {
+            published.and(self.publish_late_window(window, target))
+        });
+        match &mut self.downsample {
+            Some(downsample) if prefix.is_none() => published.and(downsample.window_flushed()),
+            _ => published,
+        }
+    }
+
+    /// Move the scores of the window being flushed to a window kept open for late values,
+    /// returning the windows that are now too old to receive any, to be published.
+    /// Latched values stay with the bucket, which publishes them upon every flush.
+    fn close_late_window(&mut self) -> Vec<AtomicBucket> {
+        let late = match &mut self.late {
+            Some(late) => late,
+            None => return vec![],
+        };
+        let window = AtomicBucket::new();
+        {
+            let mut inner = write_lock!(window.inner);
+            inner.stats = self.stats.clone();
+            inner.period_start = self.period_start;
+            inner.closed_period = Some(Duration::from_micros(self.period_start.elapsed_us()));
+            for (name, scores) in self.metrics.sorted().iter() {
+                if let Some(raw) = scores.reset_recorded_raw() {
+                    inner
+                        .metrics
+                        .get_or_insert_with(name.clone(), || {
+                            AtomicScores::new(scores.kind, scores.sampling)
+                        })
+                        .import(&raw)
+                }
+            }
+        }
+        let now = SystemTime::now();
+        late.closed.push_back((late.current_start, now, window));
+        late.current_start = now;
+        let expired = late.closed.len().saturating_sub(late.lateness);
+        late.closed
+            .drain(..expired)
+            .map(|(_, _, window)| window)
+            .collect()
+    }
+
+    /// Publish a window closed for late values over the period it was open,
+    /// discarding it if the bucket is warming up and merging it into any downsampled rollup.
+    fn publish_late_window(
+        &self,
+        window: &AtomicBucket,
+        target: &dyn InputScope,
+    ) -> io::Result<()> {
+        let mut inner = write_lock!(window.inner);
+        inner.warmup = self.warmup;
+        inner.downsample = self.downsample.as_ref().map(|downsample| Downsample {
+            factor: downsample.factor,
+            windows: 0,
+            rollup: downsample.rollup.clone(),
+        });
+        inner.publish_to(target, None)
+    }
+
+    /// Publish the aggregated values of a flush window.
+    fn publish_to(&mut self, target: &dyn InputScope, prefix: Option<&str>) -> io::Result<()> {
         let now = TimeHandle::now();
-        let duration_seconds = self.period_start.elapsed_us() as f64 / 1_000_000.0;
-        self.period_start = now;
+        let timestamp = SystemTime::now();
+        let period_us = match self.closed_period {
+            Some(period) => period.as_micros().min(u128::from(u64::MAX)) as u64,
+            None => self.period_start.elapsed_us(),
+        };
+        let duration_seconds = period_us as f64 / 1_000_000.0;
+        let prefix_starts = self.prefix_starts.clone();
+        match prefix {
+            None => {
+                self.period_start = now;
+                self.last_flush = Some(now);
+                self.prefix_starts.clear();
+            }
+            Some(prefix) => {
+                self.prefix_starts
+                    .retain(|flushed, _| !has_prefix(flushed, prefix));
+                self.prefix_starts.insert(prefix.to_string(), now);
+            }
+        }
 
-        let mut snapshot: Vec<(&MetricName, InputKind, Vec<ScoreType>)> = self
-            .metrics
+        let selected = |name: &MetricName| match prefix {
+            None => true,
+            Some(prefix) => has_prefix(&name.join("."), prefix),
+        };
+
+        // metrics last flushed by prefix have a shorter period than the bucket's
+        let period_seconds = |name: &MetricName| {
+            let name = name.join(".");
+            let elapsed_us = prefix_starts
+                .iter()
+                .filter(|(flushed, _)| has_prefix(&name, flushed))
+                .map(|(_, start)| start.elapsed_us())
+                .fold(period_us, u64::min);
+            elapsed_us as f64 / 1_000_000.0
+        };
+
+        let publish_zero = self.publish_zero;
+        let gate = self.snapshot_gate.clone();
+        let consistent = gate.locking().then(|| write_lock!(gate.lock));
+        let mut snapshot: Vec<(&MetricName, InputKind, Vec<ScoreType>, Option<Labels>)> = vec![];
+        let mut idle = true;
+        let warming_up = self
+            .warmup
+            .is_some_and(|(start, period)| u128::from(start.elapsed_us()) < period.as_micros());
+        let downsample = if warming_up { &None } else { &self.downsample };
+        let metrics = self.metrics.sorted();
+        for (name, scores) in metrics.iter().filter(|(name, _)| selected(name)) {
+            let mut collect = |scores: &AtomicScores, labels: Option<Labels>| {
+                let values = match scores.reset_raw() {
+                    Some(raw) => {
+                        idle = false;
+                        if let (Some(downsample), None) = (downsample, &labels) {
+                            downsample.merge(name, scores, &raw)
+                        }
+                        Some(scores.values(&raw, period_seconds(name)))
+                    }
+                    None if publish_zero => scores.idle(),
+                    None => None,
+                };
+                if let Some(values) = values {
+                    snapshot.push((name, scores.metric_kind(), values, labels))
+                }
+            };
+            collect(scores, None);
+            if let Some(labeled) = &scores.labeled {
+                for (key, scores) in read_lock!(labeled).iter() {
+                    let labels: HashMap<String, Arc<String>> = key.iter().cloned().collect();
+                    collect(scores, Some(Labels::from(labels)))
+                }
+            }
+        }
+        drop(consistent);
+
+        if warming_up {
+            trace!("Bucket is warming up, discarding flushed values");
+            return Ok(());
+        }
+
+        if idle && self.skip_idle_flush {
+            return Ok(());
+        }
+
+        for (name, _, scores, labels) in &snapshot {
+            if labels.is_some() {
+                continue;
+            }
+            if let Some(callbacks) = self.flush_callbacks.get(*name) {
+                callbacks.iter().for_each(|callback| callback(scores))
+            }
+        }
+
+        // update age is published even if (especially if) no data was collected for the metric
+        let update_ages: Vec<(&MetricName, f64)> = metrics
+            .iter()
+            .filter(|(name, _)| selected(name))
+            .flat_map(|(name, scores)| scores.update_age_seconds().map(|age| (name, age)))
+            .collect();
+
+        for (name, age) in &update_ages {
+            let metric = target.new_metric(name.make_name(UPDATE_AGE), InputKind::Gauge);
+            metric.write(*age as MetricValue, labels![])
+        }
+
+        // ratios are published even if their metrics had no values, as zero
+        let ratios: Vec<(&MetricName, MetricValue)> = self
+            .ratios
             .iter()
-            .flat_map(|(name, scores)| {
-                scores
-                    .reset(duration_seconds)
-                    .map(|values| (name, scores.metric_kind(), values))
+            .filter(|(name, _)| selected(name))
+            .map(|(name, ratio)| {
+                let total = |metric: &MetricName| {
+                    snapshot
+                        .iter()
+                        .filter(|(name, _, _, _)| *name == metric)
+                        .map(|(_, kind, scores, _)| total(*kind, scores))
+                        .sum::<f64>()
+                };
+                let denominator = total(&ratio.denominator);
+                let value = if denominator == 0.0 {
+                    0
+                } else {
+                    (total(&ratio.numerator) / denominator * ratio.scale as f64).round()
+                        as MetricValue
+                };
+                (name, value)
             })
             .collect();
 
+        for (name, value) in &ratios {
+            let metric = target.new_metric((*name).clone(), InputKind::Gauge);
+            metric.write(*value, labels![])
+        }
+
         if snapshot.is_empty() {
             // no data was collected for this period
             // TODO repeat previous frame min/max ?
             // TODO update some canary metric ?
-            Ok(())
+            if update_ages.is_empty() && ratios.is_empty() {
+                Ok(())
+            } else {
+                target.flush()
+            }
         } else {
             // TODO add switch for metadata such as PERIOD_LENGTH
-            if self.publish_metadata {
+            if self.publish_metadata && prefix.is_none() {
                 snapshot.push((
                     &PERIOD_LENGTH,
                     InputKind::Timer,
                     vec![Sum((duration_seconds * 1000.0) as isize)],
+                    None,
                 ));
             }
 
@@ -137,9 +785,12 @@ impl InnerAtomicBucket {
                 for score in metric.2 {
                     let filtered = stats_fn(metric.1, metric.0.clone(), score);
                     if let Some((kind, name, value)) = filtered {
-                        let metric: InputMetric = target.new_metric(name, kind);
+                        if metric.3.is_none() {
+                            self.history.record(&name, timestamp, value);
+                        }
+                        let output: InputMetric = target.new_metric(name, kind);
                         // TODO provide some stats context through labels?
-                        metric.write(value, labels![])
+                        output.write(value, metric.3.clone().unwrap_or_default())
                     }
                 }
             }
@@ -148,6 +799,12 @@ impl InnerAtomicBucket {
     }
 }
 
+/// Returns true if the dotted name is or starts with the prefix, followed by a `.`.
+fn has_prefix(name: &str, prefix: &str) -> bool {
+    name.strip_prefix(prefix)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with('.'))
+}
+
 impl<S: AsRef<str>> From<S> for AtomicBucket {
     fn from(name: S) -> AtomicBucket {
         AtomicBucket::new().named(name.as_ref())
@@ -160,12 +817,27 @@ impl AtomicBucket {
         AtomicBucket {
             attributes: Attributes::default(),
             inner: Arc::new(RwLock::new(InnerAtomicBucket {
-                metrics: BTreeMap::new(),
+                metrics: ShardedScores::default(),
                 period_start: TimeHandle::now(),
+                prefix_starts: BTreeMap::new(),
                 stats: None,
                 drain: None,
                 // TODO add API toggle for metadata publish
                 publish_metadata: false,
+                update_age: false,
+                split_labels: false,
+                publish_zero: false,
+                flush_callbacks: BTreeMap::new(),
+                ratios: BTreeMap::new(),
+                snapshot_gate: Arc::new(SnapshotGate::default()),
+                history: History::default(),
+                coalesce_window: None,
+                last_flush: None,
+                skip_idle_flush: false,
+                downsample: None,
+                warmup: None,
+                late: None,
+                closed_period: None,
             })),
         }
     }
@@ -187,7 +859,7 @@ impl AtomicBucket {
     }
 
     /// Set the default stats aggregated metrics flush output.
-    pub fn default_drain(default_config: impl Input + Send + Sync + 'static) {
+    pub fn default_drain(default_config: impl Input) {
         *write_lock!(DEFAULT_AGGREGATE_INPUT) = Arc::new(default_config);
     }
 
@@ -226,12 +898,12 @@ impl AtomicBucket {
 
     /// Set this stats's aggregated metrics flush output.
     #[deprecated(since = "0.7.2", note = "Use drain()")]
-    pub fn set_drain(&self, new_drain: impl Input + Send + Sync + 'static) {
+    pub fn set_drain(&self, new_drain: impl Input) {
         self.drain(new_drain)
     }
 
     /// Set this stats's aggregated metrics flush output.
-    pub fn drain(&self, new_drain: impl Input + Send + Sync + 'static) {
+    pub fn drain(&self, new_drain: impl Input) {
         write_lock!(self.inner).drain = Some(Arc::new(new_drain))
     }
 
@@ -240,116 +912,748 @@ impl AtomicBucket {
         write_lock!(self.inner).drain = None
     }
 
-    /// Immediately flush the stats's metrics to the specified scope and stats.
-    pub fn flush_to(&self, publish_scope: &dyn InputScope) -> io::Result<()> {
-        let mut inner = write_lock!(self.inner);
-        inner.flush_to(publish_scope)
+    /// Track the time of each metric's last update, publishing its age in seconds
+    /// as a `<metric>.last_update_age_seconds` gauge upon every flush.
+    /// The age is published even when no values were recorded during the period,
+    /// making it possible to detect producers that have stopped updating.
+    /// Only affects metrics defined afterwards.
+    pub fn track_update_age(&self, enabled: bool) {
+        write_lock!(self.inner).update_age = enabled
     }
-}
 
-impl InputScope for AtomicBucket {
-    /// Lookup or create scores for the requested metric.
-    fn new_metric(&self, name: MetricName, kind: InputKind) -> InputMetric {
-        let scores = write_lock!(self.inner)
-            .metrics
-            .entry(self.prefix_append(name.clone()))
-            .or_insert_with(|| Arc::new(AtomicScores::new(kind)))
-            .clone();
-        InputMetric::new(MetricId::forge("stats", name), move |value, _labels| {
-            scores.update(value)
-        })
+    /// Aggregate the values written with labels separately for each distinct set of labels,
+    /// publishing their statistics along with the labels, e.g. to break down a timer's latency by cache hits and misses.
+    /// Only the labels provided with values are considered, thread and app labels are not.
+    /// Labels must have few distinct values, as their statistics are kept until the metric is dropped.
+    /// Use with outputs that support labels, others will publish the statistics of each label set under the same name.
+    /// Flush callbacks only receive the scores of unlabeled values, ratios use the totals of all values.
+    /// Only affects metrics defined afterwards.
+    pub fn split_labels(&self, enabled: bool) {
+        write_lock!(self.inner).split_labels = enabled
     }
-}
 
-impl Flush for AtomicBucket {
-    /// Collect and reset aggregated data.
-    /// Publish statistics
-    fn flush(&self) -> io::Result<()> {
-        self.notify_flush_listeners();
-        let mut inner = write_lock!(self.inner);
-        inner.flush()
+    /// Skip (the default) or publish zero statistics for counters and markers without values since the last flush.
+    /// Publishing explicit zeros lets consumers tell idle metrics from missing ones, at the cost of volume.
+    /// Other metric kinds have no meaningful statistics without values and are always skipped.
+    pub fn skip_zero(&self, enabled: bool) {
+        write_lock!(self.inner).publish_zero = !enabled
     }
-}
 
-impl WithAttributes for AtomicBucket {
-    fn get_attributes(&self) -> &Attributes {
-        &self.attributes
+    /// Call the function with the aggregated scores of the named metric every time they are flushed,
+    /// e.g. to log a summary or check an alert threshold. The function is not called for periods without values.
+    /// Callbacks run while the bucket is locked for flushing, they must be fast and must not use this bucket.
+    pub fn on_metric_flush<F>(&self, name: &str, callback: F)
+    where
+        F: Fn(&[ScoreType]) + Send + Sync + 'static,
+    {
+        let name = self.prefix_append(name);
+        write_lock!(self.inner)
+            .flush_callbacks
+            .entry(name)
+            .or_default()
+            .push(Arc::new(callback));
     }
-    fn mut_attributes(&mut self) -> &mut Attributes {
-        &mut self.attributes
+
+    /// Publish a gauge computed from the totals of two metrics of this bucket upon every flush,
+    /// such as an error rate or a cache hit ratio. The ratio is multiplied by `scale` before being rounded,
+    /// e.g. a scale of 100 publishes a percentage. Zero is published if the denominator is zero.
+    /// Totals are the count of markers, timers and distributions, the sum of counters and levels
+    /// and the mean of gauges, over the flushed period.
+    pub fn register_ratio(
+        &self,
+        name: &str,
+        numerator: &str,
+        denominator: &str,
+        scale: MetricValue,
+    ) {
+        let ratio = Ratio {
+            numerator: self.prefix_append(numerator),
+            denominator: self.prefix_append(denominator),
+            scale,
+        };
+        write_lock!(self.inner)
+            .ratios
+            .insert(self.prefix_append(name), ratio);
     }
-}
 
-const HIT: usize = 0;
-const SUM: usize = 1;
-const MAX: usize = 2;
-const MIN: usize = 3;
-const SCORES_LEN: usize = 4;
+    /// Take the snapshot of all metrics at a single point in time when flushing,
+    /// so that values recorded concurrently to different metrics are always published in the same period.
+    /// Statistics of correlated metrics (e.g. request latency and database latency) are then consistent.
+    /// Metric updates briefly wait while the snapshot is taken, instead of never blocking.
+    pub fn consistent_snapshot(&self, enabled: bool) {
+        read_lock!(self.inner)
+            .snapshot_gate
+            .enabled
+            .store(enabled, Relaxed)
+    }
 
-/// A metric that holds aggregated values.
-/// Some fields are kept public to ease publishing.
-#[derive(Debug)]
-struct AtomicScores {
-    /// The kind of metric
-    kind: InputKind,
-    /// The actual recorded metric scores
-    scores: [AtomicIsize; SCORES_LEN],
-}
+    /// Retain the values published by the last `windows` flushes of each metric, to be queried with `history()`,
+    /// e.g. to draw sparklines without an external time series database. Zero (the default) retains nothing.
+    /// Values published with labels are not retained.
+    pub fn retain_history(&self, windows: usize) {
+        let mut inner = write_lock!(self.inner);
+        inner.history.windows = windows;
+        inner.history.series.retain(|_, series| {
+            while series.len() > windows {
+                series.pop_front();
+            }
+            !series.is_empty()
+        });
+    }
 
-impl AtomicScores {
-    /// Create new scores to track summary values of a metric
-    pub fn new(kind: InputKind) -> Self {
-        AtomicScores {
-            kind,
-            scores: unsafe { mem::transmute(AtomicScores::blank()) },
-        }
+    /// The time and value of the retained flushes of a published metric, oldest first.
+    /// The name is the one published by the bucket's statistics, e.g. `requests.count` with `stats_all`.
+    pub fn history(&self, name: &str) -> Vec<(SystemTime, MetricValue)> {
+        let name = self.prefix_append(name).join(".");
+        read_lock!(self.inner)
+            .history
+            .series
+            .get(&name)
+            .map(|series| series.iter().cloned().collect())
+            .unwrap_or_default()
     }
 
-    /// Returns the metric's kind.
-    pub fn metric_kind(&self) -> InputKind {
-        self.kind
+    /// Skip full flushes happening less than `window` after the previous one, e.g. when both a schedule
+    /// and the application flush the bucket. Concurrent flushes are then coalesced into a single publication,
+    /// preventing the statistics of a period from being split or zero values from being published twice.
+    pub fn coalesce_flushes(&self, window: Duration) {
+        write_lock!(self.inner).coalesce_window = Some(window)
     }
 
-    #[inline]
-    fn blank() -> [isize; SCORES_LEN] {
-        [0, 0, isize::MIN, isize::MAX]
+    /// Skip publishing anything (zero statistics, update ages, ratios) and flushing the drain
+    /// when no values were recorded since the last flush.
+    pub fn skip_idle_flush(&self, enabled: bool) {
+        write_lock!(self.inner).skip_idle_flush = enabled
     }
 
-    /// Update scores with new value
-    pub fn update(&self, value: MetricValue) {
-        // TODO detect & report any concurrent updates / resets for measurement of contention
-        // Count is tracked for all metrics
-        self.scores[HIT].fetch_add(1, Relaxed);
-        match self.kind {
-            InputKind::Marker => {}
-            InputKind::Level => {
-                // Level min & max apply to the _sum_ of values
-                // fetch_add only returns the previous sum, so min & max trail behind by one operation
-                // instead, pickup the slack by comparing again with the final sum upon `snapshot`
+    /// Discard the values flushed during the period starting now, e.g. to keep the unrepresentative values
+    /// recorded while a service starts (cold caches, connection establishment) out of dashboards and alerts.
+    /// Values are still recorded, but flushes reset them without publishing anything until the period ends.
+    pub fn warmup(&self, period: Duration) {
+        write_lock!(self.inner).warmup = Some((TimeHandle::now(), period))
+    }
+
+    /// Compute the statistics of the values recorded since the last flush, without resetting them,
+    /// e.g. to `diff()` them with a later snapshot. Uses the consistent snapshot settings of flushes.
+    /// Values aggregated separately for each set of labels are not included.
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        let inner = read_lock!(self.inner);
+        let duration_seconds = inner.period_start.elapsed_us() as f64 / 1_000_000.0;
+        let stats_fn = match inner.stats {
+            Some(ref stats_fn) => stats_fn.clone(),
+            None => read_lock!(DEFAULT_AGGREGATE_STATS).clone(),
+        };
+        let gate = inner.snapshot_gate.clone();
+        let _consistent = gate.locking().then(|| write_lock!(gate.lock));
+        let mut values = BTreeMap::new();
+        for (name, scores) in inner.metrics.sorted().iter() {
+            let raw = match scores.peek_raw() {
+                Some(raw) => raw,
+                None => continue,
+            };
+            for score in scores.values(&raw, duration_seconds) {
+                if let Some((_, name, value)) = stats_fn(scores.metric_kind(), name.clone(), score)
+                {
+                    values.insert(name.join("."), value);
+                }
+            }
+        }
+        MetricsSnapshot { values }
+    }
+
+    /// Keep the last `windows` flushed windows open to values recorded late with `record_at()`,
+    /// publishing each window only once that many flushes happened after it.
+    /// Values recorded with a time preceding the oldest open window are dropped
+    /// and counted by the `dipstick.bucket.late_dropped` internal metric.
+    /// Rates of delayed windows are computed over the period they were open.
+    /// Delayed windows are subject to `warmup()` and merged into any `downsample()` rollup once published.
+    /// Latched gauges are published with the current window, not with delayed ones.
+    /// Values aggregated separately for each set of labels are published without delay.
+    pub fn lateness(&self, windows: usize) {
+        write_lock!(self.inner).late = Some(LateWindows {
+            lateness: windows,
+            current_start: SystemTime::now(),
+            closed: VecDeque::new(),
+        })
+    }
+
+    /// Record a value in the window matching the time of the measurement rather than in the current one,
+    /// e.g. for events reported late. Without `lateness()`, values are always recorded in the current window.
+    /// Returns false if the value was dropped because its window was already published.
+    pub fn record_at(
+        &self,
+        name: &str,
+        kind: InputKind,
+        value: MetricValue,
+        time: SystemTime,
+    ) -> bool {
+        let name = self.prefix_append(name);
+        {
+            let inner = read_lock!(self.inner);
+            if let Some(late) = inner.late.as_ref().filter(|late| time < late.current_start) {
+                let window = late
+                    .closed
+                    .iter()
+                    .find(|(start, end, _)| *start <= time && time < *end);
+                return match window {
+                    Some((_, _, window)) => {
+                        let (scores, gate) = window.scores(name, kind);
+                        gate.update(&scores, value);
+                        true
+                    }
+                    None => {
+                        metrics::BUCKET_LATE_DROPPED.mark();
+                        false
+                    }
+                };
+            }
+        }
+        let (scores, gate) = self.scores(name, kind);
+        gate.update(&scores, value);
+        true
+    }
+
+    /// Merge the scores of this bucket's flushes into another bucket, flushed to the drain every `factor` flushes,
+    /// e.g. to publish 10s rollups to a costly remote backend while this bucket publishes every second locally.
+    /// Counts, sums, minimums and maximums are merged as if values had been recorded in a single window.
+    /// Returns the rollup bucket, which uses this bucket's statistics unless set otherwise.
+    /// Values aggregated separately for each set of labels are not merged.
+    pub fn downsample(&self, factor: usize, drain: impl Input) -> AtomicBucket {
+        let rollup = AtomicBucket::new();
+        rollup.drain(drain);
+        let mut inner = write_lock!(self.inner);
+        if let Some(stats) = &inner.stats {
+            write_lock!(rollup.inner).stats = Some(stats.clone());
+        }
+        inner.downsample = Some(Downsample {
+            factor: factor.max(1),
+            windows: 0,
+            rollup: rollup.clone(),
+        });
+        rollup
+    }
+
+    /// Create a group of this bucket's gauges whose values are updated together.
+    /// Flushes of the bucket then briefly wait for group updates in progress.
+    pub fn gauge_group(&self) -> GaugeGroup {
+        let gate = read_lock!(self.inner).snapshot_gate.clone();
+        gate.grouped.store(true, Relaxed);
+        GaugeGroup { gate }
+    }
+
+    /// Time elapsed since the oldest value written to the bucket and not yet flushed, zero if there is none.
+    /// Ever growing ages reveal flushes that stopped or can not keep up.
+    /// Upon every full flush, the age is also published to an internal gauge named after the bucket,
+    /// e.g. `dipstick.bucket.app.oldest_unflushed_age_ms` for a bucket named `app`
+    /// or `dipstick.bucket.oldest_unflushed_age_ms` for an unnamed bucket.
+    pub fn oldest_unflushed_age(&self) -> Duration {
+        read_lock!(self.inner)
+            .snapshot_gate
+            .oldest_unflushed_age()
+            .unwrap_or_default()
+    }
+
+    /// Define a gauge whose last value is published again upon every flush, until a new value replaces it.
+    /// Suits gauges set once or seldom, e.g. build information or configuration state.
+    pub fn latched_gauge(&self, name: &str) -> Gauge {
+        let gauge = self.gauge(name);
+        let (scores, _) = self.scores(self.prefix_append(name), InputKind::Gauge);
+        scores.latch();
+        gauge
+    }
+
+    /// Define a latched gauge whose last value stops being published once no new value was set for `ttl`,
+    /// e.g. gauges of connections or workers that may go away without resetting them.
+    pub fn expiring_gauge(&self, name: &str, ttl: Duration) -> Gauge {
+        let gauge = self.gauge(name);
+        let (scores, _) = self.scores(self.prefix_append(name), InputKind::Gauge);
+        scores.expire_after(ttl);
+        gauge
+    }
+
+    /// Define a counter publishing the running total of the values it counted upon every flush,
+    /// instead of the sum of the values counted since the last flush.
+    /// The total can be set to an absolute value, e.g. to restore a count saved before a restart.
+    /// Values counted are not delayed by `lateness()`, as they are part of the running total.
+    pub fn cumulative_counter(&self, name: &str) -> CumulativeCounter {
+        let counter = self.counter(name);
+        let (scores, gate) = self.scores(self.prefix_append(name), InputKind::Counter);
+        scores.accumulate();
+        CumulativeCounter {
+            counter,
+            scores,
+            gate,
+        }
+    }
+
+    /// Merge statistics aggregated elsewhere into the named metric's scores for the current period,
+    /// as if the values they summarize had been recorded individually.
+    /// The metric is defined as a distribution if it does not exist yet.
+    /// Intended for timers and distributions. Merged values are exact, they are not scaled if the metric is sampled.
+    pub fn record_summary(&self, name: &str, summary: &Summary) {
+        if summary.count == 0 {
+            return;
+        }
+        let (scores, gate) = self.scores(self.prefix_append(name), InputKind::Distribution);
+        gate.record(|| scores.merge(summary))
+    }
+
+    /// Serialize the scores accumulated since the last flush, e.g. to hand them over to a new process upon restart.
+    /// The state starts with a format version, so that states exported by older versions can still be imported.
+    /// Scores of values aggregated separately for each set of labels are not exported.
+    pub fn export(&self) -> Vec<u8> {
+        let inner = read_lock!(self.inner);
+        let mut state = Vec::new();
+        state.extend_from_slice(EXPORT_MAGIC);
+        state.push(EXPORT_VERSION);
+        let metrics = inner.metrics.sorted();
+        state.extend_from_slice(&(metrics.len() as u32).to_le_bytes());
+        for (name, scores) in metrics.iter() {
+            state.extend_from_slice(&(name.len() as u32).to_le_bytes());
+            for part in name.iter() {
+                state.extend_from_slice(&(part.len() as u32).to_le_bytes());
+                state.extend_from_slice(part.as_bytes());
+            }
+            state.push(kind_code(scores.kind));
+            for score in scores.export() {
+                state.extend_from_slice(&(score as i64).to_le_bytes());
+            }
+            let cumulative = scores.cumulative.load(Acquire);
+            state.push(u8::from(cumulative));
+            let total = if cumulative {
+                scores.running_total()
+            } else {
+                0
+            };
+            state.extend_from_slice(&(total as i64).to_le_bytes());
+        }
+        state
+    }
+
+    /// Merge scores exported by `export()` into the current period, as if their values had been recorded here.
+    /// Metrics keep the full names they had in the exporting bucket.
+    /// Nothing is imported if the state is invalid or of an unsupported version.
+    pub fn import(&self, state: &[u8]) -> io::Result<()> {
+        let mut reader = StateReader(state);
+        if reader.take(EXPORT_MAGIC.len())? != EXPORT_MAGIC {
+            return Err(invalid_state("not an exported bucket state"));
+        }
+        let version = reader.take(1)?[0];
+        if version != EXPORT_VERSION {
+            return Err(invalid_state(format!(
+                "unsupported bucket state version {}",
+                version
+            )));
+        }
+
+        let mut imported = vec![];
+        for _ in 0..reader.u32()? {
+            let mut parts = NameParts::default();
+            for _ in 0..reader.u32()? {
+                let len = reader.u32()? as usize;
+                let part = String::from_utf8(reader.take(len)?.to_vec())
+                    .map_err(|_| invalid_state("metric name is not UTF-8"))?;
+                parts.push_back(part);
+            }
+            let leaf = parts
+                .pop_back()
+                .ok_or_else(|| invalid_state("empty metric name"))?;
+            let kind = kind_from_code(reader.take(1)?[0])?;
+            let mut values = AtomicScores::blank();
+            for value in values.iter_mut() {
+                *value = reader.i64()? as isize;
+            }
+            let cumulative = reader.take(1)?[0] != 0;
+            let total = reader.i64()? as isize;
+            let total = cumulative.then_some(total);
+            imported.push((parts.make_name(leaf), kind, values, total));
+        }
+
+        for (name, kind, values, total) in imported {
+            let (scores, gate) = self.scores(name, kind);
+            gate.record(|| {
+                if let Some(total) = total {
+                    scores.accumulate();
+                    scores.add_total(total);
+                }
+                scores.import(&values)
+            })
+        }
+        Ok(())
+    }
+
+    /// Lookup or create scores for the requested metric, by full name.
+    fn scores(&self, name: MetricName, kind: InputKind) -> (Arc<AtomicScores>, Arc<SnapshotGate>) {
+        let sampling = self.get_kind_sampling(kind);
+        let inner = read_lock!(self.inner);
+        let scores = inner.metrics.get_or_insert_with(name, || {
+            let mut scores = AtomicScores::new(kind, sampling);
+            if inner.update_age {
+                scores = scores.with_update_age()
+            }
+            if inner.split_labels {
+                scores = scores.with_labeled()
+            }
+            scores
+        });
+        (scores, inner.snapshot_gate.clone())
+    }
+
+    /// Immediately flush the stats's metrics to the specified scope and stats.
+    pub fn flush_to(&self, publish_scope: &dyn InputScope) -> io::Result<()> {
+        let (age, result) = {
+            let mut inner = write_lock!(self.inner);
+            let age = inner.snapshot_gate.oldest_unflushed_age();
+            (age, inner.flush_to(publish_scope, None))
+        };
+        self.publish_unflushed_age(age);
+        result
+    }
+
+    /// Publish the age of the oldest unflushed value to this bucket's own internal gauge.
+    /// Must not be called with the bucket locked, since the bucket may be its own self metrics target.
+    fn publish_unflushed_age(&self, age: Option<Duration>) {
+        if let Some(age) = age {
+            let name = self.prefix_append("oldest_unflushed_age_ms").join(".");
+            metrics::DIPSTICK_METRICS
+                .named("bucket")
+                .gauge(&name)
+                .value(age.as_millis());
+        }
+    }
+}
+
+impl InputScope for AtomicBucket {
+    /// Collect, reset and publish statistics of the metrics whose name is or starts with `prefix`.
+    /// Rates are computed over the time elapsed since the metrics were last flushed, fully or by prefix.
+    /// Flush listeners (e.g. gauges observed on flush) are only notified of full flushes.
+    fn flush_prefix(&self, prefix: &str) -> io::Result<()> {
+        let prefix = self.prefix_append(prefix).join(".");
+        let mut inner = write_lock!(self.inner);
+        inner.flush_prefix(&prefix)
+    }
+
+    /// Lookup or create scores for the requested metric.
+    fn new_metric(&self, name: MetricName, kind: InputKind) -> InputMetric {
+        let (scores, gate) = self.scores(self.prefix_append(name.clone()), kind);
+        let metric_id = MetricId::forge("stats", name);
+
+        // sampling rate of an existing metric prevails, as its scores will be scaled accordingly
+        if let Sampling::Random(float_rate) = scores.sampling {
+            let int_sampling_rate = pcg32::to_int_rate(float_rate);
+            InputMetric::new(metric_id, move |value, labels| {
+                if pcg32::accept_sample(int_sampling_rate) {
+                    match scores.labeled(&labels) {
+                        Some(labeled) => gate.update(&labeled, value),
+                        None => gate.update(&scores, value),
+                    }
+                }
+            })
+        } else {
+            InputMetric::new(metric_id, move |value, labels| {
+                match scores.labeled(&labels) {
+                    Some(labeled) => gate.update(&labeled, value),
+                    None => gate.update(&scores, value),
+                }
+            })
+        }
+    }
+}
+
+impl Flush for AtomicBucket {
+    /// Collect and reset aggregated data.
+    /// Publish statistics
+    fn flush(&self) -> io::Result<()> {
+        self.notify_flush_listeners();
+        let (age, result) = {
+            let mut inner = write_lock!(self.inner);
+            let age = inner.snapshot_gate.oldest_unflushed_age();
+            (age, inner.flush())
+        };
+        self.publish_unflushed_age(age);
+        result
+    }
+}
+
+impl WithAttributes for AtomicBucket {
+    fn get_attributes(&self) -> &Attributes {
+        &self.attributes
+    }
+    fn mut_attributes(&mut self) -> &mut Attributes {
+        &mut self.attributes
+    }
+}
+
+/// Values are sampled _before_ being aggregated.
+/// Cumulative scores (count, sum, rate) are scaled back up by the sampling rate on flush,
+/// so that published statistics remain estimates of the unsampled values.
+impl Sampled for AtomicBucket {}
+
+const HIT: usize = 0;
+const SUM: usize = 1;
+const MAX: usize = 2;
+const MIN: usize = 3;
+/// Count and sum of merged summaries, which are exact and never scaled like sampled values
+const EXACT_HIT: usize = 4;
+const EXACT_SUM: usize = 5;
+const SCORES_LEN: usize = 6;
+
+const UNLATCHED: u8 = 0;
+const LATCHED: u8 = 1;
+const LATCHED_VALUE: u8 = 2;
+
+/// A metric that holds aggregated values.
+/// Some fields are kept public to ease publishing.
+#[derive(Debug)]
+struct AtomicScores {
+    /// The kind of metric
+    kind: InputKind,
+    /// The sampling applied to values before they are recorded
+    sampling: Sampling,
+    /// Weight of each recorded value if sampled at a rate of exactly one in N, else 1
+    upscale: isize,
+    /// The actual recorded metric scores
+    scores: [AtomicIsize; SCORES_LEN],
+    /// Time of last update in microseconds since the scores were created, if tracked
+    last_update: Option<(TimeHandle, AtomicIsize)>,
+    /// Scores of the values written with labels, by label set, if aggregated separately
+    labeled: Option<RwLock<BTreeMap<LabelKey, Arc<AtomicScores>>>>,
+    /// Whether the last value is kept to be published again when no new value is recorded
+    latch: AtomicU8,
+    /// The last value recorded, if latched
+    latest: AtomicIsize,
+    /// Time since the latched value was set after which it is no longer published, in microseconds, if not zero
+    expire_after: AtomicU64,
+    /// Time at which the latched value was set, in microseconds since the scores were created
+    latest_at: (TimeHandle, AtomicU64),
+    /// Whether the running total of the counted values is published instead of their sum
+    cumulative: AtomicBool,
+    /// The running total of the counted values when it was last set, if cumulative.
+    /// Locked while the count since then is reset or read, so that a total is never published with a stale count.
+    total: RwLock<MetricValue>,
+    /// Values counted since the running total was last set, as sampled, if cumulative
+    counted: AtomicIsize,
+}
+
+impl AtomicScores {
+    /// Create new scores to track summary values of a (possibly sampled) metric
+    pub fn new(kind: InputKind, sampling: Sampling) -> Self {
+        AtomicScores {
+            kind,
+            sampling,
+            upscale: exact_upscale(kind, sampling),
+            scores: unsafe {
+                mem::transmute::<[isize; SCORES_LEN], [AtomicIsize; SCORES_LEN]>(
+                    AtomicScores::blank(),
+                )
+            },
+            last_update: None,
+            labeled: None,
+            latch: AtomicU8::new(UNLATCHED),
+            latest: AtomicIsize::new(0),
+            expire_after: AtomicU64::new(0),
+            latest_at: (TimeHandle::now(), AtomicU64::new(0)),
+            cumulative: AtomicBool::new(false),
+            total: RwLock::new(0),
+            counted: AtomicIsize::new(0),
+        }
+    }
+
+    /// Keep the last value to publish it again in periods without new values.
+    pub fn latch(&self) {
+        let _ = self
+            .latch
+            .compare_exchange(UNLATCHED, LATCHED, AcqRel, Relaxed);
+    }
+
+    /// Latch the last value, publishing it again only until `ttl` has elapsed since it was set.
+    pub fn expire_after(&self, ttl: Duration) {
+        let ttl = ttl.as_micros().clamp(1, u128::from(u64::MAX)) as u64;
+        self.expire_after.store(ttl, Release);
+        self.latch()
+    }
+
+    /// Publish the running total of counted values upon every flush, even if no value was counted.
+    pub fn accumulate(&self) {
+        self.cumulative.store(true, Release)
+    }
+
+    /// Also track the time of the last update of the scores.
+    pub fn with_update_age(mut self) -> Self {
+        self.last_update = Some((TimeHandle::now(), AtomicIsize::new(0)));
+        self
+    }
+
+    /// Also track separate scores for values written with labels.
+    pub fn with_labeled(mut self) -> Self {
+        self.labeled = Some(RwLock::new(BTreeMap::new()));
+        self
+    }
+
+    /// Returns the scores of values written with the labels, if tracked separately.
+    /// Returns None if the labels are empty.
+    pub fn labeled(&self, labels: &Labels) -> Option<Arc<AtomicScores>> {
+        let labeled = self.labeled.as_ref()?;
+        let key = labels.value_pairs();
+        if key.is_empty() {
+            return None;
+        }
+        if let Some(scores) = read_lock!(labeled).get(&key) {
+            return Some(scores.clone());
+        }
+        let scores = write_lock!(labeled)
+            .entry(key)
+            .or_insert_with(|| Arc::new(AtomicScores::new(self.kind, self.sampling)))
+            .clone();
+        Some(scores)
+    }
+
+    /// Returns the time elapsed since the scores were last updated, if tracked.
+    pub fn update_age_seconds(&self) -> Option<f64> {
+        self.last_update.as_ref().map(|(created, last_update)| {
+            let age_us = created.elapsed_us() as isize - last_update.load(Acquire);
+            age_us as f64 / 1_000_000.0
+        })
+    }
+
+    /// Returns the metric's kind.
+    pub fn metric_kind(&self) -> InputKind {
+        self.kind
+    }
+
+    #[inline]
+    fn blank() -> [isize; SCORES_LEN] {
+        [0, 0, isize::MIN, isize::MAX, 0, 0]
+    }
+
+    /// Update scores with new value
+    pub fn update(&self, value: MetricValue) {
+        // TODO detect & report any concurrent updates / resets for measurement of contention
+        // Count is tracked for all metrics
+        self.scores[HIT].fetch_add(self.upscale, Relaxed);
+        if let Some((created, last_update)) = &self.last_update {
+            last_update.store(created.elapsed_us() as isize, Release);
+        }
+        match self.kind {
+            InputKind::Marker => {}
+            InputKind::Level => {
+                // Level min & max apply to the _sum_ of values
+                // fetch_add only returns the previous sum, so min & max trail behind by one operation
+                // instead, pickup the slack by comparing again with the final sum upon `snapshot`
                 // this is to avoid making an extra load() on every value
-                let prev_sum = self.scores[SUM].fetch_add(value, Relaxed);
-                swap_if(&self.scores[MAX], prev_sum, |new, current| new > current);
-                swap_if(&self.scores[MIN], prev_sum, |new, current| new < current);
+                let prev_sum = add_saturating(&self.scores[SUM], value);
+                update_max(&self.scores[MAX], prev_sum);
+                update_min(&self.scores[MIN], prev_sum);
+            }
+            InputKind::Gauge if self.latch.load(Relaxed) != UNLATCHED => {
+                self.latest.store(value, Release);
+                if self.expire_after.load(Relaxed) != 0 {
+                    let (created, latest_at) = &self.latest_at;
+                    latest_at.store(created.elapsed_us(), Release);
+                }
+                self.latch.store(LATCHED_VALUE, Release);
+                add_saturating(&self.scores[SUM], value);
+                update_max(&self.scores[MAX], value);
+                update_min(&self.scores[MIN], value);
+            }
+            InputKind::Counter if self.cumulative.load(Relaxed) => {
+                let value = value.saturating_mul(self.upscale);
+                add_saturating(&self.counted, value);
+                add_saturating(&self.scores[SUM], value);
+                update_max(&self.scores[MAX], value);
+                update_min(&self.scores[MIN], value);
             }
-            InputKind::Counter | InputKind::Timer | InputKind::Gauge => {
+            InputKind::Counter | InputKind::Timer | InputKind::Distribution | InputKind::Gauge => {
                 // gauges are non cumulative, but we keep the sum to compute the mean
-                // TODO use #![feature(atomic_min_max)] when stabilized
-                self.scores[SUM].fetch_add(value, Relaxed);
-                swap_if(&self.scores[MAX], value, |new, current| new > current);
-                swap_if(&self.scores[MIN], value, |new, current| new < current);
+                add_saturating(&self.scores[SUM], value.saturating_mul(self.upscale));
+                update_max(&self.scores[MAX], value);
+                update_min(&self.scores[MIN], value);
+            }
+        }
+    }
+
+    /// Merge statistics of values aggregated elsewhere.
+    /// Their count and sum are kept apart from sampled scores, to be published as they are.
+    pub fn merge(&self, summary: &Summary) {
+        add_saturating(&self.scores[EXACT_HIT], summary.count.as_metric_value());
+        if let Some((created, last_update)) = &self.last_update {
+            last_update.store(created.elapsed_us() as isize, Release);
+        }
+        add_saturating(&self.scores[EXACT_SUM], summary.sum);
+        update_max(&self.scores[MAX], summary.max);
+        update_min(&self.scores[MIN], summary.min);
+    }
+
+    /// Replace the running total of a cumulative counter, without counting a value.
+    pub fn set_total(&self, total: MetricValue) {
+        let mut running_total = write_lock!(self.total);
+        *running_total = total;
+        self.counted.store(0, Release);
+    }
+
+    /// Add to the running total of a cumulative counter, without counting a value.
+    fn add_total(&self, total: MetricValue) {
+        let mut running_total = write_lock!(self.total);
+        *running_total = running_total.saturating_add(total);
+    }
+
+    /// The running total of a cumulative counter, with sampled values scaled back up.
+    fn running_total(&self) -> isize {
+        let total = read_lock!(self.total);
+        let counted = self.scaled(self.counted.load(Acquire));
+        total.saturating_add(counted)
+    }
+
+    /// Scale a sampled score back up to estimate the actual score,
+    /// unless it was already scaled exactly as its values were recorded.
+    fn scaled(&self, score: isize) -> isize {
+        match self.sampling {
+            Sampling::Random(rate) if rate > 0.0 && self.upscale == 1 => {
+                (score as f64 / rate).round() as isize
             }
+            _ => score,
+        }
+    }
+
+    /// Current raw scores, without resetting them.
+    fn export(&self) -> [isize; SCORES_LEN] {
+        let mut scores = AtomicScores::blank();
+        for (exported, score) in scores.iter_mut().zip(&self.scores) {
+            *exported = score.load(Acquire);
+        }
+        scores
+    }
+
+    /// Merge raw scores exported from another bucket.
+    fn import(&self, scores: &[isize; SCORES_LEN]) {
+        if scores[HIT] == 0 && scores[EXACT_HIT] == 0 {
+            return;
         }
+        self.scores[HIT].fetch_add(scores[HIT], Relaxed);
+        add_saturating(&self.scores[SUM], scores[SUM]);
+        add_saturating(&self.scores[EXACT_HIT], scores[EXACT_HIT]);
+        add_saturating(&self.scores[EXACT_SUM], scores[EXACT_SUM]);
+        update_max(&self.scores[MAX], scores[MAX]);
+        update_min(&self.scores[MIN], scores[MIN]);
     }
 
     /// Reset scores to zero, return previous values
-    fn snapshot(&self, scores: &mut [isize; 4]) -> bool {
+    fn snapshot(&self, scores: &mut [isize; SCORES_LEN]) -> bool {
         // NOTE copy timestamp, count AND sum _before_ testing for data to reduce concurrent discrepancies
         scores[HIT] = self.scores[HIT].swap(0, AcqRel);
         scores[SUM] = self.scores[SUM].swap(0, AcqRel);
+        scores[EXACT_HIT] = self.scores[EXACT_HIT].swap(0, AcqRel);
+        scores[EXACT_SUM] = self.scores[EXACT_SUM].swap(0, AcqRel);
 
         // if hit count is zero, no values were recorded.
-        if scores[HIT] == 0 {
-            return false;
+        if scores[HIT] == 0 && scores[EXACT_HIT] == 0 {
+            return match self.latched_raw() {
+                Some(latched) => {
+                    *scores = latched;
+                    true
+                }
+                None => false,
+            };
         }
 
         scores[MAX] = self.scores[MAX].swap(isize::MIN, AcqRel);
@@ -369,73 +1673,179 @@ impl AtomicScores {
         true
     }
 
-    /// Map raw scores (if any) to applicable statistics
-    pub fn reset(&self, duration_seconds: f64) -> Option<Vec<ScoreType>> {
+    /// Zero statistics of counters and markers for which no values were recorded.
+    fn idle(&self) -> Option<Vec<ScoreType>> {
+        match self.kind {
+            InputKind::Marker => Some(vec![Count(0), Rate(0.0)]),
+            InputKind::Counter => Some(vec![Count(0), Sum(0), Rate(0.0)]),
+            _ => None,
+        }
+    }
+
+    /// Scores of the latched value standing in for a period without any recorded value, unless expired.
+    /// Cumulative counters have no latched scores, only their running total, which is always published.
+    fn latched_raw(&self) -> Option<[isize; SCORES_LEN]> {
+        if self.cumulative.load(Acquire) {
+            return Some([0, 0, 0, 0, 0, 0]);
+        }
+        if self.latch.load(Acquire) != LATCHED_VALUE {
+            return None;
+        }
+        let ttl = self.expire_after.load(Acquire);
+        let (created, latest_at) = &self.latest_at;
+        if ttl != 0 && created.elapsed_us().saturating_sub(latest_at.load(Acquire)) > ttl {
+            return None;
+        }
+        let latest = self.latest.load(Acquire);
+        Some([1, latest, latest, latest, 0, 0])
+    }
+
+    /// Current raw scores, if any value was recorded or latched, without resetting them.
+    fn peek_raw(&self) -> Option<[isize; SCORES_LEN]> {
+        let scores = [HIT, SUM, MAX, MIN, EXACT_HIT, EXACT_SUM]
+            .map(|score| self.scores[score].load(Acquire));
+        if scores[HIT] == 0 && scores[EXACT_HIT] == 0 {
+            return self.latched_raw();
+        }
+        Some(scores)
+    }
+
+    /// Reset scores, returning the raw previous scores if any values were recorded, ignoring any latched value.
+    /// Values of cumulative counters are kept, as they are part of the running total.
+    fn reset_recorded_raw(&self) -> Option<[isize; SCORES_LEN]> {
+        if self.cumulative.load(Acquire) {
+            return None;
+        }
+        if self.scores[HIT].load(Acquire) == 0 && self.scores[EXACT_HIT].load(Acquire) == 0 {
+            return None;
+        }
+        self.reset_raw()
+    }
+
+    /// Reset scores, returning the raw previous scores if any values were recorded.
+    fn reset_raw(&self) -> Option<[isize; SCORES_LEN]> {
         let mut scores = AtomicScores::blank();
-        if self.snapshot(&mut scores) {
-            let mut snapshot = Vec::new();
-            match self.kind {
-                InputKind::Marker => {
-                    snapshot.push(Count(scores[HIT]));
-                    snapshot.push(Rate(scores[HIT] as f64 / duration_seconds))
-                }
-                InputKind::Gauge => {
-                    snapshot.push(Max(scores[MAX]));
-                    snapshot.push(Min(scores[MIN]));
-                    snapshot.push(Mean(scores[SUM] as f64 / scores[HIT] as f64));
-                }
-                InputKind::Timer => {
-                    snapshot.push(Count(scores[HIT]));
-                    snapshot.push(Sum(scores[SUM]));
-
-                    snapshot.push(Max(scores[MAX]));
-                    snapshot.push(Min(scores[MIN]));
-                    snapshot.push(Mean(scores[SUM] as f64 / scores[HIT] as f64));
-                    // timer rate uses the COUNT of timer calls per second (not SUM)
-                    snapshot.push(Rate(scores[HIT] as f64 / duration_seconds))
-                }
-                InputKind::Counter => {
-                    snapshot.push(Count(scores[HIT]));
-                    snapshot.push(Sum(scores[SUM]));
-
-                    snapshot.push(Max(scores[MAX]));
-                    snapshot.push(Min(scores[MIN]));
-                    snapshot.push(Mean(scores[SUM] as f64 / scores[HIT] as f64));
-                    // counter rate uses the SUM of values per second (e.g. to get bytes/s)
-                    snapshot.push(Rate(scores[SUM] as f64 / duration_seconds))
-                }
-                InputKind::Level => {
-                    snapshot.push(Count(scores[HIT]));
-                    snapshot.push(Sum(scores[SUM]));
-
-                    snapshot.push(Max(scores[MAX]));
-                    snapshot.push(Min(scores[MIN]));
-                    snapshot.push(Mean(scores[SUM] as f64 / scores[HIT] as f64));
-                    // counter rate uses the SUM of values per second (e.g. to get bytes/s)
-                    snapshot.push(Rate(scores[SUM] as f64 / duration_seconds))
-                }
+        self.snapshot(&mut scores).then_some(scores)
+    }
+
+    /// Map raw scores to applicable statistics
+    fn values(&self, scores: &[isize; SCORES_LEN], duration_seconds: f64) -> Vec<ScoreType> {
+        // sampled scores are scaled back up to estimate the actual count & sum
+        let hit = self.scaled(scores[HIT]).saturating_add(scores[EXACT_HIT]);
+        let sum = self.scaled(scores[SUM]).saturating_add(scores[EXACT_SUM]);
+        let mean = sum as f64 / hit as f64;
+        let mut snapshot = Vec::new();
+        match self.kind {
+            InputKind::Marker => {
+                snapshot.push(Count(hit));
+                snapshot.push(Rate(hit as f64 / duration_seconds))
+            }
+            InputKind::Gauge => {
+                snapshot.push(Max(scores[MAX]));
+                snapshot.push(Min(scores[MIN]));
+                snapshot.push(Mean(mean));
+            }
+            InputKind::Timer | InputKind::Distribution => {
+                snapshot.push(Count(hit));
+                snapshot.push(Sum(sum));
+
+                snapshot.push(Max(scores[MAX]));
+                snapshot.push(Min(scores[MIN]));
+                snapshot.push(Mean(mean));
+                // timer rate uses the COUNT of timer calls per second (not SUM)
+                snapshot.push(Rate(hit as f64 / duration_seconds))
+            }
+            InputKind::Counter if self.cumulative.load(Acquire) => {
+                snapshot.push(Count(hit));
+                snapshot.push(Sum(self.running_total()));
+            }
+            InputKind::Counter => {
+                snapshot.push(Count(hit));
+                snapshot.push(Sum(sum));
+
+                snapshot.push(Max(scores[MAX]));
+                snapshot.push(Min(scores[MIN]));
+                snapshot.push(Mean(mean));
+                // counter rate uses the SUM of values per second (e.g. to get bytes/s)
+                snapshot.push(Rate(sum as f64 / duration_seconds))
+            }
+            InputKind::Level => {
+                snapshot.push(Count(hit));
+                snapshot.push(Sum(sum));
+
+                snapshot.push(Max(scores[MAX]));
+                snapshot.push(Min(scores[MIN]));
+                snapshot.push(Mean(mean));
+                // counter rate uses the SUM of values per second (e.g. to get bytes/s)
+                snapshot.push(Rate(sum as f64 / duration_seconds))
             }
-            Some(snapshot)
-        } else {
-            None
         }
+        snapshot
     }
 }
 
-/// Spinlock until success or clear loss to concurrent update.
-#[inline]
-fn swap_if(counter: &AtomicIsize, new_value: isize, compare: fn(isize, isize) -> bool) {
-    let mut current = counter.load(Acquire);
-    while compare(new_value, current) {
-        if counter
-            .compare_exchange(current, new_value, Relaxed, Relaxed)
-            .is_ok()
+/// Counters and markers sampled at a rate of exactly one in N values count every recorded value N times,
+/// so that their scaled up scores are exact integers instead of accumulating rounding errors.
+fn exact_upscale(kind: InputKind, sampling: Sampling) -> isize {
+    match (kind, sampling) {
+        (InputKind::Counter | InputKind::Marker, Sampling::Random(rate))
+            if rate > 0.0 && rate < 1.0 =>
         {
-            // update successful
-            break;
+            let n = (1.0 / rate).round();
+            if (n * rate - 1.0).abs() < 1e-9 && n <= isize::MAX as f64 {
+                n as isize
+            } else {
+                1
+            }
         }
-        // race detected, retry
-        current = counter.load(Acquire);
+        _ => 1,
+    }
+}
+
+/// The total of a metric's scores over a period, used to compute ratios.
+fn total(kind: InputKind, scores: &[ScoreType]) -> f64 {
+    scores
+        .iter()
+        .find_map(|score| match (kind, score) {
+            (InputKind::Marker | InputKind::Timer | InputKind::Distribution, Count(count)) => {
+                Some(*count as f64)
+            }
+            (InputKind::Counter | InputKind::Level, Sum(sum)) => Some(*sum as f64),
+            (InputKind::Gauge, Mean(mean)) => Some(*mean),
+            _ => None,
+        })
+        .unwrap_or(0.0)
+}
+
+/// Add the value to the score, returning the previous score.
+/// Sums that would overflow are clamped to the nearest bound rather than wrapping around,
+/// so that large increments or decrements of signed levels can not flip the sign of the sum.
+/// Concurrent updates made between the overflow and the clamp may be lost.
+#[inline]
+fn add_saturating(score: &AtomicIsize, value: isize) -> isize {
+    let prev = score.fetch_add(value, Relaxed);
+    if prev.checked_add(value).is_none() {
+        score.store(if value > 0 { isize::MAX } else { isize::MIN }, Relaxed);
+        metrics::VALUE_CLAMPED.mark();
+    }
+    prev
+}
+
+/// Raise the score to the new value if it is bigger.
+/// The plain load avoids contending for the cache line in the common case where the score stands.
+#[inline]
+fn update_max(score: &AtomicIsize, new_value: isize) {
+    if new_value > score.load(Relaxed) {
+        score.fetch_max(new_value, Relaxed);
+    }
+}
+
+/// Lower the score to the new value if it is smaller.
+/// The plain load avoids contending for the cache line in the common case where the score stands.
+#[inline]
+fn update_min(score: &AtomicIsize, new_value: isize) {
+    if new_value < score.load(Relaxed) {
+        score.fetch_min(new_value, Relaxed);
     }
 }
 
@@ -446,19 +1856,19 @@ mod bench {
 
     #[bench]
     fn update_marker(b: &mut test::Bencher) {
-        let metric = AtomicScores::new(InputKind::Marker);
+        let metric = AtomicScores::new(InputKind::Marker, Sampling::Full);
         b.iter(|| test::black_box(metric.update(1)));
     }
 
     #[bench]
     fn update_count(b: &mut test::Bencher) {
-        let metric = AtomicScores::new(InputKind::Counter);
+        let metric = AtomicScores::new(InputKind::Counter, Sampling::Full);
         b.iter(|| test::black_box(metric.update(4)));
     }
 
     #[bench]
     fn empty_snapshot(b: &mut test::Bencher) {
-        let metric = AtomicScores::new(InputKind::Counter);
+        let metric = AtomicScores::new(InputKind::Counter, Sampling::Full);
         let scores = &mut AtomicScores::blank();
         b.iter(|| test::black_box(metric.snapshot(scores)));
     }
@@ -481,10 +1891,13 @@ mod bench {
 #[cfg(test)]
 mod mtest {
     use super::*;
-    use crate::stats::{stats_all, stats_average, stats_summary};
+    use crate::stats::{stats_all, stats_all_named, stats_average, stats_summary};
 
     use crate::clock::{mock_clock_advance, mock_clock_reset};
     use crate::output::map::StatsMapScope;
+    use crate::test_util::TestMetrics;
+    use crate::Observe;
+    use std::sync::atomic::AtomicUsize;
 
     use std::collections::BTreeMap;
     use std::time::Duration;
@@ -530,6 +1943,26 @@ mod mtest {
         map.into()
     }
 
+    #[test]
+    fn subsecond_rate() {
+        mock_clock_reset();
+        let metrics = AtomicBucket::new().named("test");
+        metrics.stats(stats_all);
+        let counter = metrics.counter("counter_a");
+
+        counter.count(5);
+        mock_clock_advance(Duration::from_millis(100));
+        let map = StatsMapScope::default();
+        metrics.flush_to(&map).unwrap();
+        assert_eq!(map.into_map()["test.counter_a.rate"], 50);
+
+        counter.count(5);
+        mock_clock_advance(Duration::from_millis(250));
+        let map = StatsMapScope::default();
+        metrics.flush_to(&map).unwrap();
+        assert_eq!(map.into_map()["test.counter_a.rate"], 20);
+    }
+
     #[test]
     fn external_aggregate_all_stats() {
         let map = make_stats(&stats_all);
@@ -580,8 +2013,470 @@ mod mtest {
     }
 
     #[test]
-    fn external_aggregate_average() {
-        let map = make_stats(&stats_average);
+    fn preregistered_metrics() {
+        let metrics = AtomicBucket::new();
+        let registered = metrics.preregister(&[
+            (InputKind::Counter, "count_a"),
+            (InputKind::Timer, "time_a"),
+        ]);
+        assert_eq!(2, registered.len());
+        assert_eq!(2, read_lock!(metrics.inner).metrics.sorted().len());
+
+        // values written through later definitions land in the preregistered scores
+        metrics.counter("count_a").count(5);
+        let map = StatsMapScope::default();
+        metrics.flush_to(&map).unwrap();
+        assert_eq!(map.into_map()["count_a"], 5);
+    }
+
+    #[test]
+    fn concurrent_definitions() {
+        let metrics = AtomicBucket::new();
+        let threads: Vec<_> = (0..8)
+            .map(|i| {
+                let metrics = metrics.clone();
+                std::thread::spawn(move || {
+                    let shared = metrics.scores("shared".into(), InputKind::Counter).0;
+                    metrics.counter(&format!("own_{}", i)).count(1);
+                    shared
+                })
+            })
+            .collect();
+        let shared: Vec<_> = threads.into_iter().map(|t| t.join().unwrap()).collect();
+        assert!(shared.iter().all(|scores| Arc::ptr_eq(scores, &shared[0])));
+        assert_eq!(9, read_lock!(metrics.inner).metrics.sorted().len());
+    }
+
+    #[test]
+    fn sorted_view_cached_until_changed() {
+        let metrics = AtomicBucket::new();
+        let counter_b = metrics.counter("b");
+        let first = read_lock!(metrics.inner).metrics.sorted();
+        assert!(Arc::ptr_eq(
+            &first,
+            &read_lock!(metrics.inner).metrics.sorted()
+        ));
+
+        let counter_a = metrics.counter("a");
+        let second = read_lock!(metrics.inner).metrics.sorted();
+        let names: Vec<_> = second.iter().map(|(name, _)| name.join(".")).collect();
+        assert_eq!(names, vec!["a", "b"]);
+        drop((first, second));
+
+        // the cached view does not keep dropped metrics alive
+        drop(counter_a);
+        metrics.flush().unwrap();
+        assert_eq!(1, read_lock!(metrics.inner).metrics.sorted().len());
+        drop(counter_b);
+    }
+
+    #[test]
+    fn custom_stats_naming() {
+        let metrics = AtomicBucket::new().named("test");
+        metrics.stats(stats_all_named(|mut name, suffix| {
+            let last = name.pop_back().unwrap_or_default();
+            name.push_back(format!("{}_{}", last, suffix));
+            name
+        }));
+        metrics.counter("counter_a").count(10);
+
+        let map = StatsMapScope::default();
+        metrics.flush_to(&map).unwrap();
+        let map = map.into_map();
+        assert_eq!(map["test.counter_a_count"], 1);
+        assert_eq!(map["test.counter_a_sum"], 10);
+    }
+
+    #[test]
+    fn ratio() {
+        let metrics = AtomicBucket::new().named("test");
+        metrics.stats(stats_summary);
+        metrics.register_ratio("error_pct", "errors", "requests", 100);
+        let requests = metrics.marker("requests");
+        let errors = metrics.counter("errors");
+
+        let map = StatsMapScope::default();
+        metrics.flush_to(&map).unwrap();
+        assert_eq!(map.into_map()["test.error_pct"], 0);
+
+        for _ in 0..8 {
+            requests.mark();
+        }
+        errors.count(2);
+        let map = StatsMapScope::default();
+        metrics.flush_to(&map).unwrap();
+        assert_eq!(map.into_map()["test.error_pct"], 25);
+    }
+
+    #[test]
+    fn metric_flush_callback() {
+        let metrics = AtomicBucket::new().named("test");
+        let seen = Arc::new(RwLock::new(vec![]));
+        let sink = seen.clone();
+        metrics.on_metric_flush("counter_a", move |scores| {
+            for score in scores {
+                if let Sum(sum) = score {
+                    write_lock!(sink).push(*sum)
+                }
+            }
+        });
+        let counter = metrics.counter("counter_a");
+
+        counter.count(2);
+        counter.count(3);
+        metrics.flush_to(&StatsMapScope::default()).unwrap();
+        metrics.flush_to(&StatsMapScope::default()).unwrap();
+        counter.count(1);
+        metrics.flush_to(&StatsMapScope::default()).unwrap();
+
+        assert_eq!(vec![5, 1], *read_lock!(seen));
+    }
+
+    #[test]
+    fn saturate_sums() {
+        let metrics = AtomicBucket::new().named("test");
+        metrics.stats(stats_all);
+        let counter = metrics.counter("counter_a");
+        let level = metrics.level("level_a");
+
+        counter.count(isize::MAX as usize);
+        counter.count(1);
+        level.adjust(-2);
+        level.adjust(isize::MIN);
+        level.adjust(-1);
+
+        let map = StatsMapScope::default();
+        metrics.flush_to(&map).unwrap();
+        let map = map.into_map();
+        assert_eq!(map["test.counter_a.sum"], isize::MAX);
+        assert_eq!(map["test.level_a.sum"], isize::MIN);
+        assert_eq!(map["test.level_a.max"], 0);
+    }
+
+    #[test]
+    fn consistent_snapshot() {
+        let metrics = AtomicBucket::new().named("test");
+        metrics.stats(stats_all);
+        metrics.consistent_snapshot(true);
+        let a = metrics.counter("a");
+        let b = metrics.counter("b");
+        let done = Arc::new(AtomicBool::new(false));
+
+        let writer = {
+            let done = done.clone();
+            std::thread::spawn(move || {
+                while !done.load(Relaxed) {
+                    a.count(1);
+                    b.count(1);
+                }
+            })
+        };
+
+        let (mut total_a, mut total_b) = (0, 0);
+        for _ in 0..200 {
+            let map = StatsMapScope::default();
+            metrics.flush_to(&map).unwrap();
+            let map = map.into_map();
+            total_a += map.get("test.a.sum").unwrap_or(&0);
+            total_b += map.get("test.b.sum").unwrap_or(&0);
+            // "b" is always written after "a", and never snapshot later
+            assert!((0..=1).contains(&(total_a - total_b)));
+        }
+        done.store(true, Relaxed);
+        writer.join().unwrap();
+    }
+
+    type LabeledValues = Vec<(String, Option<String>, MetricValue)>;
+
+    /// Records the `cache` label of each value written.
+    #[derive(Clone, Default)]
+    struct LabelsScope {
+        values: Arc<RwLock<LabeledValues>>,
+    }
+
+    impl InputScope for LabelsScope {
+        fn new_metric(&self, name: MetricName, _kind: InputKind) -> InputMetric {
+            let values = self.values.clone();
+            InputMetric::new(
+                MetricId::forge("test", name.clone()),
+                move |value, labels| {
+                    let cache = labels.lookup("cache").map(|v| v.to_string());
+                    write_lock!(values).push((name.join("."), cache, value))
+                },
+            )
+        }
+    }
+
+    impl Flush for LabelsScope {
+        fn flush(&self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn split_labels() {
+        let metrics = AtomicBucket::new().named("test");
+        metrics.stats(stats_all);
+        metrics.split_labels(true);
+        let timer = metrics.timer("lookup");
+
+        timer.interval_us(10);
+        timer.time_labeled(labels!("cache" => "hit"), || ());
+        timer.stop_labeled(timer.start(), labels!("cache" => "hit"));
+        timer.write(500, labels!("cache" => "miss"));
+
+        let scope = LabelsScope::default();
+        metrics.flush_to(&scope).unwrap();
+        let values = read_lock!(scope.values);
+        let count = |cache: Option<&str>| {
+            values
+                .iter()
+                .find(|(name, c, _)| name == "test.lookup.count" && c.as_deref() == cache)
+                .map(|(_, _, value)| *value)
+        };
+        assert_eq!(Some(1), count(None));
+        assert_eq!(Some(2), count(Some("hit")));
+        assert_eq!(Some(1), count(Some("miss")));
+        assert!(values
+            .iter()
+            .any(|(name, c, value)| name == "test.lookup.max"
+                && c.as_deref() == Some("miss")
+                && *value == 500));
+    }
+
+    #[test]
+    fn merge_summaries() {
+        let metrics = AtomicBucket::new().named("test");
+        metrics.stats(stats_all);
+        let latency = metrics.timer("latency");
+
+        latency.interval_us(50);
+        metrics.record_summary(
+            "latency",
+            &Summary {
+                count: 3,
+                sum: 300,
+                min: 20,
+                max: 200,
+            },
+        );
+        metrics.record_summary(
+            "imported",
+            &Summary {
+                count: 2,
+                sum: 10,
+                min: 4,
+                max: 6,
+            },
+        );
+
+        let map = StatsMapScope::default();
+        metrics.flush_to(&map).unwrap();
+        let map = map.into_map();
+        assert_eq!(map["test.latency.count"], 4);
+        assert_eq!(map["test.latency.sum"], 350);
+        assert_eq!(map["test.latency.min"], 20);
+        assert_eq!(map["test.latency.max"], 200);
+        assert_eq!(map["test.imported.mean"], 5);
+    }
+
+    #[test]
+    fn publish_zero() {
+        let metrics = AtomicBucket::new().named("test");
+        metrics.stats(stats_all);
+        let counter = metrics.counter("counter_a");
+        let marker = metrics.marker("marker_a");
+        let gauge = metrics.gauge("gauge_a");
+
+        let map = StatsMapScope::default();
+        metrics.flush_to(&map).unwrap();
+        assert!(map.into_map().is_empty());
+
+        metrics.skip_zero(false);
+        let map = StatsMapScope::default();
+        metrics.flush_to(&map).unwrap();
+        let map = map.into_map();
+        assert_eq!(map["test.counter_a.count"], 0);
+        assert_eq!(map["test.counter_a.sum"], 0);
+        assert_eq!(map["test.marker_a.count"], 0);
+        assert!(!map.contains_key("test.gauge_a.mean"));
+        drop((counter, marker, gauge));
+    }
+
+    #[test]
+    fn flush_prefix_only() {
+        let tm = TestMetrics::new();
+        let metrics = AtomicBucket::new().named("test");
+        metrics.stats(stats_summary);
+        metrics.drain(tm.sink());
+        let http = metrics.counter("http.requests");
+        let http_client = metrics.counter("http_client");
+        let db = metrics.counter("db.queries");
+        http.count(3);
+        http_client.count(4);
+        db.count(5);
+
+        metrics.flush_prefix("http").unwrap();
+        assert_eq!(vec![3], tm.values("test.http.requests"));
+        tm.assert_absent("test.http_client");
+        tm.assert_absent("test.db.queries");
+
+        metrics.flush().unwrap();
+        assert_eq!(vec![3], tm.values("test.http.requests"));
+        tm.assert_count("test.http_client", 4);
+        tm.assert_count("test.db.queries", 5);
+    }
+
+    #[test]
+    fn flush_prefix_period() {
+        mock_clock_reset();
+        let tm = TestMetrics::new();
+        let metrics = AtomicBucket::new().named("test");
+        metrics.stats(stats_all);
+        metrics.drain(tm.sink());
+        let flushes = Arc::new(AtomicUsize::new(0));
+        let observed = flushes.clone();
+        let _cancel = metrics
+            .observe(metrics.gauge("flushes"), move |_| {
+                observed.fetch_add(1, Relaxed) as MetricValue
+            })
+            .on_flush();
+        let http = metrics.counter("http.requests");
+        let db = metrics.counter("db.queries");
+
+        http.count(10);
+        db.count(10);
+        mock_clock_advance(Duration::from_secs(1));
+        metrics.flush_prefix("http").unwrap();
+        assert_eq!(0, flushes.load(Relaxed));
+
+        http.count(10);
+        db.count(10);
+        mock_clock_advance(Duration::from_secs(1));
+        metrics.flush().unwrap();
+        assert_eq!(1, flushes.load(Relaxed));
+
+        // each flush of http covered one second, db was accumulated over both
+        assert_eq!(vec![10, 10], tm.values("test.http.requests.rate"));
+        assert_eq!(vec![10], tm.values("test.db.queries.rate"));
+    }
+
+    #[test]
+    fn update_age_published_without_updates() {
+        mock_clock_reset();
+
+        let metrics = AtomicBucket::new().named("test");
+        metrics.track_update_age(true);
+        let counter = metrics.counter("counter_a");
+
+        mock_clock_advance(Duration::from_secs(2));
+        counter.count(1);
+        mock_clock_advance(Duration::from_secs(3));
+
+        let map = StatsMapScope::default();
+        metrics.flush_to(&map).unwrap();
+        assert_eq!(
+            map.clone().into_map()["test.counter_a.last_update_age_seconds"],
+            3
+        );
+
+        // no more updates, age keeps growing
+        mock_clock_advance(Duration::from_secs(4));
+        metrics.flush_to(&map).unwrap();
+        assert_eq!(map.into_map()["test.counter_a.last_update_age_seconds"], 7);
+    }
+
+    #[test]
+    fn sampled_aggregate_is_scaled() {
+        mock_clock_reset();
+
+        let metrics = AtomicBucket::new()
+            .named("test")
+            .sampled(Sampling::Random(0.5));
+        metrics.stats(stats_all);
+
+        let marker = metrics.marker("marker_a");
+        for _ in 0..10_000 {
+            marker.mark();
+        }
+
+        mock_clock_advance(Duration::from_secs(1));
+
+        let map = StatsMapScope::default();
+        metrics.flush_to(&map).unwrap();
+        let map: BTreeMap<String, MetricValue> = map.into();
+
+        // roughly half the values were recorded, but the published count is scaled back up
+        let count = map["test.marker_a.count"];
+        assert!(count > 9_000 && count < 11_000, "count was {}", count);
+    }
+
+    #[test]
+    fn sampled_one_in_is_exact() {
+        let scores = AtomicScores::new(InputKind::Counter, Sampling::one_in(3));
+        for _ in 0..1_000 {
+            scores.update(7);
+        }
+        scores.merge(&Summary {
+            count: 2,
+            sum: 10,
+            min: 5,
+            max: 5,
+        });
+        // merged values are exact, they are not scaled like sampled values
+        let values = scores.values(&scores.reset_raw().unwrap(), 1.0);
+        assert!(matches!(values[0], Count(3_002)), "{:?}", values);
+        assert!(matches!(values[1], Sum(21_010)), "{:?}", values);
+    }
+
+    #[test]
+    fn sampled_merge_is_not_scaled() {
+        let scores = AtomicScores::new(InputKind::Distribution, Sampling::Random(0.3));
+        scores.merge(&Summary {
+            count: 2,
+            sum: 10,
+            min: 4,
+            max: 6,
+        });
+        let values = scores.values(&scores.reset_raw().unwrap(), 1.0);
+        assert!(matches!(values[0], Count(2)), "{:?}", values);
+        assert!(matches!(values[1], Sum(10)), "{:?}", values);
+        assert!(
+            matches!(values[4], Mean(mean) if mean == 5.0),
+            "{:?}",
+            values
+        );
+    }
+
+    #[test]
+    fn sampled_kind() {
+        mock_clock_reset();
+
+        let metrics = AtomicBucket::new()
+            .named("test")
+            .sampled_kind(InputKind::Timer, Sampling::Random(0.5));
+        metrics.stats(stats_all);
+
+        let marker = metrics.marker("marker_a");
+        let timer = metrics.timer("timer_a");
+        for _ in 0..10_000 {
+            marker.mark();
+            timer.interval_us(1);
+        }
+
+        let map = StatsMapScope::default();
+        metrics.flush_to(&map).unwrap();
+        let map: BTreeMap<String, MetricValue> = map.into();
+
+        // only timers are sampled, their count is scaled back up
+        assert_eq!(map["test.marker_a.count"], 10_000);
+        let count = map["test.timer_a.count"];
+        assert!(count > 9_000 && count < 11_000, "count was {}", count);
+    }
+
+    #[test]
+    fn external_aggregate_average() {
+        let map = make_stats(&stats_average);
 
         assert_eq!(map["test.counter_a"], 15);
         assert_eq!(map["test.counter_b"], 10);
@@ -590,4 +2485,448 @@ mod mtest {
         assert_eq!(map["test.gauge_a"], 15);
         assert_eq!(map["test.marker_a"], 3);
     }
+
+    #[test]
+    fn retained_history() {
+        let metrics = AtomicBucket::new().named("test");
+        metrics.stats(stats_summary);
+        metrics.retain_history(2);
+        let counter = metrics.counter("counter_a");
+
+        for count in 1..=3 {
+            counter.count(count);
+            metrics.flush_to(&StatsMapScope::default()).unwrap();
+        }
+
+        let values: Vec<MetricValue> = metrics
+            .history("counter_a")
+            .into_iter()
+            .map(|(_, value)| value)
+            .collect();
+        assert_eq!(vec![2, 3], values);
+        assert!(metrics.history("counter_b").is_empty());
+
+        metrics.retain_history(1);
+        assert_eq!(1, metrics.history("counter_a").len());
+    }
+
+    #[test]
+    fn cumulative_counter() {
+        let metrics = AtomicBucket::new().named("test");
+        metrics.stats(stats_all);
+        let counter = metrics.cumulative_counter("counter_a");
+        let flush = || {
+            let map = StatsMapScope::default();
+            metrics.flush_to(&map).unwrap();
+            let map = map.into_map();
+            (map["test.counter_a.count"], map["test.counter_a.sum"])
+        };
+
+        counter.count(3);
+        counter.set_total(10);
+        counter.count(2);
+        // setting the total does not count a value
+        assert_eq!((2, 12), flush());
+
+        // the total is published again without new values
+        assert_eq!((0, 12), flush());
+
+        counter.count(1);
+        assert_eq!((1, 13), flush());
+    }
+
+    #[test]
+    fn cumulative_total_reset_atomically() {
+        let scores = Arc::new(AtomicScores::new(InputKind::Counter, Sampling::Full));
+        scores.accumulate();
+        let writer = scores.clone();
+        let writing = std::thread::spawn(move || {
+            for _ in 0..10_000 {
+                writer.set_total(0);
+                writer.update(1);
+                writer.set_total(1000);
+            }
+        });
+        while !writing.is_finished() {
+            // a reset total is never published with the count preceding it
+            let total = scores.running_total();
+            assert!([0, 1, 1000].contains(&total), "{}", total);
+        }
+        writing.join().unwrap();
+    }
+
+    #[test]
+    fn gauge_group() {
+        let metrics = AtomicBucket::new().named("test");
+        metrics.stats(stats_summary);
+        let idle = metrics.gauge("idle");
+        let active = metrics.gauge("active");
+        let group = metrics.gauge_group();
+
+        group.set_all(&[(&idle, 3.0), (&active, 5.0)]);
+        metrics.consistent_snapshot(true);
+        group.set_all(&[(&idle, 3.0), (&active, 5.0)]);
+
+        let map = StatsMapScope::default();
+        metrics.flush_to(&map).unwrap();
+        let map = map.into_map();
+        assert_eq!(map["test.idle"], 3);
+        assert_eq!(map["test.active"], 5);
+    }
+
+    #[test]
+    fn gauge_group_concurrent() {
+        let metrics = AtomicBucket::new().named("test");
+        metrics.stats(stats_all);
+        metrics.consistent_snapshot(true);
+        let idle = metrics.gauge("idle");
+        let active = metrics.gauge("active");
+        let group = metrics.gauge_group();
+        let done = Arc::new(AtomicBool::new(false));
+
+        let writers: Vec<_> = (0..4)
+            .map(|writer| {
+                let (idle, active, group, done) =
+                    (idle.clone(), active.clone(), group.clone(), done.clone());
+                std::thread::spawn(move || {
+                    let mut value = writer;
+                    while !done.load(Relaxed) {
+                        group.set_all(&[(&idle, value as f64), (&active, value as f64)]);
+                        value += 4;
+                    }
+                })
+            })
+            .collect();
+
+        for _ in 0..200 {
+            let map = StatsMapScope::default();
+            metrics.flush_to(&map).unwrap();
+            let map = map.into_map();
+            // both gauges of every group update are snapshot together
+            for stat in ["count", "sum", "min", "max"] {
+                assert_eq!(
+                    map.get(&format!("test.idle.{}", stat)),
+                    map.get(&format!("test.active.{}", stat))
+                );
+            }
+        }
+        done.store(true, Relaxed);
+        for writer in writers {
+            writer.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn coalesce_flushes() {
+        mock_clock_reset();
+        let metrics = AtomicBucket::new().named("test");
+        metrics.stats(stats_summary);
+        metrics.coalesce_flushes(Duration::from_secs(1));
+        let counter = metrics.counter("counter_a");
+
+        counter.count(3);
+        let map = StatsMapScope::default();
+        metrics.flush_to(&map).unwrap();
+        assert_eq!(map.into_map()["test.counter_a"], 3);
+
+        // coalesced with the previous flush, values are kept for the next one
+        counter.count(4);
+        let map = StatsMapScope::default();
+        metrics.flush_to(&map).unwrap();
+        assert!(map.into_map().is_empty());
+
+        mock_clock_advance(Duration::from_secs(1));
+        let map = StatsMapScope::default();
+        metrics.flush_to(&map).unwrap();
+        assert_eq!(map.into_map()["test.counter_a"], 4);
+    }
+
+    #[test]
+    fn snapshot_diff() {
+        let metrics = AtomicBucket::new();
+        metrics.stats(stats_summary);
+        let requests = metrics.counter("requests");
+        let idle = metrics.gauge("idle");
+        requests.count(2);
+        idle.value(5);
+        let before = metrics.snapshot();
+
+        requests.count(3);
+        metrics.marker("errors").mark();
+        let after = metrics.snapshot();
+
+        let diff = before.diff(&after);
+        assert_eq!(Some(&3), diff.changed.get("requests"));
+        assert!(!diff.changed.contains_key("idle"));
+        assert_eq!(Some(&1), diff.added.get("errors"));
+        assert!(diff.removed.is_empty());
+
+        // snapshots do not reset values
+        assert_eq!(Some(5), metrics.snapshot().get("requests"));
+        metrics.flush_to(&StatsMapScope::default()).unwrap();
+        assert_eq!(
+            Some(&5),
+            after.diff(&metrics.snapshot()).removed.get("idle")
+        );
+    }
+
+    #[test]
+    fn late_values() {
+        let metrics = AtomicBucket::new();
+        metrics.stats(stats_summary);
+        metrics.lateness(1);
+        let first_window = SystemTime::now();
+        assert!(metrics.record_at("hits", InputKind::Counter, 1, first_window));
+
+        // the first window is kept open
+        let map = StatsMapScope::default();
+        metrics.flush_to(&map).unwrap();
+        assert!(!map.into_map().contains_key("hits"));
+
+        assert!(metrics.record_at("hits", InputKind::Counter, 2, first_window));
+        assert!(metrics.record_at("hits", InputKind::Counter, 4, SystemTime::now()));
+        assert!(!metrics.record_at("hits", InputKind::Counter, 8, SystemTime::UNIX_EPOCH));
+
+        let map = StatsMapScope::default();
+        metrics.flush_to(&map).unwrap();
+        assert_eq!(map.into_map()["hits"], 3);
+
+        let map = StatsMapScope::default();
+        metrics.flush_to(&map).unwrap();
+        assert_eq!(map.into_map()["hits"], 4);
+    }
+
+    #[test]
+    fn late_windows_published() {
+        mock_clock_reset();
+        let tm = TestMetrics::new();
+        let metrics = AtomicBucket::new();
+        metrics.stats(stats_all);
+        metrics.drain(tm.sink());
+        metrics.lateness(1);
+        let rollup = TestMetrics::default();
+        metrics.downsample(2, rollup.sink());
+        metrics.latched_gauge("version").value(3);
+        let hits = metrics.counter("hits");
+
+        hits.count(20);
+        mock_clock_advance(Duration::from_secs(10));
+        metrics.flush().unwrap();
+        mock_clock_advance(Duration::from_secs(30));
+        metrics.flush().unwrap();
+
+        // the latched gauge is published once per flush, not again with the delayed window
+        assert_eq!(vec![3, 3], tm.values("version.max"));
+        // the delayed window's rate is computed over the 10s it was open
+        assert_eq!(vec![2], tm.values("hits.rate"));
+        rollup.assert_count("hits.sum", 20);
+    }
+
+    #[test]
+    fn oldest_unflushed_age() {
+        mock_clock_reset();
+        let metrics = AtomicBucket::new();
+        assert_eq!(Duration::ZERO, metrics.oldest_unflushed_age());
+
+        let counter = metrics.counter("count");
+        counter.count(1);
+        mock_clock_advance(Duration::from_secs(2));
+        counter.count(1);
+        mock_clock_advance(Duration::from_secs(1));
+        assert_eq!(Duration::from_secs(3), metrics.oldest_unflushed_age());
+
+        metrics.flush_to(&StatsMapScope::default()).unwrap();
+        assert_eq!(Duration::ZERO, metrics.oldest_unflushed_age());
+    }
+
+    #[test]
+    fn expiring_gauge() {
+        mock_clock_reset();
+        let metrics = AtomicBucket::new();
+        metrics.stats(stats_summary);
+        let workers = metrics.expiring_gauge("workers", Duration::from_secs(30));
+        workers.value(4);
+
+        let flush = || {
+            let map = StatsMapScope::default();
+            metrics.flush_to(&map).unwrap();
+            map.into_map().get("workers").cloned()
+        };
+        assert_eq!(Some(4), flush());
+        mock_clock_advance(Duration::from_secs(20));
+        assert_eq!(Some(4), flush());
+        mock_clock_advance(Duration::from_secs(20));
+        assert_eq!(None, flush());
+
+        // a new value is published again
+        workers.value(2);
+        assert_eq!(Some(2), flush());
+    }
+
+    #[test]
+    fn latched_gauge() {
+        let metrics = AtomicBucket::new().named("test");
+        metrics.stats(stats_summary);
+        let version = metrics.latched_gauge("version");
+        version.value(3);
+
+        for _ in 0..2 {
+            let map = StatsMapScope::default();
+            metrics.flush_to(&map).unwrap();
+            assert_eq!(map.into_map()["test.version"], 3);
+        }
+        // snapshots see the latched value too
+        assert_eq!(Some(3), metrics.snapshot().get("test.version"));
+
+        version.value(4);
+        let map = StatsMapScope::default();
+        metrics.flush_to(&map).unwrap();
+        assert_eq!(map.into_map()["test.version"], 4);
+
+        metrics.gauge("unlatched").value(5);
+        metrics.flush().unwrap();
+        let map = StatsMapScope::default();
+        metrics.flush_to(&map).unwrap();
+        assert!(!map.into_map().contains_key("test.unlatched"));
+    }
+
+    #[derive(Clone, Default)]
+    struct Written(Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl io::Write for Written {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn downsample() {
+        let metrics = AtomicBucket::new().named("test");
+        metrics.stats(stats_summary);
+        let written = Written::default();
+        metrics.downsample(3, crate::Stream::write_to(written.clone()));
+        let counter = metrics.counter("counter_a");
+        let gauge = metrics.gauge("gauge_a");
+
+        for window in 1..=3 {
+            counter.count(window);
+            if window < 3 {
+                gauge.value(window * 10);
+            }
+            let map = StatsMapScope::default();
+            metrics.flush_to(&map).unwrap();
+            assert_eq!(map.into_map()["test.counter_a"], window as MetricValue);
+            if window < 3 {
+                assert!(written.0.lock().unwrap().is_empty());
+            }
+        }
+
+        assert_eq!(
+            "test.counter_a 6\ntest.gauge_a 15\n",
+            String::from_utf8(written.0.lock().unwrap().clone()).unwrap()
+        );
+    }
+
+    #[test]
+    fn warmup() {
+        mock_clock_reset();
+        let metrics = AtomicBucket::new().named("test");
+        metrics.stats(stats_summary);
+        metrics.warmup(Duration::from_secs(30));
+        let counter = metrics.counter("counter_a");
+
+        counter.count(3);
+        let map = StatsMapScope::default();
+        metrics.flush_to(&map).unwrap();
+        assert!(map.into_map().is_empty());
+
+        counter.count(4);
+        mock_clock_advance(Duration::from_secs(30));
+        let map = StatsMapScope::default();
+        metrics.flush_to(&map).unwrap();
+        assert_eq!(map.into_map()["test.counter_a"], 4);
+    }
+
+    #[test]
+    fn skip_idle_flush() {
+        let metrics = AtomicBucket::new().named("test");
+        metrics.stats(stats_summary);
+        metrics.skip_zero(false);
+        metrics.skip_idle_flush(true);
+        let _counter = metrics.counter("counter_a");
+
+        let map = StatsMapScope::default();
+        metrics.flush_to(&map).unwrap();
+        assert!(map.into_map().is_empty());
+    }
+
+    #[test]
+    fn export_import() {
+        let metrics = AtomicBucket::new().named("test");
+        metrics.stats(stats_summary);
+        metrics.counter("counter_a").count(5);
+        metrics.gauge("gauge_a").value(7);
+        let state = metrics.export();
+
+        let restored = AtomicBucket::new().named("test");
+        restored.stats(stats_summary);
+        restored.counter("counter_a").count(1);
+        restored.import(&state).unwrap();
+
+        let map = StatsMapScope::default();
+        restored.flush_to(&map).unwrap();
+        let map = map.into_map();
+        assert_eq!(map["test.counter_a"], 6);
+        assert_eq!(map["test.gauge_a"], 7);
+
+        assert!(restored.import(&state[..state.len() - 1]).is_err());
+        assert!(restored.import(b"DPSB\x02").is_err());
+        assert!(restored.import(b"DPSB\x01\x00\x00\x00\x00").is_ok());
+    }
+
+    #[test]
+    fn export_import_cumulative() {
+        let metrics = AtomicBucket::new().named("test");
+        metrics.stats(stats_all);
+        let counter = metrics.cumulative_counter("counter_a");
+        counter.set_total(10);
+        counter.count(2);
+        let state = metrics.export();
+
+        let restored = AtomicBucket::new().named("test");
+        restored.stats(stats_all);
+        restored.import(&state).unwrap();
+
+        let flush = || {
+            let map = StatsMapScope::default();
+            restored.flush_to(&map).unwrap();
+            map.into_map()["test.counter_a.sum"]
+        };
+        assert_eq!(12, flush());
+        // the imported total is kept as a running total
+        assert_eq!(12, flush());
+    }
+
+    #[test]
+    fn cumulative_counter_sampled() {
+        let metrics = AtomicBucket::new()
+            .named("test")
+            .sampled(Sampling::Random(0.5));
+        metrics.stats(stats_all);
+        let counter = metrics.cumulative_counter("counter_a");
+        for _ in 0..10_000 {
+            counter.count(1);
+        }
+
+        let map = StatsMapScope::default();
+        metrics.flush_to(&map).unwrap();
+        let map = map.into_map();
+        // the total is scaled back up like the count
+        let total = map["test.counter_a.sum"];
+        assert!(total > 9_000 && total < 11_000, "total was {}", total);
+        assert_eq!(map["test.counter_a.count"], total);
+    }
 }
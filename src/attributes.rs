@@ -1,14 +1,16 @@
+use std::any::Any;
 use std::collections::HashMap;
 use std::default::Default;
-use std::sync::atomic::AtomicUsize;
 use std::sync::atomic::Ordering;
+use std::sync::atomic::{AtomicIsize, AtomicUsize};
 use std::sync::Arc;
 
+use crate::clock::Clock;
 use crate::name::{MetricName, NameParts};
 use crate::scheduler::{Cancel, SCHEDULER};
-use crate::{CancelHandle, Flush, InputMetric, InputScope, MetricValue};
-use std::fmt;
+use crate::{CancelHandle, Flush, InputKind, InputMetric, InputScope, MetricValue};
 use std::time::{Duration, Instant};
+use std::{env, fmt, io};
 
 #[cfg(not(feature = "parking_lot"))]
 use std::sync::RwLock;
@@ -19,10 +21,11 @@ use parking_lot::RwLock;
 use std::ops::Deref;
 
 /// The actual distribution (random, fixed-cycled, etc) depends on selected sampling method.
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, Default)]
 pub enum Sampling {
     /// Record every collected value.
     /// Effectively disable sampling.
+    #[default]
     Full,
 
     /// Floating point sampling rate
@@ -32,18 +35,21 @@ pub enum Sampling {
     Random(f64),
 }
 
-impl Default for Sampling {
-    fn default() -> Sampling {
-        Sampling::Full
+impl Sampling {
+    /// Record one of every `n` values, e.g. `one_in(10)` to record 10% of values.
+    /// Aggregated counts and sums of counters and markers sampled at such rates are scaled up exactly.
+    pub fn one_in(n: u32) -> Sampling {
+        Sampling::Random(1.0 / f64::from(n.max(1)))
     }
 }
 
 /// A metrics buffering strategy.
 /// All strategies other than `Unbuffered` are applied as a best-effort, meaning that the buffer
 /// may be flushed at any moment before reaching the limit, for any or no reason in particular.
-#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Default)]
 pub enum Buffering {
     /// Do not buffer output.
+    #[default]
     Unbuffered,
 
     /// A buffer of maximum specified size is used.
@@ -53,12 +59,6 @@ pub enum Buffering {
     Unlimited,
 }
 
-impl Default for Buffering {
-    fn default() -> Buffering {
-        Buffering::Unbuffered
-    }
-}
-
 /// A metrics identifier
 #[derive(Clone, Debug, Hash, Eq, PartialOrd, PartialEq)]
 pub struct MetricId(String);
@@ -69,24 +69,104 @@ impl MetricId {
         let id: String = name.join("/");
         MetricId(format!("{}:{}", out_type, id))
     }
+
+    /// The identifier as a string, e.g. `statsd:app/requests`.
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
 }
 
 pub type Shared<T> = Arc<RwLock<T>>;
 
 pub struct Listener {
     listener_id: usize,
-    listener_fn: Arc<dyn Fn(Instant) + Send + Sync + 'static>,
+    listener_fn: ListenerFn,
 }
 
+/// A flush listener, called with the time of the flush and the scope being flushed.
+type ListenerFn = Arc<dyn Fn(Instant, &dyn Any) + Send + Sync + 'static>;
+
 /// Attributes common to metric components.
 /// Not all attributes used by all components.
 #[derive(Clone, Default)]
 pub struct Attributes {
     naming: NameParts,
     sampling: Sampling,
+    kind_sampling: HashMap<InputKind, Sampling>,
     buffering: Buffering,
     flush_listeners: Shared<HashMap<MetricId, Listener>>,
     tasks: Shared<Vec<CancelHandle>>,
+    immediate_flush: Arc<AtomicUsize>,
+    clock: Clock,
+}
+
+impl Attributes {
+    /// Register a function to be called upon every flush, replacing any listener with the same id.
+    /// The returned handle removes the listener, unless it was since replaced.
+    pub(crate) fn add_flush_listener<F>(&self, metric_id: MetricId, listener_fn: F) -> OnFlushCancel
+    where
+        F: Fn(Instant) + Send + Sync + 'static,
+    {
+        self.add_listener(metric_id, move |now, _scope| listener_fn(now))
+    }
+
+    /// Register a function to be called with the scope being flushed, replacing any listener with the same id.
+    fn add_listener<F>(&self, metric_id: MetricId, listener_fn: F) -> OnFlushCancel
+    where
+        F: Fn(Instant, &dyn Any) + Send + Sync + 'static,
+    {
+        let listener_id = ID_GENERATOR.fetch_add(1, Ordering::Relaxed);
+        write_lock!(self.flush_listeners).insert(
+            metric_id.clone(),
+            Listener {
+                listener_id,
+                listener_fn: Arc::new(listener_fn),
+            },
+        );
+
+        let flush_listeners = self.flush_listeners.clone();
+        OnFlushCancel(Arc::new(move || {
+            let mut listeners = write_lock!(flush_listeners);
+            let installed_listener_id = listeners.get(&metric_id).map(|v| v.listener_id);
+            if installed_listener_id == Some(listener_id) {
+                listeners.remove(&metric_id);
+            }
+        }))
+    }
+
+    /// Copy the configuration attributes but not the flush listeners or scheduled tasks,
+    /// leaving the copy with an independent lifecycle.
+    pub(crate) fn fork(&self) -> Self {
+        Attributes {
+            naming: self.naming.clone(),
+            sampling: self.sampling,
+            kind_sampling: self.kind_sampling.clone(),
+            buffering: self.buffering,
+            flush_listeners: Shared::default(),
+            tasks: Shared::default(),
+            immediate_flush: Arc::default(),
+            clock: self.clock.clone(),
+        }
+    }
+
+    /// The clock timing the metrics defined from this component.
+    #[cfg(any(test, feature = "test-util"))]
+    pub(crate) fn clock(&self) -> &Clock {
+        &self.clock
+    }
+
+    /// Time the metrics defined from this component with another clock.
+    #[cfg(any(test, feature = "test-util"))]
+    pub(crate) fn set_clock(&mut self, clock: Clock) {
+        self.clock = clock
+    }
+
+    /// Returns false if buffering is disabled or suspended by `with_immediate_flush()`.
+    /// Outputs must check this upon every write, as immediate flush may be raised after metrics are defined.
+    pub(crate) fn is_buffered(&self) -> bool {
+        !(self.buffering == Buffering::Unbuffered
+            || self.immediate_flush.load(Ordering::Acquire) > 0)
+    }
 }
 
 impl fmt::Debug for Attributes {
@@ -97,7 +177,7 @@ impl fmt::Debug for Attributes {
     }
 }
 
-/// This trait should not be exposed outside the crate.
+/// Access to the attributes of a component, also needed to implement custom outputs (see the `ext` module).
 pub trait WithAttributes: Clone {
     /// Return attributes of component.
     fn get_attributes(&self) -> &Attributes;
@@ -122,12 +202,12 @@ pub trait OnFlush {
 
 impl<T> OnFlush for T
 where
-    T: Flush + WithAttributes,
+    T: Flush + WithAttributes + 'static,
 {
     fn notify_flush_listeners(&self) {
         let now = Instant::now();
         for listener in read_lock!(self.get_attributes().flush_listeners).values() {
-            (listener.listener_fn)(now)
+            (listener.listener_fn)(now, self)
         }
     }
 }
@@ -160,26 +240,11 @@ where
         let gauge = self.metric;
         let metric_id = gauge.metric_id().clone();
         let op = self.operation;
-        let listener_id = ID_GENERATOR.fetch_add(1, Ordering::Relaxed);
-
-        write_lock!(self.target.get_attributes().flush_listeners).insert(
-            metric_id.clone(),
-            Listener {
-                listener_id,
-                listener_fn: Arc::new(move |now| gauge.write(op(now), Labels::default())),
-            },
-        );
-
-        let flush_listeners = self.target.get_attributes().flush_listeners.clone();
-        OnFlushCancel(Arc::new(move || {
-            let mut listeners = write_lock!(flush_listeners);
-            let installed_listener_id = listeners.get(&metric_id).map(|v| v.listener_id);
-            if let Some(id) = installed_listener_id {
-                if id == listener_id {
-                    listeners.remove(&metric_id);
-                }
-            }
-        }))
+        self.target
+            .get_attributes()
+            .add_flush_listener(metric_id, move |now| {
+                gauge.write(op(now), Labels::default())
+            })
     }
 
     /// Observe the metric's value periodically.
@@ -205,7 +270,7 @@ pub trait Observe {
         &self,
         metric: impl Deref<Target = InputMetric>,
         operation: F,
-    ) -> ObserveWhen<Self::Inner, F>
+    ) -> ObserveWhen<'_, Self::Inner, F>
     where
         F: Fn(Instant) -> MetricValue + Send + Sync + 'static,
         Self: Sized;
@@ -217,7 +282,7 @@ impl<T: InputScope + WithAttributes> Observe for T {
         &self,
         metric: impl Deref<Target = InputMetric>,
         operation: F,
-    ) -> ObserveWhen<Self, F>
+    ) -> ObserveWhen<'_, Self, F>
     where
         F: Fn(Instant) -> MetricValue + Send + Sync + 'static,
         Self: Sized,
@@ -230,6 +295,77 @@ impl<T: InputScope + WithAttributes> Observe for T {
     }
 }
 
+/// Emit a liveness metric whenever a scope is flushed.
+pub trait Heartbeat {
+    /// Emit a gauge of this scope's flush count upon every flush, regardless of application activity.
+    /// A silent process will still report its metric, an absent one means the process is not running.
+    /// The heartbeat stops when the returned handle is cancelled.
+    fn add_heartbeat(&self, name: &str) -> OnFlushCancel;
+}
+
+impl<T: InputScope + WithAttributes + Send + Sync> Heartbeat for T {
+    fn add_heartbeat(&self, name: &str) -> OnFlushCancel {
+        let flushes = AtomicIsize::new(0);
+        self.observe(self.gauge(name), move |_| {
+            flushes.fetch_add(1, Ordering::Relaxed) + 1
+        })
+        .on_flush()
+    }
+}
+
+/// Record several metrics at once from a single source, e.g. gauges parsed from `/proc/stat`.
+pub trait Collect {
+    /// Call the collector with this scope right before every flush, to record the values it collects.
+    /// This is the multi-metric equivalent of observing a gauge on flush.
+    /// The collector is handed the scope being flushed rather than keeping its own reference to it,
+    /// so the scope is still released when dropped, without cancelling the collector first.
+    fn add_collector<F>(&self, collector: F) -> OnFlushCancel
+    where
+        F: Fn(&Self) + Send + Sync + 'static,
+        Self: Sized;
+}
+
+impl<T: InputScope + WithAttributes + Send + Sync + 'static> Collect for T {
+    fn add_collector<F>(&self, collector: F) -> OnFlushCancel
+    where
+        F: Fn(&Self) + Send + Sync + 'static,
+    {
+        let listener_id = ID_GENERATOR.fetch_add(1, Ordering::Relaxed);
+        let metric_id = MetricId::forge("collector", listener_id.to_string().into());
+        self.get_attributes()
+            .add_listener(metric_id, move |_now, scope: &dyn Any| {
+                if let Some(scope) = scope.downcast_ref::<T>() {
+                    collector(scope)
+                }
+            })
+    }
+}
+
+/// Name of the info metric published by `Info::set_info()`.
+const INFO_METRIC: &str = "dipstick_build_info";
+
+/// Publish constant metadata, such as a build version or git SHA, as a Prometheus-style "info" metric.
+pub trait Info {
+    /// Emit a `dipstick_build_info` gauge of value 1 upon every flush, labeled with the provided key / value pairs.
+    /// Replaces any previously set info. Only outputs supporting labels (such as Prometheus) publish the metadata.
+    fn set_info(&self, info: &[(&str, &str)]);
+}
+
+impl<T: InputScope + WithAttributes + Send + Sync> Info for T {
+    fn set_info(&self, info: &[(&str, &str)]) {
+        let gauge = self.gauge(INFO_METRIC);
+        let metric_id = gauge.metric_id().clone();
+        let labels: HashMap<String, Arc<String>> = info
+            .iter()
+            .map(|(key, value)| (key.to_string(), Arc::new(value.to_string())))
+            .collect();
+        self.get_attributes()
+            .add_flush_listener(metric_id, move |_now| {
+                gauge.write(1, Labels::from(labels.clone()))
+            });
+    }
+}
+
 impl Drop for Attributes {
     fn drop(&mut self) {
         let mut tasks = write_lock!(self.tasks);
@@ -258,6 +394,20 @@ pub trait Prefixed {
     /// If multiple names are required, `add_name` may also be used.
     fn named<S: Into<String>>(&self, name: S) -> Self;
 
+    /// Extend the existing names with a child segment, which may itself be dotted, e.g. `module.operation`.
+    /// Return a clone of the component with the extended names, leaving the parent's names untouched.
+    fn nested<S: Into<String>>(&self, segment: S) -> Self;
+
+    /// Extend the existing names with segments interpolating environment variables, e.g. `{HOSTNAME}.{ENV}`.
+    /// Variables are resolved once, when this is called. Return a clone of the component with the extended names.
+    /// Fails if a referenced variable is not set, or if the pattern has an unclosed or empty `{}` reference.
+    fn with_env_prefix(&self, pattern: &str) -> io::Result<Self>
+    where
+        Self: Sized,
+    {
+        Ok(self.nested(interpolate_env(pattern)?))
+    }
+
     /// Append any name parts to the name's namespace.
     fn prefix_append<S: Into<MetricName>>(&self, name: S) -> MetricName {
         name.into().append(self.get_prefixes().clone())
@@ -269,15 +419,6 @@ pub trait Prefixed {
     }
 }
 
-/// Name operations support.
-pub trait Label {
-    /// Return the namespace of the component.
-    fn get_label(&self) -> &Arc<HashMap<String, String>>;
-
-    /// Join namespace and prepend in newly defined metrics.
-    fn label(&self, name: &str) -> Self;
-}
-
 impl<T: WithAttributes> Prefixed for T {
     /// Returns namespace of component.
     fn get_prefixes(&self) -> &NameParts {
@@ -304,6 +445,57 @@ impl<T: WithAttributes> Prefixed for T {
         let parts = NameParts::from(name);
         self.with_attributes(|new_attr| new_attr.naming = parts.clone())
     }
+
+    /// Extend the existing names with a child segment, which may itself be dotted, e.g. `module.operation`.
+    /// Return a clone of the component with the extended names, leaving the parent's names untouched.
+    fn nested<S: Into<String>>(&self, segment: S) -> Self {
+        let segment = segment.into();
+        self.with_attributes(|new_attr| {
+            segment
+                .split('.')
+                .filter(|part| !part.is_empty())
+                .for_each(|part| new_attr.naming.push_back(part.to_string()))
+        })
+    }
+}
+
+/// Replace every `{VAR}` of the pattern with the value of the environment variable.
+fn interpolate_env(pattern: &str) -> io::Result<String> {
+    let mut resolved = String::with_capacity(pattern.len());
+    let mut rest = pattern;
+    while let Some(start) = rest.find('{') {
+        resolved.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        let end = after.find('}').ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Unclosed variable reference in metrics prefix {:?}",
+                    pattern
+                ),
+            )
+        })?;
+        let var = &after[..end];
+        if var.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Empty variable reference in metrics prefix {:?}", pattern),
+            ));
+        }
+        let value = env::var(var).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!(
+                    "Environment variable {} of metrics prefix {:?} is not available: {}",
+                    var, pattern, e
+                ),
+            )
+        })?;
+        resolved.push_str(&value);
+        rest = &after[end + 1..];
+    }
+    resolved.push_str(rest);
+    Ok(resolved)
 }
 
 /// Apply statistical sampling to collected metrics data.
@@ -317,6 +509,25 @@ pub trait Sampled: WithAttributes {
     fn get_sampling(&self) -> Sampling {
         self.get_attributes().sampling
     }
+
+    /// Sample values of metrics of the kind according to the specified rate instead of the component's,
+    /// e.g. to sample timers, which read the clock, while keeping every counter value.
+    /// Only affects metrics defined afterwards.
+    fn sampled_kind(&self, kind: InputKind, sampling: Sampling) -> Self {
+        self.with_attributes(|new_attr| {
+            new_attr.kind_sampling.insert(kind, sampling);
+        })
+    }
+
+    /// Get the sampling strategy for metrics of the kind.
+    fn get_kind_sampling(&self, kind: InputKind) -> Sampling {
+        let attributes = self.get_attributes();
+        attributes
+            .kind_sampling
+            .get(&kind)
+            .copied()
+            .unwrap_or(attributes.sampling)
+    }
 }
 
 /// Determine scope buffering strategy, if supported by output.
@@ -333,10 +544,28 @@ pub trait Buffered: WithAttributes {
         self.get_attributes().buffering
     }
 
-    /// Returns false if the current buffering strategy is `Buffering::Unbuffered`.
-    /// Returns true otherwise.
+    /// Returns false if the current buffering strategy is `Buffering::Unbuffered`
+    /// or if immediate flush is in effect. Returns true otherwise.
     fn is_buffered(&self) -> bool {
-        !(self.get_attributes().buffering == Buffering::Unbuffered)
+        self.get_attributes().is_buffered()
+    }
+
+    /// Run the closure with buffering suspended, so that every value written is sent immediately,
+    /// e.g. to make sure that critical metrics are emitted during shutdown.
+    /// Applies to this component and its clones, including the metrics it already defined.
+    /// The previous buffering strategy is restored when the closure returns, or panics.
+    fn with_immediate_flush<F: FnOnce() -> R, R>(&self, closure: F) -> R {
+        struct Restore<'a>(&'a AtomicUsize);
+        impl Drop for Restore<'_> {
+            fn drop(&mut self) {
+                self.0.fetch_sub(1, Ordering::AcqRel);
+            }
+        }
+
+        let immediate_flush = &self.get_attributes().immediate_flush;
+        immediate_flush.fetch_add(1, Ordering::AcqRel);
+        let _restore = Restore(immediate_flush);
+        closure()
     }
 }
 
@@ -357,4 +586,98 @@ mod test {
         metrics.flush().unwrap();
         assert_eq!(Some(&4), metrics.into_map().get("my_gauge"))
     }
+
+    #[test]
+    fn collector() {
+        let metrics: StatsMapScope = StatsMap::default().metrics();
+        let cancel = metrics.add_collector(|scope| {
+            scope.gauge("idle").value(3);
+            scope.gauge("busy").value(5);
+        });
+        metrics.flush().unwrap();
+        assert_eq!(Some(&3), metrics.clone().into_map().get("idle"));
+        assert_eq!(Some(&5), metrics.clone().into_map().get("busy"));
+
+        cancel.cancel();
+        assert!(read_lock!(metrics.get_attributes().flush_listeners).is_empty());
+    }
+
+    #[test]
+    fn collector_released_with_scope() {
+        let metrics: StatsMapScope = StatsMap::default().metrics();
+        let _ = metrics.add_collector(|scope| scope.gauge("idle").value(3));
+        let listeners = Arc::downgrade(&metrics.get_attributes().flush_listeners);
+
+        drop(metrics);
+        assert!(listeners.upgrade().is_none());
+    }
+
+    #[test]
+    fn heartbeat() {
+        let metrics: StatsMapScope = StatsMap::default().metrics();
+        let cancel = metrics.add_heartbeat("alive");
+        metrics.flush().unwrap();
+        metrics.flush().unwrap();
+        assert_eq!(Some(&2), metrics.clone().into_map().get("alive"));
+
+        cancel.cancel();
+        assert!(read_lock!(metrics.get_attributes().flush_listeners).is_empty());
+    }
+
+    #[test]
+    fn immediate_flush() {
+        let recorder = crate::DryRun::new();
+        let metrics = crate::Statsd::send_to("localhost:8125")
+            .unwrap()
+            .dry_run(&recorder)
+            .buffered(Buffering::Unlimited)
+            .metrics();
+        let counter = metrics.counter("counter");
+
+        counter.count(1);
+        assert!(recorder.payloads().is_empty());
+
+        metrics.with_immediate_flush(|| counter.count(2));
+        let payloads = recorder.payloads();
+        assert_eq!(1, payloads.len());
+        assert!(payloads[0].contains("counter:1|c") && payloads[0].contains("counter:2|c"));
+        assert!(metrics.is_buffered());
+
+        let panicking = std::panic::AssertUnwindSafe(|| metrics.with_immediate_flush(|| panic!()));
+        assert!(std::panic::catch_unwind(panicking).is_err());
+        assert!(metrics.is_buffered());
+    }
+
+    #[test]
+    fn nested() {
+        let service: StatsMapScope = StatsMap::default().metrics().named("service");
+        let operation = service.nested("module").nested("operation.step");
+        operation.marker("done").mark();
+        service.marker("up").mark();
+        let map = service.into_map();
+        assert_eq!(Some(&1), map.get("service.module.operation.step.done"));
+        assert_eq!(Some(&1), map.get("service.up"));
+    }
+
+    #[test]
+    fn env_prefix() {
+        // a variable used by no other test, as tests run concurrently in the same process
+        env::set_var("DIPSTICK_ENV_PREFIX_TEST_REGION", "eu-west");
+        let metrics: StatsMapScope = StatsMap::default().metrics().named("app");
+        let regional = metrics.with_env_prefix(
+            "{DIPSTICK_ENV_PREFIX_TEST_REGION}.host-{DIPSTICK_ENV_PREFIX_TEST_REGION}",
+        );
+        env::remove_var("DIPSTICK_ENV_PREFIX_TEST_REGION");
+        let regional = regional.unwrap();
+        regional.marker("up").mark();
+        assert_eq!(
+            Some(&1),
+            metrics.into_map().get("app.eu-west.host-eu-west.up")
+        );
+
+        let missing = StatsMapScope::default().with_env_prefix("{DIPSTICK_ENV_PREFIX_TEST_REGION}");
+        assert_eq!(io::ErrorKind::NotFound, missing.err().unwrap().kind());
+        let unclosed = StatsMapScope::default().with_env_prefix("{DIPSTICK_ENV_PREFIX_TEST_REGION");
+        assert_eq!(io::ErrorKind::InvalidInput, unclosed.err().unwrap().kind());
+    }
 }
@@ -1,29 +1,73 @@
 #[cfg(test)]
 use std::ops::Add;
 
-#[cfg(test)]
-use std::time::Duration;
-
-use std::time::Instant;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use crate::MetricValue;
 
+#[cfg(not(feature = "parking_lot"))]
+use std::sync::RwLock;
+
+#[cfg(feature = "parking_lot")]
+use parking_lot::RwLock;
+
 #[derive(Debug, Copy, Clone)]
 /// A handle to the start time of a counter.
 /// Wrapped so it may be changed safely later.
-pub struct TimeHandle(Instant);
+pub struct TimeHandle(Start);
+
+#[derive(Debug, Copy, Clone)]
+enum Start {
+    Instant(Instant),
+    /// CPU cycle count, converted to time using the calibrated TSC frequency.
+    #[cfg(all(feature = "rdtsc", any(target_arch = "x86", target_arch = "x86_64")))]
+    Tsc(u64),
+    /// Obtained from a disabled timer, which did not read the clock.
+    Disabled,
+}
 
 impl TimeHandle {
     /// Get a handle on current time.
     /// Used by the TimerMetric start_time() method.
+    /// With the `rdtsc` feature, the CPU's time stamp counter is read instead of the system clock
+    /// if it runs at a constant rate, once a background thread started upon first use has calibrated it.
+    /// The system clock is read until then.
     pub fn now() -> TimeHandle {
-        TimeHandle(now())
+        // tests always use the mock clock
+        #[cfg(all(feature = "rdtsc", any(target_arch = "x86", target_arch = "x86_64")))]
+        if !cfg!(test) && tsc::calibrated().is_some() {
+            return TimeHandle(Start::Tsc(tsc::ticks()));
+        }
+        TimeHandle(Start::Instant(now()))
+    }
+
+    /// A handle obtained without reading the clock, ignored when stopped.
+    pub(crate) fn disabled() -> TimeHandle {
+        TimeHandle(Start::Disabled)
+    }
+
+    /// Returns true if the handle was obtained without reading the clock.
+    pub(crate) fn is_disabled(self) -> bool {
+        matches!(self.0, Start::Disabled)
     }
 
     /// Get the elapsed time in microseconds since TimeHandle was obtained.
+    /// Always zero for a handle obtained from a disabled timer.
     pub fn elapsed_us(self) -> u64 {
-        let duration = now() - self.0;
-        (duration.as_secs() * 1_000_000) + u64::from(duration.subsec_micros())
+        match self.0 {
+            Start::Instant(start) => duration_us(now() - start),
+            #[cfg(all(feature = "rdtsc", any(target_arch = "x86", target_arch = "x86_64")))]
+            Start::Tsc(start) => tsc::elapsed_ns(start) / 1000,
+            Start::Disabled => 0,
+        }
+    }
+
+    /// The shortest interval timers measure, one microsecond on every platform.
+    /// `Instant` is backed by a monotonic high resolution counter everywhere (e.g. QueryPerformanceCounter on Windows),
+    /// so intervals are limited by the microsecond precision of timers rather than by the clock.
+    pub fn resolution() -> Duration {
+        Duration::from_micros(1)
     }
 
     /// Get the elapsed time in milliseconds since TimeHandle was obtained.
@@ -38,6 +82,43 @@ impl Default for TimeHandle {
     }
 }
 
+fn duration_us(duration: Duration) -> u64 {
+    (duration.as_secs() * 1_000_000) + u64::from(duration.subsec_micros())
+}
+
+/// The source of time of the metrics defined from a scope:
+/// the system clock, unless a mock clock was injected in the scope's attributes (see `TestMetrics`).
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Clock(Option<Arc<RwLock<Instant>>>);
+
+impl Clock {
+    /// A clock reading the shared instant, which only changes when it is explicitly set.
+    #[cfg(any(test, feature = "test-util"))]
+    pub(crate) fn mock(now: Arc<RwLock<Instant>>) -> Clock {
+        Clock(Some(now))
+    }
+
+    /// Get a handle on the current time of this clock.
+    #[inline]
+    pub(crate) fn now(&self) -> TimeHandle {
+        match &self.0 {
+            None => TimeHandle::now(),
+            Some(mock) => TimeHandle(Start::Instant(*read_lock!(mock))),
+        }
+    }
+
+    /// Get the elapsed time in microseconds since the handle was obtained from this clock.
+    #[inline]
+    pub(crate) fn elapsed_us(&self, start: TimeHandle) -> u64 {
+        match (&self.0, start.0) {
+            (Some(mock), Start::Instant(start)) => {
+                duration_us(read_lock!(mock).saturating_duration_since(start))
+            }
+            _ => start.elapsed_us(),
+        }
+    }
+}
+
 /// The mock clock is thread local so that tests can run in parallel without affecting each other.
 use std::cell::RefCell;
 thread_local! {
@@ -47,12 +128,9 @@ thread_local! {
 /// Set the mock clock to the current time.
 /// Enables writing reproducible metrics tests in combination with #mock_clock_advance()
 /// Should be called at beginning of test, before the metric scope is created.
-/// Not feature-gated so it stays visible to outside crates but may not be used outside of tests.
+/// Only available to dipstick's own tests, other crates should use `TestMetrics` (`test-util` feature).
 #[cfg(test)]
 pub fn mock_clock_reset() {
-    if !cfg!(not(test)) {
-        warn!("Mock clock used outside of cfg[]tests has no effect")
-    }
     MOCK_CLOCK.with(|now| {
         *now.borrow_mut() = Instant::now();
     })
@@ -61,7 +139,7 @@ pub fn mock_clock_reset() {
 /// Advance the mock clock by a certain amount of time.
 /// Enables writing reproducible metrics tests in combination with #mock_clock_reset()
 /// Should be after metrics have been produced but before they are published.
-/// Not feature-gated so it stays visible to outside crates but may not be used outside of tests.
+/// Only available to dipstick's own tests, other crates should use `TestMetrics` (`test-util` feature).
 #[cfg(test)]
 pub fn mock_clock_advance(period: Duration) {
     MOCK_CLOCK.with(|now| {
@@ -82,3 +160,108 @@ fn now() -> Instant {
 fn now() -> Instant {
     MOCK_CLOCK.with(|now| *now.borrow())
 }
+
+/// Time measurement using the CPU's time stamp counter, avoiding the cost of querying the system clock.
+#[cfg(all(feature = "rdtsc", any(target_arch = "x86", target_arch = "x86_64")))]
+mod tsc {
+    #[cfg(target_arch = "x86")]
+    use std::arch::x86 as arch;
+    #[cfg(target_arch = "x86_64")]
+    use std::arch::x86_64 as arch;
+
+    use std::sync::atomic::AtomicU64;
+    use std::sync::atomic::Ordering::*;
+    use std::sync::Once;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    /// Time spent measuring the TSC frequency against the system clock.
+    const CALIBRATION: Duration = Duration::from_millis(10);
+
+    /// Bits of the nanoseconds per TSC tick, zero until calibrated or if the TSC is not invariant.
+    static NANOS_PER_TICK: AtomicU64 = AtomicU64::new(0);
+
+    static CALIBRATE: Once = Once::new();
+
+    /// Nanoseconds per TSC tick, if calibrated.
+    /// The first call starts calibrating in the background, without waiting for it.
+    pub fn calibrated() -> Option<f64> {
+        CALIBRATE.call_once(|| {
+            if !is_invariant() {
+                debug!("TSC is not invariant, timing with the system clock");
+                return;
+            }
+            let spawned = thread::Builder::new()
+                .name("dipstick-tsc".to_string())
+                .spawn(|| {
+                    if let Some(nanos_per_tick) = calibrate() {
+                        NANOS_PER_TICK.store(nanos_per_tick.to_bits(), Release)
+                    }
+                });
+            if let Err(err) = spawned {
+                warn!(
+                    "Could not calibrate TSC, timing with the system clock: {}",
+                    err
+                )
+            }
+        });
+        match NANOS_PER_TICK.load(Acquire) {
+            0 => None,
+            bits => Some(f64::from_bits(bits)),
+        }
+    }
+
+    pub fn ticks() -> u64 {
+        unsafe { arch::_rdtsc() }
+    }
+
+    /// Nanoseconds elapsed since the tick count was read.
+    pub fn elapsed_ns(start: u64) -> u64 {
+        let nanos_per_tick = calibrated().unwrap_or_default();
+        (ticks().saturating_sub(start) as f64 * nanos_per_tick) as u64
+    }
+
+    /// An invariant TSC runs at a constant rate regardless of power states and frequency changes.
+    fn is_invariant() -> bool {
+        let max_extended = arch::__cpuid(0x8000_0000).eax;
+        max_extended >= 0x8000_0007 && arch::__cpuid(0x8000_0007).edx & (1 << 8) != 0
+    }
+
+    fn calibrate() -> Option<f64> {
+        let start = Instant::now();
+        let start_ticks = ticks();
+        thread::sleep(CALIBRATION);
+        let elapsed = start.elapsed();
+        let elapsed_ticks = ticks().saturating_sub(start_ticks);
+        if elapsed_ticks == 0 {
+            return None;
+        }
+        let nanos_per_tick = elapsed.as_nanos() as f64 / elapsed_ticks as f64;
+        debug!("Calibrated TSC at {:.3} ns per tick", nanos_per_tick);
+        Some(nanos_per_tick)
+    }
+
+    #[cfg(test)]
+    mod test {
+        use super::*;
+
+        #[test]
+        fn measures_elapsed_time() {
+            let deadline = Instant::now() + Duration::from_secs(1);
+            while is_invariant() && calibrated().is_none() && Instant::now() < deadline {
+                thread::sleep(Duration::from_millis(5));
+            }
+            if calibrated().is_none() {
+                return;
+            }
+            let start = ticks();
+            thread::sleep(Duration::from_millis(20));
+            let elapsed_ms = elapsed_ns(start) / 1_000_000;
+            assert!(
+                (15..1000).contains(&elapsed_ms),
+                "elapsed {} ms",
+                elapsed_ms
+            );
+        }
+    }
+}
@@ -0,0 +1,63 @@
+//! Types and traits needed to implement a custom metrics output.
+//!
+//! Everything required to write a third-party backend is re-exported here,
+//! so that output implementations only need to `use dipstick::ext::*`.
+//!
+//! An output is made of an `Input`, holding the backend's configuration (e.g. a socket),
+//! from which `InputScope`s are opened. Scopes define metrics as `InputMetric` closures
+//! receiving each value written, and optionally buffer values until they are flushed.
+//!
+//! ```rust
+//! use dipstick::ext::*;
+//! use std::io;
+//!
+//! #[derive(Clone, Default)]
+//! struct Println {
+//!     attributes: Attributes,
+//! }
+//!
+//! impl WithAttributes for Println {
+//!     fn get_attributes(&self) -> &Attributes {
+//!         &self.attributes
+//!     }
+//!     fn mut_attributes(&mut self) -> &mut Attributes {
+//!         &mut self.attributes
+//!     }
+//! }
+//!
+//! impl Input for Println {
+//!     type SCOPE = Println;
+//!
+//!     fn metrics(&self) -> Self::SCOPE {
+//!         self.clone()
+//!     }
+//! }
+//!
+//! impl InputScope for Println {
+//!     fn new_metric(&self, name: MetricName, kind: InputKind) -> InputMetric {
+//!         let name = self.prefix_prepend(name).join(".");
+//!         let id = MetricId::forge("println", name.as_str().into());
+//!         InputMetric::new(id, move |value, _labels| println!("{:?} {} {}", kind, name, value))
+//!     }
+//! }
+//!
+//! impl Flush for Println {
+//!     fn flush(&self) -> io::Result<()> {
+//!         self.notify_flush_listeners();
+//!         Ok(())
+//!     }
+//! }
+//!
+//! let metrics = Println::default().named("app").metrics();
+//! metrics.counter("requests").count(1);
+//! ```
+
+pub use crate::attributes::{
+    Attributes, Buffered, Buffering, MetricId, OnFlush, Prefixed, Sampled, Sampling, WithAttributes,
+};
+pub use crate::cache::CachedInput;
+pub use crate::input::{Input, InputDyn, InputKind, InputMetric, InputScope};
+pub use crate::label::Labels;
+pub use crate::name::{MetricName, NameParts};
+pub use crate::queue::QueuedInput;
+pub use crate::{Flush, MetricValue};
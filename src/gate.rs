@@ -0,0 +1,121 @@
+//! Coherent sampling of related metric writes.
+
+use crate::attributes::{Attributes, MetricId, OnFlush, Prefixed, Sampling, WithAttributes};
+use crate::input::{InputKind, InputMetric, InputScope};
+use crate::name::MetricName;
+use crate::{pcg32, Flush};
+
+use std::io;
+use std::sync::Arc;
+
+/// A sampling decision taken once and shared by all metrics of a unit of work, such as a request.
+/// Metrics of a kept request are all recorded, metrics of a dropped request are all discarded,
+/// instead of each metric being sampled independently. Recorded values are not scaled up.
+#[derive(Debug, Clone, Copy)]
+pub struct SamplingGate {
+    keep: bool,
+}
+
+impl SamplingGate {
+    /// Decide whether to keep or drop the metrics tied to this gate, using the specified sampling.
+    pub fn new(sampling: Sampling) -> Self {
+        let keep = match sampling {
+            Sampling::Full => true,
+            Sampling::Random(float_rate) if float_rate >= 1.0 => true,
+            Sampling::Random(float_rate) => {
+                pcg32::accept_sample(pcg32::to_int_rate(float_rate.max(0.0)))
+            }
+        };
+        SamplingGate { keep }
+    }
+
+    /// Returns true if metrics tied to this gate are recorded.
+    pub fn is_kept(&self) -> bool {
+        self.keep
+    }
+}
+
+/// Tie the metrics of a scope to a sampling gate.
+pub trait Gated: InputScope + Clone + Send + Sync + 'static + Sized {
+    /// Wrap a clone of this scope so that its metrics only record values if the gate was kept.
+    /// Typically called once per request, using a cached or aggregated scope as the target.
+    fn gated(&self, gate: SamplingGate) -> GatedScope {
+        GatedScope {
+            attributes: Attributes::default(),
+            target: Arc::new(self.clone()),
+            gate,
+        }
+    }
+}
+
+impl<T: InputScope + Clone + Send + Sync + 'static> Gated for T {}
+
+/// Input scope wrapper recording values only if its sampling gate was kept.
+#[derive(Clone)]
+pub struct GatedScope {
+    attributes: Attributes,
+    target: Arc<dyn InputScope + Send + Sync + 'static>,
+    gate: SamplingGate,
+}
+
+impl GatedScope {
+    /// Returns the sampling gate of this scope.
+    pub fn gate(&self) -> SamplingGate {
+        self.gate
+    }
+}
+
+impl WithAttributes for GatedScope {
+    fn get_attributes(&self) -> &Attributes {
+        &self.attributes
+    }
+    fn mut_attributes(&mut self) -> &mut Attributes {
+        &mut self.attributes
+    }
+}
+
+impl InputScope for GatedScope {
+    fn new_metric(&self, name: MetricName, kind: InputKind) -> InputMetric {
+        let name = self.prefix_append(name);
+        if !self.gate.is_kept() {
+            let metric = InputMetric::new(MetricId::forge("gate", name), |_value, _labels| {});
+            metric.set_enabled(false);
+            return metric;
+        }
+        let target_metric = self.target.new_metric(name.clone(), kind);
+        InputMetric::new(MetricId::forge("gate", name), move |value, labels| {
+            target_metric.write(value, labels)
+        })
+    }
+}
+
+impl Flush for GatedScope {
+    fn flush(&self) -> io::Result<()> {
+        self.notify_flush_listeners();
+        self.target.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::output::map::StatsMapScope;
+
+    #[test]
+    fn gate_keeps_or_drops_all() {
+        let map = StatsMapScope::default();
+
+        let kept = map.gated(SamplingGate::new(Sampling::Full));
+        kept.counter("requests").count(1);
+        kept.marker("hits").mark();
+
+        let dropped = map.gated(SamplingGate::new(Sampling::Random(0.0)));
+        assert!(!dropped.gate().is_kept());
+        dropped.counter("requests").count(10);
+        dropped.marker("hits").mark();
+
+        let map = map.into_map();
+        assert_eq!(Some(&1), map.get("requests"));
+        assert_eq!(Some(&1), map.get("hits"));
+    }
+}
@@ -0,0 +1,100 @@
+//! Metric write observation.
+
+use crate::attributes::{Attributes, MetricId, OnFlush, Prefixed, WithAttributes};
+use crate::input::{InputKind, InputMetric, InputScope};
+use crate::name::MetricName;
+use crate::{Flush, MetricValue};
+
+use std::io;
+use std::sync::Arc;
+
+/// Observe every value written to a scope's metrics before it is forwarded to the scope.
+/// Useful for debugging (logging, conditional breakpoints) without changing the backend.
+/// The hook can neither alter the value nor prevent the write.
+pub trait WriteHooked: InputScope + Send + Sync + 'static + Sized {
+    /// Wrap this scope with a hook receiving the kind, full name and value of each metric write.
+    fn with_write_hook<F>(self, hook: F) -> WriteHookScope
+    where
+        F: Fn(InputKind, &str, MetricValue) + Send + Sync + 'static,
+    {
+        WriteHookScope {
+            attributes: Attributes::default(),
+            target: Arc::new(self),
+            hook: Arc::new(hook),
+        }
+    }
+}
+
+impl<T: InputScope + Send + Sync + 'static> WriteHooked for T {}
+
+type WriteHook = Arc<dyn Fn(InputKind, &str, MetricValue) + Send + Sync + 'static>;
+
+/// Input scope wrapper calling a hook upon every metric write.
+#[derive(Clone)]
+pub struct WriteHookScope {
+    attributes: Attributes,
+    target: Arc<dyn InputScope + Send + Sync + 'static>,
+    hook: WriteHook,
+}
+
+impl WithAttributes for WriteHookScope {
+    fn get_attributes(&self) -> &Attributes {
+        &self.attributes
+    }
+    fn mut_attributes(&mut self) -> &mut Attributes {
+        &mut self.attributes
+    }
+}
+
+impl InputScope for WriteHookScope {
+    fn new_metric(&self, name: MetricName, kind: InputKind) -> InputMetric {
+        let name = self.prefix_append(name);
+        let target_metric = self.target.new_metric(name.clone(), kind);
+        let full_name = name.join(".");
+        let hook = self.hook.clone();
+        InputMetric::new(MetricId::forge("hook", name), move |value, labels| {
+            (hook)(kind, &full_name, value);
+            target_metric.write(value, labels)
+        })
+    }
+}
+
+impl Flush for WriteHookScope {
+    fn flush(&self) -> io::Result<()> {
+        self.notify_flush_listeners();
+        self.target.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::output::map::StatsMapScope;
+
+    #[cfg(not(feature = "parking_lot"))]
+    use std::sync::RwLock;
+
+    #[cfg(feature = "parking_lot")]
+    use parking_lot::RwLock;
+
+    #[test]
+    fn hook_sees_writes() {
+        let seen = Arc::new(RwLock::new(vec![]));
+        let sink = seen.clone();
+        let map = StatsMapScope::default();
+        let metrics = map
+            .clone()
+            .with_write_hook(move |kind, name, value| {
+                write_lock!(sink).push((kind, name.to_string(), value))
+            })
+            .named("app");
+
+        metrics.counter("requests").count(3);
+
+        assert_eq!(
+            vec![(InputKind::Counter, "app.requests".to_string(), 3)],
+            *read_lock!(seen)
+        );
+        assert_eq!(Some(&3), map.into_map().get("app.requests"));
+    }
+}
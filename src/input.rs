@@ -1,17 +1,72 @@
-use crate::attributes::MetricId;
-use crate::clock::TimeHandle;
+use crate::attributes::{MetricId, Observe, OnFlushCancel, WithAttributes};
+use crate::clock::{Clock, TimeHandle};
 use crate::label::Labels;
+use crate::metrics;
 use crate::name::MetricName;
-use crate::{Flush, MetricValue};
+use crate::transform::ValuePipeline;
+use crate::{Cancel, CancelGuard, Flush, MetricValue};
 
 use std::fmt;
+use std::io;
+use std::sync::atomic::{AtomicBool, AtomicIsize, AtomicU64, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
+
+/// Conversion of primitive numbers to metric values.
+/// Out of range values saturate to the nearest bound, NaN becomes zero and fractions are truncated.
+/// Saturated and NaN values are counted by the `dipstick.input.value_clamped` internal metric,
+/// truncated fractions are not.
+pub trait AsValue {
+    /// Convert this number to a metric value.
+    fn as_metric_value(&self) -> MetricValue;
+}
+
+macro_rules! int_as_value {
+    ($($int:ty),*) => {$(
+        impl AsValue for $int {
+            #[inline]
+            #[allow(clippy::unnecessary_fallible_conversions)]
+            fn as_metric_value(&self) -> MetricValue {
+                MetricValue::try_from(*self).unwrap_or_else(|_| {
+                    metrics::VALUE_CLAMPED.mark();
+                    if *self > 0 {
+                        MetricValue::MAX
+                    } else {
+                        MetricValue::MIN
+                    }
+                })
+            }
+        }
+    )*};
+}
+
+int_as_value!(i8, i16, i32, i64, i128, isize, u8, u16, u32, u64, u128, usize);
+
+macro_rules! float_as_value {
+    ($($float:ty),*) => {$(
+        impl AsValue for $float {
+            #[inline]
+            fn as_metric_value(&self) -> MetricValue {
+                // `as` saturates out of range values and maps NaN to zero
+                if self.is_nan() || *self >= MetricValue::MAX as $float || *self < MetricValue::MIN as $float {
+                    metrics::VALUE_CLAMPED.mark();
+                }
+                *self as MetricValue
+            }
+        }
+    )*};
+}
+
+float_as_value!(f32, f64);
 
-// TODO maybe define an 'AsValue' trait + impl for supported number types, then drop 'num' crate
-pub use num::integer;
-pub use num::ToPrimitive;
 use std::ops::Deref;
 
+#[cfg(not(feature = "parking_lot"))]
+use std::sync::RwLock;
+
+#[cfg(feature = "parking_lot")]
+use parking_lot::RwLock;
+
 /// A function trait that opens a new metric capture scope.
 pub trait Input: Send + Sync + 'static + InputDyn {
     /// The type of Scope returned byt this input.
@@ -77,6 +132,59 @@ pub trait InputScope: Flush {
     fn level(&self, name: &str) -> Level {
         self.new_metric(name.into(), InputKind::Level).into()
     }
+
+    /// Define a Distribution.
+    fn distribution(&self, name: &str) -> Distribution {
+        self.new_metric(name.into(), InputKind::Distribution).into()
+    }
+
+    /// Eagerly define multiple metrics, so that their backend structures are already in place
+    /// when they are first written to from latency-sensitive code.
+    /// Some scopes (e.g. proxies and buckets) only keep metrics for as long as they are referenced,
+    /// the returned metrics should be held onto for as long as the pre-registration is useful.
+    fn preregister(&self, metrics: &[(InputKind, &str)]) -> Vec<InputMetric> {
+        metrics
+            .iter()
+            .map(|(kind, name)| self.new_metric((*name).into(), *kind))
+            .collect()
+    }
+
+    /// Flush only the metrics whose name is or starts with the prefix (followed by a `.`).
+    /// Scopes that can not partially flush their metrics flush everything.
+    fn flush_prefix(&self, _prefix: &str) -> io::Result<()> {
+        self.flush()
+    }
+}
+
+/// A closed set of metrics, usually an enum declared with `metric_keys!`, whose names can not be mistyped.
+pub trait MetricKey: Copy + 'static {
+    /// The metric's name.
+    fn name(self) -> &'static str;
+
+    /// The metric's kind.
+    fn kind(self) -> InputKind;
+
+    /// Every metric of the set.
+    fn all() -> &'static [Self];
+}
+
+/// Define metrics from keys rather than names.
+pub trait KeyedMetrics {
+    /// Define the metric of the key, e.g. `let requests: Counter = metrics.keyed(AppMetric::Requests).into()`.
+    fn keyed<K: MetricKey>(&self, key: K) -> InputMetric;
+
+    /// Eagerly define every metric of the set (see `InputScope::preregister()`).
+    fn preregister_keys<K: MetricKey>(&self) -> Vec<InputMetric>;
+}
+
+impl<T: InputScope + ?Sized> KeyedMetrics for T {
+    fn keyed<K: MetricKey>(&self, key: K) -> InputMetric {
+        self.new_metric(key.name().into(), key.kind())
+    }
+
+    fn preregister_keys<K: MetricKey>(&self) -> Vec<InputMetric> {
+        K::all().iter().map(|key| self.keyed(*key)).collect()
+    }
 }
 
 /// A metric is actually a function that knows to write a metric value to a metric output.
@@ -84,6 +192,43 @@ pub trait InputScope: Flush {
 pub struct InputMetric {
     identifier: MetricId,
     inner: Arc<dyn Fn(MetricValue, Labels) + Send + Sync>,
+    enabled: Arc<AtomicBool>,
+    clock: Clock,
+    #[cfg(feature = "write_count")]
+    writes: Arc<AtomicU64>,
+}
+
+#[cfg(feature = "write_count")]
+lazy_static! {
+    /// Number of writes of every metric since process start, by metric identifier.
+    static ref WRITE_COUNTS: RwLock<std::collections::HashMap<MetricId, Arc<AtomicU64>>> =
+        RwLock::new(std::collections::HashMap::new());
+}
+
+/// Number of values written to the metric with the identifier (e.g. `statsd:app/requests`) since process start.
+/// Each layer of a metrics stack has its own identifiers, as listed by `write_count_report()`.
+#[cfg(feature = "write_count")]
+pub fn write_count(metric_id: &str) -> u64 {
+    read_lock!(WRITE_COUNTS)
+        .iter()
+        .find(|(id, _)| id.as_str() == metric_id)
+        .map(|(_, writes)| writes.load(Ordering::Relaxed))
+        .unwrap_or_default()
+}
+
+/// A report of the number of values written to every metric since process start, one metric per line,
+/// the most written first. Helps identifying the chattiest metrics.
+#[cfg(feature = "write_count")]
+pub fn write_count_report() -> String {
+    let mut counts: Vec<(String, u64)> = read_lock!(WRITE_COUNTS)
+        .iter()
+        .map(|(id, writes)| (id.as_str().to_string(), writes.load(Ordering::Relaxed)))
+        .collect();
+    counts.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    counts
+        .iter()
+        .map(|(id, writes)| format!("{} {}\n", id, writes))
+        .collect()
 }
 
 impl fmt::Debug for InputMetric {
@@ -99,17 +244,56 @@ impl InputMetric {
         metric: F,
     ) -> InputMetric {
         InputMetric {
+            #[cfg(feature = "write_count")]
+            writes: write_lock!(WRITE_COUNTS)
+                .entry(identifier.clone())
+                .or_default()
+                .clone(),
             identifier,
             inner: Arc::new(metric),
+            enabled: Arc::new(AtomicBool::new(true)),
+            clock: Clock::default(),
+        }
+    }
+
+    /// Time this metric's values with the clock, if it is a timer, instead of the system clock.
+    #[cfg(any(test, feature = "test-util"))]
+    pub(crate) fn with_clock(self, clock: &Clock) -> InputMetric {
+        InputMetric {
+            clock: clock.clone(),
+            ..self
         }
     }
 
     /// Collect a new value for this metric.
     #[inline]
     pub fn write(&self, value: MetricValue, labels: Labels) {
+        if !self.is_enabled() {
+            return;
+        }
+        #[cfg(feature = "write_count")]
+        self.writes.fetch_add(1, Ordering::Relaxed);
         (self.inner)(value, labels)
     }
 
+    /// Number of values written to this metric, or any other with the same identifier, since process start.
+    #[cfg(feature = "write_count")]
+    pub fn write_count(&self) -> u64 {
+        self.writes.load(Ordering::Relaxed)
+    }
+
+    /// Discard or record again the values written to this metric and its clones, at runtime.
+    /// Timers of a disabled metric do not even read the clock.
+    pub fn set_enabled(&self, enabled: bool) {
+        self.enabled.store(enabled, Ordering::Relaxed)
+    }
+
+    /// Returns false if values written to this metric are discarded.
+    #[inline]
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
     /// Returns the unique identifier of this metric.
     pub fn metric_id(&self) -> &MetricId {
         &self.identifier
@@ -118,6 +302,7 @@ impl InputMetric {
 
 /// Used to differentiate between metric kinds in the backend.
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+#[non_exhaustive]
 pub enum InputKind {
     /// Monotonic counter
     Marker,
@@ -129,10 +314,13 @@ pub enum InputKind {
     Gauge,
     /// Time interval, internal to the app or provided by an external source
     Timer,
+    /// Arbitrary value whose distribution is aggregated by the backend (e.g. statsd `|d`), across all hosts.
+    /// Backends without support for distributions aggregate them per host, like timers.
+    Distribution,
 }
 
 /// Used by the metrics! macro to obtain the InputKind from the stringified type.
-impl<'a> From<&'a str> for InputKind {
+impl From<&str> for InputKind {
     fn from(s: &str) -> InputKind {
         match s {
             "Marker" => InputKind::Marker,
@@ -140,6 +328,7 @@ impl<'a> From<&'a str> for InputKind {
             "Gauge" => InputKind::Gauge,
             "Timer" => InputKind::Timer,
             "Level" => InputKind::Level,
+            "Distribution" => InputKind::Distribution,
             _ => panic!("No InputKind '{}' defined", s),
         }
     }
@@ -165,6 +354,7 @@ impl Marker {
 /// - Bytes sent
 /// - Records written
 /// - Apples eaten
+///
 /// For relative (possibly negative) values, the `Level` counter type can be used.
 /// If aggregated, minimum and maximum scores will track the collected values, not their sum.
 #[derive(Debug, Clone)]
@@ -175,7 +365,7 @@ pub struct Counter {
 impl Counter {
     /// Record a value count.
     pub fn count(&self, count: usize) {
-        self.inner.write(count as isize, labels![])
+        self.inner.write(count.as_metric_value(), labels![])
     }
 }
 
@@ -183,6 +373,7 @@ impl Counter {
 /// Can be used as a stateful `Gauge` or as a `Counter` of possibly decreasing amounts.
 /// - Size of messages in a queue
 /// - Strawberries on a conveyor belt
+///
 /// If aggregated, minimum and maximum scores will track the sum of values, not the collected values themselves.
 #[derive(Debug, Clone)]
 pub struct Level {
@@ -191,8 +382,8 @@ pub struct Level {
 
 impl Level {
     /// Record a positive or negative value count
-    pub fn adjust<V: ToPrimitive>(&self, count: V) {
-        self.inner.write(count.to_isize().unwrap(), labels![])
+    pub fn adjust<V: AsValue>(&self, count: V) {
+        self.inner.write(count.as_metric_value(), labels![])
     }
 }
 
@@ -200,12 +391,805 @@ impl Level {
 #[derive(Debug, Clone)]
 pub struct Gauge {
     inner: InputMetric,
+    min_change: Option<Arc<MinChange>>,
+    pipeline: ValuePipeline,
+}
+
+/// What to do with values written to a gauge or timer outside of its range.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutOfRange {
+    /// Write the nearest bound of the range instead,
+    /// counted by the `dipstick.input.value_clamped` internal metric.
+    Clamp,
+    /// Drop the value, counted by the `dipstick.input.value_out_of_range` internal metric.
+    Drop,
+}
+
+/// Last value emitted by a gauge, used to suppress insignificant changes.
+#[derive(Debug)]
+struct MinChange {
+    epsilon: f64,
+    last: RwLock<Option<MetricValue>>,
+}
+
+impl MinChange {
+    /// Returns true if the value differs enough from the last emitted value to be written.
+    fn accept(&self, value: MetricValue) -> bool {
+        let mut last = write_lock!(self.last);
+        match *last {
+            Some(last) if value.abs_diff(last) as f64 <= (last as f64 * self.epsilon).abs() => {
+                false
+            }
+            _ => {
+                *last = Some(value);
+                true
+            }
+        }
+    }
 }
 
 impl Gauge {
     /// Record a value point for this gauge.
-    pub fn value<V: ToPrimitive>(&self, value: V) {
-        self.inner.write(value.to_isize().unwrap(), labels![])
+    pub fn value<V: AsValue>(&self, value: V) {
+        let value = match self
+            .pipeline
+            .apply(InputKind::Gauge, value.as_metric_value())
+        {
+            Some(value) => value,
+            None => return,
+        };
+        if let Some(min_change) = &self.min_change {
+            if !min_change.accept(value) {
+                return;
+            }
+        }
+        self.inner.write(value, labels![])
+    }
+
+    /// Returns a copy of this gauge that drops values whose relative change
+    /// from the last emitted value is within `epsilon` (e.g. `0.01` for 1%).
+    /// The last emitted value is shared by all clones of the returned gauge.
+    pub fn min_change(&self, epsilon: f64) -> Gauge {
+        Gauge {
+            min_change: Some(Arc::new(MinChange {
+                epsilon,
+                last: RwLock::new(None),
+            })),
+            ..self.clone()
+        }
+    }
+
+    /// Returns a copy of this gauge that only accepts values within `min..=max`,
+    /// e.g. `0..=100` for a percentage, clamping or dropping values outside of the range.
+    /// Catches bugs reporting nonsensical values, such as a utilization of 150%.
+    /// Panics if `min` is greater than `max`.
+    pub fn clamp(&self, min: MetricValue, max: MetricValue, out_of_range: OutOfRange) -> Gauge {
+        Gauge {
+            pipeline: self
+                .pipeline
+                .clone()
+                .range(InputKind::Gauge, min, max, out_of_range),
+            ..self.clone()
+        }
+    }
+}
+
+/// A gauge recording values as a rounded percentage of a maximum, e.g. the utilization of a pool's capacity.
+/// Keeps the maximum in one place instead of computing the ratio wherever a value is recorded.
+#[derive(Debug, Clone)]
+pub struct RatioGauge {
+    gauge: Gauge,
+    max: f64,
+}
+
+impl RatioGauge {
+    /// Record the value as a percentage of the maximum, clamped between 0 and 100.
+    pub fn value(&self, value: f64) {
+        self.gauge
+            .value((value * 100.0 / self.max).clamp(0.0, 100.0).round())
+    }
+}
+
+/// Define ratio gauges, recording to a gauge of their scope.
+pub trait RatioGauging {
+    /// Define a RatioGauge, recording values as a percentage of `max`, e.g. the capacity of a pool.
+    fn ratio_gauge(&self, name: &str, max: f64) -> RatioGauge;
+}
+
+impl<T: InputScope> RatioGauging for T {
+    fn ratio_gauge(&self, name: &str, max: f64) -> RatioGauge {
+        RatioGauge {
+            gauge: self.gauge(name),
+            max,
+        }
+    }
+}
+
+/// A distribution that sends values to be aggregated by the metrics backend, across all sources.
+#[derive(Debug, Clone)]
+pub struct Distribution {
+    inner: InputMetric,
+}
+
+impl Distribution {
+    /// Record a value of this distribution.
+    pub fn record<V: AsValue>(&self, value: V) {
+        self.inner.write(value.as_metric_value(), labels![])
+    }
+}
+
+/// A counter that also publishes the per-second rate of its values upon every flush of its scope,
+/// e.g. to obtain bytes per second from recorded byte counts.
+/// Values are accumulated until the scope is flushed, at which point the raw total of the window
+/// is published as a counter and its rate as a `<name>.rate` gauge.
+/// Neither is published once the counter and all its clones are dropped.
+#[derive(Debug, Clone)]
+pub struct RateCounter {
+    sum: Arc<AtomicIsize>,
+    _observer: Arc<CancelGuard<OnFlushCancel>>,
+}
+
+impl RateCounter {
+    /// Record a value count.
+    pub fn count(&self, count: usize) {
+        self.sum
+            .fetch_add(count.as_metric_value(), Ordering::Relaxed);
+    }
+}
+
+/// Define rate counters, which need to observe their scope's flushes.
+pub trait RateCounting {
+    /// Define a RateCounter.
+    fn rate_counter(&self, name: &str) -> RateCounter;
+
+    /// Define a RateCounter of bytes, publishing `<name>_bytes` and `<name>_bytes.rate`,
+    /// which human formats (see `HumanFormat`) print in readable units.
+    fn bytes_counter(&self, name: &str) -> RateCounter {
+        self.rate_counter(&format!("{}_bytes", name))
+    }
+}
+
+impl<T: InputScope + WithAttributes + Send + Sync> RateCounting for T {
+    fn rate_counter(&self, name: &str) -> RateCounter {
+        let sum = Arc::new(AtomicIsize::new(0));
+        let total = self.counter(name);
+        let rate: Gauge = self
+            .new_metric(MetricName::from(name).make_name("rate"), InputKind::Gauge)
+            .into();
+        let window_start = RwLock::new(TimeHandle::now());
+        let window_sum = sum.clone();
+        let observer = self
+            .observe(rate, move |_| {
+                let sum = window_sum.swap(0, Ordering::Relaxed);
+                let elapsed_us = {
+                    let mut start = write_lock!(window_start);
+                    let elapsed_us = start.elapsed_us();
+                    *start = TimeHandle::now();
+                    elapsed_us
+                };
+                total.write(sum, labels![]);
+                (sum as f64 * 1_000_000.0 / elapsed_us.max(1) as f64).round() as MetricValue
+            })
+            .on_flush();
+        RateCounter {
+            sum,
+            _observer: Arc::new(observer.into_guard()),
+        }
+    }
+}
+
+/// A counter whose accumulated count decays exponentially over time, halving every half-life.
+/// The decayed count is published as a gauge every time the scope is flushed, rounded to the nearest integer.
+/// This gives a smooth measure of recent activity, e.g. "requests in the last minute or so", without windowing artifacts.
+/// The gauge stops being published once the counter and all its clones are dropped.
+#[derive(Debug, Clone)]
+pub struct DecayingCounter {
+    state: Arc<Decaying>,
+    _observer: Arc<CancelGuard<OnFlushCancel>>,
+}
+
+#[derive(Debug)]
+struct Decaying {
+    half_life_us: f64,
+    /// Count as of its last update, and time of the update
+    count: RwLock<(f64, TimeHandle)>,
+}
+
+impl Decaying {
+    /// Decay the count up to now, then add the increment. Returns the updated count.
+    fn add(&self, increment: f64) -> f64 {
+        let mut count = write_lock!(self.count);
+        let (value, updated) = &mut *count;
+        let half_lives = updated.elapsed_us() as f64 / self.half_life_us;
+        *value = *value * 0.5_f64.powf(half_lives) + increment;
+        *updated = TimeHandle::now();
+        *value
+    }
+}
+
+impl DecayingCounter {
+    /// Record a value count.
+    pub fn count(&self, count: usize) {
+        self.state.add(count as f64);
+    }
+}
+
+/// Define decaying counters, which need to observe their scope's flushes.
+pub trait DecayingCounting {
+    /// Define a DecayingCounter with the specified half-life.
+    fn decaying_counter(&self, name: &str, half_life: Duration) -> DecayingCounter;
+}
+
+impl<T: InputScope + WithAttributes + Send + Sync> DecayingCounting for T {
+    fn decaying_counter(&self, name: &str, half_life: Duration) -> DecayingCounter {
+        let state = Arc::new(Decaying {
+            half_life_us: (half_life.as_micros() as f64).max(1.0),
+            count: RwLock::new((0.0, TimeHandle::now())),
+        });
+        let decayed = state.clone();
+        let observer = self
+            .observe(self.gauge(name), move |_| {
+                decayed.add(0.0).round() as MetricValue
+            })
+            .on_flush();
+        DecayingCounter {
+            state,
+            _observer: Arc::new(observer.into_guard()),
+        }
+    }
+}
+
+/// Number of sub-buckets of a sliding window.
+const SLIDING_SLOTS: u64 = 60;
+
+/// A counter tracking its per-second rate over a trailing window (e.g. the last 60 seconds),
+/// independently of how often its scope is flushed. The window is divided in 60 sub-buckets,
+/// the oldest of which is discarded as time passes.
+/// The rate is published as a gauge every time the scope is flushed, and can be read at any time with `rate()`.
+/// The gauge stops being published once the counter and all its clones are dropped.
+#[derive(Debug, Clone)]
+pub struct SlidingRateCounter {
+    state: Arc<SlidingWindow>,
+    _observer: Arc<CancelGuard<OnFlushCancel>>,
+}
+
+#[derive(Debug)]
+struct SlidingWindow {
+    slot_us: u64,
+    created: TimeHandle,
+    /// Count of each sub-bucket, along with the sequence number of the period it was counted in
+    slots: Vec<(AtomicU64, AtomicIsize)>,
+}
+
+impl SlidingWindow {
+    fn slot(&self) -> u64 {
+        self.created.elapsed_us() / self.slot_us
+    }
+
+    /// Values counted concurrently with the reuse of a sub-bucket for a new period may be lost.
+    fn add(&self, count: isize) {
+        let slot = self.slot();
+        let (counted_slot, sum) = &self.slots[(slot % SLIDING_SLOTS) as usize];
+        let previous = counted_slot.load(Ordering::Acquire);
+        if previous != slot
+            && counted_slot
+                .compare_exchange(previous, slot, Ordering::AcqRel, Ordering::Acquire)
+                .is_ok()
+        {
+            // only the first writer of the new period discards the count of the old one
+            sum.store(0, Ordering::Release);
+        }
+        sum.fetch_add(count, Ordering::Relaxed);
+    }
+
+    fn rate(&self) -> f64 {
+        let elapsed_us = self.created.elapsed_us();
+        let slot = elapsed_us / self.slot_us;
+        let sum: isize = self
+            .slots
+            .iter()
+            .filter(|(counted_slot, _)| {
+                slot.checked_sub(counted_slot.load(Ordering::Acquire))
+                    .is_some_and(|age| age < SLIDING_SLOTS)
+            })
+            .map(|(_, sum)| sum.load(Ordering::Relaxed))
+            .sum();
+        // a window not yet filled only covers the time elapsed since creation
+        let window_us = (self.slot_us * SLIDING_SLOTS).min(elapsed_us.max(self.slot_us));
+        sum as f64 * 1_000_000.0 / window_us as f64
+    }
+}
+
+impl SlidingRateCounter {
+    /// Record a value count.
+    pub fn count(&self, count: usize) {
+        self.state.add(count.as_metric_value());
+    }
+
+    /// The per-second rate of the values counted over the trailing window.
+    pub fn rate(&self) -> f64 {
+        self.state.rate()
+    }
+}
+
+/// Define sliding rate counters, which need to observe their scope's flushes.
+pub trait SlidingRateCounting {
+    /// Define a SlidingRateCounter over the specified trailing window.
+    fn sliding_rate_counter(&self, name: &str, window: Duration) -> SlidingRateCounter;
+}
+
+impl<T: InputScope + WithAttributes + Send + Sync> SlidingRateCounting for T {
+    fn sliding_rate_counter(&self, name: &str, window: Duration) -> SlidingRateCounter {
+        let slot_us =
+            (window.as_micros() / u128::from(SLIDING_SLOTS)).clamp(1, u128::from(u64::MAX));
+        let state = Arc::new(SlidingWindow {
+            slot_us: slot_us as u64,
+            created: TimeHandle::now(),
+            slots: (0..SLIDING_SLOTS)
+                .map(|_| (AtomicU64::new(u64::MAX), AtomicIsize::new(0)))
+                .collect(),
+        });
+        let window = state.clone();
+        let observer = self
+            .observe(self.gauge(name), move |_| {
+                window.rate().round() as MetricValue
+            })
+            .on_flush();
+        SlidingRateCounter {
+            state,
+            _observer: Arc::new(observer.into_guard()),
+        }
+    }
+}
+
+/// Counters of operations completed under latency thresholds, for SLO compliance tracking.
+/// Each operation increments the `<name>.total` counter and every `<name>.under_<threshold>` counter
+/// whose threshold it did not exceed (e.g. `under_300ms`).
+/// Only plain counters are emitted, making SLO tracking possible with any backend.
+#[derive(Debug, Clone)]
+pub struct SloBucket {
+    total: Counter,
+    under: Vec<(Duration, Counter)>,
+}
+
+impl SloBucket {
+    /// Count an operation of the specified duration.
+    pub fn record(&self, duration: Duration) {
+        self.total.count(1);
+        for (threshold, counter) in self.under.iter().rev() {
+            if duration > *threshold {
+                break;
+            }
+            counter.count(1)
+        }
+    }
+
+    /// Count the duration of the provided closure.
+    pub fn time<F: FnOnce() -> R, R>(&self, operation: F) -> R {
+        let start_time = TimeHandle::now();
+        let value = operation();
+        self.record(Duration::from_micros(start_time.elapsed_us()));
+        value
+    }
+}
+
+/// Define SLO buckets, composed of several counters of their scope.
+pub trait SloCounting {
+    /// Define a SloBucket, counting operations completed under each of the duration thresholds.
+    fn slo_bucket(&self, name: &str, thresholds: &[Duration]) -> SloBucket;
+}
+
+impl<T: InputScope> SloCounting for T {
+    fn slo_bucket(&self, name: &str, thresholds: &[Duration]) -> SloBucket {
+        let name = MetricName::from(name);
+        let mut thresholds = thresholds.to_vec();
+        thresholds.sort();
+        thresholds.dedup();
+        SloBucket {
+            total: self
+                .new_metric(name.make_name("total"), InputKind::Counter)
+                .into(),
+            under: thresholds
+                .into_iter()
+                .map(|threshold| {
+                    let leaf = format!("under_{}", duration_suffix(threshold));
+                    let counter = self.new_metric(name.make_name(leaf), InputKind::Counter);
+                    (threshold, counter.into())
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Name suffix of a duration threshold, in milliseconds if it is a whole number of them, e.g. `300ms` or `250us`.
+fn duration_suffix(threshold: Duration) -> String {
+    if threshold.subsec_micros().is_multiple_of(1000) {
+        format!("{}ms", threshold.as_millis())
+    } else {
+        format!("{}us", threshold.as_micros())
+    }
+}
+
+/// A timer also counting durations in latency bands, for backends without histograms (e.g. statsd).
+/// Durations are recorded by the `<name>` timer and counted by every `<name>.le_<band>` counter
+/// whose band they do not exceed (e.g. `le_10ms`), like the cumulative buckets of a Prometheus histogram.
+#[derive(Debug, Clone)]
+pub struct BandedTimer {
+    timer: Timer,
+    bands: Vec<(Duration, Counter)>,
+}
+
+impl BandedTimer {
+    /// Record a duration, counting it in every band it falls under.
+    pub fn record_banded(&self, duration: Duration) {
+        self.timer.interval(duration);
+        for (band, counter) in self.bands.iter().rev() {
+            if duration > *band {
+                break;
+            }
+            counter.count(1)
+        }
+    }
+
+    /// Record the duration of the provided closure.
+    pub fn time<F: FnOnce() -> R, R>(&self, operation: F) -> R {
+        let start_time = TimeHandle::now();
+        let value = operation();
+        self.record_banded(Duration::from_micros(start_time.elapsed_us()));
+        value
+    }
+}
+
+/// Define banded timers, composed of a timer and several counters of their scope.
+pub trait BandedTiming {
+    /// Define a BandedTimer, also counting durations in cumulative latency bands
+    /// such as `<name>.le_10ms`, approximating histograms with plain counters.
+    fn banded_timer(&self, name: &str, bands: &[Duration]) -> BandedTimer;
+}
+
+impl<T: InputScope> BandedTiming for T {
+    fn banded_timer(&self, name: &str, bands: &[Duration]) -> BandedTimer {
+        let name = MetricName::from(name);
+        let mut bands = bands.to_vec();
+        bands.sort();
+        bands.dedup();
+        BandedTimer {
+            timer: self.new_metric(name.clone(), InputKind::Timer).into(),
+            bands: bands
+                .into_iter()
+                .map(|band| {
+                    let leaf = format!("le_{}", duration_suffix(band));
+                    let counter = self.new_metric(name.make_name(leaf), InputKind::Counter);
+                    (band, counter.into())
+                })
+                .collect(),
+        }
+    }
+}
+
+/// A timer also counting operations by size (bytes, rows...), to correlate latency with size
+/// without a full dimensional histogram. Durations are recorded by the `<name>` timer, and each operation
+/// is counted by the `<name>.<label>` counter of the first bucket whose upper bound is not below its size.
+/// Operations bigger than every bound are timed but not counted.
+#[derive(Debug, Clone)]
+pub struct SizedTimer {
+    timer: Timer,
+    buckets: Vec<(usize, Counter)>,
+}
+
+impl SizedTimer {
+    /// Record the duration of an operation of the specified size.
+    pub fn record(&self, size: usize, duration: Duration) {
+        self.timer.interval(duration);
+        self.count(size)
+    }
+
+    /// Record the time taken to execute the provided closure, counting it in the bucket of the size.
+    pub fn time_bucketed<F: FnOnce() -> R, R>(&self, size: usize, operation: F) -> R {
+        let value = self.timer.time(operation);
+        self.count(size);
+        value
+    }
+
+    fn count(&self, size: usize) {
+        if let Some((_, counter)) = self.buckets.iter().find(|(bound, _)| size <= *bound) {
+            counter.count(1)
+        }
+    }
+}
+
+/// Define sized timers, composed of a timer and several counters of their scope.
+pub trait SizedTiming {
+    /// Define a SizedTimer, timing operations and counting them by size, e.g. `&[("small", 1024), ("large", usize::MAX)]`.
+    /// Buckets are named after their label and sorted by upper bound.
+    fn sized_timer(&self, name: &str, buckets: &[(&str, usize)]) -> SizedTimer;
+}
+
+impl<T: InputScope> SizedTiming for T {
+    fn sized_timer(&self, name: &str, buckets: &[(&str, usize)]) -> SizedTimer {
+        let name = MetricName::from(name);
+        let mut buckets = buckets.to_vec();
+        buckets.sort_by_key(|(_, bound)| *bound);
+        SizedTimer {
+            timer: self.new_metric(name.clone(), InputKind::Timer).into(),
+            buckets: buckets
+                .into_iter()
+                .map(|(label, bound)| {
+                    let counter = self.new_metric(name.make_name(label), InputKind::Counter);
+                    (bound, counter.into())
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Related metrics tracking the Rate, Errors and Duration (RED) of an operation, such as the requests to an endpoint.
+/// Metrics are named `<name>.requests`, `<name>.errors` and `<name>.duration` and share their scope's prefix.
+#[derive(Debug, Clone)]
+pub struct MetricFamily {
+    requests: Marker,
+    errors: Marker,
+    duration: Timer,
+}
+
+impl MetricFamily {
+    /// Record the duration and outcome of an operation.
+    pub fn record<T, E>(&self, duration: Duration, result: &Result<T, E>) {
+        self.requests.mark();
+        if result.is_err() {
+            self.errors.mark();
+        }
+        self.duration.interval(duration);
+    }
+
+    /// Record the duration and outcome of the provided closure.
+    pub fn time<F, T, E>(&self, operation: F) -> Result<T, E>
+    where
+        F: FnOnce() -> Result<T, E>,
+    {
+        let start_time = self.duration.start();
+        let result = operation();
+        self.requests.mark();
+        if result.is_err() {
+            self.errors.mark();
+        }
+        self.duration.stop(start_time);
+        result
+    }
+}
+
+/// Define metric families, composed of several metrics of their scope.
+pub trait MetricFamilies {
+    /// Define a MetricFamily, tracking the rate, errors and duration of an operation.
+    fn family(&self, name: &str) -> MetricFamily;
+}
+
+impl<T: InputScope> MetricFamilies for T {
+    fn family(&self, name: &str) -> MetricFamily {
+        let name = MetricName::from(name);
+        MetricFamily {
+            requests: self
+                .new_metric(name.make_name("requests"), InputKind::Marker)
+                .into(),
+            errors: self
+                .new_metric(name.make_name("errors"), InputKind::Marker)
+                .into(),
+            duration: self
+                .new_metric(name.make_name("duration"), InputKind::Timer)
+                .into(),
+        }
+    }
+}
+
+/// Metrics of the requests served by an HTTP server, meant to be called from a server middleware.
+/// Metrics are named `<name>.requests`, `<name>.duration` and `<name>.1xx` to `<name>.5xx` (by response status class).
+/// Values are labeled with `route`, which should be the matched path template (e.g. `/users/{id}`)
+/// rather than the raw URL to keep the number of label values bounded.
+/// Outputs that do not support labels aggregate all routes together.
+#[derive(Debug, Clone)]
+pub struct HttpMetrics {
+    requests: Marker,
+    duration: Timer,
+    status: [Marker; 5],
+}
+
+impl HttpMetrics {
+    /// Record a served request. Statuses outside of the 100 to 599 range are not counted in any status class.
+    pub fn record(&self, route: &str, status: u16, duration: Duration) {
+        let labels = self.count(route, status);
+        self.duration.interval_labeled(duration, labels);
+    }
+
+    /// Start timing a request, to be recorded when it is finished.
+    pub fn start(&self) -> TimeHandle {
+        self.duration.start()
+    }
+
+    /// Record a served request, timed from the handle obtained from `start()`.
+    pub fn finish(&self, route: &str, status: u16, start_time: TimeHandle) {
+        let labels = self.count(route, status);
+        self.duration.stop_labeled(start_time, labels);
+    }
+
+    /// Count a served request and its status class, returning the labels of its route.
+    fn count(&self, route: &str, status: u16) -> Labels {
+        let labels = labels!("route" => route);
+        self.requests.write(1, labels.clone());
+        if let 100..=599 = status {
+            self.status[usize::from(status / 100 - 1)].write(1, labels.clone());
+        }
+        labels
+    }
+}
+
+/// Define HTTP server metrics, composed of several metrics of their scope.
+pub trait HttpMetering {
+    /// Define HttpMetrics, tracking the requests served by an HTTP server per route.
+    fn http_metrics(&self, name: &str) -> HttpMetrics;
+}
+
+impl<T: InputScope> HttpMetering for T {
+    fn http_metrics(&self, name: &str) -> HttpMetrics {
+        let name = MetricName::from(name);
+        let status_class = |class: &str| -> Marker {
+            self.new_metric(name.make_name(class), InputKind::Marker)
+                .into()
+        };
+        HttpMetrics {
+            requests: self
+                .new_metric(name.make_name("requests"), InputKind::Marker)
+                .into(),
+            duration: self
+                .new_metric(name.make_name("duration"), InputKind::Timer)
+                .into(),
+            status: [
+                status_class("1xx"),
+                status_class("2xx"),
+                status_class("3xx"),
+                status_class("4xx"),
+                status_class("5xx"),
+            ],
+        }
+    }
+}
+
+/// Update multiple metrics from each recorded event, according to a mapping declared once
+/// from the event's fields to metric handles, e.g. `EventRecorder::new().timer(latency, |e: &Request| e.duration)`.
+#[derive(Clone)]
+pub struct EventRecorder<E> {
+    fields: Vec<RecordFn<E>>,
+}
+
+type RecordFn<E> = Arc<dyn Fn(&E) + Send + Sync>;
+
+impl<E> Default for EventRecorder<E> {
+    fn default() -> Self {
+        EventRecorder { fields: vec![] }
+    }
+}
+
+impl<E> fmt::Debug for EventRecorder<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "EventRecorder({} fields)", self.fields.len())
+    }
+}
+
+impl<E> EventRecorder<E> {
+    /// Create a recorder without any mapping.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record the duration extracted from each event to the timer.
+    pub fn timer(
+        self,
+        timer: Timer,
+        field: impl Fn(&E) -> Duration + Send + Sync + 'static,
+    ) -> Self {
+        self.field(move |event| {
+            timer.interval(field(event));
+        })
+    }
+
+    /// Count the amount extracted from each event with the counter.
+    pub fn counter(
+        self,
+        counter: Counter,
+        field: impl Fn(&E) -> usize + Send + Sync + 'static,
+    ) -> Self {
+        self.field(move |event| counter.count(field(event)))
+    }
+
+    /// Mark the marker for each event matching the predicate, e.g. failed operations.
+    pub fn marker(
+        self,
+        marker: Marker,
+        predicate: impl Fn(&E) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        self.field(move |event| {
+            if predicate(event) {
+                marker.mark()
+            }
+        })
+    }
+
+    /// Set the gauge to the value extracted from each event.
+    pub fn gauge<V: AsValue>(
+        self,
+        gauge: Gauge,
+        field: impl Fn(&E) -> V + Send + Sync + 'static,
+    ) -> Self {
+        self.field(move |event| gauge.value(field(event)))
+    }
+
+    /// Record the value extracted from each event to the distribution.
+    pub fn distribution<V: AsValue>(
+        self,
+        distribution: Distribution,
+        field: impl Fn(&E) -> V + Send + Sync + 'static,
+    ) -> Self {
+        self.field(move |event| distribution.record(field(event)))
+    }
+
+    /// Update metrics from each event with an arbitrary function.
+    pub fn field(mut self, record: impl Fn(&E) + Send + Sync + 'static) -> Self {
+        self.fields.push(Arc::new(record));
+        self
+    }
+
+    /// Update all mapped metrics from the event.
+    pub fn record(&self, event: &E) {
+        for field in &self.fields {
+            field(event)
+        }
+    }
+}
+
+/// A gauge publishing the maximum value recorded since its scope was last flushed (a "high-water mark").
+/// Nothing is published for windows in which no value was recorded,
+/// nor once the gauge and all its clones are dropped.
+#[derive(Debug, Clone)]
+pub struct MaxGauge {
+    max: Arc<AtomicIsize>,
+    _listener: Arc<CancelGuard<OnFlushCancel>>,
+}
+
+/// No value was recorded in the current window.
+const MAX_UNSET: MetricValue = MetricValue::MIN;
+
+impl MaxGauge {
+    /// Record a value, keeping it if it is the biggest of the window.
+    pub fn value<V: AsValue>(&self, value: V) {
+        self.max.fetch_max(
+            value.as_metric_value().max(MAX_UNSET + 1),
+            Ordering::Relaxed,
+        );
+    }
+}
+
+/// Define max gauges, which need to observe their scope's flushes.
+pub trait MaxGauging {
+    /// Define a MaxGauge.
+    fn max_gauge(&self, name: &str) -> MaxGauge;
+}
+
+impl<T: InputScope + WithAttributes + Send + Sync> MaxGauging for T {
+    fn max_gauge(&self, name: &str) -> MaxGauge {
+        let max = Arc::new(AtomicIsize::new(MAX_UNSET));
+        let window_max = max.clone();
+        let gauge = self.gauge(name);
+        let listener =
+            self.get_attributes()
+                .add_flush_listener(gauge.metric_id().clone(), move |_| {
+                    let max = window_max.swap(MAX_UNSET, Ordering::Relaxed);
+                    if max != MAX_UNSET {
+                        gauge.write(max, labels![])
+                    }
+                });
+        MaxGauge {
+            max,
+            _listener: Arc::new(listener.into_guard()),
+        }
     }
 }
 
@@ -218,21 +1202,66 @@ impl Gauge {
 #[derive(Debug, Clone)]
 pub struct Timer {
     inner: InputMetric,
+    pipeline: ValuePipeline,
+    #[cfg(feature = "timer_debug")]
+    unstopped: Arc<Unstopped>,
+}
+
+/// Count of the handles obtained from a timer and never stopped, reported when the timer is dropped.
+#[cfg(feature = "timer_debug")]
+#[derive(Debug)]
+struct Unstopped {
+    metric_id: MetricId,
+    count: std::sync::atomic::AtomicUsize,
+}
+
+#[cfg(feature = "timer_debug")]
+impl Drop for Unstopped {
+    fn drop(&mut self) {
+        let count = self.count.load(Ordering::Acquire);
+        if count > 0 {
+            warn!(
+                "Timer {:?} dropped with {} handles never stopped",
+                self.metric_id, count
+            );
+            metrics::TIMER_UNSTOPPED.count(count);
+        }
+    }
 }
 
 impl Timer {
     /// Record a microsecond interval for this timer
     /// Can be used in place of start()/stop() if an external time interval source is used
     pub fn interval_us(&self, interval_us: u64) -> u64 {
-        self.inner.write(interval_us as isize, labels![]);
+        self.inner.write(interval_us.as_metric_value(), labels![]);
+        interval_us
+    }
+
+    /// Record a duration measured elsewhere, saturating at `u64::MAX` microseconds.
+    /// Returns the microsecond interval value that was recorded.
+    pub fn interval(&self, duration: Duration) -> u64 {
+        self.interval_labeled(duration, labels![])
+    }
+
+    /// Record a duration measured elsewhere along with the provided labels, saturating at `u64::MAX` microseconds.
+    /// Returns the microsecond interval value that was recorded.
+    pub fn interval_labeled(&self, duration: Duration, labels: Labels) -> u64 {
+        let interval_us = duration.as_micros().min(u128::from(u64::MAX)) as u64;
+        self.inner.write(interval_us.as_metric_value(), labels);
         interval_us
     }
 
     /// Obtain a opaque handle to the current time.
     /// The handle is passed back to the stop() method to record a time interval.
     /// Caveat: Handles obtained are not bound to this specific timer instance (but should be)
+    /// A disabled timer returns a handle without reading the clock, which stop() ignores.
     pub fn start(&self) -> TimeHandle {
-        TimeHandle::now()
+        if !self.inner.is_enabled() {
+            return TimeHandle::disabled();
+        }
+        #[cfg(feature = "timer_debug")]
+        self.unstopped.count.fetch_add(1, Ordering::AcqRel);
+        self.inner.clock.now()
     }
 
     /// Record the time elapsed since the start_time handle was obtained.
@@ -240,28 +1269,197 @@ impl Timer {
     /// reporting distinct time intervals each time.
     /// Returns the microsecond interval value that was recorded.
     pub fn stop(&self, start_time: TimeHandle) {
-        let elapsed_us = start_time.elapsed_us();
-        self.interval_us(elapsed_us);
+        self.stop_labeled(start_time, labels![])
+    }
+
+    /// Record the time elapsed since the start_time handle was obtained, along with the provided labels.
+    pub fn stop_labeled(&self, start_time: TimeHandle, labels: Labels) {
+        self.record_elapsed(start_time, labels);
+    }
+
+    /// Record the time elapsed since the start_time handle was obtained, returning the recorded microseconds.
+    fn record_elapsed(&self, start_time: TimeHandle, labels: Labels) -> u64 {
+        if start_time.is_disabled() {
+            return 0;
+        }
+        #[cfg(feature = "timer_debug")]
+        let _ = self
+            .unstopped
+            .count
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| n.checked_sub(1));
+        if !self.inner.is_enabled() {
+            return 0;
+        }
+        let elapsed_us = self.inner.clock.elapsed_us(start_time);
+        let value = elapsed_us.as_metric_value();
+        if let Some(value) = self.pipeline.apply(InputKind::Timer, value) {
+            self.inner.write(value, labels);
+        }
+        elapsed_us
+    }
+
+    /// Returns a copy of this timer that clamps or drops measured intervals shorter than `min`,
+    /// e.g. intervals below the resolution of the clock, which are artifacts of its ticks rather than durations.
+    /// Intervals recorded with `interval_us()` are not affected.
+    pub fn min_interval(&self, min: Duration, below: OutOfRange) -> Timer {
+        let min = min.as_micros().min(MetricValue::MAX as u128) as MetricValue;
+        Timer {
+            pipeline: self
+                .pipeline
+                .clone()
+                .range(InputKind::Timer, min, MetricValue::MAX, below),
+            ..self.clone()
+        }
+    }
+
+    /// Returns a copy of this timer that clamps or drops measured intervals shorter than
+    /// the resolution of the platform's clock (see `TimeHandle::resolution()`).
+    pub fn min_clock_resolution(&self, below: OutOfRange) -> Timer {
+        self.min_interval(TimeHandle::resolution(), below)
     }
 
     /// Record the time taken to execute the provided closure
     pub fn time<F: FnOnce() -> R, R>(&self, operations: F) -> R {
+        if !self.inner.is_enabled() {
+            return operations();
+        }
         let start_time = self.start();
         let value: R = operations();
         self.stop(start_time);
         value
     }
+
+    /// Record the time taken to execute the provided closure, also calling `on_slow` with the elapsed time
+    /// if it exceeds the threshold, e.g. to log the context of slow outliers.
+    /// Calls within the threshold only pay for the comparison.
+    pub fn time_with_slow_threshold<F, R, S>(
+        &self,
+        threshold: Duration,
+        on_slow: S,
+        operations: F,
+    ) -> R
+    where
+        F: FnOnce() -> R,
+        S: FnOnce(Duration),
+    {
+        if !self.inner.is_enabled() {
+            return operations();
+        }
+        let start_time = self.start();
+        let value: R = operations();
+        let elapsed_us = self.record_elapsed(start_time, labels![]);
+        if u128::from(elapsed_us) > threshold.as_micros() {
+            on_slow(Duration::from_micros(elapsed_us))
+        }
+        value
+    }
+
+    /// Record the time taken to execute the provided closure along with the provided labels,
+    /// e.g. `timer.time_labeled(labels!("cache" => "hit"), || lookup(key))`.
+    /// Labels should have few distinct values, as aggregating scopes may keep statistics for each of them
+    /// (see `AtomicBucket::split_labels()`).
+    pub fn time_labeled<F: FnOnce() -> R, R>(&self, labels: Labels, operations: F) -> R {
+        if !self.inner.is_enabled() {
+            return operations();
+        }
+        let start_time = self.start();
+        let value: R = operations();
+        self.stop_labeled(start_time, labels);
+        value
+    }
+
+    /// Record the time taken to execute the provided closure,
+    /// and record the size of its result (bytes written, rows fetched...) to another metric.
+    /// The size is derived from the result using the provided function.
+    pub fn time_sized<F, R, S>(
+        &self,
+        size_metric: impl Deref<Target = InputMetric>,
+        operations: F,
+        size: S,
+    ) -> R
+    where
+        F: FnOnce() -> R,
+        S: FnOnce(&R) -> usize,
+    {
+        let value: R = self.time(operations);
+        size_metric.write(size(&value).as_metric_value(), labels![]);
+        value
+    }
+
+    /// Returns the number of handles obtained from this timer (and its clones) that were not stopped yet.
+    /// Timers still holding unstopped handles when dropped log a warning
+    /// and count them in the `dipstick.input.timer_unstopped` internal metric.
+    /// Stopping a handle more than once may hide other handles that were never stopped.
+    #[cfg(feature = "timer_debug")]
+    pub fn unstopped(&self) -> usize {
+        self.unstopped.count.load(Ordering::Acquire)
+    }
+
+    /// Record the time taken to execute the provided closure,
+    /// while counting it as in flight in the provided level.
+    /// The level is decremented even if the closure panics.
+    pub fn time_tracked<F: FnOnce() -> R, R>(&self, in_flight: &Level, operations: F) -> R {
+        let _in_flight = InFlight::enter(in_flight);
+        self.time(operations)
+    }
+}
+
+/// Record durations timed elsewhere to timers of their scope.
+pub trait ExternalTiming {
+    /// Record the duration of an operation timed elsewhere, e.g. taken from a response header,
+    /// to the Timer of that name.
+    fn record_timing(&self, name: &str, duration: Duration);
+}
+
+impl<T: InputScope> ExternalTiming for T {
+    fn record_timing(&self, name: &str, duration: Duration) {
+        self.timer(name).interval(duration);
+    }
+}
+
+/// Counts an operation in a level until dropped.
+struct InFlight<'a>(&'a Level);
+
+impl<'a> InFlight<'a> {
+    fn enter(level: &'a Level) -> Self {
+        level.adjust(1);
+        InFlight(level)
+    }
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        self.0.adjust(-1)
+    }
 }
 
 impl From<InputMetric> for Gauge {
     fn from(metric: InputMetric) -> Gauge {
-        Gauge { inner: metric }
+        Gauge {
+            inner: metric,
+            min_change: None,
+            pipeline: ValuePipeline::new(),
+        }
+    }
+}
+
+impl From<InputMetric> for Distribution {
+    fn from(metric: InputMetric) -> Distribution {
+        Distribution { inner: metric }
     }
 }
 
 impl From<InputMetric> for Timer {
     fn from(metric: InputMetric) -> Timer {
-        Timer { inner: metric }
+        Timer {
+            pipeline: ValuePipeline::new(),
+            #[cfg(feature = "timer_debug")]
+            unstopped: Arc::new(Unstopped {
+                metric_id: metric.metric_id().clone(),
+                count: Default::default(),
+            }),
+            inner: metric,
+        }
     }
 }
 
@@ -315,6 +1513,14 @@ impl Deref for Gauge {
     }
 }
 
+impl Deref for Distribution {
+    type Target = InputMetric;
+
+    fn deref(&self) -> &Self::Target {
+        &self.inner
+    }
+}
+
 impl Deref for Level {
     type Target = InputMetric;
 
@@ -322,3 +1528,488 @@ impl Deref for Level {
         &self.inner
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::output::map::StatsMapScope;
+    use crate::test_util::TestMetrics;
+    use crate::{mock_clock_advance, mock_clock_reset, stats_summary, AtomicBucket};
+
+    #[test]
+    fn time_sized() {
+        let metrics = StatsMapScope::default();
+        let timer = metrics.timer("read_time");
+        let bytes = metrics.counter("read_bytes");
+
+        let read = timer.time_sized(bytes, || vec![0u8; 42], |buf| buf.len());
+
+        assert_eq!(42, read.len());
+        let map = metrics.into_map();
+        assert_eq!(map["read_bytes"], 42);
+        assert!(map.contains_key("read_time"));
+    }
+
+    #[test]
+    fn time_tracked() {
+        let tm = TestMetrics::new();
+        let in_flight = tm.sink().level("l");
+        let metrics = StatsMapScope::default();
+        let timer = metrics.timer("op_time");
+
+        let len = timer.time_tracked(&in_flight, || {
+            assert_eq!(vec![1], tm.values("l"));
+            42
+        });
+
+        assert_eq!(42, len);
+        assert_eq!(vec![1, -1], tm.values("l"));
+        assert!(metrics.into_map().contains_key("op_time"));
+    }
+
+    #[test]
+    fn timer_interval() {
+        let tm = TestMetrics::new();
+        let timer = tm.sink().timer("t");
+        assert_eq!(3_000, timer.interval(Duration::from_millis(3)));
+        assert_eq!(u64::MAX, timer.interval(Duration::MAX));
+        assert_eq!(vec![3_000, MetricValue::MAX], tm.values("t"));
+    }
+
+    #[test]
+    fn rate_counter() {
+        mock_clock_reset();
+        let metrics = StatsMapScope::default();
+        let bytes = metrics.rate_counter("bytes");
+
+        bytes.count(1500);
+        bytes.count(500);
+        mock_clock_advance(Duration::from_secs(2));
+        metrics.flush().unwrap();
+
+        let map = metrics.into_map();
+        assert_eq!(map["bytes"], 2000);
+        assert_eq!(map["bytes.rate"], 1000);
+    }
+
+    #[test]
+    fn rate_counter_dropped() {
+        let tm = TestMetrics::new();
+        let metrics = tm.sink();
+        let bytes = metrics.rate_counter("bytes");
+
+        bytes.count(100);
+        metrics.flush().unwrap();
+        assert_eq!(vec![100], tm.values("bytes"));
+        assert_eq!(1, tm.values("bytes.rate").len());
+
+        drop(bytes);
+        metrics.flush().unwrap();
+        assert_eq!(vec![100], tm.values("bytes"));
+        assert_eq!(1, tm.values("bytes.rate").len());
+    }
+
+    #[test]
+    fn decaying_counter() {
+        mock_clock_reset();
+        let metrics = StatsMapScope::default();
+        let requests = metrics.decaying_counter("requests", Duration::from_secs(60));
+
+        requests.count(1000);
+        mock_clock_advance(Duration::from_secs(60));
+        metrics.flush().unwrap();
+        assert_eq!(metrics.clone().into_map()["requests"], 500);
+
+        requests.count(500);
+        mock_clock_advance(Duration::from_secs(120));
+        metrics.flush().unwrap();
+        assert_eq!(metrics.into_map()["requests"], 250);
+    }
+
+    #[test]
+    fn decaying_counter_dropped() {
+        let tm = TestMetrics::new();
+        let metrics = tm.sink();
+        let requests = metrics.decaying_counter("requests", Duration::from_secs(60));
+        let cloned = requests.clone();
+
+        requests.count(10);
+        drop(requests);
+        metrics.flush().unwrap();
+        assert_eq!(vec![10], tm.values("requests"));
+
+        drop(cloned);
+        metrics.flush().unwrap();
+        assert_eq!(vec![10], tm.values("requests"));
+    }
+
+    #[test]
+    fn sliding_rate_counter() {
+        mock_clock_reset();
+        let metrics = StatsMapScope::default();
+        let requests = metrics.sliding_rate_counter("requests", Duration::from_secs(60));
+
+        requests.count(120);
+        mock_clock_advance(Duration::from_secs(30));
+        assert_eq!(requests.rate(), 4.0);
+
+        requests.count(60);
+        mock_clock_advance(Duration::from_secs(30));
+        metrics.flush().unwrap();
+        // the first count left the window
+        assert_eq!(metrics.clone().into_map()["requests"], 1);
+
+        mock_clock_advance(Duration::from_secs(30));
+        assert_eq!(requests.rate(), 0.0);
+    }
+
+    #[test]
+    fn sliding_rate_counter_dropped() {
+        mock_clock_reset();
+        let tm = TestMetrics::new();
+        let metrics = tm.sink();
+        let requests = metrics.sliding_rate_counter("requests", Duration::from_secs(60));
+
+        requests.count(60);
+        mock_clock_advance(Duration::from_secs(30));
+        metrics.flush().unwrap();
+        assert_eq!(vec![2], tm.values("requests"));
+
+        drop(requests);
+        metrics.flush().unwrap();
+        assert_eq!(vec![2], tm.values("requests"));
+    }
+
+    #[test]
+    fn metric_family() {
+        let metrics = StatsMapScope::default();
+        let requests = metrics.family("http");
+
+        requests.record::<(), ()>(Duration::from_millis(3), &Ok(()));
+        requests.record::<(), ()>(Duration::from_millis(5), &Err(()));
+
+        let map = metrics.into_map();
+        assert_eq!(map["http.requests"], 1);
+        assert_eq!(map["http.errors"], 1);
+        assert_eq!(map["http.duration"], 5000);
+    }
+
+    #[test]
+    fn http_metrics() {
+        let metrics = StatsMapScope::default();
+        let http = metrics.http_metrics("http");
+
+        http.record("/users/{id}", 404, Duration::from_millis(3));
+        http.finish("/users/{id}", 200, http.start());
+
+        let map = metrics.into_map();
+        assert_eq!(map["http.requests"], 1);
+        assert_eq!(map["http.2xx"], 1);
+        assert_eq!(map["http.4xx"], 1);
+        assert!(!map.contains_key("http.5xx"));
+    }
+
+    #[test]
+    fn disabled_timer() {
+        let metrics = StatsMapScope::default();
+        let timer = metrics.timer("latency");
+        timer.set_enabled(false);
+        assert_eq!(42, timer.time(|| 42));
+        timer.interval_us(10);
+        assert!(metrics.clone().into_map().is_empty());
+
+        timer.set_enabled(true);
+        timer.interval_us(10);
+        assert_eq!(Some(&10), metrics.into_map().get("latency"));
+    }
+
+    #[test]
+    fn disabled_timer_start() {
+        let metrics = StatsMapScope::default();
+        let timer = metrics.timer("latency");
+        timer.set_enabled(false);
+        let start_time = timer.start();
+        assert!(start_time.is_disabled());
+        #[cfg(feature = "timer_debug")]
+        assert_eq!(0, timer.unstopped());
+
+        // a handle obtained while disabled records nothing, even once enabled
+        timer.set_enabled(true);
+        timer.stop(start_time);
+        assert!(metrics.into_map().is_empty());
+    }
+
+    #[test]
+    fn event_recorder() {
+        struct Fetch {
+            duration: Duration,
+            bytes: usize,
+            failed: bool,
+        }
+
+        let metrics = StatsMapScope::default();
+        let recorder = EventRecorder::new()
+            .timer(metrics.timer("fetch.duration"), |e: &Fetch| e.duration)
+            .counter(metrics.counter("fetch.bytes"), |e| e.bytes)
+            .marker(metrics.marker("fetch.errors"), |e| e.failed);
+
+        recorder.record(&Fetch {
+            duration: Duration::from_millis(2),
+            bytes: 512,
+            failed: false,
+        });
+
+        let map = metrics.into_map();
+        assert_eq!(map["fetch.duration"], 2000);
+        assert_eq!(map["fetch.bytes"], 512);
+        assert!(!map.contains_key("fetch.errors"));
+    }
+
+    #[cfg(feature = "timer_debug")]
+    #[test]
+    fn timer_unstopped() {
+        let metrics = StatsMapScope::default();
+        let timer = metrics.timer("timer");
+        let started = timer.start();
+        let _forgotten = timer.start();
+        timer.time(|| ());
+        assert_eq!(2, timer.unstopped());
+        timer.stop(started);
+        assert_eq!(1, timer.unstopped());
+    }
+
+    #[test]
+    fn timer_min_interval() {
+        mock_clock_reset();
+        let metrics = AtomicBucket::new();
+        metrics.stats(stats_summary);
+        let clamped = metrics
+            .timer("clamped")
+            .min_interval(Duration::from_millis(15), OutOfRange::Clamp);
+        let dropped = metrics
+            .timer("dropped")
+            .min_interval(Duration::from_millis(15), OutOfRange::Drop);
+
+        let start = clamped.start();
+        mock_clock_advance(Duration::from_millis(20));
+        clamped.stop(start);
+        dropped.stop(start);
+        clamped.time(|| ());
+        dropped.time(|| ());
+
+        let map = StatsMapScope::default();
+        metrics.flush_to(&map).unwrap();
+        let map = map.into_map();
+        assert_eq!(map["clamped"], 35_000);
+        assert_eq!(map["dropped"], 20_000);
+    }
+
+    #[test]
+    fn ratio_gauge() {
+        let metrics = StatsMapScope::default();
+        let pool = metrics.ratio_gauge("pool", 8.0);
+        pool.value(6.0);
+        assert_eq!(Some(&75), metrics.clone().into_map().get("pool"));
+        pool.value(12.0);
+        assert_eq!(Some(&100), metrics.clone().into_map().get("pool"));
+        pool.value(-1.0);
+        assert_eq!(Some(&0), metrics.into_map().get("pool"));
+    }
+
+    #[test]
+    fn record_timing() {
+        let metrics = StatsMapScope::default();
+        metrics.record_timing("fetch", Duration::from_millis(15));
+        assert_eq!(Some(&15_000), metrics.into_map().get("fetch"));
+    }
+
+    #[test]
+    fn banded_timer() {
+        let metrics = AtomicBucket::new();
+        metrics.stats(stats_summary);
+        let latency = metrics.banded_timer(
+            "latency",
+            &[Duration::from_millis(100), Duration::from_millis(10)],
+        );
+
+        latency.record_banded(Duration::from_millis(5));
+        latency.record_banded(Duration::from_millis(10));
+        latency.record_banded(Duration::from_millis(50));
+        latency.record_banded(Duration::from_secs(1));
+
+        let map = StatsMapScope::default();
+        metrics.flush_to(&map).unwrap();
+        let map = map.into_map();
+        assert_eq!(map["latency"], 1_065_000);
+        assert_eq!(map["latency.le_10ms"], 2);
+        assert_eq!(map["latency.le_100ms"], 3);
+    }
+
+    #[test]
+    fn sized_timer() {
+        let metrics = AtomicBucket::new();
+        metrics.stats(stats_summary);
+        let queries = metrics.sized_timer(
+            "query",
+            &[("large", usize::MAX), ("small", 1024), ("medium", 65536)],
+        );
+
+        queries.record(10, Duration::from_micros(100));
+        queries.record(1024, Duration::from_micros(200));
+        queries.record(2000, Duration::from_micros(300));
+        queries.time_bucketed(1 << 20, || ());
+
+        let map = StatsMapScope::default();
+        metrics.flush_to(&map).unwrap();
+        let map = map.into_map();
+        assert!(map["query"] >= 600);
+        assert_eq!(map["query.small"], 2);
+        assert_eq!(map["query.medium"], 1);
+        assert_eq!(map["query.large"], 1);
+    }
+
+    #[test]
+    fn slo_bucket() {
+        let metrics = AtomicBucket::new();
+        metrics.stats(stats_summary);
+        let slo = metrics.slo_bucket(
+            "api",
+            &[Duration::from_millis(300), Duration::from_micros(100)],
+        );
+
+        slo.record(Duration::from_micros(50));
+        slo.record(Duration::from_millis(200));
+        slo.record(Duration::from_millis(300));
+        slo.record(Duration::from_secs(1));
+
+        let map = StatsMapScope::default();
+        metrics.flush_to(&map).unwrap();
+        let map = map.into_map();
+        assert_eq!(map["api.total"], 4);
+        assert_eq!(map["api.under_300ms"], 3);
+        assert_eq!(map["api.under_100us"], 1);
+    }
+
+    #[test]
+    fn clamp_out_of_range_values() {
+        assert_eq!(MetricValue::MAX, 1e20f64.as_metric_value());
+        assert_eq!(MetricValue::MIN, (-1e20f64).as_metric_value());
+        assert_eq!(MetricValue::MAX, u64::MAX.as_metric_value());
+        assert_eq!(0, f64::NAN.as_metric_value());
+        assert_eq!(42, 42.7f64.as_metric_value());
+    }
+
+    #[test]
+    fn max_gauge() {
+        let metrics = StatsMapScope::default();
+        let depth = metrics.max_gauge("depth");
+
+        depth.value(3);
+        depth.value(7);
+        depth.value(5);
+        metrics.flush().unwrap();
+        assert_eq!(metrics.clone().into_map()["depth"], 7);
+
+        depth.value(2);
+        metrics.flush().unwrap();
+        assert_eq!(metrics.clone().into_map()["depth"], 2);
+
+        // nothing recorded, previous value stands
+        metrics.flush().unwrap();
+        assert_eq!(metrics.into_map()["depth"], 2);
+    }
+
+    #[test]
+    fn max_gauge_dropped() {
+        let tm = TestMetrics::new();
+        let metrics = tm.sink();
+        let depth = metrics.max_gauge("depth");
+        let cloned = depth.clone();
+
+        depth.value(3);
+        drop(depth);
+        metrics.flush().unwrap();
+        assert_eq!(vec![3], tm.values("depth"));
+
+        cloned.value(4);
+        drop(cloned);
+        metrics.flush().unwrap();
+        assert_eq!(vec![3], tm.values("depth"));
+    }
+
+    #[test]
+    fn gauge_min_change() {
+        let tm = TestMetrics::new();
+        let gauge = tm.sink().gauge("g").min_change(0.01);
+
+        gauge.value(1000);
+        gauge.value(1005);
+        gauge.value(1010);
+        gauge.value(1021);
+        gauge.value(1011);
+
+        assert_eq!(vec![1000, 1021], tm.values("g"));
+    }
+
+    #[test]
+    fn gauge_min_change_extremes() {
+        let tm = TestMetrics::new();
+        let gauge = tm.sink().gauge("g").min_change(0.01);
+
+        gauge.value(MetricValue::MIN);
+        gauge.value(MetricValue::MAX);
+        gauge.value(MetricValue::MIN);
+
+        assert_eq!(
+            vec![MetricValue::MIN, MetricValue::MAX, MetricValue::MIN],
+            tm.values("g")
+        );
+    }
+
+    #[cfg(feature = "write_count")]
+    #[test]
+    fn count_writes() {
+        let counter = TestMetrics::new().sink().counter("write_count");
+        counter.count(1);
+        counter.count(2);
+        counter.count(3);
+
+        assert_eq!(3, counter.write_count());
+        assert_eq!(3, write_count("test:write_count"));
+        assert!(write_count_report().contains("test:write_count 3\n"));
+    }
+
+    #[test]
+    fn slow_threshold() {
+        let tm = TestMetrics::new();
+        let timer = tm.sink().timer("t");
+        let slow = RwLock::new(vec![]);
+
+        for delay_ms in [1, 3] {
+            timer.time_with_slow_threshold(
+                Duration::from_millis(2),
+                |elapsed| write_lock!(slow).push(elapsed),
+                || tm.clock.advance(Duration::from_millis(delay_ms)),
+            );
+        }
+
+        assert_eq!(vec![Duration::from_millis(3)], *read_lock!(slow));
+    }
+
+    #[test]
+    fn gauge_clamp() {
+        let tm = TestMetrics::new();
+        let gauge = tm.sink().gauge("g");
+
+        let clamped = gauge.clamp(0, 100, OutOfRange::Clamp);
+        clamped.value(50);
+        clamped.value(150);
+        clamped.value(-1);
+
+        let dropped = gauge.clamp(0, 100, OutOfRange::Drop);
+        dropped.value(150);
+        dropped.value(100);
+
+        assert_eq!(vec![50, 100, 0, 100], tm.values("g"));
+    }
+}
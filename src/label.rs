@@ -66,7 +66,7 @@ impl LabelScope {
 
     fn collect(&self, map: &mut HashMap<String, LabelValue>) {
         if let Some(pairs) = &self.pairs {
-            map.extend(pairs.as_ref().clone().into_iter())
+            map.extend(pairs.as_ref().clone())
         }
     }
 }
@@ -171,6 +171,10 @@ impl Default for Labels {
 impl Labels {
     /// Used to save metric context before enqueuing value for async output.
     pub fn save_context(&mut self) {
+        if self.scopes.is_empty() {
+            // keep value labels first, even if there are none
+            self.scopes.push(LabelScope::default());
+        }
         self.scopes
             .push(THREAD_LABELS.with(|map| map.borrow().clone()));
         self.scopes.push(read_lock!(APP_LABELS).clone());
@@ -208,6 +212,19 @@ impl Labels {
         }
     }
 
+    /// Returns the labels provided with the value, excluding thread and app context labels, sorted by key.
+    pub(crate) fn value_pairs(&self) -> Vec<(String, LabelValue)> {
+        let mut pairs: Vec<(String, LabelValue)> = match self.scopes.first() {
+            Some(LabelScope { pairs: Some(pairs) }) => pairs
+                .iter()
+                .map(|(key, value)| (key.clone(), value.clone()))
+                .collect(),
+            _ => vec![],
+        };
+        pairs.sort();
+        pairs
+    }
+
     /// Export current state of labels to a map.
     /// Note: An iterator would still need to allocate to check for uniqueness of keys.
     ///
@@ -243,6 +260,7 @@ impl Labels {
 }
 
 #[cfg(test)]
+#[allow(clippy::bool_assert_comparison)]
 pub mod test {
     use super::*;
 
@@ -53,59 +53,123 @@ macro_rules! read_lock {
 
 mod attributes;
 mod clock;
+pub mod ext;
 mod input;
 mod label;
 mod metrics;
 mod name;
 mod pcg32;
 mod proxy;
+mod quantile;
 mod scheduler;
+#[cfg(unix)]
+mod signal;
+mod task;
+
+#[cfg(any(test, feature = "test-util"))]
+mod test_util;
+
+#[cfg(all(target_os = "linux", feature = "process"))]
+mod process;
 
 mod atomic;
+mod pipeline;
 mod stats;
 
 mod cache;
+mod gate;
+mod hook;
 mod lru_cache;
+mod metadata;
+mod transform;
 
 mod multi;
 mod queue;
 
 pub use crate::attributes::{
-    Attributes, Buffered, Buffering, MetricId, Observe, ObserveWhen, OnFlush, OnFlushCancel,
-    Prefixed, Sampled, Sampling, WithAttributes,
+    Attributes, Buffered, Buffering, Collect, Heartbeat, Info, MetricId, Observe, ObserveWhen,
+    OnFlush, OnFlushCancel, Prefixed, Sampled, Sampling, WithAttributes,
 };
 pub use crate::clock::TimeHandle;
+#[cfg(feature = "write_count")]
+pub use crate::input::{write_count, write_count_report};
 pub use crate::input::{
-    Counter, Gauge, Input, InputDyn, InputKind, InputMetric, InputScope, Level, Marker, Timer,
+    AsValue, BandedTimer, BandedTiming, Counter, DecayingCounter, DecayingCounting, Distribution,
+    EventRecorder, ExternalTiming, Gauge, HttpMetering, HttpMetrics, Input, InputDyn, InputKind,
+    InputMetric, InputScope, KeyedMetrics, Level, Marker, MaxGauge, MaxGauging, MetricFamilies,
+    MetricFamily, MetricKey, OutOfRange, RateCounter, RateCounting, RatioGauge, RatioGauging,
+    SizedTimer, SizedTiming, SlidingRateCounter, SlidingRateCounting, SloBucket, SloCounting,
+    Timer,
 };
 pub use crate::label::{AppLabel, Labels, ThreadLabel};
-pub use crate::name::{MetricName, NameParts};
+pub use crate::name::{fnv1a_hash, is_portable_name, MetricName, NameHash, NameParts};
 pub use crate::output::void::Void;
 pub use crate::scheduler::{Cancel, CancelGuard, CancelHandle, ScheduleFlush};
+#[cfg(unix)]
+pub use crate::signal::{FlushOnSignal, Signal};
+pub use crate::task::{current_metrics, instrument, Instrumented};
+
+#[cfg(all(target_os = "linux", feature = "process"))]
+pub use crate::process::register_process_metrics;
 
 #[cfg(test)]
 pub use crate::clock::{mock_clock_advance, mock_clock_reset};
 
+#[cfg(feature = "test-util")]
+pub use crate::test_util::{MockClock, TestMetrics, TestSink};
+
 pub use crate::proxy::Proxy;
+pub use crate::quantile::{QuantileTimer, QuantileTiming};
+
+pub use crate::metrics::{
+    self_metrics_target, self_metrics_target_prefixed, unset_self_metrics_target,
+    SELF_METRICS_PREFIX,
+};
 
 mod output;
+pub use crate::output::dry_run::DryRun;
+pub use crate::output::error::SinkError;
 pub use crate::output::format::{
-    Formatting, LabelOp, LineFormat, LineOp, LineTemplate, SimpleFormat,
+    Formatting, HumanFormat, LabelOp, LineFormat, LineOp, LineTemplate, SimpleFormat,
+    DEFAULT_FLOAT_PRECISION,
 };
 pub use crate::output::graphite::{Graphite, GraphiteMetric, GraphiteScope};
+pub use crate::output::json::{JsonBatch, JsonBatchScope};
 pub use crate::output::log::{Log, LogScope};
-pub use crate::output::map::StatsMapScope;
-pub use crate::output::statsd::{Statsd, StatsdMetric, StatsdScope};
+pub use crate::output::map::{StatsMap, StatsMapScope};
+pub use crate::output::socket::SocketOptions;
+pub use crate::output::statsd::{
+    aggregate_to_statsd, statsd_sharded, Statsd, StatsdMetric, StatsdScope, StatsdSharded,
+    StatsdShardedScope,
+};
 pub use crate::output::stream::{Stream, TextScope};
 
 //#[cfg(feature="prometheus")]
 pub use crate::output::prometheus::{Prometheus, PrometheusScope};
 
-pub use crate::atomic::AtomicBucket;
+#[cfg(feature = "otlp")]
+pub use crate::output::otlp::{Otlp, OtlpMetric, OtlpScope};
+
+#[cfg(feature = "kafka")]
+pub use crate::output::kafka::{KafkaRest, KafkaRestMetric, KafkaRestScope};
+
+#[cfg(all(unix, feature = "journald"))]
+pub use crate::output::journald::{Journald, JournaldMetric, JournaldScope};
+
+pub use crate::atomic::{
+    AtomicBucket, CumulativeCounter, GaugeGroup, MetricsDiff, MetricsSnapshot, Summary,
+};
 pub use crate::cache::CachedInput;
-pub use crate::multi::{MultiInput, MultiInputScope};
+pub use crate::gate::{Gated, GatedScope, SamplingGate};
+pub use crate::hook::{WriteHookScope, WriteHooked};
+pub use crate::metadata::{MetadataScope, MetricMetadata, WithMetadata};
+pub use crate::multi::{MultiInput, MultiInputScope, OrStderr};
+pub use crate::pipeline::{Aggregation, Pipeline};
 pub use crate::queue::{InputQueue, InputQueueScope, QueuedInput};
-pub use crate::stats::{stats_all, stats_average, stats_summary, ScoreType};
+pub use crate::stats::{
+    exponential_buckets, stats_all, stats_all_named, stats_average, stats_summary, ScoreType,
+};
+pub use crate::transform::{TransformScope, Transformed, ValueFn, ValuePipeline};
 
 use std::io;
 
@@ -17,6 +17,163 @@ macro_rules! time {
     }};
 }
 
+/// Time a block or an expression like `time!`, if the `metrics` feature is enabled.
+/// Otherwise, only the body is evaluated, the timer expression is compiled out.
+#[cfg(feature = "metrics")]
+#[macro_export]
+macro_rules! maybe_time {
+    ($timer: expr, $body: expr) => {
+        $crate::time!($timer, $body)
+    };
+}
+
+/// Time a block or an expression like `time!`, if the `metrics` feature is enabled.
+/// Otherwise, only the body is evaluated, the timer expression is compiled out.
+#[cfg(not(feature = "metrics"))]
+#[macro_export]
+macro_rules! maybe_time {
+    ($timer: expr, $body: expr) => {
+        $body
+    };
+}
+
+/// Count a value, if the `metrics` feature is enabled.
+/// Otherwise, the call is compiled out and its arguments are never evaluated.
+#[cfg(feature = "metrics")]
+#[macro_export]
+macro_rules! maybe_count {
+    ($counter: expr, $count: expr) => {
+        $counter.count($count)
+    };
+}
+
+/// Count a value, if the `metrics` feature is enabled.
+/// Otherwise, the call is compiled out and its arguments are never evaluated.
+#[cfg(not(feature = "metrics"))]
+#[macro_export]
+macro_rules! maybe_count {
+    ($counter: expr, $count: expr) => {
+        ()
+    };
+}
+
+/// Mark an event, if the `metrics` feature is enabled.
+/// Otherwise, the call is compiled out and its argument is never evaluated.
+#[cfg(feature = "metrics")]
+#[macro_export]
+macro_rules! maybe_mark {
+    ($marker: expr) => {
+        $marker.mark()
+    };
+}
+
+/// Mark an event, if the `metrics` feature is enabled.
+/// Otherwise, the call is compiled out and its argument is never evaluated.
+#[cfg(not(feature = "metrics"))]
+#[macro_export]
+macro_rules! maybe_mark {
+    ($marker: expr) => {
+        ()
+    };
+}
+
+/// Record a gauge value, if the `metrics` feature is enabled.
+/// Otherwise, the call is compiled out and its arguments are never evaluated.
+#[cfg(feature = "metrics")]
+#[macro_export]
+macro_rules! maybe_value {
+    ($gauge: expr, $value: expr) => {
+        $gauge.value($value)
+    };
+}
+
+/// Record a gauge value, if the `metrics` feature is enabled.
+/// Otherwise, the call is compiled out and its arguments are never evaluated.
+#[cfg(not(feature = "metrics"))]
+#[macro_export]
+macro_rules! maybe_value {
+    ($gauge: expr, $value: expr) => {
+        ()
+    };
+}
+
+/// A metric name checked at compile time, expanding to a `&'static str`.
+/// Names must be made of dot-separated segments of ASCII letters, digits and underscores,
+/// which all outputs accept. Any other character, or an empty segment, fails the build.
+///
+/// ## Example
+///
+/// ```
+/// use dipstick::*;
+///
+/// let metrics = AtomicBucket::new();
+/// metrics.counter(metric_name!("http.requests")).count(1);
+/// ```
+///
+/// ```compile_fail
+/// let name = dipstick::metric_name!("http requests");
+/// ```
+#[macro_export]
+macro_rules! metric_name {
+    ($name:literal) => {{
+        const NAME: &str = $name;
+        const _: () = assert!(
+            $crate::is_portable_name(NAME),
+            concat!("illegal metric name ", stringify!($name))
+        );
+        NAME
+    }};
+}
+
+/// Declare an enum of metrics whose names and kinds are fixed, implementing `MetricKey`.
+/// Names are checked at compile time like those of `metric_name!`.
+///
+/// ## Example
+///
+/// ```
+/// use dipstick::*;
+///
+/// metric_keys! {
+///     /// The metrics of the application.
+///     pub enum AppMetric {
+///         Requests: Marker = "requests",
+///         Latency: Timer = "latency",
+///     }
+/// }
+///
+/// let metrics = AtomicBucket::new();
+/// let requests: Marker = metrics.keyed(AppMetric::Requests).into();
+/// requests.mark();
+/// ```
+#[macro_export]
+macro_rules! metric_keys {
+    ($(#[$attr:meta])* $vis:vis enum $ENUM:ident { $($VARIANT:ident: $KIND:ident = $NAME:literal),* $(,)? }) => {
+        $(#[$attr])*
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+        $vis enum $ENUM {
+            $(#[doc = $NAME] $VARIANT),*
+        }
+
+        impl $crate::MetricKey for $ENUM {
+            fn name(self) -> &'static str {
+                match self {
+                    $($ENUM::$VARIANT => $crate::metric_name!($NAME)),*
+                }
+            }
+
+            fn kind(self) -> $crate::InputKind {
+                match self {
+                    $($ENUM::$VARIANT => $crate::InputKind::$KIND),*
+                }
+            }
+
+            fn all() -> &'static [Self] {
+                &[$($ENUM::$VARIANT),*]
+            }
+        }
+    };
+}
+
 /// Create **Labels** from a list of key-value pairs
 /// Adapted from the hashmap!() macro in the *maplit* crate.
 ///
@@ -180,6 +337,27 @@ mod test {
         COUNTER_A: Counter = "counter_a";
     });
 
+    metric_keys! {
+        enum TestMetric {
+            Requests: Counter = "requests",
+            Latency: Timer = "http.latency",
+        }
+    }
+
+    #[test]
+    fn metric_keys() {
+        use crate::output::map::StatsMapScope;
+        use crate::{KeyedMetrics, MetricKey};
+
+        let metrics = StatsMapScope::default();
+        assert_eq!(2, metrics.preregister_keys::<TestMetric>().len());
+        let requests: Counter = metrics.keyed(TestMetric::Requests).into();
+        requests.count(3);
+        assert_eq!(InputKind::Timer, TestMetric::Latency.kind());
+        assert_eq!("http.latency", TestMetric::Latency.name());
+        assert_eq!(Some(&3), metrics.into_map().get("requests"));
+    }
+
     #[test]
     fn gurp() {
         COUNTER_A.count(11);
@@ -192,4 +370,18 @@ mod test {
         G1.value(1);
         T1.interval_us(1);
     }
+
+    #[test]
+    fn maybe_macros() {
+        let evaluated = std::cell::Cell::new(false);
+        let value = maybe_time!(T1, 42);
+        maybe_count!(C1, {
+            evaluated.set(true);
+            1
+        });
+        maybe_mark!(M1);
+        maybe_value!(G1, 1);
+        assert_eq!(42, value);
+        assert_eq!(cfg!(feature = "metrics"), evaluated.get());
+    }
 }
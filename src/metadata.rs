@@ -0,0 +1,173 @@
+//! Metric metadata notification.
+
+use crate::attributes::{Attributes, OnFlush, Prefixed, WithAttributes};
+use crate::input::{InputKind, InputMetric, InputScope};
+use crate::name::MetricName;
+use crate::Flush;
+
+use std::collections::HashMap;
+use std::io;
+use std::sync::Arc;
+
+#[cfg(not(feature = "parking_lot"))]
+use std::sync::RwLock;
+
+#[cfg(feature = "parking_lot")]
+use parking_lot::RwLock;
+
+/// The description of a metric, as notified to a metadata listener.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MetricMetadata {
+    /// The kind of metric.
+    pub kind: InputKind,
+    /// The full name of the metric.
+    pub name: String,
+    /// The unit of the metric's values, if described.
+    pub unit: Option<String>,
+    /// A description of the metric, if described.
+    pub help: Option<String>,
+}
+
+/// Notify the metadata of a scope's metrics separately from their values,
+/// e.g. to populate the type and unit registries of backends separating schema from data.
+pub trait WithMetadata: InputScope + Send + Sync + 'static + Sized {
+    /// Wrap this scope with a listener called when a metric is first defined,
+    /// and again if its kind, unit or help change.
+    fn with_metadata_listener<F>(self, listener: F) -> MetadataScope
+    where
+        F: Fn(&MetricMetadata) + Send + Sync + 'static,
+    {
+        MetadataScope {
+            attributes: Attributes::default(),
+            target: Arc::new(self),
+            listener: Arc::new(listener),
+            metadata: Arc::default(),
+        }
+    }
+}
+
+impl<T: InputScope + Send + Sync + 'static> WithMetadata for T {}
+
+type MetadataListener = Arc<dyn Fn(&MetricMetadata) + Send + Sync + 'static>;
+
+/// The known metadata of a metric, the kind being unknown until the metric is defined.
+#[derive(Default)]
+struct Description {
+    kind: Option<InputKind>,
+    unit: Option<String>,
+    help: Option<String>,
+}
+
+impl Description {
+    fn metadata(&self, name: &str) -> Option<MetricMetadata> {
+        self.kind.map(|kind| MetricMetadata {
+            kind,
+            name: name.to_string(),
+            unit: self.unit.clone(),
+            help: self.help.clone(),
+        })
+    }
+}
+
+/// Input scope wrapper notifying a listener of its metrics' metadata.
+#[derive(Clone)]
+pub struct MetadataScope {
+    attributes: Attributes,
+    target: Arc<dyn InputScope + Send + Sync + 'static>,
+    listener: MetadataListener,
+    metadata: Arc<RwLock<HashMap<String, Description>>>,
+}
+
+impl MetadataScope {
+    /// Describe the unit and purpose of a metric, notifying the listener if the metric is already defined.
+    pub fn describe(&self, name: &str, unit: Option<&str>, help: Option<&str>) {
+        let name = self.prefix_append(name).join(".");
+        let changed = {
+            let mut metadata = write_lock!(self.metadata);
+            let description = metadata.entry(name.clone()).or_default();
+            let unit = unit.map(str::to_string);
+            let help = help.map(str::to_string);
+            if description.unit == unit && description.help == help {
+                None
+            } else {
+                description.unit = unit;
+                description.help = help;
+                description.metadata(&name)
+            }
+        };
+        if let Some(changed) = changed {
+            (self.listener)(&changed)
+        }
+    }
+}
+
+impl WithAttributes for MetadataScope {
+    fn get_attributes(&self) -> &Attributes {
+        &self.attributes
+    }
+    fn mut_attributes(&mut self) -> &mut Attributes {
+        &mut self.attributes
+    }
+}
+
+impl InputScope for MetadataScope {
+    fn new_metric(&self, name: MetricName, kind: InputKind) -> InputMetric {
+        let name = self.prefix_append(name);
+        let full_name = name.join(".");
+        let changed = {
+            let mut metadata = write_lock!(self.metadata);
+            let description = metadata.entry(full_name.clone()).or_default();
+            if description.kind == Some(kind) {
+                None
+            } else {
+                description.kind = Some(kind);
+                description.metadata(&full_name)
+            }
+        };
+        if let Some(changed) = changed {
+            (self.listener)(&changed)
+        }
+        self.target.new_metric(name, kind)
+    }
+}
+
+impl Flush for MetadataScope {
+    fn flush(&self) -> io::Result<()> {
+        self.notify_flush_listeners();
+        self.target.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::output::map::StatsMapScope;
+
+    #[test]
+    fn notify_metadata_changes() {
+        let seen = Arc::new(RwLock::new(vec![]));
+        let sink = seen.clone();
+        let map = StatsMapScope::default();
+        let metrics = map
+            .clone()
+            .with_metadata_listener(move |metadata| write_lock!(sink).push(metadata.clone()))
+            .named("app");
+
+        metrics.describe("latency", Some("microseconds"), Some("Request latency"));
+        metrics.timer("latency").interval_us(3);
+        metrics.timer("latency").interval_us(4);
+        metrics.describe("latency", Some("seconds"), Some("Request latency"));
+
+        let metadata = |unit: &str| MetricMetadata {
+            kind: InputKind::Timer,
+            name: "app.latency".to_string(),
+            unit: Some(unit.to_string()),
+            help: Some("Request latency".to_string()),
+        };
+        assert_eq!(
+            vec![metadata("microseconds"), metadata("seconds")],
+            *read_lock!(seen)
+        );
+        assert_eq!(Some(&4), map.into_map().get("app.latency"));
+    }
+}
@@ -1,17 +1,76 @@
 //! Internal Dipstick runtime metrics.
 //! Because the possibly high volume of data, this is pre-set to use aggregation.
 //! This is also kept in a separate module because it is not to be exposed outside of the crate.
+//! Applications can route them to a scope of their choice with `self_metrics_target()`.
 
-use crate::attributes::Prefixed;
-use crate::input::{Counter, InputScope, Marker};
+use crate::attributes::{Prefixed, WithAttributes};
+use crate::input::{Counter, Gauge, InputScope, Marker};
 use crate::proxy::Proxy;
 
+/// Default prefix of dipstick's own metrics names.
+pub const SELF_METRICS_PREFIX: &str = "dipstick";
+
+/// Namespaces of the internal metrics, each routed separately to the self metrics target.
+/// Must list every namespace declared below.
+const SELF_METRICS_GROUPS: [&str; 9] = [
+    "input",
+    "bucket",
+    "queue",
+    "prometheus",
+    "graphite",
+    "socket",
+    "kafka",
+    "statsd",
+    "otlp",
+];
+
+/// Send dipstick's own metrics (send failures, queue depths, etc.) to the target scope,
+/// e.g. `dipstick.queue.depth`, separately from the application's metrics.
+/// Until a target is set, these metrics are discarded.
+pub fn self_metrics_target<T>(target: T)
+where
+    T: InputScope + WithAttributes + Send + Sync + 'static,
+{
+    self_metrics_target_prefixed(target, SELF_METRICS_PREFIX)
+}
+
+/// Send dipstick's own metrics to the target scope, using the specified prefix instead of `dipstick`.
+/// The prefix is appended to any names of the target scope.
+pub fn self_metrics_target_prefixed<T>(target: T, prefix: &str)
+where
+    T: InputScope + WithAttributes + Send + Sync + 'static,
+{
+    let target = target.nested(prefix);
+    for group in SELF_METRICS_GROUPS {
+        DIPSTICK_METRICS.named(group).target(target.nested(group))
+    }
+}
+
+/// Discard dipstick's own metrics again.
+pub fn unset_self_metrics_target() {
+    for group in SELF_METRICS_GROUPS {
+        DIPSTICK_METRICS.named(group).unset_target()
+    }
+}
+
 metrics! {
     /// Dipstick's own internal metrics.
     pub DIPSTICK_METRICS = "dipstick" => {
 
+        "input" => {
+            pub VALUE_CLAMPED: Marker = "value_clamped";
+            pub VALUE_OUT_OF_RANGE: Marker = "value_out_of_range";
+            pub TIMER_UNSTOPPED: Counter = "timer_unstopped";
+        }
+
+        "bucket" => {
+            pub BUCKET_LATE_DROPPED: Marker = "late_dropped";
+        }
+
         "queue" => {
             pub SEND_FAILED: Marker = "send_failed";
+            pub QUEUE_DEPTH: Gauge = "depth";
+            pub QUEUE_EXPIRED: Marker = "expired";
         }
 
         "prometheus" => {
@@ -26,9 +85,75 @@ metrics! {
             pub GRAPHITE_SENT_BYTES: Counter = "sent_bytes";
         }
 
+        "socket" => {
+            pub SOCKET_STALE: Marker = "stale";
+            pub SOCKET_DROPPED_BYTES: Counter = "dropped_bytes";
+        }
+
+        "kafka" => {
+            pub KAFKA_SEND_ERR: Marker = "send_failed";
+            pub KAFKA_SENT_BYTES: Counter = "sent_bytes";
+        }
+
         "statsd" => {
             pub STATSD_SEND_ERR: Marker ="send_failed";
             pub STATSD_SENT_BYTES: Counter = "sent_bytes";
         }
+
+        "otlp" => {
+            pub OTLP_SEND_ERR: Marker = "send_failed";
+            pub OTLP_DROPPED: Counter = "dropped_points";
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::output::map::StatsMapScope;
+    use crate::{AtomicBucket, Flush};
+    use std::sync::mpsc;
+    use std::sync::{Mutex, PoisonError};
+    use std::thread;
+    use std::time::Duration;
+
+    /// Serialize tests changing the global self metrics target.
+    static TARGET: Mutex<()> = Mutex::new(());
+
+    #[test]
+    fn prefixed_target() {
+        let _target = TARGET.lock().unwrap_or_else(PoisonError::into_inner);
+        let map = StatsMapScope::default();
+        self_metrics_target_prefixed(map.clone().named("app"), "lib.internal");
+        KAFKA_SENT_BYTES.count(3);
+        unset_self_metrics_target();
+        KAFKA_SENT_BYTES.count(4);
+
+        assert_eq!(
+            Some(&3),
+            map.into_map().get("app.lib.internal.kafka.sent_bytes")
+        );
+    }
+
+    #[test]
+    fn bucket_is_own_target() {
+        let _target = TARGET.lock().unwrap_or_else(PoisonError::into_inner);
+        let bucket = AtomicBucket::new();
+        self_metrics_target(bucket.clone());
+
+        let (done, flushed) = mpsc::channel();
+        thread::spawn(move || {
+            bucket.counter("requests").count(1);
+            bucket.flush().unwrap();
+            bucket.counter("requests").count(1);
+            bucket.flush_to(&StatsMapScope::default()).unwrap();
+            done.send(()).unwrap();
+        });
+        let flushed = flushed.recv_timeout(Duration::from_secs(5));
+        assert!(
+            flushed.is_ok(),
+            "flushing a bucket targeted by its own metrics deadlocked"
+        );
+        unset_self_metrics_target();
     }
 }
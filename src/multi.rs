@@ -3,9 +3,11 @@
 use crate::attributes::{Attributes, MetricId, OnFlush, Prefixed, WithAttributes};
 use crate::input::{Input, InputDyn, InputKind, InputMetric, InputScope};
 use crate::name::MetricName;
+use crate::output::stream::Stream;
 use crate::Flush;
 
-use std::io;
+use std::fmt;
+use std::io::{self, Write};
 use std::sync::Arc;
 
 /// Opens multiple scopes at a time from just as many outputs.
@@ -48,6 +50,36 @@ impl MultiInput {
     }
 }
 
+/// Fall back to printing metrics to stderr when an output could not be created.
+pub trait OrStderr {
+    /// Use the output if it was created. Otherwise log a warning and write metrics to stderr instead,
+    /// so that an unreachable metrics backend neither fails the application nor loses its metrics.
+    fn or_stderr(self) -> MultiInput
+    where
+        Self: Sized,
+    {
+        self.or_write_to(io::stderr())
+    }
+
+    /// Use the output if it was created. Otherwise log a warning and write metrics to the fallback instead.
+    fn or_write_to<W: Write + Send + Sync + 'static>(self, fallback: W) -> MultiInput;
+}
+
+impl<T: Input + Send + Sync + 'static, E: fmt::Display> OrStderr for Result<T, E> {
+    fn or_write_to<W: Write + Send + Sync + 'static>(self, fallback: W) -> MultiInput {
+        match self {
+            Ok(output) => MultiInput::new().add_target(output),
+            Err(err) => {
+                warn!(
+                    "Could not create metrics output, writing metrics to fallback: {}",
+                    err
+                );
+                MultiInput::new().add_target(Stream::write_to(fallback))
+            }
+        }
+    }
+}
+
 impl WithAttributes for MultiInput {
     fn get_attributes(&self) -> &Attributes {
         &self.attributes
@@ -119,3 +151,42 @@ impl WithAttributes for MultiInputScope {
         &mut self.attributes
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::Mutex;
+
+    #[derive(Clone, Default)]
+    struct Written(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Written {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn or_stderr_keeps_output() {
+        let written = Written::default();
+        let output: io::Result<_> = Ok(Stream::write_to(written.clone()));
+        let metrics = output.or_stderr().metrics();
+        metrics.counter("requests").count(3);
+        metrics.flush().unwrap();
+        assert_eq!(b"requests 3\n", written.0.lock().unwrap().as_slice());
+    }
+
+    #[test]
+    fn or_stderr_on_error() {
+        let fallback = Written::default();
+        let output: io::Result<Stream<Written>> =
+            Err(io::Error::new(io::ErrorKind::NotFound, "no such backend"));
+        let metrics = output.or_write_to(fallback.clone()).metrics();
+        metrics.counter("requests").count(3);
+        metrics.flush().unwrap();
+        assert_eq!(b"requests 3\n", fallback.0.lock().unwrap().as_slice());
+    }
+}
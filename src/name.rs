@@ -125,11 +125,58 @@ impl DerefMut for MetricName {
     }
 }
 
+/// A hash of metric names, used to consistently assign metrics to shards.
+/// Hashes must be stable across processes and platforms for the assignment to survive restarts.
+pub type NameHash = fn(&str) -> u64;
+
+/// The 64-bit FNV-1a hash of the name, stable across processes and platforms, unlike the std `Hasher`s.
+/// This is the default `NameHash`.
+pub fn fnv1a_hash(name: &str) -> u64 {
+    name.bytes().fold(0xcbf2_9ce4_8422_2325_u64, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+/// Returns true if the name is valid for all outputs: dot-separated segments of ASCII letters, digits and underscores.
+/// Used by the `metric_name!` macro to check names at compile time.
+pub const fn is_portable_name(name: &str) -> bool {
+    let bytes = name.as_bytes();
+    let mut i = 0;
+    let mut segment_len = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'.' if segment_len > 0 => segment_len = 0,
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'_' => segment_len += 1,
+            _ => return false,
+        }
+        i += 1;
+    }
+    segment_len > 0
+}
+
 #[cfg(test)]
+#[allow(clippy::bool_assert_comparison)]
 mod test {
 
     use super::*;
 
+    #[test]
+    fn fnv1a_is_stable() {
+        assert_eq!(0xcbf2_9ce4_8422_2325, fnv1a_hash(""));
+        assert_eq!(0xaf63_dc4c_8601_ec8c, fnv1a_hash("a"));
+    }
+
+    #[test]
+    fn portable_names() {
+        assert!(is_portable_name("http.requests_total"));
+        assert!(is_portable_name("p99"));
+        assert!(!is_portable_name(""));
+        assert!(!is_portable_name("http..requests"));
+        assert!(!is_portable_name("http.requests."));
+        assert!(!is_portable_name("http requests"));
+        assert!(!is_portable_name("http:requests"));
+    }
+
     #[test]
     fn string_deque_within_same() {
         let mut sd1: NameParts = "c".into();
@@ -0,0 +1,40 @@
+//! Record the payloads an output would send, without performing any I/O.
+
+use std::sync::Arc;
+
+#[cfg(not(feature = "parking_lot"))]
+use std::sync::RwLock;
+
+#[cfg(feature = "parking_lot")]
+use parking_lot::RwLock;
+
+/// A recorder of payloads that would have been sent by network outputs.
+/// Outputs set to `dry_run()` still perform all naming, formatting and buffering
+/// but hand their payloads to the recorder instead of sending them.
+/// Recorders can be cloned and shared between outputs.
+#[derive(Debug, Clone, Default)]
+pub struct DryRun {
+    payloads: Arc<RwLock<Vec<String>>>,
+}
+
+impl DryRun {
+    /// Create a new empty recorder.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record a payload as if it had been sent.
+    pub(crate) fn record(&self, payload: &str) {
+        write_lock!(self.payloads).push(payload.to_string())
+    }
+
+    /// Return a copy of all payloads recorded so far.
+    pub fn payloads(&self) -> Vec<String> {
+        read_lock!(self.payloads).clone()
+    }
+
+    /// Discard all payloads recorded so far.
+    pub fn clear(&self) {
+        write_lock!(self.payloads).clear()
+    }
+}
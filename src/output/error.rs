@@ -0,0 +1,159 @@
+//! Structured errors of output setup.
+
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::net::AddrParseError;
+
+/// The reason an output could not be set up, as returned by `Statsd::send_to` or `Graphite::send_to`.
+/// Errors are classified where the address is parsed or resolved, other `io::Error`s convert to `Io`.
+#[derive(Debug)]
+pub enum SinkError {
+    /// The address is not a valid socket address.
+    AddrParse(AddrParseError),
+    /// The host name could not be resolved.
+    Dns(io::Error),
+    /// The output's configuration is invalid, e.g. no server address was provided.
+    Config(String),
+    /// Any other failure, such as a socket that could not be opened or connected.
+    Io(io::Error),
+}
+
+impl fmt::Display for SinkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SinkError::AddrParse(e) => write!(f, "invalid address: {}", e),
+            SinkError::Dns(e) => write!(f, "address resolution failed: {}", e),
+            SinkError::Config(message) => write!(f, "invalid configuration: {}", message),
+            SinkError::Io(e) => write!(f, "output setup failed: {}", e),
+        }
+    }
+}
+
+impl Error for SinkError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            SinkError::AddrParse(e) => Some(e),
+            SinkError::Dns(e) | SinkError::Io(e) => Some(e),
+            SinkError::Config(_) => None,
+        }
+    }
+}
+
+impl From<AddrParseError> for SinkError {
+    fn from(error: AddrParseError) -> Self {
+        SinkError::AddrParse(error)
+    }
+}
+
+impl From<io::Error> for SinkError {
+    fn from(error: io::Error) -> Self {
+        SinkError::Io(error)
+    }
+}
+
+impl From<SinkError> for io::Error {
+    fn from(error: SinkError) -> Self {
+        match error {
+            SinkError::Dns(e) | SinkError::Io(e) => e,
+            SinkError::AddrParse(e) => io::Error::new(io::ErrorKind::InvalidInput, e),
+            SinkError::Config(message) => io::Error::new(io::ErrorKind::InvalidInput, message),
+        }
+    }
+}
+
+/// Check that the URL is an `http://` URL with a host and an optional port, as required by outputs posting over HTTP.
+/// Lets outputs fail upon creation rather than upon their first flush. TLS (`https://`) is not supported.
+pub(crate) fn check_http_url(url: &str) -> Result<(), SinkError> {
+    let invalid = |reason: &str| {
+        Err(SinkError::Config(format!(
+            "invalid URL {:?}, {}",
+            url, reason
+        )))
+    };
+    let Some(rest) = url.strip_prefix("http://") else {
+        return invalid("only http:// URLs are supported");
+    };
+    if rest.chars().any(char::is_whitespace) {
+        return invalid("whitespace is not allowed");
+    }
+    let authority = rest.split(['/', '?', '#']).next().unwrap_or_default();
+    let host_port = authority.rsplit('@').next().unwrap_or_default();
+    let (host, port) = match host_port.rfind(':') {
+        // the last colon of an IPv6 address is inside its brackets
+        Some(colon) if !host_port[colon..].contains(']') => {
+            (&host_port[..colon], Some(&host_port[colon + 1..]))
+        }
+        _ => (host_port, None),
+    };
+    if host.is_empty() {
+        return invalid("no host");
+    }
+    if port.is_some_and(|port| port.parse::<u16>().is_err()) {
+        return invalid("invalid port");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::output::json::JsonBatch;
+    use crate::output::prometheus::Prometheus;
+    use crate::output::statsd::{aggregate_to_statsd, statsd_sharded, Statsd};
+    use std::net::SocketAddr;
+    use std::time::Duration;
+
+    #[test]
+    fn classified() {
+        let parsed = "not an address"
+            .parse::<SocketAddr>()
+            .map_err(SinkError::from);
+        assert!(matches!(parsed, Err(SinkError::AddrParse(_))));
+
+        let empty: &[&str] = &[];
+        assert!(matches!(statsd_sharded(empty), Err(SinkError::Config(_))));
+
+        let malformed = Statsd::send_to("no port here");
+        assert!(matches!(malformed, Err(SinkError::Config(_))));
+
+        let unknown = Statsd::send_to("unknown.invalid:8125");
+        assert!(matches!(unknown, Err(SinkError::Dns(_))));
+
+        let aggregated = aggregate_to_statsd("unknown.invalid:8125", Duration::from_secs(1));
+        assert!(matches!(aggregated, Err(SinkError::Dns(_))));
+
+        let gateway = Prometheus::push_to("pushgateway:9091/metrics/job/app");
+        assert!(matches!(gateway, Err(SinkError::Config(_))));
+
+        let collector = JsonBatch::post_to("http://:8080/metrics");
+        assert!(matches!(collector, Err(SinkError::Config(_))));
+
+        let invalid = io::Error::new(io::ErrorKind::InvalidInput, "invalid");
+        assert!(matches!(SinkError::from(invalid), SinkError::Io(_)));
+
+        let refused = io::Error::new(io::ErrorKind::ConnectionRefused, "refused");
+        assert!(matches!(SinkError::from(refused), SinkError::Io(_)));
+    }
+
+    #[test]
+    fn http_urls() {
+        assert!(check_http_url("http://localhost").is_ok());
+        assert!(check_http_url("http://localhost:8082/").is_ok());
+        assert!(check_http_url("http://user@[::1]:9091/metrics/job/app").is_ok());
+        for url in [
+            "localhost:8082",
+            "https://localhost",
+            "http://",
+            "http://:8082/",
+            "http://localhost:port",
+            "http://local host",
+        ] {
+            assert!(
+                matches!(check_http_url(url), Err(SinkError::Config(_))),
+                "{}",
+                url
+            );
+        }
+    }
+}
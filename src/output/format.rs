@@ -8,6 +8,7 @@ use std::io::Write;
 use std::sync::Arc;
 
 /// Print commands are steps in the execution of output templates.
+#[non_exhaustive]
 pub enum LineOp {
     /// Print a string.
     Literal(Vec<u8>),
@@ -16,7 +17,13 @@ pub enum LineOp {
     /// Print metric value as text.
     ValueAsText,
     /// Print metric value, divided by the given scale, as text.
+    /// At most `DEFAULT_FLOAT_PRECISION` decimals are printed.
     ScaledValueAsText(f64),
+    /// Print metric value, divided by the given scale, as text with at most the given number of decimals.
+    /// Trailing zeros are not printed.
+    ScaledValueAsTextPrecision(f64, usize),
+    /// Print metric value as a human readable byte count, e.g. `1.5 MB`.
+    HumanBytesAsText,
     /// Print the newline character.labels.lookup(key)
     NewLine,
 }
@@ -58,9 +65,12 @@ impl LineTemplate {
                 Literal(src) => output.write_all(src.as_ref())?,
                 ValueAsText => output.write_all(format!("{}", value).as_ref())?,
                 ScaledValueAsText(scale) => {
-                    let scaled = value as f64 / scale;
-                    output.write_all(format!("{}", scaled).as_ref())?
+                    write_float(output, value as f64 / scale, DEFAULT_FLOAT_PRECISION)?
                 }
+                ScaledValueAsTextPrecision(scale, precision) => {
+                    write_float(output, value as f64 / scale, *precision)?
+                }
+                HumanBytesAsText => write_human_bytes(output, value)?,
                 NewLine => writeln!(output)?,
                 LabelExists(label_key, print_label) => {
                     if let Some(label_value) = lookup(label_key.as_ref()) {
@@ -79,6 +89,38 @@ impl LineTemplate {
     }
 }
 
+/// Number of decimals printed for scaled values, unless specified otherwise.
+pub const DEFAULT_FLOAT_PRECISION: usize = 6;
+
+/// Print a float rounded to the precision, without trailing zeros (e.g. `0.3` rather than `0.30000000000000004`).
+fn write_float(output: &mut dyn Write, value: f64, precision: usize) -> io::Result<()> {
+    let text = format!("{:.*}", precision, value);
+    let text = if text.contains('.') {
+        text.trim_end_matches('0').trim_end_matches('.')
+    } else {
+        &text
+    };
+    // small negative values round to zero, which has no sign
+    let text = if text == "-0" { "0" } else { text };
+    output.write_all(text.as_bytes())
+}
+
+/// Print a byte count scaled to the biggest decimal unit it reaches, with at most one decimal.
+fn write_human_bytes(output: &mut dyn Write, value: MetricValue) -> io::Result<()> {
+    const UNITS: [&str; 5] = ["KB", "MB", "GB", "TB", "PB"];
+    let mut scaled = value as f64;
+    let mut unit = "B";
+    for next in UNITS {
+        if scaled.abs() < 1000.0 {
+            break;
+        }
+        scaled /= 1000.0;
+        unit = next;
+    }
+    write_float(output, scaled, 1)?;
+    write!(output, " {}", unit)
+}
+
 /// Format output config support.
 pub trait Formatting {
     /// Specify formatting of output.
@@ -108,6 +150,33 @@ impl LineFormat for SimpleFormat {
     }
 }
 
+/// A metric output format of "MetricName {Value}" for humans, printing byte counts and rates in readable units,
+/// e.g. `received_bytes.rate 1.5 MB/s`. Byte metrics are recognized by their name ending with `bytes`,
+/// optionally followed by `rate`, such as the metrics of a `bytes_counter()`.
+#[derive(Default)]
+pub struct HumanFormat {}
+
+impl LineFormat for HumanFormat {
+    fn template(&self, name: &MetricName, _kind: InputKind) -> LineTemplate {
+        let mut header = name.join(".");
+        header.push(' ');
+        let parts: Vec<&str> = name.iter().map(String::as_str).collect();
+        let ops = match parts.as_slice() {
+            [.., bytes] if bytes.ends_with("bytes") => {
+                vec![Literal(header.into_bytes()), HumanBytesAsText, NewLine]
+            }
+            [.., bytes, "rate"] if bytes.ends_with("bytes") => vec![
+                Literal(header.into_bytes()),
+                HumanBytesAsText,
+                Literal(b"/s".to_vec()),
+                NewLine,
+            ],
+            _ => vec![Literal(header.into_bytes()), ValueAsText, NewLine],
+        };
+        LineTemplate { ops }
+    }
+}
+
 #[cfg(test)]
 pub mod test {
     use super::*;
@@ -159,6 +228,41 @@ pub mod test {
         );
     }
 
+    #[test]
+    fn print_float_precision() {
+        let print = |op: LineOp, value: MetricValue| {
+            let template = LineTemplate { ops: vec![op] };
+            let mut out = vec![];
+            template.print(&mut out, value, |_key| None).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+        assert_eq!("0.333333", print(ScaledValueAsText(3.0), 1));
+        assert_eq!("2.5", print(ScaledValueAsText(1000.0), 2500));
+        assert_eq!("-12", print(ScaledValueAsText(1.0), -12));
+        assert_eq!("0.33", print(ScaledValueAsTextPrecision(3.0, 2), 1));
+        assert_eq!("3", print(ScaledValueAsTextPrecision(1000.0, 0), 2600));
+        assert_eq!("0", print(ScaledValueAsTextPrecision(1000.0, 1), -4));
+        assert_eq!("-0.1", print(ScaledValueAsTextPrecision(1000.0, 1), -50));
+    }
+
+    #[test]
+    fn print_human_bytes() {
+        let print = |name: MetricName, value: MetricValue| {
+            let template = HumanFormat::default().template(&name, InputKind::Counter);
+            let mut out = vec![];
+            template.print(&mut out, value, |_key| None).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+        let rx = MetricName::from("rx_bytes");
+        assert_eq!("rx_bytes 512 B\n", print(rx.clone(), 512));
+        assert_eq!("rx_bytes 1.5 MB\n", print(rx.clone(), 1_500_000));
+        assert_eq!(
+            "rx_bytes.rate 2 GB/s\n",
+            print(rx.make_name("rate"), 2_000_000_000)
+        );
+        assert_eq!("requests 1500000\n", print("requests".into(), 1_500_000));
+    }
+
     #[test]
     fn print_label_not_exists() {
         let format = TestFormat {};
@@ -5,14 +5,15 @@ use crate::input::InputKind;
 use crate::input::{Input, InputMetric, InputScope};
 use crate::metrics;
 use crate::name::MetricName;
-use crate::output::socket::RetrySocket;
+use crate::output::dry_run::DryRun;
+use crate::output::error::SinkError;
+use crate::output::socket::{RetrySocket, SocketOptions};
 use crate::{CachedInput, QueuedInput};
 use crate::{Flush, MetricValue};
 
 use std::net::ToSocketAddrs;
 
 use std::fmt::Debug;
-use std::io::Write;
 use std::time::{SystemTime, UNIX_EPOCH};
 
 use std::sync::Arc;
@@ -30,6 +31,8 @@ use std::io;
 pub struct Graphite {
     attributes: Attributes,
     socket: Arc<RwLock<RetrySocket>>,
+    dry_run: Option<DryRun>,
+    pickle: bool,
 }
 
 impl Input for Graphite {
@@ -40,21 +43,51 @@ impl Input for Graphite {
             attributes: self.attributes.clone(),
             buffer: Arc::new(RwLock::new(String::new())),
             socket: self.socket.clone(),
+            dry_run: self.dry_run.clone(),
+            pickle: self.pickle,
         }
     }
 }
 
 impl Graphite {
     /// Send metrics to a graphite server at the address and port provided.
-    pub fn send_to<A: ToSocketAddrs + Debug + Clone>(address: A) -> io::Result<Graphite> {
+    pub fn send_to<A: ToSocketAddrs + Debug + Clone>(address: A) -> Result<Graphite, SinkError> {
+        Graphite::send_to_with(address, &SocketOptions::default())
+    }
+
+    /// Send metrics to a graphite server at the address and port provided,
+    /// using the specified options for every connection.
+    pub fn send_to_with<A: ToSocketAddrs + Debug + Clone>(
+        address: A,
+        options: &SocketOptions,
+    ) -> Result<Graphite, SinkError> {
         debug!("Connecting to graphite {:?}", address);
-        let socket = Arc::new(RwLock::new(RetrySocket::new(address)?));
+        let socket = Arc::new(RwLock::new(RetrySocket::new(address, options)?));
 
         Ok(Graphite {
             attributes: Attributes::default(),
             socket,
+            dry_run: None,
+            pickle: false,
         })
     }
+
+    /// Send metrics using Carbon's pickle protocol instead of the plaintext protocol.
+    /// Buffered points are sent as a single length-prefixed frame, which is much more compact for large scopes.
+    /// The server address must be Carbon's pickle receiver port (2004 by default).
+    /// Dry runs still record the plaintext form of the points.
+    pub fn pickle(&self) -> Self {
+        let mut cloned = self.clone();
+        cloned.pickle = true;
+        cloned
+    }
+
+    /// Record payloads to the provided recorder instead of sending them to the server.
+    pub fn dry_run(&self, recorder: &DryRun) -> Self {
+        let mut cloned = self.clone();
+        cloned.dry_run = Some(recorder.clone());
+        cloned
+    }
 }
 
 impl WithAttributes for Graphite {
@@ -74,6 +107,8 @@ pub struct GraphiteScope {
     attributes: Attributes,
     buffer: Arc<RwLock<String>>,
     socket: Arc<RwLock<RetrySocket>>,
+    dry_run: Option<DryRun>,
+    pickle: bool,
 }
 
 impl InputScope for GraphiteScope {
@@ -107,6 +142,18 @@ impl Flush for GraphiteScope {
 }
 
 impl GraphiteScope {
+    /// Create a scope sharing this scope's socket and attributes but with its own buffer.
+    /// Flushing the fork does not flush the original scope, and vice versa.
+    pub fn fork(&self) -> Self {
+        GraphiteScope {
+            attributes: self.attributes.fork(),
+            buffer: Arc::new(RwLock::new(String::new())),
+            socket: self.socket.clone(),
+            dry_run: self.dry_run.clone(),
+            pickle: self.pickle,
+        }
+    }
+
     fn print(&self, metric: &GraphiteMetric, value: MetricValue) {
         let scaled_value = value / metric.scale;
         let value_str = scaled_value.to_string();
@@ -134,7 +181,7 @@ impl GraphiteScope {
             }
         };
 
-        if self.is_buffered() {
+        if !self.is_buffered() {
             if let Err(e) = self.flush_inner(buffer) {
                 debug!("Could not send to graphite {}", e)
             }
@@ -146,12 +193,25 @@ impl GraphiteScope {
             return Ok(());
         }
 
+        if let Some(dry_run) = &self.dry_run {
+            dry_run.record(&buf);
+            buf.clear();
+            return Ok(());
+        }
+
+        let payload = if self.pickle {
+            encode_pickle(&buf)
+        } else {
+            buf.as_bytes().to_vec()
+        };
+
+        // the socket keeps data it could not send, to retry it later
+        buf.clear();
         let mut sock = write_lock!(self.socket);
-        match sock.write_all(buf.as_bytes()) {
-            Ok(()) => {
-                metrics::GRAPHITE_SENT_BYTES.count(buf.len());
-                trace!("Sent {} bytes to graphite", buf.len());
-                buf.clear();
+        match sock.send(&payload) {
+            Ok(sent) => {
+                metrics::GRAPHITE_SENT_BYTES.count(sent);
+                trace!("Sent {} bytes to graphite", sent);
                 Ok(())
             }
             Err(e) => {
@@ -197,6 +257,102 @@ impl Drop for GraphiteScope {
     }
 }
 
+/// Encode plaintext protocol lines (`path value timestamp`) as a pickle protocol frame,
+/// a list of `(path, (timestamp, value))` tuples pickled with protocol 2,
+/// prefixed with its length as a 32 bits big-endian integer.
+fn encode_pickle(lines: &str) -> Vec<u8> {
+    // PROTO 2, EMPTY_LIST, MARK
+    let mut pickle = vec![0x80, 0x02, b']', b'('];
+    for line in lines.lines() {
+        let mut fields = line.rsplitn(3, ' ');
+        let (Some(timestamp), Some(value), Some(path)) =
+            (fields.next(), fields.next(), fields.next())
+        else {
+            continue;
+        };
+        let (Ok(timestamp), Ok(value)) = (timestamp.parse::<i64>(), value.parse::<i64>()) else {
+            continue;
+        };
+        // BINUNICODE
+        pickle.push(b'X');
+        pickle.extend_from_slice(&(path.len() as u32).to_le_bytes());
+        pickle.extend_from_slice(path.as_bytes());
+        put_pickle_int(&mut pickle, timestamp);
+        put_pickle_int(&mut pickle, value);
+        // TUPLE2 (timestamp, value), TUPLE2 (path, (timestamp, value))
+        pickle.extend_from_slice(&[0x86, 0x86]);
+    }
+    // APPENDS, STOP
+    pickle.extend_from_slice(b"e.");
+
+    let mut frame = Vec::with_capacity(pickle.len() + 4);
+    frame.extend_from_slice(&(pickle.len() as u32).to_be_bytes());
+    frame.extend_from_slice(&pickle);
+    frame
+}
+
+/// Pickle an integer as BININT if it fits in 32 bits, as LONG1 otherwise.
+fn put_pickle_int(pickle: &mut Vec<u8>, value: i64) {
+    if let Ok(value) = i32::try_from(value) {
+        pickle.push(b'J');
+        pickle.extend_from_slice(&value.to_le_bytes());
+    } else {
+        // minimal little-endian two's complement
+        let bytes = value.to_le_bytes();
+        let mut len = bytes.len();
+        while len > 1
+            && ((bytes[len - 1] == 0x00 && bytes[len - 2] & 0x80 == 0)
+                || (bytes[len - 1] == 0xff && bytes[len - 2] & 0x80 != 0))
+        {
+            len -= 1;
+        }
+        pickle.extend_from_slice(&[0x8a, len as u8]);
+        pickle.extend_from_slice(&bytes[..len]);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Buffering;
+
+    #[test]
+    fn pickle_points() {
+        let frame = encode_pickle("a.b 5 1700000000\nc -1 4294967296\n");
+        let mut expected = vec![0x80, 0x02, b']', b'('];
+        expected
+            .extend_from_slice(b"X\x03\x00\x00\x00a.bJ\x00\xf1\x53\x65J\x05\x00\x00\x00\x86\x86");
+        expected.extend_from_slice(
+            b"X\x01\x00\x00\x00c\x8a\x05\x00\x00\x00\x00\x01J\xff\xff\xff\xff\x86\x86",
+        );
+        expected.extend_from_slice(b"e.");
+        assert_eq!((expected.len() as u32).to_be_bytes(), frame[..4]);
+        assert_eq!(expected, frame[4..]);
+    }
+
+    #[test]
+    fn immediate_flush() {
+        let recorder = DryRun::new();
+        let metrics = Graphite::send_to("localhost:2003")
+            .unwrap()
+            .dry_run(&recorder)
+            .buffered(Buffering::Unlimited)
+            .metrics();
+        let counter = metrics.counter("counter");
+
+        counter.count(1);
+        assert!(recorder.payloads().is_empty());
+
+        metrics.with_immediate_flush(|| counter.count(2));
+        let payloads = recorder.payloads();
+        assert_eq!(1, payloads.len());
+        assert!(payloads[0].starts_with("counter 1 ") && payloads[0].contains("\ncounter 2 "));
+
+        counter.count(3);
+        assert_eq!(1, recorder.payloads().len());
+    }
+}
+
 #[cfg(feature = "bench")]
 mod bench {
 
@@ -0,0 +1,975 @@
+//! A minimal gRPC client, making unary calls over cleartext HTTP/2 connections (h2c with prior knowledge).
+//! Only what exporting metrics requires is implemented: one call at a time, without TLS nor compression.
+
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::time::Duration;
+
+const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+// Frame types
+const DATA: u8 = 0x0;
+const HEADERS: u8 = 0x1;
+const RST_STREAM: u8 = 0x3;
+const SETTINGS: u8 = 0x4;
+const PING: u8 = 0x6;
+const GOAWAY: u8 = 0x7;
+const WINDOW_UPDATE: u8 = 0x8;
+const CONTINUATION: u8 = 0x9;
+
+// Frame flags
+const END_STREAM: u8 = 0x1;
+const ACK: u8 = 0x1;
+const END_HEADERS: u8 = 0x4;
+const PADDED: u8 = 0x8;
+const PRIORITY: u8 = 0x20;
+
+// Settings
+const SETTINGS_ENABLE_PUSH: u16 = 0x2;
+const SETTINGS_INITIAL_WINDOW_SIZE: u16 = 0x4;
+const SETTINGS_MAX_FRAME_SIZE: u16 = 0x5;
+
+/// Error code of a stream refused before any processing.
+const REFUSED_STREAM: u32 = 0x7;
+
+/// Flow control window of new streams and connections, until changed by the server.
+const DEFAULT_WINDOW: i64 = 65_535;
+
+/// Size of frames, until changed by the server. Also the largest frame the server may send.
+const DEFAULT_MAX_FRAME: usize = 16_384;
+
+/// Size of the header compression table, until changed by the server.
+const DEFAULT_TABLE_SIZE: usize = 4_096;
+
+/// Time to wait for the server to accept or return data before giving up on a call.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A connection to a gRPC server, established upon the first call and reestablished after errors.
+///
+/// Limits:
+/// - calls are made one at a time, on a single connection
+/// - no TLS, the server must accept cleartext HTTP/2 without upgrade (h2c with prior knowledge)
+/// - messages are neither compressed nor split, responses are only returned once complete
+/// - received data is acknowledged as it arrives, keeping the default 64 KiB flow control windows
+/// - a call waiting more than 10 seconds for the server fails
+#[derive(Debug)]
+pub(crate) struct GrpcChannel {
+    addresses: Vec<SocketAddr>,
+    authority: String,
+    connection: Option<Connection>,
+}
+
+impl GrpcChannel {
+    /// A channel to the server at the addresses, identified as the authority (e.g. `collector:4317`) in requests.
+    pub(crate) fn new(addresses: Vec<SocketAddr>, authority: &str) -> Self {
+        GrpcChannel {
+            addresses,
+            authority: authority.to_string(),
+            connection: None,
+        }
+    }
+
+    /// Call the method (e.g. `/package.Service/Method`) with the encoded message, returning the encoded response.
+    /// A call that failed on a reused connection without being processed by the server is retried once
+    /// on a new connection, e.g. after the server closed an idle connection.
+    pub(crate) fn unary(&mut self, path: &str, message: &[u8]) -> io::Result<Vec<u8>> {
+        let reused = self.connection.is_some();
+        match self.call(path, message) {
+            Err(CallError::Unprocessed(e)) if reused => {
+                debug!("Retrying gRPC call on a new connection: {}", e);
+                self.call(path, message).map_err(CallError::into_error)
+            }
+            result => result.map_err(CallError::into_error),
+        }
+    }
+
+    fn call(&mut self, path: &str, message: &[u8]) -> Result<Vec<u8>, CallError> {
+        let mut connection = match self.connection.take() {
+            Some(connection) => connection,
+            None => Connection::open(&self.addresses).map_err(CallError::Unprocessed)?,
+        };
+        let result = connection.unary(&self.authority, path, message);
+        if result.is_ok() && !connection.going_away {
+            self.connection = Some(connection)
+        }
+        result
+    }
+}
+
+/// Failure of a call, telling if it is certain that the server did not process it.
+#[derive(Debug)]
+enum CallError {
+    Unprocessed(io::Error),
+    Failed(io::Error),
+}
+
+impl CallError {
+    fn into_error(self) -> io::Error {
+        match self {
+            CallError::Unprocessed(e) | CallError::Failed(e) => e,
+        }
+    }
+}
+
+fn protocol_error(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[derive(Debug)]
+struct Frame {
+    kind: u8,
+    flags: u8,
+    stream_id: u32,
+    payload: Vec<u8>,
+}
+
+impl Frame {
+    /// The payload without padding nor priority fields.
+    fn content(&self) -> io::Result<&[u8]> {
+        let mut content = &self.payload[..];
+        let mut padding = 0;
+        if self.flags & PADDED != 0 && matches!(self.kind, DATA | HEADERS) {
+            padding = usize::from(*content.first().ok_or_else(|| invalid_frame(self))?);
+            content = &content[1..];
+        }
+        if self.flags & PRIORITY != 0 && self.kind == HEADERS {
+            content = content.get(5..).ok_or_else(|| invalid_frame(self))?;
+        }
+        content
+            .len()
+            .checked_sub(padding)
+            .map(|len| &content[..len])
+            .ok_or_else(|| invalid_frame(self))
+    }
+
+    fn u32_at(&self, offset: usize) -> io::Result<u32> {
+        match self.payload.get(offset..offset + 4) {
+            Some(bytes) => Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])),
+            None => Err(invalid_frame(self)),
+        }
+    }
+}
+
+fn invalid_frame(frame: &Frame) -> io::Error {
+    protocol_error(format!("invalid HTTP/2 frame of type {}", frame.kind))
+}
+
+fn write_frame(
+    output: &mut impl Write,
+    kind: u8,
+    flags: u8,
+    stream_id: u32,
+    payload: &[u8],
+) -> io::Result<()> {
+    let mut frame = Vec::with_capacity(9 + payload.len());
+    frame.extend_from_slice(&(payload.len() as u32).to_be_bytes()[1..]);
+    frame.push(kind);
+    frame.push(flags);
+    frame.extend_from_slice(&stream_id.to_be_bytes());
+    frame.extend_from_slice(payload);
+    output.write_all(&frame)
+}
+
+fn read_frame(input: &mut impl Read) -> io::Result<Frame> {
+    let mut header = [0u8; 9];
+    input.read_exact(&mut header)?;
+    let length = u32::from_be_bytes([0, header[0], header[1], header[2]]) as usize;
+    if length > DEFAULT_MAX_FRAME {
+        return Err(protocol_error(format!(
+            "HTTP/2 frame of {} bytes is too large",
+            length
+        )));
+    }
+    let mut payload = vec![0; length];
+    input.read_exact(&mut payload)?;
+    Ok(Frame {
+        kind: header[3],
+        flags: header[4],
+        stream_id: u32::from_be_bytes([header[5], header[6], header[7], header[8]]) & 0x7fff_ffff,
+        payload,
+    })
+}
+
+/// State of the stream of a call.
+#[derive(Debug, Default)]
+struct Call {
+    stream_id: u32,
+    send_window: i64,
+    /// Header block being received, and whether it ends the stream
+    block: Vec<u8>,
+    block_ends_stream: bool,
+    headers: Option<Vec<(String, String)>>,
+    trailers: Option<Vec<(String, String)>>,
+    response: Vec<u8>,
+    closed: bool,
+}
+
+impl Call {
+    fn header<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+        headers
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// The response message if the call succeeded.
+    fn result(self) -> Result<Vec<u8>, CallError> {
+        let failed = |message: String| CallError::Failed(io::Error::other(message));
+        let headers = self
+            .headers
+            .ok_or_else(|| failed("no gRPC response headers".to_string()))?;
+        match Call::header(&headers, ":status") {
+            Some("200") => {}
+            status => return Err(failed(format!("HTTP status {}", status.unwrap_or("none")))),
+        }
+        let trailers = self
+            .trailers
+            .ok_or_else(|| failed("no gRPC status".to_string()))?;
+        match Call::header(&trailers, "grpc-status") {
+            Some("0") => Ok(self.response.get(5..).unwrap_or_default().to_vec()),
+            Some(status) => Err(failed(format!(
+                "gRPC status {}: {}",
+                status,
+                percent_decode(Call::header(&trailers, "grpc-message").unwrap_or_default())
+            ))),
+            None => Err(failed("no gRPC status".to_string())),
+        }
+    }
+}
+
+/// Decode the percent-encoded `grpc-message`.
+fn percent_decode(message: &str) -> String {
+    let bytes = message.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = bytes.get(i + 1..i + 3).and_then(|hex| {
+            std::str::from_utf8(hex)
+                .ok()
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+        });
+        match (bytes[i], escaped) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+#[derive(Debug)]
+struct Connection {
+    stream: TcpStream,
+    next_stream_id: u32,
+    /// Bytes the server accepts on the connection
+    send_window: i64,
+    /// Bytes the server accepts on new streams
+    initial_window: i64,
+    max_frame: usize,
+    decoder: HeaderDecoder,
+    /// The server is closing the connection, no new call may be made on it
+    going_away: bool,
+}
+
+impl Connection {
+    fn open(addresses: &[SocketAddr]) -> io::Result<Connection> {
+        let stream = TcpStream::connect(addresses)?;
+        stream.set_nodelay(true)?;
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        stream.set_write_timeout(Some(TIMEOUT))?;
+        let mut connection = Connection {
+            stream,
+            next_stream_id: 1,
+            send_window: DEFAULT_WINDOW,
+            initial_window: DEFAULT_WINDOW,
+            max_frame: DEFAULT_MAX_FRAME,
+            decoder: HeaderDecoder::default(),
+            going_away: false,
+        };
+        connection.stream.write_all(PREFACE)?;
+        let mut settings = SETTINGS_ENABLE_PUSH.to_be_bytes().to_vec();
+        settings.extend_from_slice(&0u32.to_be_bytes());
+        connection.write_frame(SETTINGS, 0, 0, &settings)?;
+        Ok(connection)
+    }
+
+    fn write_frame(
+        &mut self,
+        kind: u8,
+        flags: u8,
+        stream_id: u32,
+        payload: &[u8],
+    ) -> io::Result<()> {
+        write_frame(&mut self.stream, kind, flags, stream_id, payload)
+    }
+
+    fn unary(&mut self, authority: &str, path: &str, message: &[u8]) -> Result<Vec<u8>, CallError> {
+        if self.next_stream_id > i32::MAX as u32 {
+            self.going_away = true;
+            return Err(CallError::Unprocessed(io::Error::other(
+                "HTTP/2 stream identifiers exhausted",
+            )));
+        }
+        let mut call = Call {
+            stream_id: self.next_stream_id,
+            send_window: self.initial_window,
+            ..Call::default()
+        };
+        self.next_stream_id += 2;
+
+        let headers = encode_headers(&[
+            (":method", "POST"),
+            (":scheme", "http"),
+            (":path", path),
+            (":authority", authority),
+            ("content-type", "application/grpc"),
+            ("te", "trailers"),
+        ]);
+        self.write_frame(HEADERS, END_HEADERS, call.stream_id, &headers)
+            .map_err(CallError::Unprocessed)?;
+
+        // uncompressed length-prefixed message
+        let mut body = Vec::with_capacity(5 + message.len());
+        body.push(0);
+        body.extend_from_slice(&(message.len() as u32).to_be_bytes());
+        body.extend_from_slice(message);
+
+        let mut sent = 0;
+        while sent < body.len() && !call.closed {
+            let window = self.send_window.min(call.send_window).max(0) as usize;
+            let size = (body.len() - sent).min(self.max_frame).min(window);
+            if size == 0 {
+                // wait for the server to extend the flow control windows
+                let frame = read_frame(&mut self.stream).map_err(CallError::Failed)?;
+                self.handle(frame, &mut call)?;
+                continue;
+            }
+            let flags = if sent + size == body.len() {
+                END_STREAM
+            } else {
+                0
+            };
+            self.write_frame(DATA, flags, call.stream_id, &body[sent..sent + size])
+                .map_err(CallError::Unprocessed)?;
+            sent += size;
+            self.send_window -= size as i64;
+            call.send_window -= size as i64;
+        }
+
+        while !call.closed {
+            let frame = read_frame(&mut self.stream).map_err(CallError::Failed)?;
+            self.handle(frame, &mut call)?;
+        }
+        call.result()
+    }
+
+    /// Update the connection and the call from a frame received from the server.
+    fn handle(&mut self, frame: Frame, call: &mut Call) -> Result<(), CallError> {
+        let failed = CallError::Failed;
+        let ours = frame.stream_id == call.stream_id;
+        match frame.kind {
+            SETTINGS if frame.flags & ACK == 0 => {
+                for setting in frame.payload.chunks_exact(6) {
+                    let value =
+                        u32::from_be_bytes([setting[2], setting[3], setting[4], setting[5]]);
+                    match u16::from_be_bytes([setting[0], setting[1]]) {
+                        SETTINGS_INITIAL_WINDOW_SIZE => {
+                            call.send_window += i64::from(value) - self.initial_window;
+                            self.initial_window = i64::from(value);
+                        }
+                        SETTINGS_MAX_FRAME_SIZE => self.max_frame = value as usize,
+                        _ => {}
+                    }
+                }
+                self.write_frame(SETTINGS, ACK, 0, &[]).map_err(failed)?;
+            }
+            PING if frame.flags & ACK == 0 => {
+                self.write_frame(PING, ACK, 0, &frame.payload)
+                    .map_err(failed)?;
+            }
+            WINDOW_UPDATE => {
+                let increment = i64::from(frame.u32_at(0).map_err(failed)? & 0x7fff_ffff);
+                if frame.stream_id == 0 {
+                    self.send_window += increment
+                } else if ours {
+                    call.send_window += increment
+                }
+            }
+            GOAWAY => {
+                self.going_away = true;
+                let last_stream_id = frame.u32_at(0).map_err(failed)? & 0x7fff_ffff;
+                let error_code = frame.u32_at(4).map_err(failed)?;
+                if last_stream_id < call.stream_id {
+                    return Err(CallError::Unprocessed(io::Error::new(
+                        io::ErrorKind::ConnectionAborted,
+                        format!("connection closed by server, error code {}", error_code),
+                    )));
+                }
+            }
+            RST_STREAM if ours => {
+                let error_code = frame.u32_at(0).map_err(failed)?;
+                let error = io::Error::new(
+                    io::ErrorKind::ConnectionReset,
+                    format!("stream reset by server, error code {}", error_code),
+                );
+                return Err(if error_code == REFUSED_STREAM {
+                    CallError::Unprocessed(error)
+                } else {
+                    CallError::Failed(error)
+                });
+            }
+            HEADERS | CONTINUATION => {
+                if frame.kind == HEADERS {
+                    call.block.clear();
+                    call.block_ends_stream = frame.flags & END_STREAM != 0;
+                }
+                call.block
+                    .extend_from_slice(frame.content().map_err(failed)?);
+                if frame.flags & END_HEADERS != 0 {
+                    // blocks of every stream must be decoded to keep the compression state in sync
+                    let headers = self.decoder.decode(&call.block).map_err(failed)?;
+                    if ours {
+                        if call.headers.is_none() {
+                            call.headers = Some(headers.clone())
+                        }
+                        if call.block_ends_stream {
+                            call.trailers = Some(headers);
+                            call.closed = true;
+                        }
+                    }
+                }
+            }
+            DATA => {
+                if ours {
+                    call.response
+                        .extend_from_slice(frame.content().map_err(failed)?);
+                    call.closed |= frame.flags & END_STREAM != 0;
+                }
+                if !frame.payload.is_empty() {
+                    // give back the flow control credit of the connection, and of the stream until it ends
+                    let increment = (frame.payload.len() as u32).to_be_bytes();
+                    self.write_frame(WINDOW_UPDATE, 0, 0, &increment)
+                        .map_err(failed)?;
+                    if ours && !call.closed {
+                        self.write_frame(WINDOW_UPDATE, 0, call.stream_id, &increment)
+                            .map_err(failed)?;
+                    }
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+/// Encode the headers as literals, without compression.
+fn encode_headers(headers: &[(&str, &str)]) -> Vec<u8> {
+    let mut block = Vec::new();
+    for (name, value) in headers {
+        // literal header field without indexing, new name
+        block.push(0);
+        encode_string(&mut block, name);
+        encode_string(&mut block, value);
+    }
+    block
+}
+
+fn encode_string(buf: &mut Vec<u8>, value: &str) {
+    encode_integer(buf, 0, 7, value.len());
+    buf.extend_from_slice(value.as_bytes());
+}
+
+/// Encode an HPACK integer in the low `prefix` bits of the first byte, its high bits set to `flags`.
+fn encode_integer(buf: &mut Vec<u8>, flags: u8, prefix: u8, value: usize) {
+    let max = (1usize << prefix) - 1;
+    if value < max {
+        buf.push(flags | value as u8);
+        return;
+    }
+    buf.push(flags | max as u8);
+    let mut value = value - max;
+    while value >= 0x80 {
+        buf.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+fn truncated() -> io::Error {
+    protocol_error("truncated HPACK header block".to_string())
+}
+
+fn decode_integer(block: &[u8], pos: &mut usize, prefix: u8) -> io::Result<usize> {
+    let max = (1usize << prefix) - 1;
+    let first = *block.get(*pos).ok_or_else(truncated)?;
+    *pos += 1;
+    let mut value = usize::from(first) & max;
+    if value < max {
+        return Ok(value);
+    }
+    let mut shift = 0;
+    loop {
+        let byte = *block.get(*pos).ok_or_else(truncated)?;
+        *pos += 1;
+        value += usize::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+        shift += 7;
+        if shift > 28 {
+            return Err(protocol_error("HPACK integer overflow".to_string()));
+        }
+    }
+}
+
+fn decode_string(block: &[u8], pos: &mut usize) -> io::Result<String> {
+    let huffman = block.get(*pos).ok_or_else(truncated)? & 0x80 != 0;
+    let length = decode_integer(block, pos, 7)?;
+    let bytes = block.get(*pos..*pos + length).ok_or_else(truncated)?;
+    *pos += length;
+    let bytes = if huffman {
+        huffman_decode(bytes)?
+    } else {
+        bytes.to_vec()
+    };
+    String::from_utf8(bytes).map_err(|_| protocol_error("header is not UTF-8".to_string()))
+}
+
+fn huffman_decode(bytes: &[u8]) -> io::Result<Vec<u8>> {
+    let invalid = || protocol_error("invalid Huffman encoded header".to_string());
+    let mut decoded = Vec::with_capacity(bytes.len() * 2);
+    let (mut code, mut length) = (0u32, 0u8);
+    for byte in bytes {
+        for bit in (0..8).rev() {
+            code = (code << 1) | u32::from((byte >> bit) & 1);
+            length += 1;
+            match HUFFMAN_CODES
+                .iter()
+                .position(|&entry| entry == (code, length))
+            {
+                Some(256) => return Err(invalid()),
+                Some(symbol) => {
+                    decoded.push(symbol as u8);
+                    code = 0;
+                    length = 0;
+                }
+                None if length >= 30 => return Err(invalid()),
+                None => {}
+            }
+        }
+    }
+    // padding is made of fewer than 8 bits of the EOS code, all ones
+    if length > 7 || code != (1 << length) - 1 {
+        return Err(invalid());
+    }
+    Ok(decoded)
+}
+
+/// Decoder of HPACK header blocks, maintaining the dynamic table shared by the blocks of a connection.
+#[derive(Debug)]
+struct HeaderDecoder {
+    table: VecDeque<(String, String)>,
+    size: usize,
+    max_size: usize,
+}
+
+impl Default for HeaderDecoder {
+    fn default() -> Self {
+        HeaderDecoder {
+            table: VecDeque::new(),
+            size: 0,
+            max_size: DEFAULT_TABLE_SIZE,
+        }
+    }
+}
+
+impl HeaderDecoder {
+    fn decode(&mut self, block: &[u8]) -> io::Result<Vec<(String, String)>> {
+        let mut headers = Vec::new();
+        let mut pos = 0;
+        while pos < block.len() {
+            let byte = block[pos];
+            if byte & 0x80 != 0 {
+                let index = decode_integer(block, &mut pos, 7)?;
+                headers.push(self.entry(index)?);
+            } else if byte & 0x40 != 0 {
+                let (name, value) = self.literal(block, &mut pos, 6)?;
+                self.insert(name.clone(), value.clone());
+                headers.push((name, value));
+            } else if byte & 0x20 != 0 {
+                let size = decode_integer(block, &mut pos, 5)?;
+                if size > DEFAULT_TABLE_SIZE {
+                    return Err(protocol_error(format!(
+                        "HPACK table size {} too large",
+                        size
+                    )));
+                }
+                self.max_size = size;
+                self.evict();
+            } else {
+                // literal without indexing or never indexed
+                headers.push(self.literal(block, &mut pos, 4)?);
+            }
+        }
+        Ok(headers)
+    }
+
+    fn literal(&self, block: &[u8], pos: &mut usize, prefix: u8) -> io::Result<(String, String)> {
+        let index = decode_integer(block, pos, prefix)?;
+        let name = match index {
+            0 => decode_string(block, pos)?,
+            index => self.entry(index)?.0,
+        };
+        Ok((name, decode_string(block, pos)?))
+    }
+
+    fn entry(&self, index: usize) -> io::Result<(String, String)> {
+        let entry = match index {
+            0 => None,
+            1..=61 => {
+                let (name, value) = STATIC_TABLE[index - 1];
+                Some((name.to_string(), value.to_string()))
+            }
+            _ => self.table.get(index - 62).cloned(),
+        };
+        entry.ok_or_else(|| protocol_error(format!("invalid HPACK index {}", index)))
+    }
+
+    fn insert(&mut self, name: String, value: String) {
+        self.size += name.len() + value.len() + 32;
+        self.table.push_front((name, value));
+        self.evict()
+    }
+
+    fn evict(&mut self) {
+        while self.size > self.max_size {
+            match self.table.pop_back() {
+                Some((name, value)) => self.size -= name.len() + value.len() + 32,
+                None => break,
+            }
+        }
+    }
+}
+
+/// HPACK static table (RFC 7541, Appendix A).
+const STATIC_TABLE: [(&str, &str); 61] = [
+    (":authority", ""),
+    (":method", "GET"),
+    (":method", "POST"),
+    (":path", "/"),
+    (":path", "/index.html"),
+    (":scheme", "http"),
+    (":scheme", "https"),
+    (":status", "200"),
+    (":status", "204"),
+    (":status", "206"),
+    (":status", "304"),
+    (":status", "400"),
+    (":status", "404"),
+    (":status", "500"),
+    ("accept-charset", ""),
+    ("accept-encoding", "gzip, deflate"),
+    ("accept-language", ""),
+    ("accept-ranges", ""),
+    ("accept", ""),
+    ("access-control-allow-origin", ""),
+    ("age", ""),
+    ("allow", ""),
+    ("authorization", ""),
+    ("cache-control", ""),
+    ("content-disposition", ""),
+    ("content-encoding", ""),
+    ("content-language", ""),
+    ("content-length", ""),
+    ("content-location", ""),
+    ("content-range", ""),
+    ("content-type", ""),
+    ("cookie", ""),
+    ("date", ""),
+    ("etag", ""),
+    ("expect", ""),
+    ("expires", ""),
+    ("from", ""),
+    ("host", ""),
+    ("if-match", ""),
+    ("if-modified-since", ""),
+    ("if-none-match", ""),
+    ("if-range", ""),
+    ("if-unmodified-since", ""),
+    ("last-modified", ""),
+    ("link", ""),
+    ("location", ""),
+    ("max-forwards", ""),
+    ("proxy-authenticate", ""),
+    ("proxy-authorization", ""),
+    ("range", ""),
+    ("referer", ""),
+    ("refresh", ""),
+    ("retry-after", ""),
+    ("server", ""),
+    ("set-cookie", ""),
+    ("strict-transport-security", ""),
+    ("transfer-encoding", ""),
+    ("user-agent", ""),
+    ("vary", ""),
+    ("via", ""),
+    ("www-authenticate", ""),
+];
+
+/// HPACK Huffman code and length in bits of every byte, and of EOS (RFC 7541, Appendix B).
+#[rustfmt::skip]
+const HUFFMAN_CODES: [(u32, u8); 257] = [
+    (0x1ff8, 13), (0x7fffd8, 23), (0xfffffe2, 28), (0xfffffe3, 28), (0xfffffe4, 28),
+    (0xfffffe5, 28), (0xfffffe6, 28), (0xfffffe7, 28), (0xfffffe8, 28), (0xffffea, 24),
+    (0x3ffffffc, 30), (0xfffffe9, 28), (0xfffffea, 28), (0x3ffffffd, 30), (0xfffffeb, 28),
+    (0xfffffec, 28), (0xfffffed, 28), (0xfffffee, 28), (0xfffffef, 28), (0xffffff0, 28),
+    (0xffffff1, 28), (0xffffff2, 28), (0x3ffffffe, 30), (0xffffff3, 28), (0xffffff4, 28),
+    (0xffffff5, 28), (0xffffff6, 28), (0xffffff7, 28), (0xffffff8, 28), (0xffffff9, 28),
+    (0xffffffa, 28), (0xffffffb, 28), (0x14, 6), (0x3f8, 10), (0x3f9, 10), (0xffa, 12),
+    (0x1ff9, 13), (0x15, 6), (0xf8, 8), (0x7fa, 11), (0x3fa, 10), (0x3fb, 10), (0xf9, 8),
+    (0x7fb, 11), (0xfa, 8), (0x16, 6), (0x17, 6), (0x18, 6), (0x0, 5), (0x1, 5), (0x2, 5),
+    (0x19, 6), (0x1a, 6), (0x1b, 6), (0x1c, 6), (0x1d, 6), (0x1e, 6), (0x1f, 6), (0x5c, 7),
+    (0xfb, 8), (0x7ffc, 15), (0x20, 6), (0xffb, 12), (0x3fc, 10), (0x1ffa, 13), (0x21, 6),
+    (0x5d, 7), (0x5e, 7), (0x5f, 7), (0x60, 7), (0x61, 7), (0x62, 7), (0x63, 7), (0x64, 7),
+    (0x65, 7), (0x66, 7), (0x67, 7), (0x68, 7), (0x69, 7), (0x6a, 7), (0x6b, 7), (0x6c, 7),
+    (0x6d, 7), (0x6e, 7), (0x6f, 7), (0x70, 7), (0x71, 7), (0x72, 7), (0xfc, 8), (0x73, 7),
+    (0xfd, 8), (0x1ffb, 13), (0x7fff0, 19), (0x1ffc, 13), (0x3ffc, 14), (0x22, 6), (0x7ffd, 15),
+    (0x3, 5), (0x23, 6), (0x4, 5), (0x24, 6), (0x5, 5), (0x25, 6), (0x26, 6), (0x27, 6), (0x6, 5),
+    (0x74, 7), (0x75, 7), (0x28, 6), (0x29, 6), (0x2a, 6), (0x7, 5), (0x2b, 6), (0x76, 7),
+    (0x2c, 6), (0x8, 5), (0x9, 5), (0x2d, 6), (0x77, 7), (0x78, 7), (0x79, 7), (0x7a, 7), (0x7b, 7),
+    (0x7ffe, 15), (0x7fc, 11), (0x3ffd, 14), (0x1ffd, 13), (0xffffffc, 28), (0xfffe6, 20),
+    (0x3fffd2, 22), (0xfffe7, 20), (0xfffe8, 20), (0x3fffd3, 22), (0x3fffd4, 22), (0x3fffd5, 22),
+    (0x7fffd9, 23), (0x3fffd6, 22), (0x7fffda, 23), (0x7fffdb, 23), (0x7fffdc, 23), (0x7fffdd, 23),
+    (0x7fffde, 23), (0xffffeb, 24), (0x7fffdf, 23), (0xffffec, 24), (0xffffed, 24), (0x3fffd7, 22),
+    (0x7fffe0, 23), (0xffffee, 24), (0x7fffe1, 23), (0x7fffe2, 23), (0x7fffe3, 23), (0x7fffe4, 23),
+    (0x1fffdc, 21), (0x3fffd8, 22), (0x7fffe5, 23), (0x3fffd9, 22), (0x7fffe6, 23), (0x7fffe7, 23),
+    (0xffffef, 24), (0x3fffda, 22), (0x1fffdd, 21), (0xfffe9, 20), (0x3fffdb, 22), (0x3fffdc, 22),
+    (0x7fffe8, 23), (0x7fffe9, 23), (0x1fffde, 21), (0x7fffea, 23), (0x3fffdd, 22), (0x3fffde, 22),
+    (0xfffff0, 24), (0x1fffdf, 21), (0x3fffdf, 22), (0x7fffeb, 23), (0x7fffec, 23), (0x1fffe0, 21),
+    (0x1fffe1, 21), (0x3fffe0, 22), (0x1fffe2, 21), (0x7fffed, 23), (0x3fffe1, 22), (0x7fffee, 23),
+    (0x7fffef, 23), (0xfffea, 20), (0x3fffe2, 22), (0x3fffe3, 22), (0x3fffe4, 22), (0x7ffff0, 23),
+    (0x3fffe5, 22), (0x3fffe6, 22), (0x7ffff1, 23), (0x3ffffe0, 26), (0x3ffffe1, 26), (0xfffeb, 20),
+    (0x7fff1, 19), (0x3fffe7, 22), (0x7ffff2, 23), (0x3fffe8, 22), (0x1ffffec, 25), (0x3ffffe2, 26),
+    (0x3ffffe3, 26), (0x3ffffe4, 26), (0x7ffffde, 27), (0x7ffffdf, 27), (0x3ffffe5, 26),
+    (0xfffff1, 24), (0x1ffffed, 25), (0x7fff2, 19), (0x1fffe3, 21), (0x3ffffe6, 26),
+    (0x7ffffe0, 27), (0x7ffffe1, 27), (0x3ffffe7, 26), (0x7ffffe2, 27), (0xfffff2, 24),
+    (0x1fffe4, 21), (0x1fffe5, 21), (0x3ffffe8, 26), (0x3ffffe9, 26), (0xffffffd, 28),
+    (0x7ffffe3, 27), (0x7ffffe4, 27), (0x7ffffe5, 27), (0xfffec, 20), (0xfffff3, 24), (0xfffed, 20),
+    (0x1fffe6, 21), (0x3fffe9, 22), (0x1fffe7, 21), (0x1fffe8, 21), (0x7ffff3, 23), (0x3fffea, 22),
+    (0x3fffeb, 22), (0x1ffffee, 25), (0x1ffffef, 25), (0xfffff4, 24), (0xfffff5, 24),
+    (0x3ffffea, 26), (0x7ffff4, 23), (0x3ffffeb, 26), (0x7ffffe6, 27), (0x3ffffec, 26),
+    (0x3ffffed, 26), (0x7ffffe7, 27), (0x7ffffe8, 27), (0x7ffffe9, 27), (0x7ffffea, 27),
+    (0x7ffffeb, 27), (0xffffffe, 28), (0x7ffffec, 27), (0x7ffffed, 27), (0x7ffffee, 27),
+    (0x7ffffef, 27), (0x7fffff0, 27), (0x3ffffee, 26), (0x3fffffff, 30),
+];
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread;
+
+    fn hex(hex: &str) -> Vec<u8> {
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    fn pairs(headers: &[(&str, &str)]) -> Vec<(String, String)> {
+        headers
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn integers() {
+        let mut buf = vec![];
+        encode_integer(&mut buf, 0, 5, 10);
+        encode_integer(&mut buf, 0, 5, 1337);
+        assert_eq!(vec![0x0a, 0x1f, 0x9a, 0x0a], buf);
+
+        let mut pos = 0;
+        assert_eq!(10, decode_integer(&buf, &mut pos, 5).unwrap());
+        assert_eq!(1337, decode_integer(&buf, &mut pos, 5).unwrap());
+        assert_eq!(buf.len(), pos);
+    }
+
+    #[test]
+    fn huffman() {
+        let decoded = huffman_decode(&hex("f1e3c2e5f23a6ba0ab90f4ff")).unwrap();
+        assert_eq!(b"www.example.com".to_vec(), decoded);
+        // padding longer than 7 bits
+        assert!(huffman_decode(&hex("f1e3c2e5f23a6ba0ab90f4ffff")).is_err());
+    }
+
+    /// Requests of RFC 7541 C.4, sharing the dynamic table.
+    #[test]
+    fn decode_blocks() {
+        let mut decoder = HeaderDecoder::default();
+        let first = decoder
+            .decode(&hex("828684418cf1e3c2e5f23a6ba0ab90f4ff"))
+            .unwrap();
+        let second = decoder.decode(&hex("828684be5886a8eb10649cbf")).unwrap();
+        let third = decoder
+            .decode(&hex("828785bf408825a849e95ba97d7f8925a849e95bb8e8b4bf"))
+            .unwrap();
+
+        let request = [
+            (":method", "GET"),
+            (":scheme", "http"),
+            (":path", "/"),
+            (":authority", "www.example.com"),
+        ];
+        assert_eq!(pairs(&request), first);
+        let mut cached = request.to_vec();
+        cached.push(("cache-control", "no-cache"));
+        assert_eq!(pairs(&cached), second);
+        let custom = [
+            (":method", "GET"),
+            (":scheme", "https"),
+            (":path", "/index.html"),
+            (":authority", "www.example.com"),
+            ("custom-key", "custom-value"),
+        ];
+        assert_eq!(pairs(&custom), third);
+        assert_eq!(164, decoder.size);
+    }
+
+    #[test]
+    fn grpc_message() {
+        assert_eq!("50% off", percent_decode("50%25 off"));
+        assert_eq!("bad %zz", percent_decode("bad %zz"));
+    }
+
+    /// Serve unary calls, answering each with a response or a gRPC error status.
+    fn serve(replies: Vec<Result<&'static [u8], &'static str>>) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut preface = [0; 24];
+            stream.read_exact(&mut preface).unwrap();
+            assert_eq!(PREFACE, &preface[..]);
+            write_frame(&mut stream, SETTINGS, 0, 0, &[]).unwrap();
+            for reply in replies {
+                let mut request = vec![];
+                let stream_id = loop {
+                    let frame = read_frame(&mut stream).unwrap();
+                    if frame.kind == DATA {
+                        request.extend_from_slice(&frame.payload);
+                        if frame.flags & END_STREAM != 0 {
+                            break frame.stream_id;
+                        }
+                    }
+                };
+                assert_eq!(b"ping".to_vec(), request[5..].to_vec());
+                match reply {
+                    Ok(response) => {
+                        let headers = [(":status", "200"), ("content-type", "application/grpc")];
+                        let headers = encode_headers(&headers);
+                        write_frame(&mut stream, HEADERS, END_HEADERS, stream_id, &headers)
+                            .unwrap();
+                        let mut message = vec![0, 0, 0, 0, response.len() as u8];
+                        message.extend_from_slice(response);
+                        write_frame(&mut stream, DATA, 0, stream_id, &message).unwrap();
+                        let trailers = encode_headers(&[("grpc-status", "0")]);
+                        let flags = END_HEADERS | END_STREAM;
+                        write_frame(&mut stream, HEADERS, flags, stream_id, &trailers).unwrap();
+                    }
+                    Err(message) => {
+                        let headers = [
+                            (":status", "200"),
+                            ("grpc-status", "3"),
+                            ("grpc-message", message),
+                        ];
+                        let flags = END_HEADERS | END_STREAM;
+                        let headers = encode_headers(&headers);
+                        write_frame(&mut stream, HEADERS, flags, stream_id, &headers).unwrap();
+                    }
+                }
+            }
+        });
+        address
+    }
+
+    /// Serve one call with a response larger than the initial flow control windows,
+    /// only sending data as fast as the client extends both the connection and stream windows.
+    fn serve_large(size: usize) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut preface = [0; 24];
+            stream.read_exact(&mut preface).unwrap();
+            write_frame(&mut stream, SETTINGS, 0, 0, &[]).unwrap();
+            let stream_id = loop {
+                let frame = read_frame(&mut stream).unwrap();
+                if frame.kind == DATA && frame.flags & END_STREAM != 0 {
+                    break frame.stream_id;
+                }
+            };
+            let headers = encode_headers(&[(":status", "200")]);
+            write_frame(&mut stream, HEADERS, END_HEADERS, stream_id, &headers).unwrap();
+
+            let mut message = vec![0];
+            message.extend_from_slice(&(size as u32).to_be_bytes());
+            message.resize(5 + size, 7);
+            let (mut connection_window, mut stream_window) = (DEFAULT_WINDOW, DEFAULT_WINDOW);
+            let mut sent = 0;
+            while sent < message.len() {
+                let window = connection_window.min(stream_window) as usize;
+                let len = (message.len() - sent).min(DEFAULT_MAX_FRAME).min(window);
+                if len == 0 {
+                    let frame = read_frame(&mut stream).unwrap();
+                    if frame.kind == WINDOW_UPDATE {
+                        let increment = i64::from(frame.u32_at(0).unwrap());
+                        if frame.stream_id == 0 {
+                            connection_window += increment
+                        } else {
+                            stream_window += increment
+                        }
+                    }
+                    continue;
+                }
+                let data = &message[sent..sent + len];
+                write_frame(&mut stream, DATA, 0, stream_id, data).unwrap();
+                sent += len;
+                connection_window -= len as i64;
+                stream_window -= len as i64;
+            }
+            let trailers = encode_headers(&[("grpc-status", "0")]);
+            let flags = END_HEADERS | END_STREAM;
+            write_frame(&mut stream, HEADERS, flags, stream_id, &trailers).unwrap();
+        });
+        address
+    }
+
+    #[test]
+    fn large_response() {
+        let address = serve_large(200_000);
+        let mut channel = GrpcChannel::new(vec![address], &address.to_string());
+
+        let response = channel.unary("/test.Service/Ping", b"ping").unwrap();
+        assert_eq!(vec![7; 200_000], response);
+    }
+
+    #[test]
+    fn unary_calls() {
+        let address = serve(vec![Ok(b"pong"), Err("bad%20ping")]);
+        let mut channel = GrpcChannel::new(vec![address], &address.to_string());
+
+        let response = channel.unary("/test.Service/Ping", b"ping").unwrap();
+        assert_eq!(b"pong".to_vec(), response);
+
+        let error = channel.unary("/test.Service/Ping", b"ping").unwrap_err();
+        assert_eq!("gRPC status 3: bad ping", error.to_string());
+    }
+}
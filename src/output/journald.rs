@@ -0,0 +1,205 @@
+//! Send metrics to the systemd journal, using the native journal protocol.
+
+use crate::attributes::{Attributes, MetricId, OnFlush, Prefixed, WithAttributes};
+use crate::input::InputKind;
+use crate::input::{Input, InputMetric, InputScope};
+use crate::name::MetricName;
+use crate::output::error::SinkError;
+use crate::{CachedInput, QueuedInput};
+use crate::{Flush, MetricValue};
+
+use std::io;
+use std::os::unix::net::UnixDatagram;
+use std::path::Path;
+use std::sync::Arc;
+
+/// Default location of the journald native protocol socket.
+const JOURNALD_SOCKET: &str = "/run/systemd/journal/socket";
+
+/// Default syslog priority of metric entries (`info`).
+const DEFAULT_PRIORITY: u8 = 6;
+
+/// Journald Input holds a datagram (unix) socket to the journal.
+/// The socket is shared between scopes opened from the Input.
+#[derive(Clone, Debug)]
+pub struct Journald {
+    attributes: Attributes,
+    socket: Arc<UnixDatagram>,
+    priority: u8,
+}
+
+impl Journald {
+    /// Send metrics to the local systemd journal.
+    pub fn send_to_journal() -> Result<Journald, SinkError> {
+        Self::send_to(JOURNALD_SOCKET)
+    }
+
+    /// Send metrics to a journal listening on the unix datagram socket at the provided path.
+    pub fn send_to<P: AsRef<Path>>(path: P) -> Result<Journald, SinkError> {
+        let socket = UnixDatagram::unbound()?;
+        socket.connect(path)?;
+
+        Ok(Journald {
+            attributes: Attributes::default(),
+            socket: Arc::new(socket),
+            priority: DEFAULT_PRIORITY,
+        })
+    }
+
+    /// Sets the syslog priority (0 for `emerg` through 7 for `debug`) of the metric entries.
+    pub fn priority(&self, priority: u8) -> Self {
+        let mut cloned = self.clone();
+        cloned.priority = priority.min(7);
+        cloned
+    }
+}
+
+impl QueuedInput for Journald {}
+
+impl CachedInput for Journald {}
+
+impl Input for Journald {
+    type SCOPE = JournaldScope;
+
+    fn metrics(&self) -> Self::SCOPE {
+        JournaldScope {
+            attributes: self.attributes.clone(),
+            socket: self.socket.clone(),
+            priority: self.priority,
+        }
+    }
+}
+
+impl WithAttributes for Journald {
+    fn get_attributes(&self) -> &Attributes {
+        &self.attributes
+    }
+    fn mut_attributes(&mut self) -> &mut Attributes {
+        &mut self.attributes
+    }
+}
+
+/// Journald Input
+/// Every metric value is sent as a single journal entry, with structured fields
+/// `METRIC_NAME`, `METRIC_VALUE` and `METRIC_KIND`.
+#[derive(Debug, Clone)]
+pub struct JournaldScope {
+    attributes: Attributes,
+    socket: Arc<UnixDatagram>,
+    priority: u8,
+}
+
+impl InputScope for JournaldScope {
+    /// Define a metric of the specified type.
+    fn new_metric(&self, name: MetricName, kind: InputKind) -> InputMetric {
+        let metric = JournaldMetric {
+            name: self.prefix_prepend(name.clone()).join("."),
+            kind,
+            priority: self.priority,
+        };
+        let socket = self.socket.clone();
+        let metric_id = MetricId::forge("journald", name);
+
+        InputMetric::new(metric_id, move |value, _labels| {
+            if let Err(e) = socket.send(&metric.entry(value)) {
+                debug!("Could not send to journald {}", e)
+            }
+        })
+    }
+}
+
+impl Flush for JournaldScope {
+    /// Entries are never buffered, flushing only notifies the flush listeners.
+    fn flush(&self) -> io::Result<()> {
+        self.notify_flush_listeners();
+        Ok(())
+    }
+}
+
+impl WithAttributes for JournaldScope {
+    fn get_attributes(&self) -> &Attributes {
+        &self.attributes
+    }
+    fn mut_attributes(&mut self) -> &mut Attributes {
+        &mut self.attributes
+    }
+}
+
+/// Key of a journald metric.
+#[derive(Debug, Clone)]
+pub struct JournaldMetric {
+    name: String,
+    kind: InputKind,
+    priority: u8,
+}
+
+impl JournaldMetric {
+    /// Serialize a metric value as a journal native protocol datagram.
+    fn entry(&self, value: MetricValue) -> Vec<u8> {
+        let mut entry = Vec::with_capacity(128);
+        let message = format!("{} {}", self.name, value);
+        add_field(&mut entry, "MESSAGE", &message);
+        add_field(&mut entry, "PRIORITY", &self.priority.to_string());
+        add_field(&mut entry, "METRIC_NAME", &self.name);
+        add_field(&mut entry, "METRIC_VALUE", &value.to_string());
+        add_field(&mut entry, "METRIC_KIND", &format!("{:?}", self.kind));
+        entry
+    }
+}
+
+/// Append a field to a journal entry.
+/// Values containing newlines use the length-prefixed binary encoding.
+fn add_field(entry: &mut Vec<u8>, key: &str, value: &str) {
+    entry.extend_from_slice(key.as_bytes());
+    if value.contains('\n') {
+        entry.push(b'\n');
+        entry.extend_from_slice(&(value.len() as u64).to_le_bytes());
+        entry.extend_from_slice(value.as_bytes());
+    } else {
+        entry.push(b'=');
+        entry.extend_from_slice(value.as_bytes());
+    }
+    entry.push(b'\n');
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn journal_entry_fields() {
+        let metric = JournaldMetric {
+            name: "app.requests".into(),
+            kind: InputKind::Counter,
+            priority: DEFAULT_PRIORITY,
+        };
+        assert_eq!(
+            "MESSAGE=app.requests 42\nPRIORITY=6\nMETRIC_NAME=app.requests\nMETRIC_VALUE=42\nMETRIC_KIND=Counter\n",
+            String::from_utf8(metric.entry(42)).unwrap()
+        );
+    }
+
+    #[test]
+    fn journal_multiline_field() {
+        let mut entry = vec![];
+        add_field(&mut entry, "MESSAGE", "a\nb");
+        assert_eq!(b"MESSAGE\n\x03\0\0\0\0\0\0\0a\nb\n".to_vec(), entry);
+    }
+
+    #[test]
+    fn send_to_socket() {
+        let dir = std::env::temp_dir().join(format!("dipstick-journald-{}", std::process::id()));
+        let _ = std::fs::remove_file(&dir);
+        let journal = UnixDatagram::bind(&dir).unwrap();
+
+        let metrics = Journald::send_to(&dir).unwrap().metrics();
+        metrics.marker("event").mark();
+
+        let mut buf = [0u8; 256];
+        let len = journal.recv(&mut buf).unwrap();
+        let entry = String::from_utf8_lossy(&buf[..len]);
+        assert!(entry.contains("METRIC_NAME=event\n"));
+        assert!(entry.contains("METRIC_KIND=Marker\n"));
+        let _ = std::fs::remove_file(&dir);
+    }
+}
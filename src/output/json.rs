@@ -0,0 +1,271 @@
+//! Post all metrics of a scope as a single JSON document upon every flush.
+
+use crate::attributes::{
+    Attributes, Buffered, Buffering, MetricId, OnFlush, Prefixed, WithAttributes,
+};
+use crate::input::InputKind;
+use crate::input::{Input, InputMetric, InputScope};
+use crate::name::MetricName;
+use crate::output::dry_run::DryRun;
+use crate::output::error::{check_http_url, SinkError};
+use crate::{CachedInput, QueuedInput};
+use crate::{Flush, MetricValue};
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::io;
+use std::sync::Arc;
+
+#[cfg(not(feature = "parking_lot"))]
+use std::sync::{RwLock, RwLockWriteGuard};
+
+#[cfg(feature = "parking_lot")]
+use parking_lot::{RwLock, RwLockWriteGuard};
+
+/// Its hard to see how a single scope could get more metrics than this.
+// TODO make configurable?
+const BUFFER_FLUSH_THRESHOLD: usize = 4096;
+
+/// JsonBatch Input holds the URL of an HTTP endpoint receiving metrics documents.
+/// Values are batched until the scope is flushed, then posted as a single JSON object
+/// mapping each metric name to its kind and latest value, e.g. `{"requests":{"kind":"counter","value":3}}`.
+/// Typically used as the drain of an `AtomicBucket`, which publishes one value per statistic.
+/// Labels are not sent.
+#[derive(Clone, Debug)]
+pub struct JsonBatch {
+    attributes: Attributes,
+    url: String,
+    dry_run: Option<DryRun>,
+}
+
+impl JsonBatch {
+    /// Post metrics documents to the URL provided.
+    /// Fails if the URL is not a valid `http://` URL.
+    pub fn post_to(url: &str) -> Result<JsonBatch, SinkError> {
+        check_http_url(url)?;
+        debug!("Posting JSON metrics to {:?}", url);
+
+        Ok(JsonBatch {
+            attributes: Attributes::default(),
+            url: url.to_string(),
+            dry_run: None,
+        }
+        .buffered(Buffering::Unlimited))
+    }
+
+    /// Record payloads to the provided recorder instead of posting them.
+    pub fn dry_run(&self, recorder: &DryRun) -> Self {
+        let mut cloned = self.clone();
+        cloned.dry_run = Some(recorder.clone());
+        cloned
+    }
+}
+
+impl Input for JsonBatch {
+    type SCOPE = JsonBatchScope;
+
+    fn metrics(&self) -> Self::SCOPE {
+        JsonBatchScope {
+            attributes: self.attributes.clone(),
+            batch: Arc::new(Batch {
+                buffer: RwLock::new(BTreeMap::new()),
+                url: self.url.clone(),
+                dry_run: self.dry_run.clone(),
+            }),
+        }
+    }
+}
+
+impl WithAttributes for JsonBatch {
+    fn get_attributes(&self) -> &Attributes {
+        &self.attributes
+    }
+    fn mut_attributes(&mut self) -> &mut Attributes {
+        &mut self.attributes
+    }
+}
+
+impl Buffered for JsonBatch {}
+
+impl QueuedInput for JsonBatch {}
+impl CachedInput for JsonBatch {}
+
+/// JsonBatch Input
+#[derive(Debug, Clone)]
+pub struct JsonBatchScope {
+    attributes: Attributes,
+    batch: Arc<Batch>,
+}
+
+type Buffer = BTreeMap<String, (&'static str, MetricValue)>;
+
+/// Buffered values, shared by all clones of a scope so that they are posted together.
+#[derive(Debug)]
+struct Batch {
+    buffer: RwLock<Buffer>,
+    url: String,
+    dry_run: Option<DryRun>,
+}
+
+impl InputScope for JsonBatchScope {
+    /// Define a metric of the specified type.
+    fn new_metric(&self, name: MetricName, kind: InputKind) -> InputMetric {
+        let full_name = self.prefix_prepend(name.clone()).join(".");
+        let kind = kind_name(kind);
+        let cloned = self.clone();
+        let metric_id = MetricId::forge("json", name);
+
+        InputMetric::new(metric_id, move |value, _labels| {
+            let mut buffer = write_lock!(cloned.batch.buffer);
+            buffer.insert(full_name.clone(), (kind, value));
+
+            if !cloned.is_buffered() || buffer.len() >= BUFFER_FLUSH_THRESHOLD {
+                if let Err(e) = cloned.batch.flush_inner(buffer) {
+                    debug!("Could not post JSON metrics {}", e)
+                }
+            }
+        })
+    }
+}
+
+impl Flush for JsonBatchScope {
+    fn flush(&self) -> io::Result<()> {
+        self.notify_flush_listeners();
+        let buf = write_lock!(self.batch.buffer);
+        self.batch.flush_inner(buf)
+    }
+}
+
+impl Batch {
+    fn flush_inner(&self, mut buf: RwLockWriteGuard<Buffer>) -> io::Result<()> {
+        if buf.is_empty() {
+            return Ok(());
+        }
+
+        let mut payload = String::with_capacity(buf.len() * 48);
+        payload.push('{');
+        for (i, (name, (kind, value))) in buf.iter().enumerate() {
+            if i > 0 {
+                payload.push(',');
+            }
+            push_json_string(&mut payload, name);
+            let _ = write!(payload, ":{{\"kind\":\"{}\",\"value\":{}}}", kind, value);
+        }
+        payload.push('}');
+
+        if let Some(dry_run) = &self.dry_run {
+            dry_run.record(&payload);
+            buf.clear();
+            return Ok(());
+        }
+
+        match minreq::post(self.url.as_str())
+            .with_header("Content-Type", "application/json")
+            .with_body(payload.as_str())
+            .send()
+        {
+            Ok(response) if (200..300).contains(&response.status_code) => {
+                trace!("Posted {} JSON metrics", buf.len());
+                buf.clear();
+                Ok(())
+            }
+            Ok(response) => {
+                debug!(
+                    "JSON metrics endpoint refused document: {} {}",
+                    response.status_code, response.reason_phrase
+                );
+                buf.clear();
+                Err(io::Error::other(response.reason_phrase))
+            }
+            Err(e) => {
+                debug!("Failed to post JSON metrics: {}", e);
+                Err(io::Error::other(e))
+            }
+        }
+    }
+}
+
+impl WithAttributes for JsonBatchScope {
+    fn get_attributes(&self) -> &Attributes {
+        &self.attributes
+    }
+    fn mut_attributes(&mut self) -> &mut Attributes {
+        &mut self.attributes
+    }
+}
+
+impl Buffered for JsonBatchScope {}
+
+/// Any remaining buffered data is flushed when the last clone of the scope is dropped.
+impl Drop for Batch {
+    fn drop(&mut self) {
+        let buf = write_lock!(self.buffer);
+        if let Err(err) = self.flush_inner(buf) {
+            warn!("Could not flush JSON metrics upon Drop: {}", err)
+        }
+    }
+}
+
+/// Lowercase name of a metric kind, as used in JSON documents.
+pub(crate) fn kind_name(kind: InputKind) -> &'static str {
+    match kind {
+        InputKind::Marker => "marker",
+        InputKind::Counter => "counter",
+        InputKind::Level => "level",
+        InputKind::Gauge => "gauge",
+        InputKind::Timer => "timer",
+        InputKind::Distribution => "distribution",
+    }
+}
+
+/// Append a quoted JSON string, escaping quotes, backslashes and control characters.
+pub(crate) fn push_json_string(buf: &mut String, value: &str) {
+    buf.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => buf.push_str("\\\""),
+            '\\' => buf.push_str("\\\\"),
+            '\n' => buf.push_str("\\n"),
+            '\r' => buf.push_str("\\r"),
+            '\t' => buf.push_str("\\t"),
+            c if c.is_control() => {
+                let _ = write!(buf, "\\u{:04x}", c as u32);
+            }
+            c => buf.push(c),
+        }
+    }
+    buf.push('"');
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{stats_summary, AtomicBucket};
+
+    #[test]
+    fn document_per_flush() {
+        let recorder = DryRun::new();
+        let bucket = AtomicBucket::new();
+        bucket.stats(stats_summary);
+        bucket.drain(
+            JsonBatch::post_to("http://localhost:8080/metrics")
+                .unwrap()
+                .dry_run(&recorder),
+        );
+        let counter = bucket.counter("requests");
+        let gauge = bucket.gauge("queue \"a\"");
+
+        counter.count(2);
+        counter.count(3);
+        gauge.value(7);
+        bucket.flush().unwrap();
+
+        assert_eq!(
+            vec![
+                "{\"queue \\\"a\\\"\":{\"kind\":\"gauge\",\"value\":7},\
+                 \"requests\":{\"kind\":\"counter\",\"value\":5}}"
+            ],
+            recorder.payloads()
+        );
+    }
+}
@@ -0,0 +1,408 @@
+//! Produce metrics to a Kafka topic, through a Kafka REST proxy (Confluent REST Proxy API v2).
+//! Records are produced using the REST proxy v2 JSON embedded format.
+//! Kafka's native binary protocol and Avro serialization are not supported.
+//! Batches are produced by a background thread, never blocking the thread recording or flushing metrics.
+
+use crate::attributes::{
+    Attributes, Buffered, Buffering, MetricId, OnFlush, Prefixed, WithAttributes,
+};
+use crate::input::InputKind;
+use crate::input::{Input, InputMetric, InputScope};
+use crate::label::Labels;
+use crate::metrics;
+use crate::name::MetricName;
+use crate::output::dry_run::DryRun;
+use crate::output::error::{check_http_url, SinkError};
+use crate::output::json::{kind_name, push_json_string};
+use crate::{CachedInput, QueuedInput};
+use crate::{Flush, MetricValue};
+
+use std::fmt::{self, Write};
+use std::io;
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[cfg(not(feature = "parking_lot"))]
+use std::sync::{RwLock, RwLockWriteGuard};
+
+#[cfg(feature = "parking_lot")]
+use parking_lot::{RwLock, RwLockWriteGuard};
+
+/// Records to accumulate before producing them, even if the scope was not flushed.
+// TODO make configurable?
+const BUFFER_FLUSH_THRESHOLD: usize = 1024;
+
+/// Batches waiting to be produced, beyond which new batches are dropped and reported as failed.
+// TODO make configurable?
+const MAX_PENDING_BATCHES: usize = 16;
+
+/// Function called with every delivery error.
+type ErrorFn = Arc<dyn Fn(&io::Error) + Send + Sync>;
+
+/// A batch of records to produce, with the function to report its delivery error to.
+struct Batch {
+    topic_url: String,
+    payload: String,
+    records: usize,
+    on_error: ErrorFn,
+}
+
+/// KafkaRest Input holds the URL of a topic on a Kafka REST proxy.
+/// Each value is produced as a JSON record keyed by metric name, keeping each metric on a single partition.
+/// Records are batched until the scope is flushed, unless buffering is disabled.
+/// Batches are produced one at a time by a background thread, shared by all scopes of the input.
+/// At most 16 batches wait to be produced, further batches are dropped.
+/// Delivery errors, including dropped batches, are reported to the function set with `on_error()`.
+#[derive(Clone)]
+pub struct KafkaRest {
+    attributes: Attributes,
+    topic_url: String,
+    dry_run: Option<DryRun>,
+    producer: mpsc::SyncSender<Batch>,
+    on_error: ErrorFn,
+}
+
+impl KafkaRest {
+    /// Produce metrics to a topic through the Kafka REST proxy at the URL provided.
+    /// For example `send_to("http://kafka-rest.example.org:8082", "metrics")`
+    /// Fails if the URL or topic name is invalid, or if the producing thread can not be started.
+    pub fn send_to(proxy_url: &str, topic: &str) -> Result<KafkaRest, SinkError> {
+        check_http_url(proxy_url)?;
+        let valid_char = |c: char| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-');
+        if topic.is_empty() || topic.len() > 249 || !topic.chars().all(valid_char) {
+            return Err(SinkError::Config(format!(
+                "invalid Kafka topic name {:?}",
+                topic
+            )));
+        }
+        let topic_url = format!("{}/topics/{}", proxy_url.trim_end_matches('/'), topic);
+        debug!("Producing to Kafka {:?}", topic_url);
+
+        Ok(KafkaRest {
+            attributes: Attributes::default(),
+            topic_url,
+            dry_run: None,
+            producer: spawn_producer()?,
+            on_error: Arc::new(|e| warn!("Could not produce metrics to Kafka: {}", e)),
+        }
+        .buffered(Buffering::Unlimited))
+    }
+
+    /// Record payloads to the provided recorder instead of producing them.
+    pub fn dry_run(&self, recorder: &DryRun) -> Self {
+        let mut cloned = self.clone();
+        cloned.dry_run = Some(recorder.clone());
+        cloned
+    }
+
+    /// Call the function with every error producing the batches of this input's scopes,
+    /// instead of logging it. Errors are reported from the producing thread.
+    pub fn on_error<F>(&self, on_error: F) -> Self
+    where
+        F: Fn(&io::Error) + Send + Sync + 'static,
+    {
+        let mut cloned = self.clone();
+        cloned.on_error = Arc::new(on_error);
+        cloned
+    }
+}
+
+impl fmt::Debug for KafkaRest {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "KafkaRest {{ topic_url: {:?} }}", self.topic_url)
+    }
+}
+
+impl Input for KafkaRest {
+    type SCOPE = KafkaRestScope;
+
+    fn metrics(&self) -> Self::SCOPE {
+        KafkaRestScope {
+            attributes: self.attributes.clone(),
+            buffer: Arc::new(RwLock::new(Vec::new())),
+            topic_url: self.topic_url.clone(),
+            dry_run: self.dry_run.clone(),
+            producer: self.producer.clone(),
+            on_error: self.on_error.clone(),
+        }
+    }
+}
+
+/// Start the thread producing batches until every input and scope sending to it is dropped.
+fn spawn_producer() -> io::Result<mpsc::SyncSender<Batch>> {
+    let (sender, receiver) = mpsc::sync_channel::<Batch>(MAX_PENDING_BATCHES);
+    thread::Builder::new()
+        .name("dipstick-kafka".to_string())
+        .spawn(move || {
+            for batch in receiver {
+                if let Err(e) = produce(&batch) {
+                    metrics::KAFKA_SEND_ERR.mark();
+                    (batch.on_error)(&e)
+                }
+            }
+            debug!("Kafka producer terminated");
+        })?;
+    Ok(sender)
+}
+
+/// Post the batch to the REST proxy, failing if the proxy or Kafka refused any of its records.
+fn produce(batch: &Batch) -> io::Result<()> {
+    let response = minreq::post(batch.topic_url.as_str())
+        .with_header("Content-Type", "application/vnd.kafka.json.v2+json")
+        .with_body(batch.payload.as_str())
+        .send()
+        .map_err(|e| io::Error::other(format!("{} records not produced: {}", batch.records, e)))?;
+    if !(200..300).contains(&response.status_code) {
+        return Err(io::Error::other(format!(
+            "{} records refused by Kafka proxy: {} {}",
+            batch.records, response.status_code, response.reason_phrase
+        )));
+    }
+    let failed = response.as_str().map(failed_records).unwrap_or_default();
+    if failed > 0 {
+        return Err(io::Error::other(format!(
+            "{} of {} records refused by Kafka",
+            failed, batch.records
+        )));
+    }
+    metrics::KAFKA_SENT_BYTES.count(batch.payload.len());
+    trace!("Produced {} records to Kafka", batch.records);
+    Ok(())
+}
+
+/// Number of records of a REST proxy produce response that have an error code.
+fn failed_records(response: &str) -> usize {
+    response
+        .split("\"error_code\":")
+        .skip(1)
+        .filter(|code| !code.trim_start().starts_with("null"))
+        .count()
+}
+
+impl WithAttributes for KafkaRest {
+    fn get_attributes(&self) -> &Attributes {
+        &self.attributes
+    }
+    fn mut_attributes(&mut self) -> &mut Attributes {
+        &mut self.attributes
+    }
+}
+
+impl Buffered for KafkaRest {}
+
+impl QueuedInput for KafkaRest {}
+impl CachedInput for KafkaRest {}
+
+/// KafkaRest Input
+#[derive(Clone)]
+pub struct KafkaRestScope {
+    attributes: Attributes,
+    buffer: Arc<RwLock<Vec<String>>>,
+    topic_url: String,
+    dry_run: Option<DryRun>,
+    producer: mpsc::SyncSender<Batch>,
+    on_error: ErrorFn,
+}
+
+impl fmt::Debug for KafkaRestScope {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "KafkaRestScope {{ topic_url: {:?} }}", self.topic_url)
+    }
+}
+
+/// Key of a Kafka metric.
+#[derive(Debug)]
+pub struct KafkaRestMetric {
+    name: String,
+    kind: &'static str,
+}
+
+impl InputScope for KafkaRestScope {
+    /// Define a metric of the specified type.
+    fn new_metric(&self, name: MetricName, kind: InputKind) -> InputMetric {
+        let metric = KafkaRestMetric {
+            name: self.prefix_prepend(name.clone()).join("."),
+            kind: kind_name(kind),
+        };
+        let cloned = self.clone();
+        let metric_id = MetricId::forge("kafka", name);
+
+        InputMetric::new(metric_id, move |value, labels| {
+            cloned.print(&metric, value, labels);
+        })
+    }
+}
+
+impl Flush for KafkaRestScope {
+    fn flush(&self) -> io::Result<()> {
+        self.notify_flush_listeners();
+        let buf = write_lock!(self.buffer);
+        self.flush_inner(buf)
+    }
+}
+
+impl KafkaRestScope {
+    fn print(&self, metric: &KafkaRestMetric, value: MetricValue, labels: Labels) {
+        let mut labels: Vec<(String, Arc<String>)> = labels.into_map().into_iter().collect();
+        labels.sort();
+
+        let mut record = String::with_capacity(128);
+        record.push_str("{\"key\":");
+        push_json_string(&mut record, &metric.name);
+        record.push_str(",\"value\":{\"name\":");
+        push_json_string(&mut record, &metric.name);
+        let _ = write!(
+            record,
+            ",\"kind\":\"{}\",\"value\":{},\"timestamp\":{},\"labels\":{{",
+            metric.kind,
+            value,
+            now_unix_millis()
+        );
+        for (i, (key, value)) in labels.iter().enumerate() {
+            if i > 0 {
+                record.push(',');
+            }
+            push_json_string(&mut record, key);
+            record.push(':');
+            push_json_string(&mut record, value);
+        }
+        record.push_str("}}}");
+
+        let mut buffer = write_lock!(self.buffer);
+        buffer.push(record);
+
+        if !self.is_buffered() || buffer.len() >= BUFFER_FLUSH_THRESHOLD {
+            // a batch that could not be queued was already reported
+            let _ = self.flush_inner(buffer);
+        }
+    }
+
+    /// Hand the buffered records to the producing thread, without waiting for them to be produced.
+    /// Fails if the batch was dropped because too many batches are waiting to be produced.
+    fn flush_inner(&self, mut buf: RwLockWriteGuard<Vec<String>>) -> io::Result<()> {
+        if buf.is_empty() {
+            return Ok(());
+        }
+
+        let payload = format!("{{\"records\":[{}]}}", buf.join(","));
+        let records = buf.len();
+        buf.clear();
+
+        if let Some(dry_run) = &self.dry_run {
+            dry_run.record(&payload);
+            return Ok(());
+        }
+
+        let batch = Batch {
+            topic_url: self.topic_url.clone(),
+            payload,
+            records,
+            on_error: self.on_error.clone(),
+        };
+        self.producer.try_send(batch).map_err(|e| {
+            metrics::KAFKA_SEND_ERR.mark();
+            let error = match e {
+                mpsc::TrySendError::Full(_) => io::Error::new(
+                    io::ErrorKind::WouldBlock,
+                    format!("{} records dropped, too many batches pending", records),
+                ),
+                mpsc::TrySendError::Disconnected(_) => io::Error::new(
+                    io::ErrorKind::BrokenPipe,
+                    format!("{} records dropped, Kafka producer terminated", records),
+                ),
+            };
+            (self.on_error)(&error);
+            error
+        })
+    }
+}
+
+impl WithAttributes for KafkaRestScope {
+    fn get_attributes(&self) -> &Attributes {
+        &self.attributes
+    }
+    fn mut_attributes(&mut self) -> &mut Attributes {
+        &mut self.attributes
+    }
+}
+
+impl Buffered for KafkaRestScope {}
+
+/// Any remaining buffered data is flushed on Drop.
+impl Drop for KafkaRestScope {
+    fn drop(&mut self) {
+        if let Err(err) = self.flush() {
+            warn!("Could not flush Kafka metrics upon Drop: {}", err)
+        }
+    }
+}
+
+fn now_unix_millis() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn batch_on_flush() {
+        let recorder = DryRun::new();
+        let metrics = KafkaRest::send_to("http://localhost:8082/", "metrics")
+            .unwrap()
+            .dry_run(&recorder)
+            .metrics()
+            .named("app");
+        let counter = metrics.counter("requests");
+        counter.count(3);
+        counter.write(1, labels!("path" => "/\"x\""));
+        assert!(recorder.payloads().is_empty());
+
+        metrics.flush().unwrap();
+        let payloads = recorder.payloads();
+        assert_eq!(1, payloads.len());
+        assert!(payloads[0].starts_with("{\"records\":[{\"key\":\"app.requests\",\"value\":{"));
+        assert!(payloads[0].contains("\"kind\":\"counter\",\"value\":3,"));
+        assert!(payloads[0].contains("\"path\":\"/\\\"x\\\"\""));
+        assert_eq!(2, payloads[0].matches("\"key\"").count());
+    }
+
+    #[test]
+    fn refused_records() {
+        let response = r#"{"offsets":[{"partition":0,"offset":7,"error_code":null,"error":null},
+            {"partition":null,"offset":null,"error_code":40403,"error":"Topic not found"}]}"#;
+        assert_eq!(1, failed_records(response));
+        assert_eq!(0, failed_records("{\"offsets\":[]}"));
+    }
+
+    #[test]
+    fn report_delivery_errors() {
+        let (sender, receiver) = mpsc::channel();
+        let sender = std::sync::Mutex::new(sender);
+        // nothing listens on port 1
+        let metrics = KafkaRest::send_to("http://127.0.0.1:1", "metrics")
+            .unwrap()
+            .on_error(move |e| sender.lock().unwrap().send(e.to_string()).unwrap())
+            .metrics();
+        metrics.counter("requests").count(3);
+
+        metrics.flush().unwrap();
+        let error = receiver
+            .recv_timeout(std::time::Duration::from_secs(10))
+            .unwrap();
+        assert!(error.starts_with("1 records not produced"), "{}", error);
+    }
+
+    #[test]
+    fn reject_invalid_config() {
+        let proxy = KafkaRest::send_to("kafka-rest:8082", "metrics");
+        assert!(matches!(proxy, Err(SinkError::Config(_))));
+        let topic = KafkaRest::send_to("http://kafka-rest:8082", "metrics/app");
+        assert!(matches!(topic, Err(SinkError::Config(_))));
+    }
+}
@@ -93,6 +93,18 @@ impl Log {
     }
 }
 
+impl LogScope {
+    /// Create a scope sharing this scope's log configuration and attributes but with its own buffer.
+    /// Flushing the fork does not flush the original scope, and vice versa.
+    pub fn fork(&self) -> Self {
+        LogScope {
+            attributes: self.attributes.fork(),
+            entries: Arc::new(RwLock::new(Vec::new())),
+            log: self.log.clone(),
+        }
+    }
+}
+
 impl WithAttributes for LogScope {
     fn get_attributes(&self) -> &Attributes {
         &self.attributes
@@ -112,59 +124,55 @@ impl InputScope for LogScope {
         let name = self.prefix_append(name);
         let template = self.log.format.template(&name, kind);
         let entries = self.entries.clone();
-
-        if self.is_buffered() {
-            // buffered
-            InputMetric::new(MetricId::forge("log", name), move |value, labels| {
-                let mut buffer = Vec::with_capacity(32);
-                match template.print(&mut buffer, value, |key| labels.lookup(key)) {
-                    Ok(()) => {
-                        let mut entries = write_lock!(entries);
-                        entries.push(buffer)
+        let attributes = self.attributes.clone();
+        let log = self.log.clone();
+
+        InputMetric::new(MetricId::forge("log", name), move |value, labels| {
+            let mut buffer = Vec::with_capacity(32);
+            match template.print(&mut buffer, value, |key| labels.lookup(key)) {
+                // buffering may be suspended at any time, check upon every write
+                Ok(()) if attributes.is_buffered() => write_lock!(entries).push(buffer),
+                Ok(()) => {
+                    // log any previously buffered entries first, to preserve ordering
+                    if let Err(e) = log.log_entries(&mut write_lock!(entries)) {
+                        debug!("Could not log buffered metrics: {}", e)
                     }
-                    Err(err) => debug!("Could not format buffered log metric: {}", err),
+                    log.log(&buffer)
                 }
-            })
+                Err(err) => debug!("Could not format buffered log metric: {}", err),
+            }
+        })
+    }
+}
+
+impl Log {
+    fn log(&self, buffer: &[u8]) {
+        if let Some(target) = &self.target {
+            log!(target: target, self.level, "{:?}", buffer)
         } else {
-            // unbuffered
-            let level = self.log.level;
-            let target = self.log.target.clone();
-            InputMetric::new(MetricId::forge("log", name), move |value, labels| {
-                let mut buffer = Vec::with_capacity(32);
-                match template.print(&mut buffer, value, |key| labels.lookup(key)) {
-                    Ok(()) => {
-                        if let Some(target) = &target {
-                            log!(target: target, level, "{:?}", &buffer)
-                        } else {
-                            log!(level, "{:?}", &buffer)
-                        }
-                    }
-                    Err(err) => debug!("Could not format buffered log metric: {}", err),
-                }
-            })
+            log!(self.level, "{:?}", buffer)
         }
     }
-}
 
-impl Flush for LogScope {
-    fn flush(&self) -> io::Result<()> {
-        self.notify_flush_listeners();
-        let mut entries = write_lock!(self.entries);
+    fn log_entries(&self, entries: &mut Vec<Vec<u8>>) -> io::Result<()> {
         if !entries.is_empty() {
             let mut buf: Vec<u8> = Vec::with_capacity(32 * entries.len());
             for entry in entries.drain(..) {
                 writeln!(&mut buf, "{:?}", &entry)?;
             }
-            if let Some(target) = &self.log.target {
-                log!(target: target, self.log.level, "{:?}", &buf)
-            } else {
-                log!(self.log.level, "{:?}", &buf)
-            }
+            self.log(&buf)
         }
         Ok(())
     }
 }
 
+impl Flush for LogScope {
+    fn flush(&self) -> io::Result<()> {
+        self.notify_flush_listeners();
+        self.log.log_entries(&mut write_lock!(self.entries))
+    }
+}
+
 impl Drop for LogScope {
     fn drop(&mut self) {
         if let Err(e) = self.flush() {
@@ -175,6 +183,7 @@ impl Drop for LogScope {
 
 #[cfg(test)]
 mod test {
+    use crate::attributes::{Buffered, Buffering};
     use crate::input::*;
 
     #[test]
@@ -183,4 +192,21 @@ mod test {
         let m = c.new_metric("test".into(), InputKind::Marker);
         m.write(33, labels![]);
     }
+
+    #[test]
+    fn immediate_flush() {
+        let metrics = super::Log::to_log()
+            .buffered(Buffering::Unlimited)
+            .metrics();
+        let counter = metrics.counter("counter");
+
+        counter.count(1);
+        assert_eq!(1, read_lock!(metrics.entries).len());
+
+        metrics.with_immediate_flush(|| counter.count(2));
+        assert!(read_lock!(metrics.entries).is_empty());
+
+        counter.count(3);
+        assert_eq!(1, read_lock!(metrics.entries).len());
+    }
 }
@@ -1,5 +1,9 @@
 pub mod void;
 
+pub mod dry_run;
+
+pub mod error;
+
 pub mod format;
 
 pub mod map;
@@ -16,3 +20,17 @@ pub mod statsd;
 
 //#[cfg(feature="prometheus")]
 pub mod prometheus;
+
+pub mod json;
+
+#[cfg(feature = "otlp")]
+pub mod otlp;
+
+#[cfg(feature = "otlp")]
+mod grpc;
+
+#[cfg(feature = "kafka")]
+pub mod kafka;
+
+#[cfg(all(unix, feature = "journald"))]
+pub mod journald;
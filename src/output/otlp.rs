@@ -0,0 +1,766 @@
+//! Send metrics to an OpenTelemetry collector, using the OTLP/gRPC protocol.
+//! Requests are sent over cleartext HTTP/2 connections, TLS is not supported.
+
+use crate::attributes::{Attributes, Buffered, MetricId, OnFlush, Prefixed, WithAttributes};
+use crate::input::InputKind;
+use crate::input::{Input, InputMetric, InputScope};
+use crate::label::Labels;
+use crate::metrics;
+use crate::name::MetricName;
+use crate::output::error::SinkError;
+use crate::output::grpc::GrpcChannel;
+use crate::output::socket::resolve;
+use crate::{CachedInput, QueuedInput};
+use crate::{Flush, MetricValue};
+
+use std::collections::BTreeMap;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[cfg(not(feature = "parking_lot"))]
+use std::sync::{RwLock, RwLockWriteGuard};
+
+#[cfg(feature = "parking_lot")]
+use parking_lot::{RwLock, RwLockWriteGuard};
+
+/// Default histogram bucket bounds for timers, in milliseconds.
+const DEFAULT_TIMER_BOUNDS: &[f64] = &[
+    5.0, 10.0, 25.0, 50.0, 100.0, 250.0, 500.0, 1000.0, 2500.0, 5000.0, 10000.0,
+];
+
+/// Its hard to see how a single scope could get more series than this.
+// TODO make configurable?
+const BUFFER_FLUSH_THRESHOLD: usize = 4096;
+
+/// Requests waiting to be exported, beyond which new requests are dropped.
+// TODO make configurable?
+const MAX_PENDING_EXPORTS: usize = 16;
+
+/// The gRPC method receiving metrics.
+const EXPORT_METHOD: &str = "/opentelemetry.proto.collector.metrics.v1.MetricsService/Export";
+
+/// OTLP Input holds the address of an OpenTelemetry collector.
+/// Values are aggregated per metric and labels until the scope is flushed, unless buffering is disabled.
+/// Counters and markers are sent as monotonic delta sums, levels as non-monotonic delta sums,
+/// gauges as their last value, timers as histograms (in milliseconds) and distributions as histograms without buckets.
+/// Labels are sent as data point attributes.
+/// Requests are exported one at a time by a background thread, shared by all scopes of the input.
+/// At most 16 requests wait to be exported, further requests are dropped.
+#[derive(Clone, Debug)]
+pub struct Otlp {
+    attributes: Attributes,
+    service_name: Option<String>,
+    timer_bounds: Arc<Vec<f64>>,
+    exporter: mpsc::SyncSender<Export>,
+}
+
+impl Otlp {
+    /// Send metrics to the OTLP/gRPC endpoint of an OpenTelemetry collector.
+    /// For example `collector.example.org:4317` or `http://collector.example.org:4317`.
+    /// Fails if the address can not be resolved or uses TLS (`https://`), or if the exporting thread can not be started.
+    pub fn send_to(address: &str) -> Result<Otlp, SinkError> {
+        let authority = address
+            .strip_prefix("http://")
+            .unwrap_or(address)
+            .trim_end_matches('/');
+        if authority.contains("://") {
+            return Err(SinkError::Config(format!(
+                "unsupported OTLP endpoint {}, only cleartext gRPC is supported",
+                address
+            )));
+        }
+        let addresses = resolve(authority)?;
+        debug!("Sending to OTLP collector {:?}", authority);
+
+        Ok(Otlp {
+            attributes: Attributes::default(),
+            service_name: None,
+            timer_bounds: Arc::new(DEFAULT_TIMER_BOUNDS.to_vec()),
+            exporter: spawn_exporter(addresses, authority)?,
+        })
+    }
+
+    /// Sets the `service.name` attribute of the exported resource.
+    pub fn service_name(&self, name: &str) -> Self {
+        let mut cloned = self.clone();
+        cloned.service_name = Some(name.to_string());
+        cloned
+    }
+
+    /// Sets the explicit bucket bounds, in milliseconds, of the histograms exported for timers.
+    /// Use `exponential_buckets()` to generate bounds covering a wide range of durations.
+    pub fn timer_buckets(&self, bounds: &[f64]) -> Self {
+        let mut cloned = self.clone();
+        cloned.timer_bounds = Arc::new(bounds.to_vec());
+        cloned
+    }
+
+    /// Hand the request to the exporting thread, without waiting for it to be exported.
+    /// Fails if the request was dropped because too many requests are waiting to be exported.
+    fn export(&self, export: Export) -> io::Result<()> {
+        let points = export.points;
+        self.exporter.try_send(export).map_err(|e| {
+            metrics::OTLP_SEND_ERR.mark();
+            metrics::OTLP_DROPPED.count(points);
+            match e {
+                mpsc::TrySendError::Full(_) => io::Error::new(
+                    io::ErrorKind::WouldBlock,
+                    format!("{} data points dropped, too many exports pending", points),
+                ),
+                mpsc::TrySendError::Disconnected(_) => io::Error::new(
+                    io::ErrorKind::BrokenPipe,
+                    format!("{} data points dropped, OTLP exporter terminated", points),
+                ),
+            }
+        })
+    }
+}
+
+/// An encoded `ExportMetricsServiceRequest`, waiting to be exported.
+#[derive(Debug)]
+struct Export {
+    request: Vec<u8>,
+    points: usize,
+}
+
+/// Start the thread exporting requests until every input and scope sending to it is dropped.
+fn spawn_exporter(
+    addresses: Vec<SocketAddr>,
+    authority: &str,
+) -> io::Result<mpsc::SyncSender<Export>> {
+    let (sender, receiver) = mpsc::sync_channel::<Export>(MAX_PENDING_EXPORTS);
+    let mut channel = GrpcChannel::new(addresses, authority);
+    thread::Builder::new()
+        .name("dipstick-otlp".to_string())
+        .spawn(move || {
+            for export in receiver {
+                match channel.unary(EXPORT_METHOD, &export.request) {
+                    Ok(response) => match rejected_points(&response) {
+                        Some((rejected, message)) if rejected > 0 => {
+                            metrics::OTLP_DROPPED.count(rejected as usize);
+                            debug!(
+                                "OTLP collector rejected {} data points: {}",
+                                rejected, message
+                            );
+                        }
+                        _ => trace!("Sent {} data points to OTLP collector", export.points),
+                    },
+                    Err(e) => {
+                        // drop data points that could not be sent rather than pile them up while the collector is down
+                        metrics::OTLP_SEND_ERR.mark();
+                        metrics::OTLP_DROPPED.count(export.points);
+                        debug!("Failed to send data points to OTLP collector: {}", e);
+                    }
+                }
+            }
+            debug!("OTLP exporter terminated");
+        })?;
+    Ok(sender)
+}
+
+impl Input for Otlp {
+    type SCOPE = OtlpScope;
+
+    fn metrics(&self) -> Self::SCOPE {
+        OtlpScope {
+            attributes: self.attributes.clone(),
+            window: Arc::new(RwLock::new(Window {
+                start_unix_nano: now_unix_nano(),
+                series: BTreeMap::new(),
+            })),
+            otlp: self.clone(),
+        }
+    }
+}
+
+impl WithAttributes for Otlp {
+    fn get_attributes(&self) -> &Attributes {
+        &self.attributes
+    }
+    fn mut_attributes(&mut self) -> &mut Attributes {
+        &mut self.attributes
+    }
+}
+
+impl Buffered for Otlp {}
+
+impl QueuedInput for Otlp {}
+impl CachedInput for Otlp {}
+
+/// OTLP Input
+#[derive(Debug, Clone)]
+pub struct OtlpScope {
+    attributes: Attributes,
+    window: Arc<RwLock<Window>>,
+    otlp: Otlp,
+}
+
+/// Key of an OTLP metric.
+#[derive(Debug)]
+pub struct OtlpMetric {
+    name: String,
+    kind: InputKind,
+}
+
+/// Sorted labels of a series.
+type SeriesLabels = Vec<(String, Arc<String>)>;
+
+/// Values recorded since the scope was last flushed, aggregated by metric name and labels.
+#[derive(Debug)]
+struct Window {
+    start_unix_nano: u64,
+    series: BTreeMap<(String, SeriesLabels), Series>,
+}
+
+/// The aggregated values of a metric with a set of labels.
+#[derive(Debug)]
+struct Series {
+    kind: InputKind,
+    aggregate: Aggregate,
+}
+
+#[derive(Debug)]
+enum Aggregate {
+    /// Sum of the counts or level changes
+    Sum(MetricValue),
+    /// Last gauge value
+    Last(MetricValue),
+    /// Distribution of the values among the explicit bucket bounds
+    Histogram {
+        count: u64,
+        sum: f64,
+        min: f64,
+        max: f64,
+        bucket_counts: Vec<u64>,
+    },
+}
+
+impl Series {
+    fn new(kind: InputKind, timer_bounds: &[f64]) -> Self {
+        let histogram = |buckets: usize| Aggregate::Histogram {
+            count: 0,
+            sum: 0.0,
+            min: f64::INFINITY,
+            max: f64::NEG_INFINITY,
+            bucket_counts: vec![0; buckets],
+        };
+        let aggregate = match kind {
+            InputKind::Marker | InputKind::Counter | InputKind::Level => Aggregate::Sum(0),
+            InputKind::Gauge => Aggregate::Last(0),
+            InputKind::Timer => histogram(timer_bounds.len() + 1),
+            InputKind::Distribution => histogram(1),
+        };
+        Series { kind, aggregate }
+    }
+
+    fn record(&mut self, value: MetricValue, timer_bounds: &[f64]) {
+        match &mut self.aggregate {
+            Aggregate::Sum(sum) => *sum = sum.saturating_add(value),
+            Aggregate::Last(last) => *last = value,
+            Aggregate::Histogram {
+                count,
+                sum,
+                min,
+                max,
+                bucket_counts,
+            } => {
+                // timers are in µs, histograms are in milliseconds
+                let (value, bounds) = match self.kind {
+                    InputKind::Timer => (value as f64 / 1000.0, timer_bounds),
+                    _ => (value as f64, &[][..]),
+                };
+                *count += 1;
+                *sum += value;
+                *min = min.min(value);
+                *max = max.max(value);
+                let bucket = bounds
+                    .iter()
+                    .position(|bound| value <= *bound)
+                    .unwrap_or(bounds.len());
+                bucket_counts[bucket] += 1;
+            }
+        }
+    }
+}
+
+impl InputScope for OtlpScope {
+    /// Define a metric of the specified type.
+    fn new_metric(&self, name: MetricName, kind: InputKind) -> InputMetric {
+        let metric = OtlpMetric {
+            name: self.prefix_prepend(name.clone()).join("."),
+            kind,
+        };
+        let cloned = self.clone();
+        let metric_id = MetricId::forge("otlp", name);
+
+        InputMetric::new(metric_id, move |value, labels| {
+            cloned.print(&metric, value, labels);
+        })
+    }
+}
+
+impl Flush for OtlpScope {
+    fn flush(&self) -> io::Result<()> {
+        self.notify_flush_listeners();
+        let window = write_lock!(self.window);
+        self.flush_inner(window)
+    }
+}
+
+impl OtlpScope {
+    fn print(&self, metric: &OtlpMetric, value: MetricValue, labels: Labels) {
+        let mut labels: SeriesLabels = labels.into_map().into_iter().collect();
+        labels.sort();
+
+        let mut window = write_lock!(self.window);
+        let timer_bounds = &self.otlp.timer_bounds;
+        window
+            .series
+            .entry((metric.name.clone(), labels))
+            .or_insert_with(|| Series::new(metric.kind, timer_bounds))
+            .record(value, timer_bounds);
+
+        if !self.is_buffered() || window.series.len() >= BUFFER_FLUSH_THRESHOLD {
+            if let Err(e) = self.flush_inner(window) {
+                debug!("Could not send to OTLP collector {}", e)
+            }
+        }
+    }
+
+    /// Close the window, handing its data points to the exporting thread.
+    fn flush_inner(&self, mut window: RwLockWriteGuard<Window>) -> io::Result<()> {
+        if window.series.is_empty() {
+            return Ok(());
+        }
+
+        let now = now_unix_nano();
+        let request = encode_request(&window, now, &self.otlp);
+        let points = window.series.len();
+        window.series.clear();
+        window.start_unix_nano = now;
+        self.otlp.export(Export { request, points })
+    }
+}
+
+impl WithAttributes for OtlpScope {
+    fn get_attributes(&self) -> &Attributes {
+        &self.attributes
+    }
+    fn mut_attributes(&mut self) -> &mut Attributes {
+        &mut self.attributes
+    }
+}
+
+impl Buffered for OtlpScope {}
+
+/// Any remaining buffered data is flushed on Drop.
+impl Drop for OtlpScope {
+    fn drop(&mut self) {
+        if let Err(err) = self.flush() {
+            warn!("Could not flush OTLP metrics upon Drop: {}", err)
+        }
+    }
+}
+
+fn now_unix_nano() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or_default()
+}
+
+/// OTLP `AggregationTemporality.AGGREGATION_TEMPORALITY_DELTA`
+const DELTA: u64 = 1;
+
+/// Encode an `ExportMetricsServiceRequest` of the window ending at `time_unix_nano`, grouping series by metric.
+fn encode_request(window: &Window, time_unix_nano: u64, otlp: &Otlp) -> Vec<u8> {
+    let mut metrics: BTreeMap<&str, Vec<(&SeriesLabels, &Series)>> = BTreeMap::new();
+    for ((name, labels), series) in &window.series {
+        metrics.entry(name).or_default().push((labels, series));
+    }
+    let times = (window.start_unix_nano, time_unix_nano);
+
+    let mut request = Vec::new();
+    // ExportMetricsServiceRequest.resource_metrics
+    put_message(&mut request, 1, |resource_metrics| {
+        // ResourceMetrics.resource
+        put_message(resource_metrics, 1, |resource| {
+            if let Some(service_name) = &otlp.service_name {
+                // Resource.attributes
+                put_key_value(resource, 1, "service.name", service_name);
+            }
+        });
+        // ResourceMetrics.scope_metrics
+        put_message(resource_metrics, 2, |scope_metrics| {
+            // ScopeMetrics.scope
+            put_message(scope_metrics, 1, |scope| put_string(scope, 1, "dipstick"));
+            for (name, series) in &metrics {
+                // ScopeMetrics.metrics
+                put_message(scope_metrics, 2, |metric| {
+                    encode_metric(metric, name, series, times, &otlp.timer_bounds)
+                });
+            }
+        });
+    });
+    request
+}
+
+/// Encode a metric's series, skipping any defined with another kind than the first one under the same name.
+fn encode_metric(
+    buf: &mut Vec<u8>,
+    name: &str,
+    series: &[(&SeriesLabels, &Series)],
+    times: (u64, u64),
+    timer_bounds: &[f64],
+) {
+    let kind = series[0].1.kind;
+    let points = series.iter().filter(|(_, series)| series.kind == kind);
+    put_string(buf, 1, name);
+    match kind {
+        InputKind::Gauge => {
+            // Metric.gauge
+            put_message(buf, 5, |gauge| {
+                for (labels, series) in points {
+                    put_message(gauge, 1, |dp| {
+                        encode_number_point(dp, labels, series, times)
+                    });
+                }
+            })
+        }
+        InputKind::Marker | InputKind::Counter | InputKind::Level => {
+            // Metric.sum
+            put_message(buf, 7, |sum| {
+                for (labels, series) in points {
+                    put_message(sum, 1, |dp| encode_number_point(dp, labels, series, times));
+                }
+                put_varint_field(sum, 2, DELTA);
+                put_varint_field(sum, 3, (kind != InputKind::Level) as u64);
+            })
+        }
+        InputKind::Timer | InputKind::Distribution => {
+            let bounds = if kind == InputKind::Timer {
+                put_string(buf, 3, "ms");
+                timer_bounds
+            } else {
+                &[]
+            };
+            // Metric.histogram
+            put_message(buf, 9, |histogram| {
+                for (labels, series) in points {
+                    put_message(histogram, 1, |dp| {
+                        encode_histogram_point(dp, labels, series, times, bounds)
+                    });
+                }
+                put_varint_field(histogram, 2, DELTA);
+            })
+        }
+    }
+}
+
+fn encode_number_point(
+    buf: &mut Vec<u8>,
+    labels: &SeriesLabels,
+    series: &Series,
+    (start_time_unix_nano, time_unix_nano): (u64, u64),
+) {
+    let value = match series.aggregate {
+        Aggregate::Sum(value) | Aggregate::Last(value) => value,
+        Aggregate::Histogram { .. } => return,
+    };
+    put_fixed64(buf, 2, start_time_unix_nano);
+    put_fixed64(buf, 3, time_unix_nano);
+    // NumberDataPoint.as_int
+    put_fixed64(buf, 6, value as u64);
+    for (key, value) in labels {
+        put_key_value(buf, 7, key, value);
+    }
+}
+
+fn encode_histogram_point(
+    buf: &mut Vec<u8>,
+    labels: &SeriesLabels,
+    series: &Series,
+    (start_time_unix_nano, time_unix_nano): (u64, u64),
+    bounds: &[f64],
+) {
+    let Aggregate::Histogram {
+        count,
+        sum,
+        min,
+        max,
+        bucket_counts,
+    } = &series.aggregate
+    else {
+        return;
+    };
+    put_fixed64(buf, 2, start_time_unix_nano);
+    put_fixed64(buf, 3, time_unix_nano);
+    put_fixed64(buf, 4, *count);
+    put_fixed64(buf, 5, sum.to_bits());
+    put_message(buf, 6, |counts| {
+        for count in bucket_counts {
+            counts.extend_from_slice(&count.to_le_bytes());
+        }
+    });
+    if !bounds.is_empty() {
+        put_message(buf, 7, |explicit_bounds| {
+            for bound in bounds {
+                explicit_bounds.extend_from_slice(&bound.to_bits().to_le_bytes());
+            }
+        });
+    }
+    for (key, value) in labels {
+        put_key_value(buf, 9, key, value);
+    }
+    put_fixed64(buf, 11, min.to_bits());
+    put_fixed64(buf, 12, max.to_bits());
+}
+
+/// Number of data points rejected by the collector and why, from an `ExportMetricsServiceResponse`.
+fn rejected_points(response: &[u8]) -> Option<(u64, String)> {
+    // ExportMetricsServiceResponse.partial_success
+    let partial_success = get_fields(response)?
+        .into_iter()
+        .find_map(|field| match field {
+            (1, FieldValue::Bytes(bytes)) => Some(bytes),
+            _ => None,
+        })?;
+    let mut rejected = (0, String::new());
+    for field in get_fields(partial_success)? {
+        match field {
+            (1, FieldValue::Varint(count)) => rejected.0 = count,
+            (2, FieldValue::Bytes(message)) => {
+                rejected.1 = String::from_utf8_lossy(message).into_owned()
+            }
+            _ => {}
+        }
+    }
+    Some(rejected)
+}
+
+const WIRE_VARINT: u64 = 0;
+const WIRE_FIXED64: u64 = 1;
+const WIRE_LEN: u64 = 2;
+const WIRE_FIXED32: u64 = 5;
+
+fn put_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+fn put_tag(buf: &mut Vec<u8>, field: u64, wire_type: u64) {
+    put_varint(buf, (field << 3) | wire_type)
+}
+
+fn put_varint_field(buf: &mut Vec<u8>, field: u64, value: u64) {
+    put_tag(buf, field, WIRE_VARINT);
+    put_varint(buf, value);
+}
+
+fn put_fixed64(buf: &mut Vec<u8>, field: u64, value: u64) {
+    put_tag(buf, field, WIRE_FIXED64);
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put_string(buf: &mut Vec<u8>, field: u64, value: &str) {
+    put_tag(buf, field, WIRE_LEN);
+    put_varint(buf, value.len() as u64);
+    buf.extend_from_slice(value.as_bytes());
+}
+
+/// Encode a length-delimited embedded message (or packed repeated field).
+fn put_message<F: FnOnce(&mut Vec<u8>)>(buf: &mut Vec<u8>, field: u64, encode: F) {
+    let mut message = Vec::new();
+    encode(&mut message);
+    put_tag(buf, field, WIRE_LEN);
+    put_varint(buf, message.len() as u64);
+    buf.extend_from_slice(&message);
+}
+
+/// Encode a `KeyValue` with a string `AnyValue`.
+fn put_key_value(buf: &mut Vec<u8>, field: u64, key: &str, value: &str) {
+    put_message(buf, field, |kv| {
+        put_string(kv, 1, key);
+        put_message(kv, 2, |any_value| put_string(any_value, 1, value));
+    })
+}
+
+/// Value of a decoded field, fixed size values are skipped.
+#[derive(Debug)]
+enum FieldValue<'a> {
+    Varint(u64),
+    Bytes(&'a [u8]),
+    Fixed,
+}
+
+fn get_varint(buf: &mut &[u8]) -> Option<u64> {
+    let mut value = 0;
+    for shift in (0..64).step_by(7) {
+        let (byte, rest) = buf.split_first()?;
+        *buf = rest;
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+/// Decode the fields of a message, as field numbers and values. Returns None if the message is malformed.
+fn get_fields(mut buf: &[u8]) -> Option<Vec<(u64, FieldValue<'_>)>> {
+    let mut fields = Vec::new();
+    while !buf.is_empty() {
+        let tag = get_varint(&mut buf)?;
+        let value = match tag & 0x7 {
+            WIRE_VARINT => FieldValue::Varint(get_varint(&mut buf)?),
+            WIRE_LEN => {
+                let len = get_varint(&mut buf)? as usize;
+                let bytes = buf.get(..len)?;
+                buf = &buf[len..];
+                FieldValue::Bytes(bytes)
+            }
+            wire_type => {
+                let len = match wire_type {
+                    WIRE_FIXED64 => 8,
+                    WIRE_FIXED32 => 4,
+                    _ => return None,
+                };
+                buf = buf.get(len..)?;
+                FieldValue::Fixed
+            }
+        };
+        fields.push((tag >> 3, value));
+    }
+    Some(fields)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Buffering;
+
+    /// An input whose requests are never sent, as nothing listens on port 1.
+    fn unreachable() -> Otlp {
+        Otlp::send_to("127.0.0.1:1").unwrap()
+    }
+
+    #[test]
+    fn varint() {
+        let mut buf = vec![];
+        put_varint(&mut buf, 300);
+        assert_eq!(vec![0xAC, 0x02], buf);
+        assert_eq!(Some(300), get_varint(&mut &buf[..]));
+    }
+
+    #[test]
+    fn endpoints() {
+        assert!(Otlp::send_to("http://127.0.0.1:4317/").is_ok());
+        assert!(matches!(
+            Otlp::send_to("https://127.0.0.1:4317"),
+            Err(SinkError::Config(_))
+        ));
+    }
+
+    #[test]
+    fn aggregate_window() {
+        let scope = unreachable().buffered(Buffering::Unlimited).metrics();
+        let start = read_lock!(scope.window).start_unix_nano;
+        let timer = scope.timer("t");
+        for ms in [3, 7, 30] {
+            timer.interval_us(ms * 1000);
+        }
+        let counter = scope.counter("c");
+        counter.count(1);
+        counter.count(2);
+        counter.write(4, labels!("path" => "/"));
+
+        {
+            let window = read_lock!(scope.window);
+            assert_eq!(3, window.series.len());
+            // app labels set by other tests may be attached to every series
+            let unlabeled = |name: &str| {
+                let series = window.series.iter().find(|((series_name, labels), _)| {
+                    series_name == name && labels.iter().all(|(key, _)| key != "path")
+                });
+                &series.unwrap().1.aggregate
+            };
+            assert!(matches!(unlabeled("c"), Aggregate::Sum(3)));
+            match unlabeled("t") {
+                Aggregate::Histogram {
+                    count,
+                    sum,
+                    min,
+                    max,
+                    bucket_counts,
+                } => {
+                    assert_eq!((3, 40.0, 3.0, 30.0), (*count, *sum, *min, *max));
+                    assert_eq!(&vec![1, 1, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0], bucket_counts);
+                }
+                aggregate => panic!("{:?}", aggregate),
+            }
+        }
+
+        scope.flush().unwrap();
+        let window = read_lock!(scope.window);
+        assert!(window.series.is_empty());
+        assert!(window.start_unix_nano >= start);
+    }
+
+    #[test]
+    fn encode_counter() {
+        let otlp = unreachable();
+        let mut window = Window {
+            start_unix_nano: 1,
+            series: BTreeMap::new(),
+        };
+        window.series.insert(
+            ("c".to_string(), vec![]),
+            Series {
+                kind: InputKind::Counter,
+                aggregate: Aggregate::Sum(7),
+            },
+        );
+
+        let number_point = [
+            0x11, 1, 0, 0, 0, 0, 0, 0, 0, // start_time_unix_nano
+            0x19, 2, 0, 0, 0, 0, 0, 0, 0, // time_unix_nano
+            0x31, 7, 0, 0, 0, 0, 0, 0, 0, // as_int
+        ];
+        let mut sum = vec![0x0A, number_point.len() as u8];
+        sum.extend_from_slice(&number_point);
+        sum.extend_from_slice(&[0x10, 1, 0x18, 1]); // delta, monotonic
+        let mut metric = vec![0x0A, 1, b'c', 0x3A, sum.len() as u8];
+        metric.extend_from_slice(&sum);
+        let scope = [
+            0x0A, 10, 0x0A, 8, b'd', b'i', b'p', b's', b't', b'i', b'c', b'k',
+        ];
+        let mut scope_metrics = scope.to_vec();
+        scope_metrics.extend_from_slice(&[0x12, metric.len() as u8]);
+        scope_metrics.extend_from_slice(&metric);
+        let mut resource_metrics = vec![0x0A, 0]; // empty resource
+        resource_metrics.extend_from_slice(&[0x12, scope_metrics.len() as u8]);
+        resource_metrics.extend_from_slice(&scope_metrics);
+        let mut request = vec![0x0A, resource_metrics.len() as u8];
+        request.extend_from_slice(&resource_metrics);
+
+        assert_eq!(request, encode_request(&window, 2, &otlp));
+    }
+
+    #[test]
+    fn partial_success() {
+        let mut response = vec![];
+        put_message(&mut response, 1, |partial_success| {
+            put_varint_field(partial_success, 1, 3);
+            put_string(partial_success, 2, "too old");
+        });
+        assert_eq!(Some((3, "too old".to_string())), rejected_points(&response));
+        assert_eq!(None, rejected_points(&[]));
+    }
+}
@@ -6,10 +6,14 @@ use crate::input::{Input, InputMetric, InputScope};
 use crate::label::Labels;
 use crate::metrics;
 use crate::name::MetricName;
+use crate::output::dry_run::DryRun;
+use crate::output::error::{check_http_url, SinkError};
 use crate::{CachedInput, QueuedInput};
 use crate::{Flush, MetricValue};
 
+use std::collections::{BTreeMap, HashMap};
 use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 #[cfg(not(feature = "parking_lot"))]
 use std::sync::{RwLock, RwLockWriteGuard};
@@ -24,8 +28,15 @@ use std::io;
 pub struct Prometheus {
     attributes: Attributes,
     push_url: String,
+    dry_run: Option<DryRun>,
+    created_timestamps: bool,
+    created: Created,
 }
 
+/// Unix time at which each counter series was first written, by name and label set,
+/// shared by all scopes of an output so that the time does not change from one scope or flush to the next.
+type Created = Arc<RwLock<HashMap<String, String>>>;
+
 impl Input for Prometheus {
     type SCOPE = PrometheusScope;
 
@@ -34,6 +45,9 @@ impl Input for Prometheus {
             attributes: self.attributes.clone(),
             buffer: Arc::new(RwLock::new(String::new())),
             push_url: self.push_url.clone(),
+            dry_run: self.dry_run.clone(),
+            created_timestamps: self.created_timestamps,
+            created: self.created.clone(),
         }
     }
 }
@@ -43,14 +57,37 @@ impl Prometheus {
     /// URL path must include group identifier labels `job`
     /// as shown in https://github.com/prometheus/pushgateway#command-line
     /// For example `http://pushgateway.example.org:9091/metrics/job/some_job`
-    pub fn push_to(url: &str) -> io::Result<Prometheus> {
+    /// Fails if the URL is not a valid `http://` URL.
+    pub fn push_to(url: &str) -> Result<Prometheus, SinkError> {
+        check_http_url(url)?;
         debug!("Pushing to Prometheus {:?}", url);
 
         Ok(Prometheus {
             attributes: Attributes::default(),
             push_url: url.to_string(),
+            dry_run: None,
+            created_timestamps: false,
+            created: Created::default(),
         })
     }
+
+    /// Also write the time at which each counter series was first written as a `<name>_created` line,
+    /// as specified by OpenMetrics, helping consumers tell counter resets from restarts.
+    /// Each combination of name and labels is a distinct series with its own time,
+    /// kept for all later scopes and flushes, e.g. of a bucket.
+    /// Series beyond the first 10000 get no `_created` line.
+    pub fn created_timestamps(&self, enabled: bool) -> Self {
+        let mut cloned = self.clone();
+        cloned.created_timestamps = enabled;
+        cloned
+    }
+
+    /// Record payloads to the provided recorder instead of pushing them to the gateway.
+    pub fn dry_run(&self, recorder: &DryRun) -> Self {
+        let mut cloned = self.clone();
+        cloned.dry_run = Some(recorder.clone());
+        cloned
+    }
 }
 
 impl WithAttributes for Prometheus {
@@ -70,6 +107,9 @@ pub struct PrometheusScope {
     attributes: Attributes,
     buffer: Arc<RwLock<String>>,
     push_url: String,
+    dry_run: Option<DryRun>,
+    created_timestamps: bool,
+    created: Created,
 }
 
 impl InputScope for PrometheusScope {
@@ -83,8 +123,15 @@ impl InputScope for PrometheusScope {
             _ => 1,
         };
 
+        let created =
+            self.created_timestamps && matches!(kind, InputKind::Counter | InputKind::Marker);
+
         let cloned = self.clone();
-        let metric = PrometheusMetric { prefix, scale };
+        let metric = PrometheusMetric {
+            prefix,
+            scale,
+            created,
+        };
 
         let metric_id = MetricId::forge("prometheus", name);
 
@@ -103,36 +150,63 @@ impl Flush for PrometheusScope {
 }
 
 impl PrometheusScope {
+    /// Create a scope sharing this scope's gateway and attributes but with its own buffer.
+    /// Flushing the fork does not flush the original scope, and vice versa.
+    pub fn fork(&self) -> Self {
+        PrometheusScope {
+            attributes: self.attributes.fork(),
+            buffer: Arc::new(RwLock::new(String::new())),
+            push_url: self.push_url.clone(),
+            dry_run: self.dry_run.clone(),
+            created_timestamps: self.created_timestamps,
+            created: self.created.clone(),
+        }
+    }
+
     fn print(&self, metric: &PrometheusMetric, value: MetricValue, labels: Labels) {
         let scaled_value = value / metric.scale;
         let value_str = scaled_value.to_string();
 
-        let mut strbuf = String::new();
-        // prometheus format be like `http_requests_total{method="post",code="200"} 1027 1395066363000`
-        strbuf.push_str(&metric.prefix);
-
-        let labels_map = labels.into_map();
+        let mut labels_str = String::new();
+        // sorted so that a series always prints, and is keyed, the same
+        let labels_map: BTreeMap<_, _> = labels.into_map().into_iter().collect();
         if !labels_map.is_empty() {
-            strbuf.push('{');
+            labels_str.push('{');
             let mut i = labels_map.into_iter();
             let mut next = i.next();
             while let Some((k, v)) = next {
-                strbuf.push_str(&k);
-                strbuf.push_str("=\"");
-                strbuf.push_str(&v);
+                push_label_name(&mut labels_str, &k);
+                labels_str.push_str("=\"");
+                push_label_value(&mut labels_str, &v);
                 next = i.next();
                 if next.is_some() {
-                    strbuf.push_str("\",");
+                    labels_str.push_str("\",");
                 } else {
-                    strbuf.push('"');
+                    labels_str.push('"');
                 }
             }
-            strbuf.push_str("} ");
+            labels_str.push_str("} ");
         } else {
-            strbuf.push(' ');
+            labels_str.push(' ');
         }
+
+        let mut strbuf = String::new();
+        // prometheus format be like `http_requests_total{method="post",code="200"} 1027 1395066363000`
+        strbuf.push_str(&metric.prefix);
+        strbuf.push_str(&labels_str);
         strbuf.push_str(&value_str);
         strbuf.push('\n');
+        if let Some(created) = metric
+            .created
+            .then(|| self.created_at(&metric.prefix, &labels_str))
+            .flatten()
+        {
+            strbuf.push_str(&metric.prefix);
+            strbuf.push_str("_created");
+            strbuf.push_str(&labels_str);
+            strbuf.push_str(&created);
+            strbuf.push('\n');
+        }
 
         let mut buffer = write_lock!(self.buffer);
         if strbuf.len() + buffer.len() > BUFFER_FLUSH_THRESHOLD {
@@ -154,11 +228,37 @@ impl PrometheusScope {
         }
     }
 
+    /// Unix time at which the series of a counter with the printed labels was first written.
+    /// None once the number of series timed reached `MAX_CREATED_SERIES`, rather than an unstable time.
+    fn created_at(&self, prefix: &str, labels_str: &str) -> Option<String> {
+        let series = format!("{}{}", prefix, labels_str);
+        if let Some(created) = read_lock!(self.created).get(&series) {
+            return Some(created.clone());
+        }
+        let mut created = write_lock!(self.created);
+        if created.len() >= MAX_CREATED_SERIES && !created.contains_key(&series) {
+            return None;
+        }
+        let created = created.entry(series).or_insert_with(|| {
+            let since_epoch = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default();
+            format!("{:.3}", since_epoch.as_secs_f64())
+        });
+        Some(created.clone())
+    }
+
     fn flush_inner(&self, mut buf: RwLockWriteGuard<String>) -> io::Result<()> {
         if buf.is_empty() {
             return Ok(());
         }
 
+        if let Some(dry_run) = &self.dry_run {
+            dry_run.record(&buf);
+            buf.clear();
+            return Ok(());
+        }
+
         match minreq::post(self.push_url.as_str())
             .with_body(buf.as_str())
             .send()
@@ -176,7 +276,7 @@ impl PrometheusScope {
             Err(e) => {
                 metrics::PROMETHEUS_SEND_ERR.mark();
                 debug!("Failed to send buffer to Prometheus: {}", e);
-                Err(io::Error::new(io::ErrorKind::Other, e))
+                Err(io::Error::other(e))
             }
         }
     }
@@ -200,11 +300,16 @@ impl CachedInput for Prometheus {}
 // TODO make configurable?
 const BUFFER_FLUSH_THRESHOLD: usize = 65_536;
 
+/// Number of counter series whose creation time is kept, bounding the memory used by labels of high cardinality.
+const MAX_CREATED_SERIES: usize = 10_000;
+
 /// Key of a Prometheus metric.
 #[derive(Debug, Clone)]
 pub struct PrometheusMetric {
     prefix: String,
     scale: isize,
+    /// Whether the creation time of the counter's series is written
+    created: bool,
 }
 
 /// Any remaining buffered data is flushed on Drop.
@@ -215,3 +320,152 @@ impl Drop for PrometheusScope {
         }
     }
 }
+
+/// Label names must match `[a-zA-Z_][a-zA-Z0-9_]*`, invalid characters are replaced with `_`.
+fn push_label_name(strbuf: &mut String, name: &str) {
+    for (i, c) in name.chars().enumerate() {
+        if c.is_ascii_alphabetic() || c == '_' || (i > 0 && c.is_ascii_digit()) {
+            strbuf.push(c)
+        } else {
+            strbuf.push('_')
+        }
+    }
+}
+
+/// Label values may contain any character, but backslashes, double-quotes and newlines must be escaped.
+fn push_label_value(strbuf: &mut String, value: &str) {
+    for c in value.chars() {
+        match c {
+            '\\' => strbuf.push_str("\\\\"),
+            '"' => strbuf.push_str("\\\""),
+            '\n' => strbuf.push_str("\\n"),
+            c => strbuf.push(c),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{stats_summary, AtomicBucket, Buffering, DryRun, Info};
+
+    #[test]
+    fn escape_labels() {
+        let recorder = DryRun::new();
+        let metrics = Prometheus::push_to("http://localhost:9091/metrics/job/test")
+            .unwrap()
+            .dry_run(&recorder)
+            .metrics();
+        let counter = metrics.counter("requests");
+        counter.write(1, labels!("user-agent" => "say \"hi\"\\\n"));
+
+        // concurrent tests may set app labels, only check for our own
+        let payload = recorder.payloads().concat();
+        assert!(payload.starts_with("requests{"));
+        assert!(payload.contains("user_agent=\"say \\\"hi\\\"\\\\\\n\""));
+    }
+
+    #[test]
+    fn created_timestamps() {
+        let recorder = DryRun::new();
+        let metrics = Prometheus::push_to("http://localhost:9091/metrics/job/test")
+            .unwrap()
+            .dry_run(&recorder)
+            .created_timestamps(true)
+            .buffered(Buffering::Unlimited)
+            .metrics();
+        metrics.counter("requests").count(3);
+        metrics.gauge("queue").value(2);
+        metrics.flush().unwrap();
+
+        let payload = recorder.payloads().concat();
+        let lines: Vec<&str> = payload.lines().collect();
+        assert_eq!(3, lines.len(), "{}", payload);
+        assert!(lines[0].starts_with("requests") && lines[0].ends_with(" 3"));
+        assert!(lines[1].starts_with("requests_created"));
+        let created: f64 = lines[1].rsplit(' ').next().unwrap().parse().unwrap();
+        assert!(created > 1_500_000_000.0);
+        assert!(lines[2].starts_with("queue"));
+    }
+
+    #[test]
+    fn created_timestamps_stable() {
+        let recorder = DryRun::new();
+        let bucket = AtomicBucket::new();
+        bucket.stats(stats_summary);
+        bucket.drain(
+            Prometheus::push_to("http://localhost:9091/metrics/job/test")
+                .unwrap()
+                .dry_run(&recorder)
+                .created_timestamps(true)
+                .buffered(Buffering::Unlimited),
+        );
+        let requests = bucket.counter("requests");
+        let created = || {
+            requests.count(1);
+            bucket.flush().unwrap();
+            let payload = recorder.payloads().pop().unwrap();
+            let line = payload
+                .lines()
+                .find(|line| line.starts_with("requests_created"))
+                .map(str::to_string);
+            line.unwrap()
+        };
+
+        let first = created();
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        assert_eq!(first, created());
+    }
+
+    #[test]
+    fn created_timestamps_by_labels() {
+        let recorder = DryRun::new();
+        let metrics = Prometheus::push_to("http://localhost:9091/metrics/job/test")
+            .unwrap()
+            .dry_run(&recorder)
+            .created_timestamps(true)
+            .buffered(Buffering::Unlimited)
+            .metrics();
+        let requests = metrics.counter("requests");
+        requests.write(1, labels!("code" => "200"));
+        metrics.flush().unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        requests.write(1, labels!("code" => "500"));
+        requests.write(1, labels!("code" => "200"));
+        metrics.flush().unwrap();
+
+        let payload = recorder.payloads().concat();
+        let created = |code: &str| -> Vec<String> {
+            payload
+                .lines()
+                .filter(|line| line.starts_with("requests_created"))
+                .filter(|line| line.contains(&format!("code=\"{}\"", code)))
+                .map(|line| line.rsplit(' ').next().unwrap().to_string())
+                .collect()
+        };
+        let ok = created("200");
+        assert_eq!(2, ok.len());
+        assert_eq!(ok[0], ok[1]);
+        let err = created("500");
+        assert_eq!(1, err.len());
+        assert_ne!(ok[0], err[0]);
+    }
+
+    #[test]
+    fn info_metric() {
+        let recorder = DryRun::new();
+        let metrics = Prometheus::push_to("http://localhost:9091/metrics/job/test")
+            .unwrap()
+            .dry_run(&recorder)
+            .buffered(Buffering::Unlimited)
+            .metrics();
+        metrics.set_info(&[("version", "1.2.3")]);
+        metrics.flush().unwrap();
+
+        // concurrent tests may set app labels, only check for our own
+        let payload = recorder.payloads().concat();
+        assert!(payload.starts_with("dipstick_build_info{"));
+        assert!(payload.contains("version=\"1.2.3\""));
+        assert!(payload.ends_with("} 1\n"));
+    }
+}
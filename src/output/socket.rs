@@ -1,21 +1,219 @@
 //! A TCP Socket wrapper that reconnects automatically.
 
+use crate::metrics;
+use crate::output::error::SinkError;
+
+use std::collections::VecDeque;
 use std::fmt;
 use std::io;
 use std::io::Write;
-use std::net::TcpStream;
 use std::net::{SocketAddr, ToSocketAddrs};
+use std::net::{TcpStream, UdpSocket};
 use std::time::{Duration, Instant};
 
 const MIN_RECONNECT_DELAY_MS: u64 = 50;
 const MAX_RECONNECT_DELAY_MS: u64 = 10_000;
 
+const DEFAULT_KEEPALIVE: Duration = Duration::from_secs(60);
+const DEFAULT_MAX_UNSENT_BYTES: usize = 1024 * 1024;
+
+/// Options applied to the sockets of network outputs when they are created.
+/// Options not applicable to an output's transport are ignored.
+#[derive(Debug, Clone)]
+pub struct SocketOptions {
+    local_address: Option<SocketAddr>,
+    ttl: Option<u32>,
+    multicast_ttl: Option<u32>,
+    nodelay: bool,
+    keepalive: Option<Duration>,
+    max_unsent: usize,
+}
+
+impl Default for SocketOptions {
+    fn default() -> Self {
+        SocketOptions {
+            local_address: None,
+            ttl: None,
+            multicast_ttl: None,
+            nodelay: false,
+            keepalive: Some(DEFAULT_KEEPALIVE),
+            max_unsent: DEFAULT_MAX_UNSENT_BYTES,
+        }
+    }
+}
+
+impl SocketOptions {
+    /// Create default options.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Bind UDP sockets to a specific local address (and interface), instead of any address.
+    pub fn bind(&self, local_address: SocketAddr) -> Self {
+        let mut cloned = self.clone();
+        cloned.local_address = Some(local_address);
+        cloned
+    }
+
+    /// Set the IP time-to-live of sent packets.
+    pub fn ttl(&self, ttl: u32) -> Self {
+        let mut cloned = self.clone();
+        cloned.ttl = Some(ttl);
+        cloned
+    }
+
+    /// Set the time-to-live of IPv4 multicast packets sent by UDP sockets, e.g. for multicast statsd collectors.
+    pub fn multicast_ttl(&self, ttl: u32) -> Self {
+        let mut cloned = self.clone();
+        cloned.multicast_ttl = Some(ttl);
+        cloned
+    }
+
+    /// Disable Nagle's algorithm on TCP sockets, sending small writes immediately.
+    pub fn nodelay(&self, nodelay: bool) -> Self {
+        let mut cloned = self.clone();
+        cloned.nodelay = nodelay;
+        cloned
+    }
+
+    /// Probe idle TCP connections after the specified time (60 seconds by default),
+    /// so that connections to crashed servers are eventually detected as dead and reopened.
+    /// The idle time is only set on Linux and Android, other Unix platforms use the system's.
+    /// `None` disables keepalive probes. Keepalive is not supported on non-Unix platforms.
+    pub fn keepalive(&self, idle: Option<Duration>) -> Self {
+        let mut cloned = self.clone();
+        cloned.keepalive = idle;
+        cloned
+    }
+
+    /// Keep at most this many bytes (1MB by default) of data that could not be sent on TCP sockets,
+    /// to be retried once connected again. The oldest data is dropped first.
+    pub fn max_unsent(&self, bytes: usize) -> Self {
+        let mut cloned = self.clone();
+        cloned.max_unsent = bytes;
+        cloned
+    }
+
+    /// Create a non-blocking UDP socket connected to the address.
+    pub(crate) fn udp_socket<A: ToSocketAddrs>(&self, address: A) -> Result<UdpSocket, SinkError> {
+        let addresses = resolve(address)?;
+        let local_address = self
+            .local_address
+            .unwrap_or_else(|| SocketAddr::from(([0, 0, 0, 0], 0)));
+        let socket = UdpSocket::bind(local_address)?;
+        if let Some(ttl) = self.ttl {
+            socket.set_ttl(ttl)?;
+        }
+        if let Some(ttl) = self.multicast_ttl {
+            socket.set_multicast_ttl_v4(ttl)?;
+        }
+        socket.set_nonblocking(true)?;
+        socket.connect(&addresses[..])?;
+        Ok(socket)
+    }
+
+    fn apply_tcp(&self, socket: &TcpStream) -> io::Result<()> {
+        if let Some(ttl) = self.ttl {
+            socket.set_ttl(ttl)?;
+        }
+        if let Some(idle) = self.keepalive {
+            set_keepalive(socket, idle)?;
+        }
+        socket.set_nodelay(self.nodelay)
+    }
+}
+
+#[cfg(unix)]
+fn set_keepalive(socket: &TcpStream, idle: Duration) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let fd = socket.as_raw_fd();
+    set_socket_option(fd, libc::SOL_SOCKET, libc::SO_KEEPALIVE, 1)?;
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    {
+        let secs = idle.as_secs().clamp(1, libc::c_int::MAX as u64) as libc::c_int;
+        set_socket_option(fd, libc::IPPROTO_TCP, libc::TCP_KEEPIDLE, secs)?;
+        set_socket_option(fd, libc::IPPROTO_TCP, libc::TCP_KEEPINTVL, secs)?;
+    }
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    let _ = idle;
+
+    Ok(())
+}
+
+#[cfg(unix)]
+fn set_socket_option(
+    fd: libc::c_int,
+    level: libc::c_int,
+    name: libc::c_int,
+    value: libc::c_int,
+) -> io::Result<()> {
+    let result = unsafe {
+        libc::setsockopt(
+            fd,
+            level,
+            name,
+            std::ptr::addr_of!(value).cast::<libc::c_void>(),
+            size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if result == -1 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(not(unix))]
+fn set_keepalive(_socket: &TcpStream, _idle: Duration) -> io::Result<()> {
+    debug!("TCP keepalive is not supported on this platform");
+    Ok(())
+}
+
+/// Returns an error if the connection was closed or reset by the peer.
+/// Metric servers never send any data, so anything read from the socket signals the end of the connection.
+fn check_alive(socket: &TcpStream) -> io::Result<()> {
+    let mut buf = [0u8; 1];
+    match socket.peek(&mut buf) {
+        Ok(0) => Err(io::Error::new(
+            io::ErrorKind::ConnectionAborted,
+            "connection closed by peer",
+        )),
+        Err(e) if e.kind() != io::ErrorKind::WouldBlock => Err(e),
+        _ => Ok(()),
+    }
+}
+
 /// A socket that retries
 pub struct RetrySocket {
     retries: usize,
     next_try: Instant,
     addresses: Vec<SocketAddr>,
+    options: SocketOptions,
     socket: Option<TcpStream>,
+    /// Payloads not sent yet, the first one possibly partially written to the current connection
+    unsent: VecDeque<Vec<u8>>,
+    unsent_bytes: usize,
+    /// Bytes of the first unsent payload already written to the current connection
+    offset: usize,
+}
+
+/// Resolve the socket addresses of a server.
+/// Malformed addresses and addresses resolving to nothing are configuration errors,
+/// any other failure of the lookup is a name resolution error.
+pub(crate) fn resolve<A: ToSocketAddrs>(address: A) -> Result<Vec<SocketAddr>, SinkError> {
+    let addresses: Vec<SocketAddr> = match address.to_socket_addrs() {
+        Ok(addresses) => addresses.collect(),
+        Err(e) if e.kind() == io::ErrorKind::InvalidInput => {
+            return Err(SinkError::Config(format!("invalid address: {}", e)))
+        }
+        Err(e) => return Err(SinkError::Dns(e)),
+    };
+    if addresses.is_empty() {
+        return Err(SinkError::Config("address resolved to nothing".to_string()));
+    }
+    Ok(addresses)
 }
 
 impl fmt::Debug for RetrySocket {
@@ -27,17 +225,21 @@ impl fmt::Debug for RetrySocket {
 
 impl RetrySocket {
     /// Create a new socket that will retry
-    pub fn new<A: ToSocketAddrs>(addresses: A) -> io::Result<Self> {
+    pub fn new<A: ToSocketAddrs>(addresses: A, options: &SocketOptions) -> Result<Self, SinkError> {
         // FIXME instead of collecting addresses early, store ToSocketAddrs as trait object
         // FIXME apparently this can not be one because of Associated Types clusterfuck (?!)
-        let addresses = addresses.to_socket_addrs()?.collect();
+        let addresses = resolve(addresses)?;
         const INIT_DELAY: Duration = Duration::from_millis(MIN_RECONNECT_DELAY_MS);
         let next_try = Instant::now().checked_add(INIT_DELAY).expect("init delay");
         let mut socket = RetrySocket {
             retries: 0,
             next_try,
             addresses,
+            options: options.clone(),
             socket: None,
+            unsent: VecDeque::new(),
+            unsent_bytes: 0,
+            offset: 0,
         };
 
         // try early connect
@@ -47,12 +249,92 @@ impl RetrySocket {
 }
 
 impl RetrySocket {
+    /// Queue the payload, then send as much of the queued data as possible without blocking.
+    /// Returns the number of bytes sent, which may include data from previous payloads.
+    /// Data that could not be sent is retried upon the next send, on a new connection if the previous one failed.
+    /// Payloads are resent whole on a new connection, the server discards any partially received data.
+    pub fn send(&mut self, payload: &[u8]) -> io::Result<usize> {
+        if !payload.is_empty() {
+            self.unsent_bytes += payload.len();
+            self.unsent.push_back(payload.to_vec());
+        }
+        // drop the oldest payloads beyond the limit, but never one partially written
+        while self.unsent_bytes > self.options.max_unsent && self.unsent.len() > 1 {
+            let oldest = if self.offset > 0 { 1 } else { 0 };
+            if let Some(dropped) = self.unsent.remove(oldest) {
+                self.unsent_bytes -= dropped.len();
+                metrics::SOCKET_DROPPED_BYTES.count(dropped.len());
+                warn!(
+                    "Dropped {} unsent bytes to {:?}, exceeding limit of {} bytes",
+                    dropped.len(),
+                    self.addresses,
+                    self.options.max_unsent
+                );
+            }
+        }
+        self.send_unsent()
+    }
+
+    fn send_unsent(&mut self) -> io::Result<usize> {
+        if let Some(socket) = &self.socket {
+            if let Err(e) = check_alive(socket) {
+                metrics::SOCKET_STALE.mark();
+                warn!(
+                    "Connection to {:?} is stale, reconnecting. {}",
+                    self.addresses, e
+                );
+                self.socket = None;
+                self.offset = 0;
+            }
+        }
+        if let Err(e) = self.try_connect() {
+            return Err(self.backoff(e));
+        }
+
+        let mut sent = 0;
+        let result = match self.socket {
+            Some(ref mut socket) => loop {
+                let Some(payload) = self.unsent.front() else {
+                    break Ok(sent);
+                };
+                match socket.write(&payload[self.offset..]) {
+                    Ok(0) => break Err(io::Error::from(io::ErrorKind::WriteZero)),
+                    Ok(written) => {
+                        sent += written;
+                        self.offset += written;
+                        if self.offset == payload.len() {
+                            self.unsent_bytes -= payload.len();
+                            self.unsent.pop_front();
+                            self.offset = 0;
+                        }
+                    }
+                    // send buffer is full, retry remaining data later
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => break Ok(sent),
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                    Err(e) => break Err(e),
+                }
+            },
+            // still none, quiescent
+            None => Err(io::Error::from(io::ErrorKind::NotConnected)),
+        };
+
+        result.map_err(|e| {
+            if self.socket.is_some() {
+                self.offset = 0;
+                self.backoff(e)
+            } else {
+                e
+            }
+        })
+    }
+
     fn try_connect(&mut self) -> io::Result<()> {
         if self.socket.is_none() {
             let now = Instant::now();
             if now > self.next_try {
                 let addresses: &[SocketAddr] = self.addresses.as_ref();
                 let socket = TcpStream::connect(addresses)?;
+                self.options.apply_tcp(&socket)?;
                 socket.set_nonblocking(true)?;
                 self.retries = 0;
                 info!("Connected to {:?}", addresses);
@@ -98,6 +380,20 @@ impl RetrySocket {
     }
 }
 
+/// Any unsent data is given a last chance on Drop.
+impl Drop for RetrySocket {
+    fn drop(&mut self) {
+        if !self.unsent.is_empty() {
+            if let Err(e) = self.send_unsent() {
+                warn!(
+                    "Could not send {} bytes upon Drop: {}",
+                    self.unsent_bytes, e
+                )
+            }
+        }
+    }
+}
+
 impl Write for RetrySocket {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
         self.with_socket(|sock| sock.write(buf))
@@ -107,3 +403,49 @@ impl Write for RetrySocket {
         self.with_socket(TcpStream::flush)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Read;
+    use std::net::TcpListener;
+    use std::thread::sleep;
+
+    fn receive(listener: &TcpListener, len: usize) -> String {
+        let (mut conn, _) = listener.accept().unwrap();
+        conn.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut buf = vec![0; len];
+        conn.read_exact(&mut buf).unwrap();
+        String::from_utf8(buf).unwrap()
+    }
+
+    #[test]
+    fn reconnect_stale() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut socket =
+            RetrySocket::new(listener.local_addr().unwrap(), &SocketOptions::default()).unwrap();
+        sleep(Duration::from_millis(MIN_RECONNECT_DELAY_MS * 2));
+
+        assert_eq!(6, socket.send(b"a 1 1\n").unwrap());
+        // server closes the connection
+        assert_eq!("a 1 1\n", receive(&listener, 6));
+        sleep(Duration::from_millis(MIN_RECONNECT_DELAY_MS));
+
+        assert_eq!(6, socket.send(b"b 2 2\n").unwrap());
+        assert_eq!("b 2 2\n", receive(&listener, 6));
+    }
+
+    #[test]
+    fn drop_oldest_unsent() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        drop(listener);
+        let mut socket = RetrySocket::new(address, &SocketOptions::new().max_unsent(12)).unwrap();
+
+        for payload in [b"a 1 1\n", b"b 2 2\n", b"c 3 3\n"] {
+            assert!(socket.send(payload).is_err());
+        }
+        assert_eq!(12, socket.unsent_bytes);
+        assert_eq!(Some(&b"b 2 2\n".to_vec()), socket.unsent.front());
+    }
+}
@@ -6,15 +6,20 @@ use crate::attributes::{
 use crate::input::InputKind;
 use crate::input::{Input, InputMetric, InputScope};
 use crate::metrics;
-use crate::name::MetricName;
+use crate::name::{fnv1a_hash, MetricName, NameHash};
+use crate::output::dry_run::DryRun;
+use crate::output::error::SinkError;
+use crate::output::socket::SocketOptions;
 use crate::pcg32;
-use crate::{CachedInput, QueuedInput};
+use crate::{AtomicBucket, CachedInput, QueuedInput};
+use crate::{Cancel, CancelGuard, CancelHandle, ScheduleFlush};
 use crate::{Flush, MetricValue};
 use std::fmt::Write;
 
 use std::net::ToSocketAddrs;
 use std::net::UdpSocket;
 use std::sync::Arc;
+use std::time::Duration;
 
 #[cfg(not(feature = "parking_lot"))]
 use std::sync::{RwLock, RwLockWriteGuard};
@@ -33,20 +38,49 @@ const MAX_UDP_PAYLOAD: usize = 576;
 pub struct Statsd {
     attributes: Attributes,
     socket: Arc<UdpSocket>,
+    dry_run: Option<DryRun>,
 }
 
 impl Statsd {
     /// Send metrics to a statsd server at the address and port provided.
-    pub fn send_to<ADDR: ToSocketAddrs>(address: ADDR) -> io::Result<Statsd> {
-        let socket = Arc::new(UdpSocket::bind("0.0.0.0:0")?);
-        socket.set_nonblocking(true)?;
-        socket.connect(address)?;
+    pub fn send_to<ADDR: ToSocketAddrs>(address: ADDR) -> Result<Statsd, SinkError> {
+        Statsd::send_to_with(address, &SocketOptions::default())
+    }
+
+    /// Send metrics to a statsd server at the address and port provided,
+    /// from a socket created with the specified options.
+    pub fn send_to_with<ADDR: ToSocketAddrs>(
+        address: ADDR,
+        options: &SocketOptions,
+    ) -> Result<Statsd, SinkError> {
+        let socket = Arc::new(options.udp_socket(address)?);
 
         Ok(Statsd {
             attributes: Attributes::default(),
             socket,
+            dry_run: None,
         })
     }
+
+    /// Record payloads to the provided recorder instead of sending them to the server.
+    pub fn dry_run(&self, recorder: &DryRun) -> Self {
+        let mut cloned = self.clone();
+        cloned.dry_run = Some(recorder.clone());
+        cloned
+    }
+}
+
+/// Aggregate metrics locally, publishing their statistics to a statsd server every `period`.
+/// Flushing is done by the background scheduler thread until the returned guard is dropped.
+/// Values recorded after the last scheduled flush can still be published by flushing the bucket manually.
+pub fn aggregate_to_statsd<ADDR: ToSocketAddrs>(
+    address: ADDR,
+    period: Duration,
+) -> Result<(AtomicBucket, CancelGuard<CancelHandle>), SinkError> {
+    let bucket = AtomicBucket::new();
+    bucket.drain(Statsd::send_to(address)?);
+    let flush = bucket.flush_every(period).into_guard();
+    Ok((bucket, flush))
 }
 
 impl Buffered for Statsd {}
@@ -65,6 +99,7 @@ impl Input for Statsd {
             attributes: self.attributes.clone(),
             buffer: Arc::new(RwLock::new(String::with_capacity(MAX_UDP_PAYLOAD))),
             socket: self.socket.clone(),
+            dry_run: self.dry_run.clone(),
         }
     }
 }
@@ -84,6 +119,7 @@ pub struct StatsdScope {
     attributes: Attributes,
     buffer: Arc<RwLock<String>>,
     socket: Arc<UdpSocket>,
+    dry_run: Option<DryRun>,
 }
 
 impl Sampled for StatsdScope {}
@@ -100,6 +136,7 @@ impl InputScope for StatsdScope {
             InputKind::Marker | InputKind::Counter => "c",
             InputKind::Gauge | InputKind::Level => "g",
             InputKind::Timer => "ms",
+            InputKind::Distribution => "d",
         });
 
         let scale = match kind {
@@ -111,7 +148,7 @@ impl InputScope for StatsdScope {
         let cloned = self.clone();
         let metric_id = MetricId::forge("statsd", name);
 
-        if let Sampling::Random(float_rate) = self.get_sampling() {
+        if let Sampling::Random(float_rate) = self.get_kind_sampling(kind) {
             let _ = writeln!(suffix, "|@{}", float_rate);
             let int_sampling_rate = pcg32::to_int_rate(float_rate);
             let metric = StatsdMetric {
@@ -148,6 +185,17 @@ impl Flush for StatsdScope {
 }
 
 impl StatsdScope {
+    /// Create a scope sharing this scope's socket and attributes but with its own buffer.
+    /// Flushing the fork does not flush the original scope, and vice versa.
+    pub fn fork(&self) -> Self {
+        StatsdScope {
+            attributes: self.attributes.fork(),
+            buffer: Arc::new(RwLock::new(String::with_capacity(MAX_UDP_PAYLOAD))),
+            socket: self.socket.clone(),
+            dry_run: self.dry_run.clone(),
+        }
+    }
+
     fn print(&self, metric: &StatsdMetric, value: MetricValue) {
         let scaled_value = value / metric.scale;
         let value_str = scaled_value.to_string();
@@ -182,6 +230,13 @@ impl StatsdScope {
     }
 
     fn flush_inner(&self, mut buffer: RwLockWriteGuard<String>) -> io::Result<()> {
+        if let Some(dry_run) = &self.dry_run {
+            if !buffer.is_empty() {
+                dry_run.record(&buffer);
+                buffer.clear();
+            }
+            return Ok(());
+        }
         if !buffer.is_empty() {
             match self.socket.send(buffer.as_bytes()) {
                 Ok(size) => {
@@ -227,6 +282,136 @@ impl Drop for StatsdScope {
     }
 }
 
+/// Statsd Input distributing metrics over multiple statsd servers.
+/// Each metric is assigned to a server using a consistent hash of its qualified name,
+/// so that all of its values are sent to the same server, even across process restarts.
+#[derive(Clone, Debug)]
+pub struct StatsdSharded {
+    attributes: Attributes,
+    shards: Vec<Statsd>,
+    name_hash: NameHash,
+}
+
+/// Send metrics to multiple statsd servers, sharding them by name.
+pub fn statsd_sharded<ADDR: ToSocketAddrs>(addresses: &[ADDR]) -> Result<StatsdSharded, SinkError> {
+    if addresses.is_empty() {
+        return Err(SinkError::Config("No statsd server address".to_string()));
+    }
+    Ok(StatsdSharded {
+        attributes: Attributes::default(),
+        shards: addresses
+            .iter()
+            .map(Statsd::send_to)
+            .collect::<Result<_, _>>()?,
+        name_hash: fnv1a_hash,
+    })
+}
+
+impl StatsdSharded {
+    /// Record payloads to the provided recorder instead of sending them to the servers.
+    pub fn dry_run(&self, recorder: &DryRun) -> Self {
+        let mut cloned = self.clone();
+        cloned.shards = self.shards.iter().map(|s| s.dry_run(recorder)).collect();
+        cloned
+    }
+
+    /// Hash qualified metric names with the provided function instead of the default `fnv1a_hash`.
+    /// Every process sending to the same servers should use the same hash.
+    pub fn name_hash(&self, name_hash: NameHash) -> Self {
+        let mut cloned = self.clone();
+        cloned.name_hash = name_hash;
+        cloned
+    }
+}
+
+impl Buffered for StatsdSharded {}
+
+impl Sampled for StatsdSharded {}
+
+impl QueuedInput for StatsdSharded {}
+
+impl CachedInput for StatsdSharded {}
+
+impl WithAttributes for StatsdSharded {
+    fn get_attributes(&self) -> &Attributes {
+        &self.attributes
+    }
+    fn mut_attributes(&mut self) -> &mut Attributes {
+        &mut self.attributes
+    }
+}
+
+impl Input for StatsdSharded {
+    type SCOPE = StatsdShardedScope;
+
+    fn metrics(&self) -> Self::SCOPE {
+        StatsdShardedScope {
+            attributes: self.attributes.clone(),
+            shards: self.shards.iter().map(Input::metrics).collect(),
+            name_hash: self.name_hash,
+        }
+    }
+}
+
+/// Statsd scope distributing metrics over multiple statsd servers.
+#[derive(Debug, Clone)]
+pub struct StatsdShardedScope {
+    attributes: Attributes,
+    shards: Vec<StatsdScope>,
+    name_hash: NameHash,
+}
+
+impl InputScope for StatsdShardedScope {
+    fn new_metric(&self, name: MetricName, kind: InputKind) -> InputMetric {
+        let name = self.prefix_prepend(name);
+        let hash = (self.name_hash)(&name.join("."));
+        let shard = &self.shards[shard_index(hash, self.shards.len())];
+        shard
+            .sampled(self.get_kind_sampling(kind))
+            .buffered(self.get_buffering())
+            .new_metric(name, kind)
+    }
+}
+
+impl Flush for StatsdShardedScope {
+    fn flush(&self) -> io::Result<()> {
+        self.notify_flush_listeners();
+        let mut result = Ok(());
+        for shard in &self.shards {
+            if let Err(e) = shard.flush() {
+                result = Err(e)
+            }
+        }
+        result
+    }
+}
+
+impl WithAttributes for StatsdShardedScope {
+    fn get_attributes(&self) -> &Attributes {
+        &self.attributes
+    }
+    fn mut_attributes(&mut self) -> &mut Attributes {
+        &mut self.attributes
+    }
+}
+
+impl Buffered for StatsdShardedScope {}
+
+impl Sampled for StatsdShardedScope {}
+
+/// Pick a shard for the name's hash using jump consistent hashing.
+/// Adding a shard only moves the metrics that it takes over.
+fn shard_index(mut key: u64, shards: usize) -> usize {
+    let mut bucket: i64 = -1;
+    let mut jump: i64 = 0;
+    while jump < shards as i64 {
+        bucket = jump;
+        key = key.wrapping_mul(2_862_933_555_777_941_757).wrapping_add(1);
+        jump = ((bucket + 1) as f64 * ((1_u64 << 31) as f64 / ((key >> 33) + 1) as f64)) as i64;
+    }
+    bucket as usize
+}
+
 // use crate::output::format::LineOp::{ScaledValueAsText, ValueAsText};
 //
 // impl LineFormat for StatsdScope {
@@ -263,6 +448,151 @@ impl Drop for StatsdScope {
 //     }
 // }
 
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::attributes::Buffering;
+
+    #[test]
+    fn socket_options() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let options = SocketOptions::new()
+            .bind("127.0.0.1:0".parse().unwrap())
+            .ttl(16);
+        let metrics = Statsd::send_to_with(server.local_addr().unwrap(), &options)
+            .unwrap()
+            .metrics();
+        metrics.counter("requests").count(1);
+
+        let mut buf = [0u8; 64];
+        server
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let (len, from) = server.recv_from(&mut buf).unwrap();
+        assert!(from.ip().is_loopback());
+        assert_eq!(b"requests:1|c\n", &buf[..len]);
+    }
+
+    #[test]
+    fn aggregate_to_statsd_flushes_periodically() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        server
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let (bucket, flush) =
+            aggregate_to_statsd(server.local_addr().unwrap(), Duration::from_millis(10)).unwrap();
+
+        let hits = bucket.counter("hits");
+        hits.count(2);
+
+        let mut buf = [0u8; MAX_UDP_PAYLOAD];
+        let len = server.recv(&mut buf).unwrap();
+        assert!(String::from_utf8_lossy(&buf[..len]).contains("hits"));
+        drop(flush);
+    }
+
+    #[test]
+    fn shards_are_stable() {
+        for shards in 1..10 {
+            for name in ["a", "app.requests", "app.latency"] {
+                let index = shard_index(fnv1a_hash(name), shards);
+                assert!(index < shards);
+                // growing the shards only moves metrics to the new shard
+                let grown = shard_index(fnv1a_hash(name), shards + 1);
+                assert!(grown == index || grown == shards);
+            }
+        }
+        assert_eq!(0, shard_index(fnv1a_hash("app.requests"), 1));
+    }
+
+    #[test]
+    fn custom_name_hash() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        static HASHED: AtomicUsize = AtomicUsize::new(0);
+        fn counting_hash(name: &str) -> u64 {
+            HASHED.fetch_add(1, Ordering::Relaxed);
+            fnv1a_hash(name)
+        }
+
+        let metrics = statsd_sharded(&["localhost:8125", "localhost:8126"])
+            .unwrap()
+            .name_hash(counting_hash)
+            .dry_run(&DryRun::new())
+            .metrics();
+        metrics.counter("a").count(1);
+        metrics.counter("b").count(1);
+        assert_eq!(2, HASHED.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn sharded_metric_sticks_to_one_shard() {
+        let recorder = DryRun::new();
+        let metrics = statsd_sharded(&["localhost:8125", "localhost:8126", "localhost:8127"])
+            .unwrap()
+            .dry_run(&recorder)
+            .named("app")
+            .buffered(Buffering::Unlimited)
+            .metrics();
+
+        let requests = metrics.counter("requests");
+        requests.count(1);
+        requests.count(2);
+        metrics.flush().unwrap();
+
+        // a single shard buffered both values
+        let payloads = recorder.payloads();
+        assert_eq!(1, payloads.len());
+        assert!(payloads[0].starts_with("app.requests:1|c\n"));
+        assert!(payloads[0].ends_with("app.requests:2|c\n"));
+    }
+
+    #[test]
+    fn dry_run_records_payloads() {
+        let recorder = DryRun::new();
+        let metrics = Statsd::send_to("localhost:8125")
+            .unwrap()
+            .dry_run(&recorder)
+            .named("app")
+            .metrics();
+
+        metrics.counter("requests").count(3);
+        metrics.timer("latency").interval_us(12_000);
+        metrics.distribution("size").record(512);
+
+        assert_eq!(
+            vec![
+                "app.requests:3|c\n",
+                "app.latency:12|ms\n",
+                "app.size:512|d\n"
+            ],
+            recorder.payloads()
+        );
+    }
+
+    #[test]
+    fn forked_scope_flushes_independently() {
+        let recorder = DryRun::new();
+        let metrics = Statsd::send_to("localhost:8125")
+            .unwrap()
+            .dry_run(&recorder)
+            .buffered(Buffering::Unlimited)
+            .metrics();
+        let fork = metrics.fork();
+
+        // metric handles are kept, as dropping them flushes their scope
+        let parent_marker = metrics.marker("parent");
+        let fork_marker = fork.marker("fork");
+        parent_marker.mark();
+        fork_marker.mark();
+
+        fork.flush().unwrap();
+        assert_eq!(vec!["fork:1|c\n"], recorder.payloads());
+
+        metrics.flush().unwrap();
+        assert_eq!(vec!["fork:1|c\n", "parent:1|c\n"], recorder.payloads());
+    }
+}
+
 #[cfg(feature = "bench")]
 mod bench {
     use super::*;
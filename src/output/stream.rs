@@ -61,11 +61,7 @@ impl Stream<File> {
 
     /// Write metric values to a file.
     pub fn write_to_file<P: AsRef<Path>>(file: P) -> io::Result<Stream<File>> {
-        let file = OpenOptions::new()
-            .write(true)
-            .create(true)
-            .append(true)
-            .open(file)?;
+        let file = OpenOptions::new().create(true).append(true).open(file)?;
         Ok(Stream::write_to(file))
     }
 
@@ -87,6 +83,7 @@ impl Stream<File> {
         let file = OpenOptions::new()
             .write(true)
             .create(true)
+            .truncate(clobber)
             .create_new(!clobber)
             .open(file)?;
         Ok(Stream::write_to(file))
@@ -160,6 +157,18 @@ pub struct TextScope<W: Write + Send + Sync + 'static> {
     input: Stream<W>,
 }
 
+impl<W: Write + Send + Sync + 'static> TextScope<W> {
+    /// Create a scope sharing this scope's output and attributes but with its own buffer.
+    /// Flushing the fork does not flush the original scope, and vice versa.
+    pub fn fork(&self) -> Self {
+        TextScope {
+            attributes: self.attributes.fork(),
+            entries: Arc::new(RwLock::new(Vec::new())),
+            input: self.input.clone(),
+        }
+    }
+}
+
 impl<W: Write + Send + Sync + 'static> Clone for TextScope<W> {
     fn clone(&self) -> Self {
         TextScope {
@@ -188,43 +197,32 @@ impl<W: Write + Send + Sync + 'static> InputScope for TextScope<W> {
 
         let entries = self.entries.clone();
         let metric_id = MetricId::forge("stream", name);
-
-        if self.is_buffered() {
-            InputMetric::new(metric_id, move |value, labels| {
-                let mut buffer = Vec::with_capacity(32);
-                match template.print(&mut buffer, value, |key| labels.lookup(key)) {
-                    Ok(()) => {
-                        let mut entries = write_lock!(entries);
-                        entries.push(buffer)
-                    }
-                    Err(err) => debug!("{}", err),
-                }
-            })
-        } else {
-            // unbuffered
-            let input = self.input.clone();
-            InputMetric::new(metric_id, move |value, labels| {
-                let mut buffer = Vec::with_capacity(32);
-                match template.print(&mut buffer, value, |key| labels.lookup(key)) {
-                    Ok(()) => {
-                        let mut input = write_lock!(input.inner);
-                        if let Err(e) = input.write_all(&buffer).and_then(|_| input.flush()) {
-                            debug!("Could not write text metrics: {}", e)
-                        }
+        let attributes = self.attributes.clone();
+        let input = self.input.clone();
+
+        InputMetric::new(metric_id, move |value, labels| {
+            let mut buffer = Vec::with_capacity(32);
+            match template.print(&mut buffer, value, |key| labels.lookup(key)) {
+                // buffering may be suspended at any time, check upon every write
+                Ok(()) if attributes.is_buffered() => write_lock!(entries).push(buffer),
+                Ok(()) => {
+                    // write any previously buffered entries first, to preserve ordering
+                    let mut entries = write_lock!(entries);
+                    entries.push(buffer);
+                    if let Err(e) = input.write_entries(&mut entries) {
+                        debug!("Could not write text metrics: {}", e)
                     }
-                    Err(err) => debug!("{}", err),
                 }
-            })
-        }
+                Err(err) => debug!("{}", err),
+            }
+        })
     }
 }
 
-impl<W: Write + Send + Sync + 'static> Flush for TextScope<W> {
-    fn flush(&self) -> io::Result<()> {
-        self.notify_flush_listeners();
-        let mut entries = write_lock!(self.entries);
+impl<W: Write + Send + Sync + 'static> Stream<W> {
+    fn write_entries(&self, entries: &mut Vec<Vec<u8>>) -> io::Result<()> {
         if !entries.is_empty() {
-            let mut input = write_lock!(self.input.inner);
+            let mut input = write_lock!(self.inner);
             for entry in entries.drain(..) {
                 input.write_all(&entry)?
             }
@@ -234,6 +232,14 @@ impl<W: Write + Send + Sync + 'static> Flush for TextScope<W> {
     }
 }
 
+impl<W: Write + Send + Sync + 'static> Flush for TextScope<W> {
+    fn flush(&self) -> io::Result<()> {
+        self.notify_flush_listeners();
+        let mut entries = write_lock!(self.entries);
+        self.input.write_entries(&mut entries)
+    }
+}
+
 impl<W: Write + Send + Sync + 'static> Drop for TextScope<W> {
     fn drop(&mut self) {
         if let Err(e) = self.flush() {
@@ -246,12 +252,31 @@ impl<W: Write + Send + Sync + 'static> Drop for TextScope<W> {
 mod test {
     use super::*;
     use crate::input::InputKind;
+    use crate::Buffering;
     use std::io;
 
     #[test]
     fn sink_print() {
-        let c = super::Stream::write_to(io::stdout()).metrics();
+        let c = Stream::write_to(io::stdout()).metrics();
         let m = c.new_metric("test".into(), InputKind::Marker);
         m.write(33, labels![]);
     }
+
+    #[test]
+    fn immediate_flush() {
+        let metrics = Stream::write_to(Vec::new())
+            .buffered(Buffering::Unlimited)
+            .metrics();
+        let counter = metrics.counter("counter");
+        let written = || String::from_utf8(read_lock!(metrics.input.inner).clone()).unwrap();
+
+        counter.count(1);
+        assert!(written().is_empty());
+
+        metrics.with_immediate_flush(|| counter.count(2));
+        assert_eq!("counter 1\ncounter 2\n", written());
+
+        counter.count(3);
+        assert_eq!("counter 1\ncounter 2\n", written());
+    }
 }
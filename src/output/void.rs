@@ -45,7 +45,9 @@ impl Input for Void {
 
 impl InputScope for VoidInput {
     fn new_metric(&self, name: MetricName, _kind: InputKind) -> InputMetric {
-        InputMetric::new(MetricId::forge("void", name), |_value, _labels| {})
+        let metric = InputMetric::new(MetricId::forge("void", name), |_value, _labels| {});
+        metric.set_enabled(false);
+        metric
     }
 }
 
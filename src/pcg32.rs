@@ -1,7 +1,6 @@
 //! PCG32 random number generation for fast sampling
 //! Kept here for low dependency count.
 
-#![cfg_attr(feature = "tool_lints", allow(clippy::unreadable_literal))]
 #![allow(clippy::unreadable_literal)]
 
 use std::cell::RefCell;
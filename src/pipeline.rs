@@ -0,0 +1,125 @@
+//! Compose sampling and aggregation in the order that keeps aggregated statistics correct.
+
+use crate::atomic::AtomicBucket;
+use crate::attributes::{Sampled, Sampling};
+use crate::input::{Input, InputKind};
+use crate::name::MetricName;
+use crate::stats::ScoreType;
+use crate::MetricValue;
+
+use std::sync::Arc;
+
+type StatsFn = Arc<
+    dyn Fn(InputKind, MetricName, ScoreType) -> Option<(InputKind, MetricName, MetricValue)>
+        + Send
+        + Sync,
+>;
+
+/// Build an aggregating pipeline, sampling values before they are aggregated.
+/// Aggregated counts and sums are then scaled back up by the sampling rate when published.
+///
+/// Sampling is only available before `aggregate()`, so that published statistics can not be sampled by mistake:
+/// ```compile_fail
+/// use dipstick::*;
+/// let bucket = Pipeline::new()
+///     .aggregate()
+///     .sample(Sampling::Random(0.1));
+/// ```
+///
+/// ```
+/// use dipstick::*;
+/// let bucket = Pipeline::new()
+///     .sample(Sampling::Random(0.1))
+///     .aggregate()
+///     .drain(Stream::write_to_stdout());
+/// bucket.counter("requests").count(1);
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct Pipeline {
+    sampling: Sampling,
+}
+
+impl Pipeline {
+    /// Start a pipeline recording every value.
+    pub fn new() -> Pipeline {
+        Pipeline::default()
+    }
+
+    /// Sample values according to the specified rate before they are aggregated.
+    pub fn sample(self, sampling: Sampling) -> Pipeline {
+        Pipeline { sampling }
+    }
+
+    /// Aggregate the sampled values, publishing their statistics once drained.
+    pub fn aggregate(self) -> Aggregation {
+        Aggregation {
+            sampling: self.sampling,
+            stats: None,
+        }
+    }
+}
+
+/// The aggregating stage of a `Pipeline`, which can no longer be sampled.
+#[derive(Clone, Default)]
+pub struct Aggregation {
+    sampling: Sampling,
+    stats: Option<StatsFn>,
+}
+
+impl Aggregation {
+    /// Publish the statistics selected by this function instead of the bucket's default stats.
+    pub fn stats<F>(self, func: F) -> Aggregation
+    where
+        F: Fn(InputKind, MetricName, ScoreType) -> Option<(InputKind, MetricName, MetricValue)>
+            + Send
+            + Sync
+            + 'static,
+    {
+        Aggregation {
+            stats: Some(Arc::new(func)),
+            ..self
+        }
+    }
+
+    /// Create the bucket aggregating sampled values, publishing its statistics to the output when flushed.
+    /// The output should not be sampled itself, since it receives statistics rather than values.
+    pub fn drain(self, output: impl Input) -> AtomicBucket {
+        let bucket = AtomicBucket::new().sampled(self.sampling);
+        if let Some(stats) = self.stats {
+            bucket.stats(move |kind, name, score| stats(kind, name, score));
+        }
+        bucket.drain(output);
+        bucket
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::input::InputScope;
+    use crate::stats::stats_all;
+    use crate::test_util::TestMetrics;
+    use crate::Flush;
+
+    #[test]
+    fn sampled_then_aggregated() {
+        let tm = TestMetrics::new();
+        let bucket = Pipeline::new()
+            .sample(Sampling::Random(0.5))
+            .aggregate()
+            .stats(stats_all)
+            .drain(tm.sink());
+
+        let counter = bucket.counter("requests");
+        for _ in 0..10_000 {
+            counter.count(2);
+        }
+        bucket.flush().unwrap();
+
+        // roughly half the values were recorded, but the published statistics are scaled back up
+        let count: MetricValue = tm.values("requests.count").iter().sum();
+        assert!(count > 9_000 && count < 11_000, "count was {}", count);
+        let sum: MetricValue = tm.values("requests.sum").iter().sum();
+        assert_eq!(count * 2, sum);
+    }
+}
@@ -0,0 +1,105 @@
+//! Observe basic process health metrics using the Linux `/proc` filesystem.
+
+use crate::attributes::{Observe, OnFlushCancel, WithAttributes};
+use crate::input::InputScope;
+use crate::MetricValue;
+
+use std::fs;
+use std::io;
+
+/// Linux reports CPU times in clock ticks of `USER_HZ`, which is 100 on all supported architectures.
+const USER_HZ: MetricValue = 100;
+
+/// Register gauges observing the process' resident memory (`rss_bytes`), total user + system
+/// CPU time (`cpu_time_ms`), open file descriptors (`open_fds`) and thread count (`threads`).
+/// Values are sampled every time the scope is flushed.
+/// Use a named scope (e.g. `metrics.named("process")`) to give the gauges a common prefix.
+/// Returns the handles required to cancel the observations.
+pub fn register_process_metrics<T>(scope: &T) -> Vec<OnFlushCancel>
+where
+    T: InputScope + WithAttributes + Send + Sync,
+{
+    vec![
+        scope
+            .observe(scope.gauge("rss_bytes"), |_| or_zero(rss_bytes()))
+            .on_flush(),
+        scope
+            .observe(scope.gauge("cpu_time_ms"), |_| or_zero(cpu_time_ms()))
+            .on_flush(),
+        scope
+            .observe(scope.gauge("open_fds"), |_| or_zero(open_fds()))
+            .on_flush(),
+        scope
+            .observe(scope.gauge("threads"), |_| or_zero(threads()))
+            .on_flush(),
+    ]
+}
+
+fn or_zero(value: io::Result<MetricValue>) -> MetricValue {
+    value.unwrap_or_else(|e| {
+        debug!("Could not read process metric: {}", e);
+        0
+    })
+}
+
+/// Lookup a `key: value` line from `/proc/self/status`, returning the first word of the value.
+fn status_field(key: &str) -> io::Result<MetricValue> {
+    let status = fs::read_to_string("/proc/self/status")?;
+    status
+        .lines()
+        .filter_map(|line| line.strip_prefix(key))
+        .filter_map(|value| value.strip_prefix(':'))
+        .filter_map(|value| value.split_whitespace().next())
+        .find_map(|value| value.parse().ok())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, key.to_string()))
+}
+
+fn rss_bytes() -> io::Result<MetricValue> {
+    // VmRSS is reported in kB
+    status_field("VmRSS").map(|kb| kb * 1024)
+}
+
+fn threads() -> io::Result<MetricValue> {
+    status_field("Threads")
+}
+
+fn open_fds() -> io::Result<MetricValue> {
+    Ok(fs::read_dir("/proc/self/fd")?.count() as MetricValue)
+}
+
+fn cpu_time_ms() -> io::Result<MetricValue> {
+    let stat = fs::read_to_string("/proc/self/stat")?;
+    // the command name field may contain spaces, skip past its closing parenthesis
+    let fields: Vec<&str> = stat
+        .rsplit_once(')')
+        .map(|(_, fields)| fields.split_whitespace().collect())
+        .unwrap_or_default();
+    // utime and stime are the 14th and 15th fields, counting from the pid
+    let ticks = |index: usize| -> io::Result<MetricValue> {
+        fields
+            .get(index)
+            .and_then(|value| value.parse().ok())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "/proc/self/stat"))
+    };
+    Ok((ticks(11)? + ticks(12)?) * 1000 / USER_HZ)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::output::map::StatsMap;
+    use crate::{Flush, Input};
+
+    #[test]
+    fn observe_process_metrics() {
+        let metrics = StatsMap::default().metrics();
+        let _cancel = register_process_metrics(&metrics);
+        metrics.flush().unwrap();
+
+        let map = metrics.into_map();
+        assert!(map["rss_bytes"] > 0);
+        assert!(map["open_fds"] > 0);
+        assert!(map["threads"] > 0);
+        assert!(map["cpu_time_ms"] >= 0);
+    }
+}
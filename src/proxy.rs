@@ -279,6 +279,28 @@ impl WithAttributes for Proxy {
     }
 }
 
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::output::map::StatsMapScope;
+
+    #[test]
+    fn swap_target() {
+        let proxy = Proxy::new().named("app");
+        let counter = proxy.counter("requests");
+        let blue = StatsMapScope::default();
+        let green = StatsMapScope::default();
+
+        proxy.target(blue.clone());
+        counter.count(1);
+        proxy.target(green.clone());
+        counter.count(2);
+
+        assert_eq!(Some(&1), blue.into_map().get("requests"));
+        assert_eq!(Some(&2), green.into_map().get("requests"));
+    }
+}
+
 #[cfg(feature = "bench")]
 mod bench {
 
@@ -0,0 +1,268 @@
+//! Streaming quantile estimation of timer values using the P² algorithm (Jain & Chlamtac, 1985).
+//! Each estimate is updated in constant time and memory per observation, without keeping samples.
+//! Estimates are less accurate than those of sampling methods for multi-modal or very skewed distributions.
+
+use crate::attributes::{OnFlushCancel, WithAttributes};
+use crate::clock::TimeHandle;
+use crate::input::{Gauge, InputScope, Timer};
+use crate::{Cancel, CancelGuard, MetricValue};
+
+use std::sync::Arc;
+use std::time::Duration;
+
+#[cfg(not(feature = "parking_lot"))]
+use std::sync::RwLock;
+
+#[cfg(feature = "parking_lot")]
+use parking_lot::RwLock;
+
+/// Estimate of a single quantile, tracked by five markers.
+#[derive(Debug, Clone)]
+struct P2Quantile {
+    quantile: f64,
+    count: usize,
+    /// Marker heights, the first observations until there are five of them
+    heights: [f64; 5],
+    /// Actual marker positions
+    positions: [f64; 5],
+    /// Desired marker positions
+    desired: [f64; 5],
+}
+
+impl P2Quantile {
+    fn new(quantile: f64) -> Self {
+        let p = quantile.clamp(0.0, 1.0);
+        P2Quantile {
+            quantile: p,
+            count: 0,
+            heights: [0.0; 5],
+            positions: [0.0, 1.0, 2.0, 3.0, 4.0],
+            desired: [0.0, 2.0 * p, 4.0 * p, 2.0 + 2.0 * p, 4.0],
+        }
+    }
+
+    fn observe(&mut self, value: f64) {
+        if self.count < 5 {
+            self.heights[self.count] = value;
+            self.count += 1;
+            if self.count == 5 {
+                self.heights.sort_by(f64::total_cmp);
+            }
+            return;
+        }
+        self.count += 1;
+
+        let q = &mut self.heights;
+        let cell = if value < q[0] {
+            q[0] = value;
+            0
+        } else if value >= q[4] {
+            q[4] = value;
+            3
+        } else {
+            (1..5).find(|&i| value < q[i]).unwrap_or(4) - 1
+        };
+        for position in &mut self.positions[cell + 1..] {
+            *position += 1.0
+        }
+        let p = self.quantile;
+        for (desired, increment) in
+            self.desired
+                .iter_mut()
+                .zip([0.0, p / 2.0, p, (1.0 + p) / 2.0, 1.0])
+        {
+            *desired += increment
+        }
+
+        for i in 1..4 {
+            let d = self.desired[i] - self.positions[i];
+            let n = &self.positions;
+            if (d >= 1.0 && n[i + 1] - n[i] > 1.0) || (d <= -1.0 && n[i - 1] - n[i] < -1.0) {
+                let d = d.signum();
+                let parabolic = self.parabolic(i, d);
+                self.heights[i] =
+                    if self.heights[i - 1] < parabolic && parabolic < self.heights[i + 1] {
+                        parabolic
+                    } else {
+                        self.linear(i, d)
+                    };
+                self.positions[i] += d;
+            }
+        }
+    }
+
+    fn parabolic(&self, i: usize, d: f64) -> f64 {
+        let (q, n) = (&self.heights, &self.positions);
+        q[i] + d / (n[i + 1] - n[i - 1])
+            * ((n[i] - n[i - 1] + d) * (q[i + 1] - q[i]) / (n[i + 1] - n[i])
+                + (n[i + 1] - n[i] - d) * (q[i] - q[i - 1]) / (n[i] - n[i - 1]))
+    }
+
+    fn linear(&self, i: usize, d: f64) -> f64 {
+        let j = if d > 0.0 { i + 1 } else { i - 1 };
+        self.heights[i]
+            + d * (self.heights[j] - self.heights[i]) / (self.positions[j] - self.positions[i])
+    }
+
+    /// The current estimate, exact until five values were observed.
+    fn estimate(&self) -> Option<f64> {
+        match self.count {
+            0 => None,
+            count if count < 5 => {
+                let mut values = self.heights[..count].to_vec();
+                values.sort_by(f64::total_cmp);
+                Some(values[(self.quantile * (count - 1) as f64).round() as usize])
+            }
+            _ => Some(self.heights[2]),
+        }
+    }
+}
+
+/// A timer also estimating quantiles of its intervals, e.g. p50, p90 and p99, as they are recorded.
+/// Estimates cover the intervals recorded since the scope was last flushed,
+/// upon which they are published as `<name>.p50` gauges (in microseconds) before starting over.
+/// Estimation uses the P² algorithm, costing constant time and memory per interval:
+/// estimates of multi-modal or very skewed distributions are less accurate than those computed from samples.
+/// Estimates are no longer published once the timer and all its clones are dropped.
+#[derive(Debug, Clone)]
+pub struct QuantileTimer {
+    timer: Timer,
+    estimators: Arc<RwLock<Vec<P2Quantile>>>,
+    _listener: Arc<CancelGuard<OnFlushCancel>>,
+}
+
+impl QuantileTimer {
+    /// Record a duration to the timer and the quantile estimates.
+    pub fn record(&self, duration: Duration) {
+        let interval_us = self.timer.interval(duration);
+        for estimator in write_lock!(self.estimators).iter_mut() {
+            estimator.observe(interval_us as f64)
+        }
+    }
+
+    /// Record the time taken to execute the provided closure.
+    pub fn time<F: FnOnce() -> R, R>(&self, operation: F) -> R {
+        let start_time = TimeHandle::now();
+        let value = operation();
+        self.record(Duration::from_micros(start_time.elapsed_us()));
+        value
+    }
+
+    /// The current estimate of the quantile, if it is one of this timer's and any interval was recorded since the last flush.
+    pub fn quantile(&self, quantile: f64) -> Option<Duration> {
+        read_lock!(self.estimators)
+            .iter()
+            .find(|estimator| estimator.quantile == quantile)
+            .and_then(P2Quantile::estimate)
+            .map(|us| Duration::from_micros(us.max(0.0) as u64))
+    }
+}
+
+/// Name suffix of a quantile, e.g. `p99` for 0.99 or `p999` for 0.999.
+fn quantile_suffix(quantile: f64) -> String {
+    let percent = format!("{:.3}", quantile * 100.0);
+    let percent = percent.trim_end_matches('0').trim_end_matches('.');
+    format!("p{}", percent.replace('.', ""))
+}
+
+/// Define quantile timers, which need to observe their scope's flushes.
+pub trait QuantileTiming {
+    /// Define a QuantileTimer estimating the quantiles, e.g. `&[0.5, 0.9, 0.99]`.
+    fn quantile_timer(&self, name: &str, quantiles: &[f64]) -> QuantileTimer;
+}
+
+impl<T: InputScope + WithAttributes + Send + Sync> QuantileTiming for T {
+    fn quantile_timer(&self, name: &str, quantiles: &[f64]) -> QuantileTimer {
+        let timer = self.timer(name);
+        let estimators = Arc::new(RwLock::new(
+            quantiles
+                .iter()
+                .map(|q| P2Quantile::new(*q))
+                .collect::<Vec<_>>(),
+        ));
+        let gauges: Vec<Gauge> = quantiles
+            .iter()
+            .map(|q| self.gauge(&format!("{}.{}", name, quantile_suffix(*q))))
+            .collect();
+        let window = estimators.clone();
+        let listener =
+            self.get_attributes()
+                .add_flush_listener(timer.metric_id().clone(), move |_| {
+                    for (estimator, gauge) in write_lock!(window).iter_mut().zip(&gauges) {
+                        if let Some(estimate) = estimator.estimate() {
+                            gauge.value(estimate.round() as MetricValue)
+                        }
+                        *estimator = P2Quantile::new(estimator.quantile);
+                    }
+                });
+        QuantileTimer {
+            timer,
+            estimators,
+            _listener: Arc::new(listener.into_guard()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::output::map::StatsMapScope;
+    use crate::test_util::TestMetrics;
+    use crate::Flush;
+
+    #[test]
+    fn p2_uniform() {
+        let mut median = P2Quantile::new(0.5);
+        let mut p90 = P2Quantile::new(0.9);
+        // a permutation of 0..1000
+        for i in 0..1000 {
+            let value = ((i * 617) % 1000) as f64;
+            median.observe(value);
+            p90.observe(value);
+        }
+        assert!((median.estimate().unwrap() - 500.0).abs() < 25.0);
+        assert!((p90.estimate().unwrap() - 900.0).abs() < 25.0);
+    }
+
+    #[test]
+    fn exact_until_five() {
+        let mut median = P2Quantile::new(0.5);
+        assert_eq!(None, median.estimate());
+        for value in [30.0, 10.0, 20.0] {
+            median.observe(value)
+        }
+        assert_eq!(Some(20.0), median.estimate());
+    }
+
+    #[test]
+    fn quantile_timer() {
+        let metrics = StatsMapScope::default();
+        let latency = metrics.quantile_timer("latency", &[0.5, 0.999]);
+        for ms in [1, 2, 3] {
+            latency.record(Duration::from_millis(ms));
+        }
+        assert_eq!(Some(Duration::from_millis(2)), latency.quantile(0.5));
+
+        metrics.flush().unwrap();
+        let map = metrics.into_map();
+        assert_eq!(map["latency.p50"], 2000);
+        assert_eq!(map["latency.p999"], 3000);
+        assert_eq!(None, latency.quantile(0.5));
+    }
+
+    #[test]
+    fn quantile_timer_dropped() {
+        let tm = TestMetrics::new();
+        let metrics = tm.sink();
+        let latency = metrics.quantile_timer("latency", &[0.5]);
+
+        latency.record(Duration::from_millis(1));
+        metrics.flush().unwrap();
+        assert_eq!(vec![1000], tm.values("latency.p50"));
+
+        latency.record(Duration::from_millis(2));
+        drop(latency);
+        metrics.flush().unwrap();
+        assert_eq!(vec![1000], tm.values("latency.p50"));
+    }
+}
@@ -10,9 +10,11 @@ use crate::name::MetricName;
 use crate::CachedInput;
 use crate::{Flush, MetricValue};
 
+use std::sync::atomic::{AtomicUsize, Ordering};
 #[cfg(not(feature = "crossbeam-channel"))]
 use std::sync::mpsc;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use std::{io, thread};
 
 #[cfg(feature = "crossbeam-channel")]
@@ -32,16 +34,24 @@ pub trait QueuedInput: Input + Send + Sync + 'static + Sized {
 ///
 /// Panics if the OS fails to create a thread.
 #[cfg(not(feature = "crossbeam-channel"))]
-fn new_async_channel(length: usize) -> Arc<mpsc::SyncSender<InputQueueCmd>> {
+fn new_async_channel(length: usize) -> (Arc<mpsc::SyncSender<InputQueueCmd>>, Arc<AtomicUsize>) {
     let (sender, receiver) = mpsc::sync_channel::<InputQueueCmd>(length);
+    let depth = Arc::new(AtomicUsize::new(0));
+    let dequeued = depth.clone();
 
     thread::Builder::new()
         .name("dipstick-queue-in".to_string())
         .spawn(move || {
             let mut done = false;
             while !done {
-                match receiver.recv() {
-                    Ok(InputQueueCmd::Write(metric, value, labels)) => metric.write(value, labels),
+                let cmd = receiver.recv();
+                if cmd.is_ok() {
+                    dequeued.fetch_sub(1, Ordering::Relaxed);
+                }
+                match cmd {
+                    Ok(InputQueueCmd::Write(metric, value, labels, deadline)) => {
+                        write_before(deadline, metric, value, labels)
+                    }
                     Ok(InputQueueCmd::Flush(scope)) => {
                         if let Err(e) = scope.flush() {
                             debug!("Could not asynchronously flush metrics: {}", e);
@@ -56,23 +66,31 @@ fn new_async_channel(length: usize) -> Arc<mpsc::SyncSender<InputQueueCmd>> {
             }
         })
         .unwrap(); // TODO: Panic, change API to return Result?
-    Arc::new(sender)
+    (Arc::new(sender), depth)
 }
 
 /// # Panics
 ///
 /// Panics if the OS fails to create a thread.
 #[cfg(feature = "crossbeam-channel")]
-fn new_async_channel(length: usize) -> Arc<crossbeam::Sender<InputQueueCmd>> {
+fn new_async_channel(length: usize) -> (Arc<crossbeam::Sender<InputQueueCmd>>, Arc<AtomicUsize>) {
     let (sender, receiver) = crossbeam::bounded::<InputQueueCmd>(length);
+    let depth = Arc::new(AtomicUsize::new(0));
+    let dequeued = depth.clone();
 
     thread::Builder::new()
         .name("dipstick-queue-in".to_string())
         .spawn(move || {
             let mut done = false;
             while !done {
-                match receiver.recv() {
-                    Ok(InputQueueCmd::Write(metric, value, labels)) => metric.write(value, labels),
+                let cmd = receiver.recv();
+                if cmd.is_ok() {
+                    dequeued.fetch_sub(1, Ordering::Relaxed);
+                }
+                match cmd {
+                    Ok(InputQueueCmd::Write(metric, value, labels, deadline)) => {
+                        write_before(deadline, metric, value, labels)
+                    }
                     Ok(InputQueueCmd::Flush(scope)) => {
                         if let Err(e) = scope.flush() {
                             debug!("Could not asynchronously flush metrics: {}", e);
@@ -87,7 +105,50 @@ fn new_async_channel(length: usize) -> Arc<crossbeam::Sender<InputQueueCmd>> {
             }
         })
         .unwrap(); // TODO: Panic, change API to return Result?
-    Arc::new(sender)
+    (Arc::new(sender), depth)
+}
+
+/// Write the value unless it has been waiting in the queue past its deadline.
+fn write_before(
+    deadline: Option<Instant>,
+    metric: InputMetric,
+    value: MetricValue,
+    labels: Labels,
+) {
+    if deadline.is_some_and(|deadline| Instant::now() > deadline) {
+        metrics::QUEUE_EXPIRED.mark();
+        trace!("Dropped queued metric write past its deadline");
+    } else {
+        metric.write(value, labels)
+    }
+}
+
+/// Send a command to the queue, counting it until it is received by the background thread.
+#[cfg(not(feature = "crossbeam-channel"))]
+fn send(
+    sender: &mpsc::SyncSender<InputQueueCmd>,
+    depth: &AtomicUsize,
+    cmd: InputQueueCmd,
+) -> Result<(), InputQueueCmd> {
+    depth.fetch_add(1, Ordering::Relaxed);
+    sender.send(cmd).map_err(|e| {
+        depth.fetch_sub(1, Ordering::Relaxed);
+        e.0
+    })
+}
+
+/// Send a command to the queue, counting it until it is received by the background thread.
+#[cfg(feature = "crossbeam-channel")]
+fn send(
+    sender: &crossbeam::Sender<InputQueueCmd>,
+    depth: &AtomicUsize,
+    cmd: InputQueueCmd,
+) -> Result<(), InputQueueCmd> {
+    depth.fetch_add(1, Ordering::Relaxed);
+    sender.send(cmd).map_err(|e| {
+        depth.fetch_sub(1, Ordering::Relaxed);
+        e.into_inner()
+    })
 }
 
 /// Wrap new scopes with an asynchronous metric write & flush dispatcher.
@@ -99,17 +160,37 @@ pub struct InputQueue {
     sender: Arc<mpsc::SyncSender<InputQueueCmd>>,
     #[cfg(feature = "crossbeam-channel")]
     sender: Arc<crossbeam::Sender<InputQueueCmd>>,
+    depth: Arc<AtomicUsize>,
+    max_age: Option<Duration>,
 }
 
 impl InputQueue {
     /// Wrap new scopes with an asynchronous metric write & flush dispatcher.
     pub fn new<OUT: Input + Send + Sync + 'static>(target: OUT, queue_length: usize) -> Self {
+        let (sender, depth) = new_async_channel(queue_length);
         InputQueue {
             attributes: Attributes::default(),
             target: Arc::new(target),
-            sender: new_async_channel(queue_length),
+            sender,
+            depth,
+            max_age: None,
         }
     }
+
+    /// Drop writes that waited in the queue for longer than the specified time, instead of sending stale values
+    /// once a backlog clears. Dropped writes are counted by the `dipstick.queue.expired` internal metric.
+    /// Flushes are never dropped.
+    pub fn max_age(&self, max_age: Duration) -> Self {
+        let mut cloned = self.clone();
+        cloned.max_age = Some(max_age);
+        cloned
+    }
+
+    /// Number of commands waiting in the queue to be processed by the background thread.
+    /// Sustained high depth indicates the thread can not keep up with the metrics volume.
+    pub fn depth(&self) -> usize {
+        self.depth.load(Ordering::Relaxed)
+    }
 }
 
 impl CachedInput for InputQueue {}
@@ -132,6 +213,8 @@ impl Input for InputQueue {
         InputQueueScope {
             attributes: self.attributes.clone(),
             sender: self.sender.clone(),
+            depth: self.depth.clone(),
+            max_age: self.max_age,
             target: target_scope,
         }
     }
@@ -140,8 +223,8 @@ impl Input for InputQueue {
 /// This is only `pub` because `error` module needs to know about it.
 /// Async commands should be of no concerns to applications.
 pub enum InputQueueCmd {
-    /// Send metric write
-    Write(InputMetric, MetricValue, Labels),
+    /// Send metric write, to be dropped if not received before the deadline
+    Write(InputMetric, MetricValue, Labels, Option<Instant>),
     /// Send metric flush
     Flush(Arc<dyn InputScope + Send + Sync + 'static>),
 }
@@ -155,6 +238,8 @@ pub struct InputQueueScope {
     sender: Arc<mpsc::SyncSender<InputQueueCmd>>,
     #[cfg(feature = "crossbeam-channel")]
     sender: Arc<crossbeam::Sender<InputQueueCmd>>,
+    depth: Arc<AtomicUsize>,
+    max_age: Option<Duration>,
     target: Arc<dyn InputScope + Send + Sync + 'static>,
 }
 
@@ -164,12 +249,34 @@ impl InputQueueScope {
         target_scope: SC,
         queue_length: usize,
     ) -> Self {
+        let (sender, depth) = new_async_channel(queue_length);
         InputQueueScope {
             attributes: Attributes::default(),
-            sender: new_async_channel(queue_length),
+            sender,
+            depth,
+            max_age: None,
             target: Arc::new(target_scope),
         }
     }
+
+    /// Drop writes that waited in the queue for longer than the specified time, instead of sending stale values
+    /// once a backlog clears. Dropped writes are counted by the `dipstick.queue.expired` internal metric.
+    /// Flushes are never dropped. Only affects metrics defined afterwards.
+    pub fn max_age(&self, max_age: Duration) -> Self {
+        let mut cloned = self.clone();
+        cloned.max_age = Some(max_age);
+        cloned
+    }
+
+    /// Number of commands waiting in the queue to be processed by the background thread.
+    /// Sustained high depth indicates the thread can not keep up with the metrics volume.
+    pub fn depth(&self) -> usize {
+        self.depth.load(Ordering::Relaxed)
+    }
+
+    fn send(&self, cmd: InputQueueCmd) -> Result<(), InputQueueCmd> {
+        send(&self.sender, &self.depth, cmd)
+    }
 }
 
 impl WithAttributes for InputQueueScope {
@@ -186,12 +293,15 @@ impl InputScope for InputQueueScope {
         let name = self.prefix_append(name);
         let target_metric = self.target.new_metric(name.clone(), kind);
         let sender = self.sender.clone();
+        let depth = self.depth.clone();
+        let max_age = self.max_age;
         InputMetric::new(MetricId::forge("queue", name), move |value, mut labels| {
             labels.save_context();
-            if let Err(e) = sender.send(InputQueueCmd::Write(target_metric.clone(), value, labels))
-            {
+            let deadline = max_age.and_then(|max_age| Instant::now().checked_add(max_age));
+            let cmd = InputQueueCmd::Write(target_metric.clone(), value, labels, deadline);
+            if send(&sender, &depth, cmd).is_err() {
                 metrics::SEND_FAILED.mark();
-                debug!("Failed to send async metrics: {}", e);
+                debug!("Failed to send async metrics: queue disconnected");
             }
         })
     }
@@ -200,12 +310,86 @@ impl InputScope for InputQueueScope {
 impl Flush for InputQueueScope {
     fn flush(&self) -> io::Result<()> {
         self.notify_flush_listeners();
-        if let Err(e) = self.sender.send(InputQueueCmd::Flush(self.target.clone())) {
+        metrics::QUEUE_DEPTH.value(self.depth());
+        if self
+            .send(InputQueueCmd::Flush(self.target.clone()))
+            .is_err()
+        {
             metrics::SEND_FAILED.mark();
-            debug!("Failed to flush async metrics: {}", e);
-            Err(io::Error::new(io::ErrorKind::Other, e))
+            debug!("Failed to flush async metrics: queue disconnected");
+            Err(io::Error::other("Async metrics queue disconnected"))
         } else {
             Ok(())
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{StatsMapScope, WriteHooked};
+    use std::sync::mpsc as std_mpsc;
+    use std::sync::Mutex;
+    use std::time::{Duration, Instant};
+
+    fn wait_for_depth(queue: &InputQueueScope, depth: usize) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while queue.depth() != depth {
+            assert!(Instant::now() < deadline, "queue depth {}", queue.depth());
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn queue_depth() {
+        let (release, blocked) = std_mpsc::channel::<()>();
+        let blocked = Mutex::new(blocked);
+        let target = StatsMapScope::default().with_write_hook(move |_, _, _| {
+            blocked.lock().unwrap().recv().unwrap();
+        });
+        let queue = InputQueueScope::wrap(target, 10);
+        let counter = queue.counter("count");
+
+        counter.count(1);
+        counter.count(2);
+        counter.count(3);
+        // first write is blocked in the background thread, the others wait in the queue
+        wait_for_depth(&queue, 2);
+
+        for _ in 0..3 {
+            release.send(()).unwrap();
+        }
+        wait_for_depth(&queue, 0);
+    }
+
+    #[test]
+    fn drop_expired_writes() {
+        let (release, blocked) = std_mpsc::channel::<()>();
+        let blocked = Mutex::new(blocked);
+        let written = Arc::new(Mutex::new(vec![]));
+        let sink = written.clone();
+        let target = StatsMapScope::default().with_write_hook(move |_, _, value| {
+            sink.lock().unwrap().push(value);
+            blocked.lock().unwrap().recv().unwrap();
+        });
+        let queue = InputQueueScope::wrap(target, 10).max_age(Duration::from_millis(10));
+        let counter = queue.counter("count");
+
+        counter.count(1);
+        counter.count(2);
+        // second write expires while the first is blocked in the background thread
+        wait_for_depth(&queue, 1);
+        thread::sleep(Duration::from_millis(50));
+        release.send(()).unwrap();
+
+        counter.count(3);
+        release.send(()).unwrap();
+        wait_for_depth(&queue, 0);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while written.lock().unwrap().len() < 2 {
+            assert!(Instant::now() < deadline);
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(vec![1, 3], *written.lock().unwrap());
+    }
+}
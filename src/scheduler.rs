@@ -4,11 +4,12 @@ use crate::input::InputScope;
 
 use std::cmp::{max, Ordering};
 use std::collections::BinaryHeap;
+use std::fmt;
 use std::sync::atomic::AtomicBool;
 use std::sync::atomic::Ordering::SeqCst;
 use std::sync::{Arc, Condvar, Mutex};
 use std::thread;
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 /// A guard canceling the inner handle when dropped.
 ///
@@ -31,6 +32,12 @@ impl<C: Cancel> CancelGuard<C> {
     }
 }
 
+impl<C: Cancel> fmt::Debug for CancelGuard<C> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "CancelGuard {{ armed: {} }}", self.inner.is_some())
+    }
+}
+
 impl<C: Cancel> Drop for CancelGuard<C> {
     fn drop(&mut self) {
         if let Some(inner) = self.inner.take() {
@@ -58,11 +65,11 @@ pub trait Cancel {
 pub struct CancelHandle(Arc<AtomicBool>);
 
 impl CancelHandle {
-    fn new() -> CancelHandle {
+    pub(crate) fn new() -> CancelHandle {
         CancelHandle(Arc::new(AtomicBool::new(false)))
     }
 
-    fn is_cancelled(&self) -> bool {
+    pub(crate) fn is_cancelled(&self) -> bool {
         self.0.load(SeqCst)
     }
 }
@@ -80,6 +87,15 @@ impl Cancel for CancelHandle {
 pub trait ScheduleFlush {
     /// Flush this scope at regular intervals.
     fn flush_every(&self, period: Duration) -> CancelHandle;
+
+    /// Flush this scope at regular intervals aligned on the wall clock,
+    /// e.g. at the start of every minute for a period of 60 seconds.
+    /// Processes flushing with the same period then publish values covering the same time windows,
+    /// making their metrics easier to correlate.
+    ///
+    /// # Panics
+    /// If the period is zero, as there are no boundaries to align on.
+    fn flush_every_aligned(&self, period: Duration) -> CancelHandle;
 }
 
 impl<T: InputScope + Send + Sync + Clone + 'static> ScheduleFlush for T {
@@ -92,6 +108,15 @@ impl<T: InputScope + Send + Sync + Clone + 'static> ScheduleFlush for T {
             }
         })
     }
+
+    fn flush_every_aligned(&self, period: Duration) -> CancelHandle {
+        let scope = self.clone();
+        SCHEDULER.schedule_aligned(period, move |_| {
+            if let Err(err) = scope.flush() {
+                error!("Could not flush metrics: {}", err);
+            }
+        })
+    }
 }
 
 lazy_static! {
@@ -101,6 +126,7 @@ lazy_static! {
 struct ScheduledTask {
     next_time: Instant,
     period: Duration,
+    aligned: bool,
     handle: CancelHandle,
     operation: Arc<dyn Fn(Instant) + Send + Sync + 'static>,
 }
@@ -113,7 +139,7 @@ impl Ord for ScheduledTask {
 
 impl PartialOrd for ScheduledTask {
     fn partial_cmp(&self, other: &ScheduledTask) -> Option<Ordering> {
-        other.next_time.partial_cmp(&self.next_time)
+        Some(self.cmp(other))
     }
 }
 
@@ -127,9 +153,11 @@ impl Eq for ScheduledTask {}
 
 pub struct Scheduler {
     next_tasks: Arc<(Mutex<BinaryHeap<ScheduledTask>>, Condvar)>,
+    closed: Arc<AtomicBool>,
 }
 
-pub static MIN_DELAY: Duration = Duration::from_millis(50);
+/// Shortest wait of the scheduler thread for a task that is due, fine enough for sub-second periods to run on time.
+pub static MIN_DELAY: Duration = Duration::from_millis(1);
 
 impl Scheduler {
     /// Launch a new scheduler thread.
@@ -137,27 +165,35 @@ impl Scheduler {
         let sched: Arc<(Mutex<BinaryHeap<ScheduledTask>>, Condvar)> =
             Arc::new((Mutex::new(BinaryHeap::new()), Condvar::new()));
         let sched1 = Arc::downgrade(&sched);
+        let closed = Arc::new(AtomicBool::new(false));
+        let closed1 = closed.clone();
 
         thread::Builder::new()
             .name("dipstick_scheduler".to_string())
             .spawn(move || {
-                let mut wait_for = MIN_DELAY;
                 while let Some(sss) = sched1.upgrade() {
                     let (heap_mutex, condvar) = &*sss;
                     let heap = heap_mutex.lock().unwrap();
-                    let (mut tasks, _timed_out) = condvar.wait_timeout(heap, wait_for).unwrap();
+                    if closed1.load(SeqCst) {
+                        break;
+                    }
+                    let now = Instant::now();
+                    let next_time = heap.peek().map(|task| task.next_time);
+                    let mut tasks = match next_time {
+                        // nothing to run until a task is scheduled, which notifies the condvar
+                        None => condvar.wait(heap).unwrap(),
+                        Some(next_time) if next_time > now => {
+                            let wait_for = max(MIN_DELAY, next_time - now);
+                            condvar.wait_timeout(heap, wait_for).unwrap().0
+                        }
+                        Some(_) => heap,
+                    };
                     'work: loop {
                         let now = Instant::now();
                         match tasks.peek() {
-                            Some(task) if task.next_time > now => {
-                                // next task is not ready yet, update schedule
-                                wait_for = max(MIN_DELAY, task.next_time - now);
-                                break 'work;
-                            }
-                            None => {
-                                // TODO no tasks left. exit thread?
-                                break 'work;
-                            }
+                            // next task is not ready yet, wait for it
+                            Some(task) if task.next_time > now => break 'work,
+                            None => break 'work,
                             _ => {}
                         }
                         if let Some(mut task) = tasks.pop() {
@@ -166,7 +202,16 @@ impl Scheduler {
                                 continue;
                             }
                             (task.operation)(now);
-                            task.next_time = now + task.period;
+                            task.next_time = if task.aligned {
+                                // stay on boundaries, skipping any that were missed
+                                let mut next_time = task.next_time + task.period;
+                                while next_time <= now {
+                                    next_time += task.period;
+                                }
+                                next_time
+                            } else {
+                                now + task.period
+                            };
                             tasks.push(task);
                         }
                     }
@@ -174,7 +219,10 @@ impl Scheduler {
             })
             .unwrap();
 
-        Scheduler { next_tasks: sched }
+        Scheduler {
+            next_tasks: sched,
+            closed,
+        }
     }
 
     #[cfg(test)]
@@ -184,13 +232,41 @@ impl Scheduler {
 
     /// Schedule a task to run periodically.
     pub fn schedule<F>(&self, period: Duration, operation: F) -> CancelHandle
+    where
+        F: Fn(Instant) + Send + Sync + 'static,
+    {
+        self.schedule_at(Instant::now() + period, period, false, operation)
+    }
+
+    /// Schedule a task to run periodically, at multiples of the period since the Unix epoch.
+    /// Panics if the period is zero.
+    pub fn schedule_aligned<F>(&self, period: Duration, operation: F) -> CancelHandle
+    where
+        F: Fn(Instant) + Send + Sync + 'static,
+    {
+        assert!(
+            !period.is_zero(),
+            "Aligned schedule period must not be zero"
+        );
+        let delay = until_aligned(period, SystemTime::now());
+        self.schedule_at(Instant::now() + delay, period, true, operation)
+    }
+
+    fn schedule_at<F>(
+        &self,
+        next_time: Instant,
+        period: Duration,
+        aligned: bool,
+        operation: F,
+    ) -> CancelHandle
     where
         F: Fn(Instant) + Send + Sync + 'static,
     {
         let handle = CancelHandle::new();
         let new_task = ScheduledTask {
-            next_time: Instant::now() + period,
+            next_time,
             period,
+            aligned,
             handle: handle.clone(),
             operation: Arc::new(operation),
         };
@@ -200,6 +276,27 @@ impl Scheduler {
     }
 }
 
+/// Stop the scheduler thread, which may be waiting for a task to be scheduled.
+impl Drop for Scheduler {
+    fn drop(&mut self) {
+        if let Ok(_tasks) = self.next_tasks.0.lock() {
+            self.closed.store(true, SeqCst);
+            self.next_tasks.1.notify_one();
+        }
+    }
+}
+
+/// Time remaining from `now` until the next multiple of the period since the Unix epoch.
+fn until_aligned(period: Duration, now: SystemTime) -> Duration {
+    let period_ns = period.as_nanos();
+    if period_ns == 0 {
+        return Duration::ZERO;
+    }
+    let since_epoch = now.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let remainder = (since_epoch.as_nanos() % period_ns) as u64;
+    period - Duration::from_nanos(remainder)
+}
+
 #[cfg(test)]
 pub mod test {
     use super::*;
@@ -333,4 +430,37 @@ pub mod test {
         handle1.cancel();
         handle2.cancel();
     }
+
+    #[test]
+    fn schedule_when_idle() {
+        let trig1a = Arc::new(AtomicUsize::new(0));
+        let trig1b = trig1a.clone();
+
+        let sched = Scheduler::new();
+        // let the thread wait for a task to be scheduled
+        thread::sleep(Duration::from_millis(20));
+
+        let handle1 = sched.schedule(Duration::from_millis(50), move |_| {
+            trig1b.fetch_add(1, SeqCst);
+        });
+        thread::sleep(Duration::from_millis(70));
+        assert_eq!(1, trig1a.load(SeqCst));
+        handle1.cancel();
+    }
+
+    #[test]
+    fn aligned_delay() {
+        let minute = Duration::from_secs(60);
+        let at = |secs| UNIX_EPOCH + Duration::from_secs(secs);
+        assert_eq!(Duration::from_secs(55), until_aligned(minute, at(125)));
+        assert_eq!(minute, until_aligned(minute, at(120)));
+        assert_eq!(Duration::from_secs(1), until_aligned(minute, at(179)));
+    }
+
+    #[test]
+    #[should_panic(expected = "must not be zero")]
+    fn aligned_zero_period() {
+        let sched = Scheduler::new();
+        sched.schedule_aligned(Duration::ZERO, |_| {});
+    }
 }
@@ -0,0 +1,280 @@
+//! Flush scopes upon reception of a process signal.
+
+use crate::scheduler::CancelHandle;
+use crate::Flush;
+
+use std::collections::HashMap;
+use std::io;
+use std::mem;
+use std::ptr;
+use std::sync::atomic::AtomicI32;
+use std::sync::atomic::Ordering::SeqCst;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// Process signals upon which scopes can be flushed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Signal {
+    /// SIGTERM, the conventional request to terminate.
+    Term,
+    /// SIGINT, usually sent by Ctrl-C.
+    Int,
+    /// SIGHUP
+    Hup,
+    /// SIGQUIT
+    Quit,
+    /// SIGUSR1
+    Usr1,
+    /// SIGUSR2
+    Usr2,
+}
+
+impl Signal {
+    fn number(self) -> libc::c_int {
+        match self {
+            Signal::Term => libc::SIGTERM,
+            Signal::Int => libc::SIGINT,
+            Signal::Hup => libc::SIGHUP,
+            Signal::Quit => libc::SIGQUIT,
+            Signal::Usr1 => libc::SIGUSR1,
+            Signal::Usr2 => libc::SIGUSR2,
+        }
+    }
+}
+
+/// Flush a scope when the process receives a signal, e.g. for a clean shutdown.
+pub trait FlushOnSignal {
+    /// Flush this scope upon any of the signals, before the signal is handed on
+    /// to the disposition it had before. A previous handler is called on every signal,
+    /// a default disposition (terminating the process) is restored and the signal raised again.
+    /// The handler only wakes a dedicated thread, which does the actual flushing.
+    fn flush_on_signal(&self, signals: &[Signal]) -> io::Result<CancelHandle>;
+}
+
+impl<T: Flush + Send + Sync + Clone + 'static> FlushOnSignal for T {
+    fn flush_on_signal(&self, signals: &[Signal]) -> io::Result<CancelHandle> {
+        let handle = CancelHandle::new();
+        let numbers: Vec<libc::c_int> = signals.iter().map(|s| s.number()).collect();
+        for number in &numbers {
+            install(*number)?;
+        }
+        REGISTRY.lock().unwrap().push(Registration {
+            signals: numbers,
+            handle: handle.clone(),
+            scope: Arc::new(self.clone()),
+        });
+        Ok(handle)
+    }
+}
+
+struct Registration {
+    signals: Vec<libc::c_int>,
+    handle: CancelHandle,
+    scope: Arc<dyn Flush + Send + Sync>,
+}
+
+lazy_static! {
+    static ref REGISTRY: Mutex<Vec<Registration>> = Mutex::new(Vec::new());
+    /// Dispositions replaced by our handler, chained to whenever the signal is received.
+    static ref PREVIOUS: Mutex<HashMap<libc::c_int, libc::sigaction>> = Mutex::new(HashMap::new());
+}
+
+/// Write end of the pipe waking up the flush thread, -1 until it is started.
+static PIPE_WRITE: AtomicI32 = AtomicI32::new(-1);
+
+/// Only does what is async-signal-safe: write the signal number to the pipe.
+/// The interrupted code's `errno` is preserved on platforms where its location is known.
+extern "C" fn on_signal(signal: libc::c_int) {
+    let byte = signal as u8;
+    unsafe {
+        let errno = errno_location();
+        let saved = errno.map(|errno| *errno);
+        libc::write(
+            PIPE_WRITE.load(SeqCst),
+            ptr::addr_of!(byte).cast::<libc::c_void>(),
+            1,
+        );
+        if let (Some(errno), Some(saved)) = (errno, saved) {
+            *errno = saved;
+        }
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+unsafe fn errno_location() -> Option<*mut libc::c_int> {
+    Some(libc::__errno_location())
+}
+
+#[cfg(any(target_os = "macos", target_os = "ios", target_os = "freebsd"))]
+unsafe fn errno_location() -> Option<*mut libc::c_int> {
+    Some(libc::__error())
+}
+
+#[cfg(any(target_os = "openbsd", target_os = "netbsd"))]
+unsafe fn errno_location() -> Option<*mut libc::c_int> {
+    Some(libc::__errno())
+}
+
+/// Other platforms leave `errno` alone, which the write only changes if it fails.
+#[cfg(not(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "freebsd",
+    target_os = "openbsd",
+    target_os = "netbsd"
+)))]
+unsafe fn errno_location() -> Option<*mut libc::c_int> {
+    None
+}
+
+fn install(signal: libc::c_int) -> io::Result<()> {
+    let mut previous = PREVIOUS.lock().unwrap();
+    if previous.contains_key(&signal) {
+        return Ok(());
+    }
+    if PIPE_WRITE.load(SeqCst) < 0 {
+        start_flush_thread()?;
+    }
+
+    let handler: extern "C" fn(libc::c_int) = on_signal;
+    unsafe {
+        let mut action: libc::sigaction = mem::zeroed();
+        action.sa_sigaction = handler as libc::sighandler_t;
+        action.sa_flags = libc::SA_RESTART;
+        libc::sigemptyset(&mut action.sa_mask);
+        let mut replaced: libc::sigaction = mem::zeroed();
+        if libc::sigaction(signal, &action, &mut replaced) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        previous.insert(signal, replaced);
+    }
+    Ok(())
+}
+
+fn start_flush_thread() -> io::Result<()> {
+    let mut fds: [libc::c_int; 2] = [0; 2];
+    unsafe {
+        if libc::pipe(fds.as_mut_ptr()) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        for fd in &fds {
+            libc::fcntl(*fd, libc::F_SETFD, libc::FD_CLOEXEC);
+        }
+        // the signal handler must never block
+        libc::fcntl(fds[1], libc::F_SETFL, libc::O_NONBLOCK);
+    }
+    let read_fd = fds[0];
+    thread::Builder::new()
+        .name("dipstick-signal".to_string())
+        .spawn(move || loop {
+            let mut byte = 0u8;
+            let read = unsafe { libc::read(read_fd, ptr::addr_of_mut!(byte).cast(), 1) };
+            if read == 1 {
+                on_received(libc::c_int::from(byte));
+            } else {
+                let err = io::Error::last_os_error();
+                if err.kind() != io::ErrorKind::Interrupted {
+                    error!("Signal flush thread stopped: {}", err);
+                    break;
+                }
+            }
+        })?;
+    PIPE_WRITE.store(fds[1], SeqCst);
+    Ok(())
+}
+
+/// Flush the scopes registered for the signal, then hand it on to its previous disposition.
+fn on_received(signal: libc::c_int) {
+    let scopes: Vec<Arc<dyn Flush + Send + Sync>> = {
+        let mut registry = REGISTRY.lock().unwrap();
+        registry.retain(|reg| !reg.handle.is_cancelled());
+        registry
+            .iter()
+            .filter(|reg| reg.signals.contains(&signal))
+            .map(|reg| reg.scope.clone())
+            .collect()
+    };
+    for scope in scopes {
+        if let Err(err) = scope.flush() {
+            error!("Could not flush metrics upon signal {}: {}", signal, err);
+        }
+    }
+    let previous = match PREVIOUS.lock().unwrap().get(&signal) {
+        Some(previous) => *previous,
+        None => return,
+    };
+    match previous.sa_sigaction {
+        libc::SIG_IGN => {}
+        libc::SIG_DFL => unsafe {
+            // every supported signal terminates by default, hand it over for good
+            libc::sigaction(signal, &previous, ptr::null_mut());
+            libc::raise(signal);
+        },
+        handler if previous.sa_flags & libc::SA_SIGINFO != 0 => unsafe {
+            let action: extern "C" fn(libc::c_int, *mut libc::siginfo_t, *mut libc::c_void) =
+                mem::transmute(handler);
+            let mut info: libc::siginfo_t = mem::zeroed();
+            info.si_signo = signal;
+            action(signal, &mut info, ptr::null_mut());
+        },
+        handler => unsafe {
+            let action: extern "C" fn(libc::c_int) = mem::transmute(handler);
+            action(signal);
+        },
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Cancel;
+    use std::sync::atomic::AtomicUsize;
+    use std::time::{Duration, Instant};
+
+    #[derive(Clone, Default)]
+    struct FlushCount(Arc<AtomicUsize>);
+
+    impl Flush for FlushCount {
+        fn flush(&self) -> io::Result<()> {
+            self.0.fetch_add(1, SeqCst);
+            Ok(())
+        }
+    }
+
+    static PREVIOUS_CALLED: AtomicUsize = AtomicUsize::new(0);
+
+    extern "C" fn previous_handler(_signal: libc::c_int) {
+        PREVIOUS_CALLED.fetch_add(1, SeqCst);
+    }
+
+    fn wait_previous(count: usize) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while PREVIOUS_CALLED.load(SeqCst) < count && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert_eq!(count, PREVIOUS_CALLED.load(SeqCst));
+    }
+
+    #[test]
+    fn flush_then_previous_handler() {
+        let handler: extern "C" fn(libc::c_int) = previous_handler;
+        unsafe { libc::signal(libc::SIGUSR2, handler as libc::sighandler_t) };
+
+        let flushed = FlushCount::default();
+        let cancelled = FlushCount::default();
+        flushed.flush_on_signal(&[Signal::Usr2]).unwrap();
+        cancelled.flush_on_signal(&[Signal::Usr2]).unwrap().cancel();
+        unsafe { libc::raise(libc::SIGUSR2) };
+        wait_previous(1);
+        assert_eq!(1, flushed.0.load(SeqCst));
+        assert_eq!(0, cancelled.0.load(SeqCst));
+
+        // still installed, chained again
+        unsafe { libc::raise(libc::SIGUSR2) };
+        wait_previous(2);
+        assert_eq!(2, flushed.0.load(SeqCst));
+        assert_eq!(0, cancelled.0.load(SeqCst));
+    }
+}
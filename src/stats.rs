@@ -29,15 +29,43 @@ pub fn stats_all(
     name: MetricName,
     score: ScoreType,
 ) -> Option<(InputKind, MetricName, MetricValue)> {
+    all_stats_named(kind, name, score, |name, suffix| name.make_name(suffix))
+}
+
+/// An export strategy reporting all aggregated stats for all metric types, like `stats_all`,
+/// but naming the stats with the provided function. The function receives each metric's name
+/// and the stat's default suffix (e.g. "count", "max"). Can be used to match local naming
+/// conventions, for example to publish `request_count` instead of `request.count`.
+pub fn stats_all_named<N>(
+    naming: N,
+) -> impl Fn(InputKind, MetricName, ScoreType) -> Option<(InputKind, MetricName, MetricValue)>
+       + Send
+       + Sync
+       + 'static
+where
+    N: Fn(MetricName, &str) -> MetricName + Send + Sync + 'static,
+{
+    move |kind, name, score| all_stats_named(kind, name, score, &naming)
+}
+
+fn all_stats_named<N>(
+    kind: InputKind,
+    name: MetricName,
+    score: ScoreType,
+    naming: N,
+) -> Option<(InputKind, MetricName, MetricValue)>
+where
+    N: Fn(MetricName, &str) -> MetricName,
+{
     match score {
-        ScoreType::Count(hit) => Some((InputKind::Counter, name.make_name("count"), hit)),
-        ScoreType::Sum(sum) => Some((kind, name.make_name("sum"), sum)),
-        ScoreType::Mean(mean) => Some((kind, name.make_name("mean"), mean.round() as MetricValue)),
-        ScoreType::Max(max) => Some((InputKind::Gauge, name.make_name("max"), max)),
-        ScoreType::Min(min) => Some((InputKind::Gauge, name.make_name("min"), min)),
+        ScoreType::Count(hit) => Some((InputKind::Counter, naming(name, "count"), hit)),
+        ScoreType::Sum(sum) => Some((kind, naming(name, "sum"), sum)),
+        ScoreType::Mean(mean) => Some((kind, naming(name, "mean"), mean.round() as MetricValue)),
+        ScoreType::Max(max) => Some((InputKind::Gauge, naming(name, "max"), max)),
+        ScoreType::Min(min) => Some((InputKind::Gauge, naming(name, "min"), min)),
         ScoreType::Rate(rate) => Some((
             InputKind::Gauge,
-            name.make_name("rate"),
+            naming(name, "rate"),
             rate.round() as MetricValue,
         )),
     }
@@ -69,6 +97,7 @@ pub fn stats_average(
 /// - Timers and Counters each export their sums
 /// - Markers each export their hit count
 /// - Gauges each export their average
+///
 /// Since there is only one stat per metric, there is no risk of collision
 /// and so exported stats copy their metric's name.
 #[allow(dead_code)]
@@ -82,7 +111,7 @@ pub fn stats_summary(
             ScoreType::Count(count) => Some((InputKind::Counter, name, count)),
             _ => None,
         },
-        InputKind::Counter | InputKind::Timer => match score {
+        InputKind::Counter | InputKind::Timer | InputKind::Distribution => match score {
             ScoreType::Sum(sum) => Some((kind, name, sum)),
             _ => None,
         },
@@ -92,3 +121,26 @@ pub fn stats_summary(
         },
     }
 }
+
+/// Generate `count` histogram bucket bounds, the first being `start`
+/// and each following bound being `factor` times the previous one.
+/// Useful to cover values spanning several orders of magnitude, such as latencies.
+///
+/// ```
+/// let bounds = dipstick::exponential_buckets(1.0, 2.0, 4);
+/// assert_eq!(vec![1.0, 2.0, 4.0, 8.0], bounds);
+/// ```
+///
+/// # Panics
+/// If `start` is not positive, `factor` is not greater than one or `count` is zero.
+pub fn exponential_buckets(start: f64, factor: f64, count: usize) -> Vec<f64> {
+    assert!(start > 0.0, "exponential buckets start must be positive");
+    assert!(
+        factor > 1.0,
+        "exponential buckets factor must be greater than 1"
+    );
+    assert!(count > 0, "exponential buckets count must be positive");
+    std::iter::successors(Some(start), |bound| Some(bound * factor))
+        .take(count)
+        .collect()
+}
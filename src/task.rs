@@ -0,0 +1,105 @@
+//! Scope metrics names by asynchronous task.
+//! Thread-local state does not follow tasks that move between threads across `.await` points.
+//! Instead, the task's names are installed while its future is being polled.
+
+use crate::attributes::Prefixed;
+use crate::name::NameParts;
+use crate::proxy::Proxy;
+
+use std::cell::RefCell;
+use std::future::Future;
+use std::mem;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+thread_local! {
+    static TASK_NAMES: RefCell<Option<NameParts>> = const { RefCell::new(None) };
+}
+
+/// Wrap a future so that `current_metrics()` returns a proxy prefixed with the provided name
+/// whenever it is called from within the future.
+/// Instrumented futures can be nested, in which case names accumulate.
+pub fn instrument<S: Into<String>, F: Future>(prefix: S, future: F) -> Instrumented<F> {
+    let mut names = TASK_NAMES
+        .with(|names| names.borrow().clone())
+        .unwrap_or_default();
+    names.push_back(prefix.into());
+    Instrumented {
+        names: Some(names),
+        inner: future,
+    }
+}
+
+/// Returns the default metrics proxy, prefixed with the names of the currently polled
+/// instrumented future(s), if any.
+pub fn current_metrics() -> Proxy {
+    TASK_NAMES.with(|names| match &*names.borrow() {
+        Some(names) => names
+            .iter()
+            .fold(Proxy::default(), |proxy, name| proxy.add_name(name.clone())),
+        None => Proxy::default(),
+    })
+}
+
+/// A future running with task-scoped metrics names.
+/// See [instrument](fn.instrument.html).
+#[derive(Debug)]
+pub struct Instrumented<F> {
+    names: Option<NameParts>,
+    inner: F,
+}
+
+/// Swaps the task's names back out of the thread, even if the inner future panics.
+struct Restore<'a>(&'a mut Option<NameParts>);
+
+impl Drop for Restore<'_> {
+    fn drop(&mut self) {
+        TASK_NAMES.with(|names| mem::swap(&mut *names.borrow_mut(), self.0))
+    }
+}
+
+impl<F: Future> Future for Instrumented<F> {
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // SAFETY: the inner future is never moved out of its pinned wrapper
+        let this = unsafe { self.get_unchecked_mut() };
+        let inner = unsafe { Pin::new_unchecked(&mut this.inner) };
+
+        TASK_NAMES.with(|names| mem::swap(&mut *names.borrow_mut(), &mut this.names));
+        let _restore = Restore(&mut this.names);
+        inner.poll(cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::task::Waker;
+
+    fn poll_once<F: Future>(future: F) -> Poll<F::Output> {
+        let mut future = Box::pin(future);
+        future
+            .as_mut()
+            .poll(&mut Context::from_waker(Waker::noop()))
+    }
+
+    #[test]
+    fn task_names() {
+        let polled = poll_once(instrument("request", async {
+            let outer = current_metrics().get_prefixes().clone();
+            let inner = instrument("db", async { current_metrics().get_prefixes().clone() }).await;
+            (outer, inner)
+        }));
+
+        if let Poll::Ready((outer, inner)) = polled {
+            assert_eq!(vec!["request"], outer.iter().collect::<Vec<_>>());
+            assert_eq!(vec!["request", "db"], inner.iter().collect::<Vec<_>>());
+        } else {
+            panic!("future was not ready")
+        }
+
+        // names do not leak out of the task
+        assert!(current_metrics().get_prefixes().is_empty());
+    }
+}
@@ -0,0 +1,270 @@
+//! Capture metrics and assert their values in tests.
+
+use crate::attributes::{Attributes, MetricId, OnFlush, Prefixed, WithAttributes};
+use crate::clock::Clock;
+use crate::input::{Input, InputKind, InputMetric, InputScope};
+use crate::name::MetricName;
+use crate::{Flush, MetricValue};
+
+use std::collections::BTreeMap;
+use std::io;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+#[cfg(not(feature = "parking_lot"))]
+use std::sync::RwLock;
+
+#[cfg(feature = "parking_lot")]
+use parking_lot::RwLock;
+
+/// A test harness bundling a sink capturing every written value, assertions on captured values
+/// and control of the clock timing the timers defined from the sink.
+/// Other metrics and scopes keep measuring time with the system clock.
+///
+/// ```
+/// use dipstick::*;
+/// use std::time::Duration;
+///
+/// let tm = TestMetrics::new();
+/// let metrics = tm.sink().metrics();
+/// let timer = metrics.timer("request");
+///
+/// let start = timer.start();
+/// tm.clock.advance(Duration::from_millis(1));
+/// timer.stop(start);
+///
+/// tm.assert_timer("request", 1000);
+/// ```
+#[derive(Clone)]
+pub struct TestMetrics {
+    /// The clock timing the timers defined from the sink.
+    pub clock: MockClock,
+    sink: TestSink,
+}
+
+impl Default for TestMetrics {
+    fn default() -> Self {
+        let clock = MockClock::default();
+        let mut sink = TestSink::default();
+        sink.attributes.set_clock(clock.source());
+        TestMetrics { clock, sink }
+    }
+}
+
+impl TestMetrics {
+    /// Create a harness with an empty sink and a clock set to the current time.
+    pub fn new() -> TestMetrics {
+        TestMetrics::default()
+    }
+
+    /// The input capturing values for this harness' assertions.
+    pub fn sink(&self) -> TestSink {
+        self.sink.clone()
+    }
+
+    /// All values written to the metric, in order.
+    pub fn values(&self, name: &str) -> Vec<MetricValue> {
+        self.sink
+            .captured(name)
+            .map(|(_, values)| values)
+            .unwrap_or_default()
+    }
+
+    /// Assert that values were written to the counter or marker, summing to `total`.
+    pub fn assert_count(&self, name: &str, total: MetricValue) {
+        let values = self.expect_kind(name, &[InputKind::Counter, InputKind::Marker]);
+        assert_eq!(
+            total,
+            values.iter().sum::<MetricValue>(),
+            "total count of {}",
+            name
+        );
+    }
+
+    /// Assert that the last value written to the gauge or level is `value`.
+    pub fn assert_gauge(&self, name: &str, value: MetricValue) {
+        let values = self.expect_kind(name, &[InputKind::Gauge, InputKind::Level]);
+        assert_eq!(Some(&value), values.last(), "last value of {}", name);
+    }
+
+    /// Assert that the last interval recorded by the timer is `micros` microseconds.
+    pub fn assert_timer(&self, name: &str, micros: MetricValue) {
+        let values = self.expect_kind(name, &[InputKind::Timer]);
+        assert_eq!(Some(&micros), values.last(), "last interval of {}", name);
+    }
+
+    /// Assert that no value was written to the metric.
+    pub fn assert_absent(&self, name: &str) {
+        let values = self.values(name);
+        assert!(values.is_empty(), "{} has values {:?}", name, values);
+    }
+
+    fn expect_kind(&self, name: &str, kinds: &[InputKind]) -> Vec<MetricValue> {
+        match self.sink.captured(name) {
+            Some((kind, values)) => {
+                assert!(kinds.contains(&kind), "{} is a {:?}", name, kind);
+                values
+            }
+            None => panic!("No value written to {}", name),
+        }
+    }
+}
+
+/// A clock that only moves when told to, shared by its clones.
+#[derive(Debug, Clone)]
+pub struct MockClock {
+    now: Arc<RwLock<Instant>>,
+}
+
+impl Default for MockClock {
+    fn default() -> Self {
+        MockClock {
+            now: Arc::new(RwLock::new(Instant::now())),
+        }
+    }
+}
+
+impl MockClock {
+    /// Move time forward.
+    pub fn advance(&self, period: Duration) {
+        *write_lock!(self.now) += period
+    }
+
+    /// Set the clock to the current time.
+    pub fn reset(&self) {
+        *write_lock!(self.now) = Instant::now()
+    }
+
+    fn source(&self) -> Clock {
+        Clock::mock(self.now.clone())
+    }
+}
+
+type Captured = BTreeMap<String, (InputKind, Vec<MetricValue>)>;
+
+/// An input capturing every value written to its metrics, by name.
+/// Clones and scopes of the sink share the captured values.
+#[derive(Clone, Default)]
+pub struct TestSink {
+    attributes: Attributes,
+    captured: Arc<RwLock<Captured>>,
+}
+
+impl TestSink {
+    fn captured(&self, name: &str) -> Option<(InputKind, Vec<MetricValue>)> {
+        read_lock!(self.captured).get(name).cloned()
+    }
+}
+
+impl WithAttributes for TestSink {
+    fn get_attributes(&self) -> &Attributes {
+        &self.attributes
+    }
+    fn mut_attributes(&mut self) -> &mut Attributes {
+        &mut self.attributes
+    }
+}
+
+impl Input for TestSink {
+    type SCOPE = TestSink;
+
+    fn metrics(&self) -> Self::SCOPE {
+        self.clone()
+    }
+}
+
+impl InputScope for TestSink {
+    fn new_metric(&self, name: MetricName, kind: InputKind) -> InputMetric {
+        let name = self.prefix_append(name);
+        let key = name.join(".");
+        let captured = self.captured.clone();
+        InputMetric::new(MetricId::forge("test", name), move |value, _labels| {
+            write_lock!(captured)
+                .entry(key.clone())
+                .or_insert_with(|| (kind, vec![]))
+                .1
+                .push(value)
+        })
+        .with_clock(self.attributes.clock())
+    }
+}
+
+impl Flush for TestSink {
+    fn flush(&self) -> io::Result<()> {
+        self.notify_flush_listeners();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{stats_summary, AtomicBucket, Prefixed};
+
+    #[test]
+    fn assert_captured() {
+        let tm = TestMetrics::new();
+        let metrics = tm.sink().metrics().named("app");
+        metrics.counter("requests").count(2);
+        metrics.marker("requests").mark();
+        metrics.gauge("queue").value(4);
+        metrics.gauge("queue").value(5);
+
+        tm.assert_count("app.requests", 3);
+        tm.assert_gauge("app.queue", 5);
+        tm.assert_absent("app.errors");
+        assert_eq!(vec![4, 5], tm.values("app.queue"));
+    }
+
+    #[test]
+    fn capture_bucket_drain() {
+        let tm = TestMetrics::new();
+        let bucket = AtomicBucket::new();
+        bucket.stats(stats_summary);
+        bucket.drain(tm.sink());
+        bucket.counter("requests").count(7);
+        bucket.gauge("queue").value(3);
+        bucket.flush().unwrap();
+
+        tm.assert_count("requests", 7);
+        tm.assert_gauge("queue", 3);
+    }
+
+    #[test]
+    fn assert_timed() {
+        let tm = TestMetrics::new();
+        let timer = tm.sink().timer("request");
+        tm.clock.advance(Duration::from_millis(5));
+        tm.clock.reset();
+
+        let start = timer.start();
+        tm.clock.advance(Duration::from_millis(2));
+        timer.stop(start);
+
+        tm.assert_timer("request", 2000);
+    }
+
+    #[test]
+    fn clock_per_harness() {
+        let tm = TestMetrics::new();
+        let other = TestMetrics::new();
+        let timer = tm.sink().timer("request");
+        let other_timer = other.sink().timer("request");
+
+        let (start, other_start) = (timer.start(), other_timer.start());
+        tm.clock.advance(Duration::from_millis(2));
+        timer.stop(start);
+        other_timer.stop(other_start);
+
+        tm.assert_timer("request", 2000);
+        other.assert_timer("request", 0);
+    }
+
+    #[test]
+    #[should_panic]
+    fn assert_wrong_kind() {
+        let tm = TestMetrics::new();
+        tm.sink().metrics().counter("requests").count(1);
+        tm.assert_gauge("requests", 1);
+    }
+}
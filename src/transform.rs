@@ -0,0 +1,315 @@
+//! Metric value transformation.
+
+use crate::attributes::{Attributes, MetricId, OnFlush, Prefixed, WithAttributes};
+use crate::input::{InputKind, InputMetric, InputScope, OutOfRange};
+use crate::metrics;
+use crate::name::MetricName;
+use crate::pcg32;
+use crate::{Flush, MetricValue};
+
+use std::sync::Arc;
+use std::{fmt, io};
+
+/// Convert the values of all metrics of a kind before they are forwarded to the scope,
+/// e.g. to publish timers in milliseconds or byte counts in megabytes.
+/// The transform is called upon every write and should be cheap.
+pub trait Transformed: InputScope + Send + Sync + 'static + Sized {
+    /// Wrap this scope with a transform applied to every value written to metrics of the specified kind.
+    /// Metrics of other kinds are left untouched. Wrap again to transform more than one kind.
+    fn with_transform<F>(self, kind: InputKind, transform: F) -> TransformScope
+    where
+        F: Fn(MetricValue) -> MetricValue + Send + Sync + 'static,
+    {
+        self.with_pipeline(ValuePipeline::new().stage(kind, move |value| Some(transform(value))))
+    }
+
+    /// Wrap this scope with a pipeline of stages applied in order to every value written to its metrics.
+    fn with_pipeline(self, pipeline: ValuePipeline) -> TransformScope {
+        TransformScope {
+            attributes: Attributes::default(),
+            target: Arc::new(self),
+            pipeline,
+        }
+    }
+
+    /// Wrap this scope to multiply counter and marker values by the factor, e.g. 10 if a tenth of a fleet reports,
+    /// so that central totals approximate those of the whole fleet. Other kinds of metrics are left untouched.
+    /// Buckets count markers without their values: wrap the scope markers are published to instead.
+    fn fleet_scale(self, factor: f64) -> TransformScope {
+        self.with_pipeline(
+            ValuePipeline::new()
+                .scale(InputKind::Counter, factor)
+                .scale(InputKind::Marker, factor),
+        )
+    }
+}
+
+impl<T: InputScope + Send + Sync + 'static> Transformed for T {}
+
+/// A function processing the value written to a metric of a kind, returning None to drop the value.
+pub type ValueFn =
+    Arc<dyn Fn(InputKind, MetricValue) -> Option<MetricValue> + Send + Sync + 'static>;
+
+type StageFn = Arc<dyn Fn(MetricValue) -> Option<MetricValue> + Send + Sync + 'static>;
+
+/// An ordered chain of stages processing metric values before they are written,
+/// e.g. `ValuePipeline::new().scale(InputKind::Timer, 0.001).clamp(InputKind::Gauge, 0, 100)`.
+/// Each stage only applies to metrics of its kind. A stage dropping a value ends the pipeline.
+#[derive(Clone, Default)]
+pub struct ValuePipeline {
+    stages: Vec<(InputKind, StageFn)>,
+}
+
+impl fmt::Debug for ValuePipeline {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ValuePipeline({} stages)", self.stages.len())
+    }
+}
+
+impl ValuePipeline {
+    /// Create an empty pipeline, passing values through unchanged.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a stage processing values of the kind, returning None to drop a value.
+    pub fn stage<F>(mut self, kind: InputKind, stage: F) -> Self
+    where
+        F: Fn(MetricValue) -> Option<MetricValue> + Send + Sync + 'static,
+    {
+        self.stages.push((kind, Arc::new(stage)));
+        self
+    }
+
+    /// Multiply values by the factor, e.g. `0.001` to publish timers in milliseconds.
+    pub fn scale(self, kind: InputKind, factor: f64) -> Self {
+        self.stage(kind, move |value| {
+            Some((value as f64 * factor).round() as MetricValue)
+        })
+    }
+
+    /// Replace values outside of `min..=max` by the nearest bound.
+    /// Panics if `min` is greater than `max`.
+    pub fn clamp(self, kind: InputKind, min: MetricValue, max: MetricValue) -> Self {
+        assert!(min <= max, "Clamp min {} is greater than max {}", min, max);
+        self.stage(kind, move |value| Some(value.clamp(min, max)))
+    }
+
+    /// Round values to the nearest multiple of the step, e.g. to reduce the cardinality of published values.
+    pub fn quantize(self, kind: InputKind, step: MetricValue) -> Self {
+        self.stage(kind, move |value| {
+            if step <= 0 {
+                return Some(value);
+            }
+            let half = if value < 0 { -step / 2 } else { step / 2 };
+            Some(value.saturating_add(half) / step * step)
+        })
+    }
+
+    /// Clamp or drop values outside of `min..=max`, counted by the `dipstick.input.value_clamped`
+    /// and `dipstick.input.value_out_of_range` internal metrics, e.g. to catch nonsensical values.
+    /// Panics if `min` is greater than `max`.
+    pub fn range(
+        self,
+        kind: InputKind,
+        min: MetricValue,
+        max: MetricValue,
+        out_of_range: OutOfRange,
+    ) -> Self {
+        assert!(min <= max, "Range min {} is greater than max {}", min, max);
+        self.stage(kind, move |value| {
+            if (min..=max).contains(&value) {
+                return Some(value);
+            }
+            match out_of_range {
+                OutOfRange::Clamp => {
+                    metrics::VALUE_CLAMPED.mark();
+                    Some(value.clamp(min, max))
+                }
+                OutOfRange::Drop => {
+                    metrics::VALUE_OUT_OF_RANGE.mark();
+                    None
+                }
+            }
+        })
+    }
+
+    /// Randomly keep a fraction of the values, e.g. `0.1` to keep one value in ten.
+    /// Kept values are not scaled up.
+    pub fn sample(self, kind: InputKind, rate: f64) -> Self {
+        let int_rate = pcg32::to_int_rate(rate);
+        self.stage(kind, move |value| {
+            if pcg32::accept_sample(int_rate) {
+                Some(value)
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Returns true if any stage processes values of the kind.
+    pub fn applies_to(&self, kind: InputKind) -> bool {
+        self.stages
+            .iter()
+            .any(|(stage_kind, _)| *stage_kind == kind)
+    }
+
+    /// Process a value written to a metric of the kind, returning None if a stage dropped it.
+    pub fn apply(&self, kind: InputKind, value: MetricValue) -> Option<MetricValue> {
+        self.stages
+            .iter()
+            .filter(|(stage_kind, _)| *stage_kind == kind)
+            .try_fold(value, |value, (_, stage)| stage(value))
+    }
+
+    /// Only keep the stages processing values of the kind.
+    fn only(&self, kind: InputKind) -> Self {
+        let stages = self
+            .stages
+            .iter()
+            .filter(|(stage_kind, _)| *stage_kind == kind);
+        ValuePipeline {
+            stages: stages.cloned().collect(),
+        }
+    }
+
+    /// Combine the stages into a single function.
+    pub fn build(self) -> ValueFn {
+        Arc::new(move |kind, value| self.apply(kind, value))
+    }
+}
+
+/// Input scope wrapper transforming the values written to its metrics.
+#[derive(Clone)]
+pub struct TransformScope {
+    attributes: Attributes,
+    target: Arc<dyn InputScope + Send + Sync + 'static>,
+    pipeline: ValuePipeline,
+}
+
+impl WithAttributes for TransformScope {
+    fn get_attributes(&self) -> &Attributes {
+        &self.attributes
+    }
+    fn mut_attributes(&mut self) -> &mut Attributes {
+        &mut self.attributes
+    }
+}
+
+impl InputScope for TransformScope {
+    fn new_metric(&self, name: MetricName, kind: InputKind) -> InputMetric {
+        let name = self.prefix_append(name);
+        let target_metric = self.target.new_metric(name.clone(), kind);
+        if !self.pipeline.applies_to(kind) {
+            return target_metric;
+        }
+        let pipeline = self.pipeline.only(kind);
+        InputMetric::new(MetricId::forge("transform", name), move |value, labels| {
+            if let Some(value) = pipeline.apply(kind, value) {
+                target_metric.write(value, labels)
+            }
+        })
+    }
+}
+
+impl Flush for TransformScope {
+    fn flush(&self) -> io::Result<()> {
+        self.notify_flush_listeners();
+        self.target.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::output::map::StatsMapScope;
+
+    #[test]
+    fn transform_kind_only() {
+        let map = StatsMapScope::default();
+        let metrics = map
+            .clone()
+            .with_transform(InputKind::Timer, |us| us / 1000)
+            .named("app");
+
+        metrics.timer("latency").interval_us(25_000);
+        metrics.counter("bytes").count(25_000);
+
+        let map = map.into_map();
+        assert_eq!(Some(&25), map.get("app.latency"));
+        assert_eq!(Some(&25_000), map.get("app.bytes"));
+    }
+
+    #[test]
+    fn fleet_scale_additive_only() {
+        let map = StatsMapScope::default();
+        let metrics = map.clone().fleet_scale(4.0);
+
+        metrics.counter("bytes").count(10);
+        metrics.marker("requests").mark();
+        metrics.gauge("queue").value(10);
+        metrics.timer("latency").interval_us(10);
+
+        let map = map.into_map();
+        assert_eq!(Some(&40), map.get("bytes"));
+        assert_eq!(Some(&4), map.get("requests"));
+        assert_eq!(Some(&10), map.get("queue"));
+        assert_eq!(Some(&10), map.get("latency"));
+    }
+
+    #[test]
+    fn pipeline_in_order() {
+        let map = StatsMapScope::default();
+        let pipeline = ValuePipeline::new()
+            .scale(InputKind::Gauge, 2.0)
+            .clamp(InputKind::Gauge, 0, 100)
+            .quantize(InputKind::Gauge, 10)
+            .stage(InputKind::Gauge, |value| (value != 20).then_some(value))
+            .sample(InputKind::Counter, 0.0);
+        let metrics = map.clone().with_pipeline(pipeline);
+
+        metrics.gauge("scaled").value(17);
+        metrics.gauge("clamped").value(80);
+        metrics.gauge("dropped").value(10);
+        metrics.counter("sampled").count(1);
+        metrics.timer("untouched").interval_us(17);
+
+        let map = map.into_map();
+        assert_eq!(Some(&30), map.get("scaled"));
+        assert_eq!(Some(&100), map.get("clamped"));
+        assert_eq!(None, map.get("dropped"));
+        assert_eq!(None, map.get("sampled"));
+        assert_eq!(Some(&17), map.get("untouched"));
+    }
+
+    #[test]
+    #[should_panic(expected = "greater than max")]
+    fn clamp_inverted_bounds() {
+        let _ = ValuePipeline::new().clamp(InputKind::Gauge, 100, 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "greater than max")]
+    fn range_inverted_bounds() {
+        let _ = ValuePipeline::new().range(InputKind::Gauge, 100, 0, OutOfRange::Drop);
+    }
+
+    #[test]
+    fn pipeline_pass_through() {
+        let map = StatsMapScope::default();
+        let metrics = map
+            .clone()
+            .with_pipeline(ValuePipeline::new().quantize(InputKind::Gauge, 10));
+
+        let gauge = metrics.new_metric("gauge".into(), InputKind::Gauge);
+        let counter = metrics.new_metric("counter".into(), InputKind::Counter);
+        assert_eq!("transform:gauge", gauge.metric_id().as_str());
+        assert_ne!("transform:counter", counter.metric_id().as_str());
+
+        gauge.write(MetricValue::MAX, labels![]);
+        assert_eq!(
+            Some(&(MetricValue::MAX / 10 * 10)),
+            map.into_map().get("gauge")
+        );
+    }
+}